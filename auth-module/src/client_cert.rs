@@ -0,0 +1,173 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use http::StatusCode;
+use log::{info, trace};
+use pandora_module_utils::pingora::{Error, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::RequestFilterResult;
+
+use crate::AuthConf;
+
+pub(crate) async fn client_cert_auth(
+    conf: &AuthConf,
+    session: &mut impl SessionWrapper,
+) -> Result<RequestFilterResult, Box<Error>> {
+    let Some(certificate) = session.client_certificate() else {
+        trace!("Rejecting request, no client certificate");
+        error_response(session, StatusCode::FORBIDDEN).await?;
+        return Ok(RequestFilterResult::ResponseSent);
+    };
+
+    let common_name = certificate.common_name.as_deref();
+    let authorized = conf
+        .auth_client_subjects
+        .iter()
+        .any(|subject| *subject == certificate.subject || Some(subject.as_str()) == common_name);
+    if !authorized {
+        info!(
+            "Rejecting request, client certificate subject {} isn't authorized",
+            certificate.subject
+        );
+        error_response(session, StatusCode::FORBIDDEN).await?;
+        return Ok(RequestFilterResult::ResponseSent);
+    }
+
+    let user = common_name.unwrap_or(&certificate.subject).to_owned();
+    session.set_remote_user(user);
+    Ok(RequestFilterResult::Unhandled)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use pandora_module_utils::pingora::{
+        create_test_session, ClientCertificate, RequestHeader, Session,
+    };
+    use pandora_module_utils::standard_response::response_text;
+    use pandora_module_utils::{DeserializeMap, FromYaml, RequestFilter};
+    use startup_module::{AppResult, DefaultApp};
+    use test_log::test;
+
+    use super::*;
+    use crate::AuthHandler;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct CertificateConf {
+        subject: Option<String>,
+        common_name: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct CertificateHandler {
+        conf: CertificateConf,
+    }
+
+    #[async_trait]
+    impl RequestFilter for CertificateHandler {
+        type Conf = CertificateConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn early_request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            if let Some(subject) = &self.conf.subject {
+                session.set_client_certificate(ClientCertificate {
+                    subject: subject.clone(),
+                    common_name: self.conf.common_name.clone(),
+                    ..Default::default()
+                });
+            }
+            Ok(())
+        }
+    }
+
+    impl TryFrom<CertificateConf> for CertificateHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: CertificateConf) -> Result<Self, Self::Error> {
+            Ok(Self { conf })
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct Handler {
+        certificate: CertificateHandler,
+        auth: AuthHandler,
+    }
+
+    fn make_app(certificate: &str) -> DefaultApp<Handler> {
+        let conf = format!(
+            r#"
+auth_mode: client-cert
+auth_client_subjects:
+- alice
+- CN=bob,O=Example
+{certificate}
+            "#
+        );
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session() -> Session {
+        let header = RequestHeader::build("GET", b"/", None).unwrap();
+        create_test_session(header).await
+    }
+
+    fn check_forbidden(result: &mut AppResult) {
+        assert_eq!(result.session().response_written().unwrap().status, 403);
+        assert_eq!(result.body_str(), response_text(StatusCode::FORBIDDEN));
+        assert_eq!(result.session().remote_user(), None);
+    }
+
+    #[test(tokio::test)]
+    async fn no_certificate() {
+        let mut app = make_app("");
+        let mut result = app.handle_request(make_session().await).await;
+        check_forbidden(&mut result);
+    }
+
+    #[test(tokio::test)]
+    async fn unknown_subject() {
+        let mut app = make_app("subject: CN=eve,O=Example\ncommon_name: eve");
+        let mut result = app.handle_request(make_session().await).await;
+        check_forbidden(&mut result);
+    }
+
+    #[test(tokio::test)]
+    async fn common_name() {
+        let mut app = make_app("subject: CN=alice,O=Example\ncommon_name: alice");
+        let mut result = app.handle_request(make_session().await).await;
+        assert_eq!(result.session().remote_user(), Some("alice"));
+    }
+
+    #[test(tokio::test)]
+    async fn full_subject() {
+        let mut app = make_app("subject: CN=bob,O=Example\ncommon_name: bob");
+        let mut result = app.handle_request(make_session().await).await;
+        assert_eq!(result.session().remote_user(), Some("bob"));
+
+        let mut app = make_app("subject: CN=bob,O=Other\ncommon_name: bob");
+        let mut result = app.handle_request(make_session().await).await;
+        check_forbidden(&mut result);
+    }
+}
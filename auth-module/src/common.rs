@@ -48,6 +48,9 @@ pub(crate) fn is_rate_limited(
         limited = true;
     }
     if limits.per_ip > 0 {
+        // Clients connecting via a Unix socket have no meaningful address, these share a single
+        // key with clients of unknown address. Typically, these requests are forwarded by another
+        // local server, the Real IP module can be used to recover the actual client addresses.
         let ip = session
             .client_addr()
             .and_then(|addr| match addr {
@@ -15,6 +15,7 @@
 #![doc = include_str!("../README.md")]
 
 mod basic;
+mod client_cert;
 mod common;
 mod page;
 
@@ -23,13 +24,14 @@ use clap::Parser;
 use http::Uri;
 use log::{error, info};
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
 use serde::{de::Unexpected, Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use std::time::Duration;
 
 use basic::basic_auth;
+use client_cert::client_cert_auth;
 use page::page_auth;
 
 /// Authentication mode
@@ -41,6 +43,9 @@ pub enum AuthMode {
     /// Webpage-based authentication
     #[default]
     Page,
+    /// TLS client certificate authentication
+    #[serde(rename = "client-cert")]
+    ClientCert,
 }
 
 impl FromStr for AuthMode {
@@ -50,6 +55,7 @@ impl FromStr for AuthMode {
         match s {
             "http" => Ok(Self::HTTP),
             "page" => Ok(Self::Page),
+            "client-cert" => Ok(Self::ClientCert),
             _ => Err(Error::explain(
                 ErrorType::InternalError,
                 "invalid auth mode value",
@@ -74,7 +80,7 @@ pub struct AuthOpt {
     /// command line flag to generate a password hash without third-party tools.
     #[clap(long)]
     pub auth_credentials: Option<Vec<String>>,
-    /// Authentication mode, either "http" or "page"
+    /// Authentication mode, either "http", "page" or "client-cert"
     #[clap(long)]
     pub auth_mode: Option<AuthMode>,
     /// The authentication realm to communicate to the browser (HTTP mode only)
@@ -199,7 +205,7 @@ where
 }
 
 /// Session settings (page mode only)
-#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Clone, PartialEq, Eq, DeserializeMap)]
 pub struct AuthPageSession {
     /// URI path of the page to be used for logging in instead of the default login page.
     #[pandora(deserialize_with = "deserialize_uri")]
@@ -229,6 +235,22 @@ pub struct AuthPageSession {
     pub session_expiration: Duration,
 }
 
+impl std::fmt::Debug for AuthPageSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Make sure the secret doesn’t show up when the configuration is printed out
+        f.debug_struct("AuthPageSession")
+            .field("login_page", &self.login_page)
+            .field(
+                "token_secret",
+                &self.token_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("cookie_name", &self.cookie_name)
+            .field("secure_cookie", &self.secure_cookie)
+            .field("session_expiration", &self.session_expiration)
+            .finish()
+    }
+}
+
 impl Default for AuthPageSession {
     fn default() -> Self {
         Self {
@@ -242,7 +264,7 @@ impl Default for AuthPageSession {
 }
 
 /// Authentication configuration
-#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+#[derive(Clone, PartialEq, Eq, DeserializeMap)]
 pub struct AuthConf {
     /// If `true`, the credentials of failed login attempts will be displayed on the resulting
     /// 401 Unauthorized page.
@@ -251,6 +273,9 @@ pub struct AuthConf {
     /// Accepted credentials by user name
     pub auth_credentials: HashMap<String, String>,
 
+    /// Accepted client certificate subjects or common names (client certificate mode only)
+    pub auth_client_subjects: OneOrMany<String>,
+
     /// Login rate limits
     ///
     /// Note that in Basic HTTP mode each request is a “login”
@@ -269,6 +294,27 @@ pub struct AuthConf {
     pub auth_page_session: AuthPageSession,
 }
 
+impl std::fmt::Debug for AuthConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Password hashes shouldn’t show up when the configuration is printed out
+        let credentials = self
+            .auth_credentials
+            .keys()
+            .map(|user| (user, "<redacted>"))
+            .collect::<BTreeMap<_, _>>();
+        f.debug_struct("AuthConf")
+            .field("auth_display_hash", &self.auth_display_hash)
+            .field("auth_credentials", &credentials)
+            .field("auth_client_subjects", &self.auth_client_subjects)
+            .field("auth_rate_limits", &self.auth_rate_limits)
+            .field("auth_mode", &self.auth_mode)
+            .field("auth_realm", &self.auth_realm)
+            .field("auth_page_strings", &self.auth_page_strings)
+            .field("auth_page_session", &self.auth_page_session)
+            .finish()
+    }
+}
+
 impl AuthConf {
     /// Merges the command line options into the current configuration. Command line options
     /// present overwrite existing settings, with the exception of `--auth-credentials` that adds
@@ -304,6 +350,7 @@ impl Default for AuthConf {
         Self {
             auth_display_hash: false,
             auth_credentials: HashMap::new(),
+            auth_client_subjects: Default::default(),
             auth_rate_limits: Default::default(),
             auth_mode: AuthMode::Page,
             auth_realm: "Server authentication".to_owned(),
@@ -355,13 +402,45 @@ impl RequestFilter for AuthHandler {
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        if self.conf.auth_credentials.is_empty() {
+        let unconfigured = if self.conf.auth_mode == AuthMode::ClientCert {
+            self.conf.auth_client_subjects.is_empty()
+        } else {
+            self.conf.auth_credentials.is_empty()
+        };
+        if unconfigured {
             return Ok(RequestFilterResult::Unhandled);
         }
 
         match self.conf.auth_mode {
             AuthMode::HTTP => basic_auth(&self.conf, session).await,
             AuthMode::Page => page_auth(&self.conf, session).await,
+            AuthMode::ClientCert => client_cert_auth(&self.conf, session).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::FromYaml;
+
+    #[test]
+    fn debug_redacted() {
+        let conf = AuthConf::from_yaml(
+            r#"
+auth_credentials:
+    me: $2y$12$iuKHb5UsRqktrX2X9.iSEOP1n1.tS7s/KB.Dq3HlE0E6CxlfsJyZK
+auth_page_session:
+    token_secret: abcdef0123456789
+            "#,
+        )
+        .unwrap();
+        let debug = format!("{conf:#?}");
+        assert!(debug.contains("\"me\""));
+        assert!(!debug.contains("$2y$12$"));
+        // First byte of the token secret
+        assert!(!debug.contains("171"));
+        assert!(debug.contains("<redacted>"));
+    }
+}
@@ -55,6 +55,8 @@ pub enum LogField {
     BytesSent,
     /// Time it took to process the request, `processing_time` in config file
     ProcessingTime,
+    /// ID assigned to the request, `request_id` in config file
+    RequestId,
     /// A request header, `http_<header>` in config file
     RequestHeader(HeaderName),
     /// A response header, `sent_http_<header>` in config file
@@ -76,6 +78,7 @@ impl TryFrom<&str> for LogField {
             "status" => Ok(Self::Status),
             "bytes_sent" => Ok(Self::BytesSent),
             "processing_time" => Ok(Self::ProcessingTime),
+            "request_id" => Ok(Self::RequestId),
             name => {
                 if let Some(header) = name.strip_prefix("http_") {
                     let header = header.replace('_', "-");
@@ -147,7 +150,7 @@ mod tests {
 
     #[test]
     fn log_field_parsing() {
-        let log_fields: Vec<_> = "remote_addr - remote_name time_local request status bytes_sent http_referer http_user_agent processing_time sent_http_content_type remote_port time_iso8601".split_ascii_whitespace().map(|s| {
+        let log_fields: Vec<_> = "remote_addr - remote_name time_local request status bytes_sent http_referer http_user_agent processing_time sent_http_content_type remote_port time_iso8601 request_id".split_ascii_whitespace().map(|s| {
             LogField::try_from(s).unwrap()
         }).collect();
         assert_eq!(
@@ -166,6 +169,7 @@ mod tests {
                 LogField::ResponseHeader(header::CONTENT_TYPE),
                 LogField::RemotePort,
                 LogField::TimeISO,
+                LogField::RequestId,
             ]
         );
         assert!(LogField::try_from("unsupported_field").is_err());
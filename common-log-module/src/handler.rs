@@ -28,6 +28,35 @@ use tokio::sync::mpsc::{channel, Sender};
 use crate::configuration::{CommonLogConf, LogField};
 use crate::writer::{log_writer, LogToken, WriterMessage};
 
+static LOG_SENDER: Lazy<Arc<Sender<WriterMessage>>> = Lazy::new(|| {
+    let (sender, receiver) = channel(100);
+
+    tokio::spawn(async move { log_writer(receiver).await });
+
+    #[cfg(unix)]
+    crate::signal::listen(&sender);
+
+    Arc::new(sender)
+});
+
+/// Closes and reopens all log files, e.g. after these have been rotated.
+///
+/// This has the same effect as sending `SIGHUP` or `SIGUSR1` signal to the process. It will block
+/// and shouldn’t be called from an async context.
+pub fn reopen_log_files() -> Result<(), Box<Error>> {
+    // If nothing has been logged yet, there are no open log files.
+    if let Some(sender) = Lazy::get(&LOG_SENDER) {
+        sender.blocking_send(WriterMessage::Reopen).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                "failed reopening log files, thread crashed?",
+                err,
+            )
+        })?;
+    }
+    Ok(())
+}
+
 fn normalize_path(path: PathBuf) -> Result<PathBuf, Box<Error>> {
     if path.as_os_str().is_empty() || path.as_os_str() == "-" {
         // Don't change special paths
@@ -149,6 +178,13 @@ impl RequestFilter for CommonLogHandler {
                     let version = &header.version;
                     LogToken::Request(format!("{method} {uri} {version:?}"))
                 }
+                LogField::RequestId => {
+                    if let Some(request_id) = session.request_id() {
+                        LogToken::RequestId(request_id.to_owned())
+                    } else {
+                        LogToken::None
+                    }
+                }
                 LogField::RequestHeader(name) => {
                     if let Some(value) = session.req_header().headers.get(name) {
                         LogToken::Header(value.clone())
@@ -189,6 +225,7 @@ impl RequestFilter for CommonLogHandler {
                 | LogField::TimeLocal
                 | LogField::TimeISO
                 | LogField::Request
+                | LogField::RequestId
                 | LogField::RequestHeader(_) => {
                     // This is a token we’ve added previously. Panic if we don’t have one, it’s
                     // a bug that needs investigating.
@@ -228,17 +265,6 @@ impl RequestFilter for CommonLogHandler {
             });
         }
 
-        static LOG_SENDER: Lazy<Arc<Sender<WriterMessage>>> = Lazy::new(|| {
-            let (sender, receiver) = channel(100);
-
-            tokio::spawn(async move { log_writer(receiver).await });
-
-            #[cfg(unix)]
-            crate::signal::listen(&sender);
-
-            Arc::new(sender)
-        });
-
         let message = WriterMessage::log_data(ctx.time, &self.conf.log_file, tokens);
         if let Err(err) = Arc::make_mut(&mut (*LOG_SENDER).clone())
             .send(message)
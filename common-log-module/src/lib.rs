@@ -21,4 +21,4 @@ mod signal;
 mod writer;
 
 pub use configuration::{CommonLogConf, CommonLogOpt};
-pub use handler::CommonLogHandler;
+pub use handler::{reopen_log_files, CommonLogHandler};
@@ -37,6 +37,7 @@ pub(crate) enum LogToken {
     Status(u16),
     BytesSent(usize),
     ProcessingTime(Duration),
+    RequestId(String),
     Header(HeaderValue),
 }
 
@@ -110,9 +111,9 @@ fn stringify_data(buf: &mut Vec<u8>, time: SystemTime, tokens: Vec<LogToken>) {
             }
             LogToken::RemoteAddr(SocketAddr::Unix(addr)) => {
                 if let Some(path) = addr.as_pathname().and_then(|p| p.as_os_str().to_str()) {
-                    write!(buf, "{path}")
+                    write!(buf, "unix:{path}")
                 } else {
-                    write!(buf, "-")
+                    write!(buf, "unix:")
                 }
             }
             LogToken::RemotePort(SocketAddr::Inet(addr)) => {
@@ -134,6 +135,7 @@ fn stringify_data(buf: &mut Vec<u8>, time: SystemTime, tokens: Vec<LogToken>) {
             LogToken::ProcessingTime(time) => {
                 write!(buf, "{:.3}", time.as_secs_f32() * 1000.0)
             }
+            LogToken::RequestId(request_id) => write_escaped(buf, request_id),
             LogToken::Header(value) => write_escaped(buf, value),
         };
     }
@@ -201,13 +203,31 @@ mod tests {
             LogToken::ProcessingTime(Duration::from_nanos(1234567)),
             LogToken::RemotePort(SocketAddr::Inet("127.0.0.1:8080".parse().unwrap())),
             LogToken::TimeISO,
+            LogToken::RequestId("0123abcd".to_owned()),
         ];
 
         let mut buf = Vec::new();
         stringify_data(&mut buf, time, tokens);
         assert_eq!(
             String::from_utf8(buf).unwrap(),
-            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00]\n"
+            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00] \"0123abcd\"\n"
         );
     }
+
+    #[test]
+    fn unix_socket_addr() {
+        let time = SystemTime::UNIX_EPOCH;
+        let tokens = vec![
+            LogToken::RemoteAddr(SocketAddr::Unix(
+                std::os::unix::net::SocketAddr::from_pathname("/run/client.sock").unwrap(),
+            )),
+            LogToken::RemotePort(SocketAddr::Unix(
+                std::os::unix::net::SocketAddr::from_pathname("/run/client.sock").unwrap(),
+            )),
+        ];
+
+        let mut buf = Vec::new();
+        stringify_data(&mut buf, time, tokens);
+        assert_eq!(String::from_utf8(buf).unwrap(), "unix:/run/client.sock -\n");
+    }
 }
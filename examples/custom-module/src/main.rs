@@ -76,9 +76,10 @@ fn main() {
     conf.handler.auth.merge_with_opt(opt.auth);
     conf.handler.web_app.merge_with_opt(opt.web_app);
 
-    let server = match DefaultApp::<Handler>::from_conf(conf.handler)
-        .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
-    {
+    let server = match DefaultApp::<Handler>::from_conf(conf.handler).and_then(|app| {
+        conf.startup
+            .into_server_with_h2c(app.clone(), app, Some(opt.startup))
+    }) {
         Ok(server) => server,
         Err(err) => {
             error!("{err}");
@@ -196,6 +196,7 @@ mod tests {
                 Some(
                     UpstreamConf {
                         upstream: Some("http://127.0.0.1".try_into().unwrap()),
+                        ..Default::default()
                     }
                     .try_into()
                     .unwrap(),
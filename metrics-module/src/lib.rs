@@ -0,0 +1,422 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use http::{header, Method, StatusCode};
+use log::trace;
+use once_cell::sync::Lazy;
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
+use pandora_module_utils::standard_response::error_response;
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
+use pingora::apps::prometheus_http_app::PrometheusServer;
+use pingora::services::listening::Service;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::time::Instant;
+
+/// Metrics configuration
+#[derive(Debug, Clone, PartialEq, Eq, Default, DeserializeMap)]
+pub struct MetricsConf {
+    /// If `true`, metrics will be collected for all requests
+    pub metrics_enabled: bool,
+
+    /// Path on which the metrics should be served, e.g. `/metrics`
+    ///
+    /// Metrics are shared by all handlers in the process, so these are served even if
+    /// `metrics_enabled` is `false` for this handler.
+    pub metrics_path: Option<String>,
+
+    /// Addresses on which the metrics should be served separately from the web server
+    pub metrics_listen: OneOrMany<String>,
+}
+
+impl MetricsConf {
+    /// Creates a service serving the metrics on the addresses configured in `metrics_listen`.
+    ///
+    /// This will return `None` if no addresses are configured. The service needs to be added to
+    /// the server via `Server::add_service()`.
+    pub fn to_service(&self) -> Option<Service<PrometheusServer>> {
+        if self.metrics_listen.is_empty() {
+            return None;
+        }
+
+        let mut service = Service::prometheus_http_service();
+        for addr in &self.metrics_listen {
+            service.add_tcp(addr);
+        }
+        Some(service)
+    }
+}
+
+/// Metrics collected by the module
+struct Metrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    upstream_connect_errors: IntCounterVec,
+    in_flight: IntGauge,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+    requests: register_int_counter_vec!(
+        "pandora_requests_total",
+        "Number of requests processed",
+        &["vhost", "status_class", "handler"]
+    )
+    .unwrap(),
+    duration: register_histogram_vec!(
+        "pandora_request_duration_seconds",
+        "Time it took to process a request",
+        &["vhost", "status_class", "handler"]
+    )
+    .unwrap(),
+    upstream_connect_errors: register_int_counter_vec!(
+        "pandora_upstream_connect_errors_total",
+        "Number of failed attempts to connect to an upstream server",
+        &["vhost"]
+    )
+    .unwrap(),
+    in_flight: register_int_gauge!(
+        "pandora_requests_in_flight",
+        "Number of requests currently being processed"
+    )
+    .unwrap(),
+});
+
+fn status_class(status: Option<u16>) -> &'static str {
+    match status {
+        Some(100..=199) => "1xx",
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        _ => "unknown",
+    }
+}
+
+fn is_connect_error(e: &Error) -> bool {
+    matches!(
+        e.etype,
+        ErrorType::ConnectTimedout
+            | ErrorType::ConnectRefused
+            | ErrorType::ConnectNoRoute
+            | ErrorType::ConnectProxyFailure
+            | ErrorType::ConnectError
+            | ErrorType::BindError
+            | ErrorType::TLSHandshakeFailure
+            | ErrorType::TLSHandshakeTimedout
+            | ErrorType::InvalidCert
+            | ErrorType::HandshakeError
+    )
+}
+
+/// Context for the metrics handler
+#[derive(Debug, Default)]
+pub struct MetricsCtx {
+    start: Option<Instant>,
+}
+
+/// Metrics module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsHandler {
+    conf: MetricsConf,
+}
+
+impl TryFrom<MetricsConf> for MetricsHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: MetricsConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for MetricsHandler {
+    type Conf = MetricsConf;
+
+    type CTX = MetricsCtx;
+
+    fn new_ctx() -> Self::CTX {
+        Default::default()
+    }
+
+    async fn early_request_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if self.conf.metrics_enabled {
+            METRICS.in_flight.inc();
+            ctx.start = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.conf.metrics_path.as_deref() != Some(session.uri().path()) {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        let method = &session.req_header().method;
+        if method != Method::GET && method != Method::HEAD {
+            error_response(session, StatusCode::METHOD_NOT_ALLOWED).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        trace!("serving metrics");
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder
+            .encode(&prometheus::gather(), &mut buffer)
+            .map_err(|err| {
+                Error::because(ErrorType::InternalError, "failed encoding metrics", err)
+            })?;
+
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(3))?;
+        header.insert_header(header::CONTENT_TYPE, encoder.format_type())?;
+        header.insert_header(header::CONTENT_LENGTH, buffer.len())?;
+        header.insert_header(header::CACHE_CONTROL, "no-store")?;
+
+        let send_body = session.req_header().method != Method::HEAD;
+        session
+            .write_response_header(Box::new(header), !send_body)
+            .await?;
+        if send_body {
+            session
+                .write_response_body(Some(buffer.into()), true)
+                .await?;
+        }
+
+        Ok(RequestFilterResult::ResponseSent)
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        let Some(start) = ctx.start.take() else {
+            return;
+        };
+        METRICS.in_flight.dec();
+
+        let status = session
+            .response_written()
+            .map(|header| header.status.as_u16())
+            .or_else(|| match e.map(|e| &e.etype) {
+                Some(ErrorType::HTTPStatus(status)) => Some(*status),
+                _ => None,
+            });
+        let vhost = session.virtual_host().unwrap_or_default();
+        let labels = [
+            vhost,
+            status_class(status),
+            session.handled_by().unwrap_or_default(),
+        ];
+        METRICS.requests.with_label_values(&labels).inc();
+        METRICS
+            .duration
+            .with_label_values(&labels)
+            .observe(start.elapsed().as_secs_f64());
+
+        if e.is_some_and(is_connect_error) {
+            METRICS
+                .upstream_connect_errors
+                .with_label_values(&[vhost])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use response_module::ResponseHandler;
+    use startup_module::{AppResult, DefaultApp};
+    use test_log::test;
+    use upstream_module::UpstreamHandler;
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct Handler {
+        metrics: MetricsHandler,
+        response: ResponseHandler,
+        upstream: UpstreamHandler,
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<Handler> {
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(method: &str, path: &str) -> Session {
+        let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+        create_test_session(header).await
+    }
+
+    fn requests(status_class: &str, handler: &str) -> u64 {
+        METRICS
+            .requests
+            .with_label_values(&["", status_class, handler])
+            .get()
+    }
+
+    fn samples(status_class: &str, handler: &str) -> u64 {
+        METRICS
+            .duration
+            .with_label_values(&["", status_class, handler])
+            .get_sample_count()
+    }
+
+    async fn serve_metrics(app: &mut DefaultApp<Handler>, method: &str) -> AppResult {
+        let session = make_session(method, "/metrics").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().handled_by(), Some("metrics"));
+
+        let session = result.session();
+        let header = session.response_written().unwrap();
+        assert_eq!(header.status, 200);
+        assert_eq!(
+            header.headers.get("Content-Type").unwrap(),
+            TextEncoder::new().format_type()
+        );
+        drop(session);
+        result
+    }
+
+    #[test(tokio::test)]
+    async fn records_requests() {
+        let mut app = make_app(
+            r#"
+                metrics_enabled: true
+                response: Hi!
+            "#,
+        );
+
+        let before = requests("2xx", "response");
+        let samples_before = samples("2xx", "response");
+
+        let session = make_session("GET", "/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().handled_by(), Some("response"));
+
+        assert!(requests("2xx", "response") > before);
+        assert!(samples("2xx", "response") > samples_before);
+    }
+
+    #[test(tokio::test)]
+    async fn disabled() {
+        let mut app = make_app(
+            r#"
+                metrics_path: /metrics
+                upstream: http://127.0.0.1
+            "#,
+        );
+
+        let before = requests("2xx", "upstream");
+
+        let session = make_session("GET", "/").await;
+        let result = app
+            .handle_request_with_upstream(session, |_, _| ResponseHeader::build(200, None))
+            .await;
+        assert!(result.err().is_none());
+
+        assert_eq!(requests("2xx", "upstream"), before);
+
+        // Metrics are still served
+        serve_metrics(&mut app, "GET").await;
+    }
+
+    #[test(tokio::test)]
+    async fn connect_errors() {
+        let mut app = make_app(
+            r#"
+                metrics_enabled: true
+                upstream: http://127.0.0.1
+            "#,
+        );
+
+        let errors = || {
+            METRICS
+                .upstream_connect_errors
+                .with_label_values(&[""])
+                .get()
+        };
+        let before = errors();
+        let unknown_before = requests("unknown", "upstream");
+
+        let session = make_session("GET", "/").await;
+        let result = app
+            .handle_request_with_upstream(session, |_, _| {
+                Err(Error::new(ErrorType::ConnectRefused))
+            })
+            .await;
+        assert!(result.err().is_some());
+
+        assert!(errors() > before);
+        assert!(requests("unknown", "upstream") > unknown_before);
+    }
+
+    #[test(tokio::test)]
+    async fn serves_metrics() {
+        let mut app = make_app(
+            r#"
+                metrics_enabled: true
+                metrics_path: /metrics
+                response: Hi!
+            "#,
+        );
+
+        let session = make_session("GET", "/").await;
+        let result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+
+        let result = serve_metrics(&mut app, "GET").await;
+        let body = result.body_str();
+        assert!(body.contains("pandora_requests_total{"));
+        assert!(body.contains("handler=\"response\""));
+        assert!(body.contains("pandora_request_duration_seconds_bucket{"));
+        assert!(body.contains("pandora_requests_in_flight "));
+
+        let result = serve_metrics(&mut app, "HEAD").await;
+        assert_eq!(result.body_str(), "");
+
+        let session = make_session("POST", "/metrics").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().response_written().unwrap().status, 405);
+
+        // Other paths are not affected
+        let session = make_session("GET", "/metrics/").await;
+        let mut result = app.handle_request(session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().handled_by(), Some("response"));
+    }
+}
@@ -135,6 +135,10 @@ fn generate_request_filter_impl(
                     #(
                         let result = self.#field_name.request_filter(_session, &mut _ctx.#field_name).await?;
                         if result != ::pandora_module_utils::RequestFilterResult::Unhandled {
+                            ::pandora_module_utils::pingora::SessionWrapper::set_handled_by(
+                                _session,
+                                ::std::stringify!(#field_name),
+                            );
                             return ::std::result::Result::Ok(result);
                         }
                     )*
@@ -154,12 +158,40 @@ fn generate_request_filter_impl(
                         if let ::std::option::Option::Some(peer) =
                             self.#field_name.upstream_peer(_session, &mut _ctx.#field_name).await?
                         {
+                            ::pandora_module_utils::pingora::SessionWrapper::set_handled_by(
+                                _session,
+                                ::std::stringify!(#field_name),
+                            );
                             return ::std::result::Result::Ok(::std::option::Option::Some(peer));
                         }
                     )*
                     ::std::result::Result::Ok(::std::option::Option::None)
                 }
 
+                async fn connected_to_upstream(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _reused: bool,
+                    _peer: &::pandora_module_utils::pingora::HttpPeer,
+                    _fd: ::std::os::unix::io::RawFd,
+                    _ctx: &mut Self::CTX,
+                ) -> ::std::result::Result<
+                    (),
+                    ::std::boxed::Box<::pandora_module_utils::pingora::Error>
+                >
+                {
+                    #(
+                        self.#field_name.connected_to_upstream(
+                            _session,
+                            _reused,
+                            _peer,
+                            _fd,
+                            &mut _ctx.#field_name,
+                        ).await?;
+                    )*
+                    ::std::result::Result::Ok(())
+                }
+
                 async fn logging(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -177,11 +177,12 @@ async fn handler() -> Result<(), Box<Error>> {
     let handler = Handler::<String, u32>::try_from(conf).unwrap();
     let mut app = DefaultApp::new(handler);
 
-    let result = app.handle_request(session).await;
+    let mut result = app.handle_request(session).await;
     assert_eq!(
         result.err().as_ref().map(|err| &err.etype),
         Some(&ErrorType::HTTPStatus(404))
     );
+    assert_eq!(result.session().handled_by(), None);
 
     let header = RequestHeader::build("GET", "/".as_bytes(), None)?;
     let session = create_test_session(header).await;
@@ -191,8 +192,9 @@ async fn handler() -> Result<(), Box<Error>> {
     handler.handler1.handle_request = true;
     let mut app = DefaultApp::new(handler);
 
-    let result = app.handle_request(session).await;
+    let mut result = app.handle_request(session).await;
     assert!(result.err().is_none());
+    assert_eq!(result.session().handled_by(), Some("handler1"));
 
     Ok(())
 }
@@ -404,7 +406,7 @@ fn from_yaml_seed() {
         .unwrap();
         assert_hash_eq(&conf.conf1.value1, vec![("hi", 1234)]);
         assert_eq!(conf.conf1.value2, 12);
-        assert_eq!(conf.conf2.value3, Vec::new());
+        assert_eq!(conf.conf2.value3, Vec::<bool>::new());
         assert_eq!(conf.conf2.value4, String::new());
 
         let conf = conf.merge_from_yaml("value3: [true, false]").unwrap();
@@ -30,9 +30,10 @@ use serde::{de::DeserializeSeed, Deserialize};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::BufReader;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 
-pub use deserialize::{DeserializeMap, MapVisitor, OneOrMany, _private};
+pub use deserialize::{_private, DeserializeMap, MapVisitor, OneOrMany};
 pub use pandora_module_utils_macros::{merge_conf, merge_opt, DeserializeMap, RequestFilter};
 
 // Required for macros
@@ -127,6 +128,19 @@ pub trait RequestFilter: Sized {
         Ok(None)
     }
 
+    /// Handler to run during Pingora’s `connected_to_upstream` phase, see
+    /// [`pingora::ProxyHttp::connected_to_upstream`].
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _reused: bool,
+        _peer: &HttpPeer,
+        _fd: RawFd,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        Ok(())
+    }
+
     /// Handler to run during Pingora’s `logging` phase, see [`pingora::ProxyHttp::logging`].
     async fn logging(
         &self,
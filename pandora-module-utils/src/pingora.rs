@@ -130,6 +130,77 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         self.extensions_mut().insert(RemoteUser(remote_user));
     }
 
+    /// Returns the verified TLS client certificate of the connection if any
+    fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.extensions().get()
+    }
+
+    /// Sets the verified TLS client certificate of the connection
+    fn set_client_certificate(&mut self, certificate: ClientCertificate) {
+        self.extensions_mut().insert(certificate);
+    }
+
+    /// Returns the ID assigned to this request if any
+    fn request_id(&self) -> Option<&str> {
+        if let Some(RequestId(request_id)) = self.extensions().get() {
+            Some(request_id)
+        } else {
+            None
+        }
+    }
+
+    /// Sets the ID of this request, used to correlate log entries
+    fn set_request_id(&mut self, request_id: String) {
+        self.extensions_mut().insert(RequestId(request_id));
+    }
+
+    /// Returns the name of the listening address this request was received on if it has a name
+    fn listener(&self) -> Option<&str> {
+        if let Some(Listener(name)) = self.extensions().get() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Sets the name of the listening address this request was received on
+    fn set_listener(&mut self, name: String) {
+        self.extensions_mut().insert(Listener(name));
+    }
+
+    /// Returns the name of the virtual host handling this request if any
+    fn virtual_host(&self) -> Option<&str> {
+        if let Some(VirtualHost(name)) = self.extensions().get() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Sets the name of the virtual host handling this request
+    fn set_virtual_host(&mut self, name: String) {
+        self.extensions_mut().insert(VirtualHost(name));
+    }
+
+    /// Returns the name of the handler that handled this request if any
+    ///
+    /// This is the name of the field in a structure deriving `RequestFilter`. For nested handlers
+    /// like virtual hosts, the name of the innermost handler is returned.
+    fn handled_by(&self) -> Option<&'static str> {
+        if let Some(HandledBy(name)) = self.extensions().get() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Records the name of the handler that handled this request unless one is set already
+    ///
+    /// This is called automatically by handlers deriving `RequestFilter`.
+    fn set_handled_by(&mut self, name: &'static str) {
+        self.extensions_mut().get_or_insert(HandledBy(name));
+    }
+
     /// See [`Session::response_written`](pingora::protocols::http::server::Session::response_written)
     fn response_written(&self) -> Option<&ResponseHeader> {
         self.deref().response_written()
@@ -147,10 +218,43 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     }
 }
 
+/// Details of a verified TLS client certificate
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Certificate subject in RFC 4514 format, e.g. `CN=alice,O=Example`
+    pub subject: String,
+
+    /// Common name from the certificate subject if present
+    pub common_name: Option<String>,
+
+    /// DNS names, email addresses and URIs listed as subject alternative names
+    pub subject_alt_names: Vec<String>,
+
+    /// SHA-256 fingerprint of the certificate as lowercase hex string
+    pub fingerprint: String,
+}
+
 /// Type used to store remote user’s name in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct RemoteUser(String);
 
+/// Type used to store the request ID in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Type used to store listening address name in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct Listener(String);
+
+/// Type used to store virtual host name in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct VirtualHost(String);
+
+/// Type used to store the name of the handler that handled the request in
+/// `SessionWrapper::extensions`
+#[derive(Debug, Clone, Copy)]
+struct HandledBy(&'static str);
+
 /// Type used to store original request URI in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct OriginalUri(Uri);
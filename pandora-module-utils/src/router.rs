@@ -182,12 +182,22 @@ impl<Value> Router<Value> {
         } else {
             None
         }
-        .or_else(|| self.fallback.lookup(make_key("", path)))
+        .or_else(|| {
+            // Fallback values are indexed after the host-specific ones
+            self.fallback
+                .lookup(make_key("", path))
+                .map(|result| result.offset_index(self.trie.value_count()))
+        })
     }
 
     /// Retrieves the value from a previous lookup by its index
     pub fn retrieve(&self, index: usize) -> Option<&Value> {
-        self.trie.retrieve(index)
+        let count = self.trie.value_count();
+        if index < count {
+            self.trie.retrieve(index)
+        } else {
+            self.fallback.retrieve(index - count)
+        }
     }
 }
 
@@ -494,5 +504,14 @@ mod tests {
         // of the path, essentially causing everything after the slash to be ignored. As such, this
         // is not an issue but it might become one as the implementation changes.
         assert_eq!(lookup(&router, "localhost/def", "/abc"), Some(2));
+
+        for (host, path) in [
+            ("localhost", "/abc"),
+            ("example.com", "/x"),
+            ("example.net", "/abc"),
+        ] {
+            let result = router.lookup(host, path).unwrap();
+            assert_eq!(router.retrieve(result.index()), Some(result.as_value()));
+        }
     }
 }
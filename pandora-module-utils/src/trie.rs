@@ -92,6 +92,12 @@ impl<'a, Value> LookupResult<'a, Value> {
         self.index
     }
 
+    /// Shifts the index, used when combining multiple tries into one index space
+    pub(crate) fn offset_index(mut self, offset: usize) -> Self {
+        self.index += offset;
+        self
+    }
+
     /// Retrieves the inner value
     ///
     /// Unlike dereferencing, this propagates lifetimes properly
@@ -221,6 +227,11 @@ impl<Value> Trie<Value> {
         self.values.get(index)
     }
 
+    /// Returns the number of values stored in the trie
+    pub(crate) fn value_count(&self) -> usize {
+        self.values.len()
+    }
+
     fn fmt_field(
         &self,
         f: &mut std::fmt::DebugStruct<'_, '_>,
@@ -16,11 +16,18 @@
 
 use clap::Parser;
 use log::error;
-use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
-use startup_module::{DefaultApp, StartupConf, StartupOpt};
+use pandora_module_utils::pingora::Error;
+use pandora_module_utils::{merge_conf, merge_opt, DeserializeMap, FromYaml, RequestFilter};
+use startup_module::{AdminApi, DefaultApp, StartupConf, StartupOpt};
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
+    #[cfg(feature = "metrics-top-level")]
+    metrics: metrics_module::MetricsHandler,
+    #[cfg(feature = "real-ip-top-level")]
+    real_ip: real_ip_module::RealIpHandler,
+    #[cfg(feature = "request-id-top-level")]
+    request_id: request_id_module::RequestIdHandler,
     #[cfg(feature = "ip-anonymization-top-level")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
     #[cfg(feature = "common-log-top-level")]
@@ -45,6 +52,7 @@ struct Handler {
         feature = "compression-per-host",
         feature = "headers-per-host",
         feature = "ip-anonymization-per-host",
+        feature = "request-id-per-host",
         feature = "rewrite-per-host",
         feature = "response-per-host",
         feature = "static-files-per-host",
@@ -55,6 +63,8 @@ struct Handler {
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct HostHandler {
+    #[cfg(feature = "request-id-per-host")]
+    request_id: request_id_module::RequestIdHandler,
     #[cfg(feature = "ip-anonymization-per-host")]
     anonymization: ip_anonymization_module::IPAnonymizationHandler,
     #[cfg(feature = "common-log-per-host")]
@@ -91,11 +101,61 @@ struct Opt {
     static_files: static_files_module::StaticFilesOpt,
 }
 
+/// Configuration of the handlers running on the TLS redirector
+#[derive(Debug, Default, DeserializeMap)]
+struct RedirectorConf {
+    /// Handler settings for the requests received by the TLS redirector
+    redirector_handler: <Handler as RequestFilter>::Conf,
+}
+
 /// The configuration of Pandora Web Server
 #[merge_conf]
 struct Conf {
     startup: StartupConf,
     handler: <Handler as RequestFilter>::Conf,
+    redirector: RedirectorConf,
+}
+
+/// Merges command line options into the configuration, returns the options of the Startup module
+#[allow(unused_variables)]
+fn apply_opt(conf: &mut Conf, opt: Opt) -> StartupOpt {
+    #[cfg(feature = "ip-anonymization-top-level")]
+    conf.handler.anonymization.merge_with_opt(opt.anonymization);
+    #[cfg(feature = "common-log-top-level")]
+    conf.handler.log.merge_with_opt(opt.log);
+    #[cfg(feature = "compression-top-level")]
+    conf.handler.compression.merge_with_opt(opt.compression);
+    #[cfg(feature = "auth-top-level")]
+    conf.handler.auth.merge_with_opt(opt.auth);
+    #[cfg(feature = "static-files-top-level")]
+    conf.handler.static_files.merge_with_opt(opt.static_files);
+
+    opt.startup
+}
+
+/// Produces the configuration shown by the admin API. This is only the handler configuration,
+/// startup settings aren’t applied on reload and are left out.
+fn applied_config(conf: &Conf) -> String {
+    format!(
+        "handler: {:#?}\nredirector_handler: {:#?}\n",
+        conf.handler, conf.redirector.redirector_handler
+    )
+}
+
+/// Reloads configuration files and produces new handlers for the server and the TLS redirector
+/// from them
+fn reload_handler() -> Result<(Handler, Handler, String), Box<Error>> {
+    // Command line options are unchanged, so parsing them again won’t fail.
+    let opt = Opt::parse();
+    let mut conf = Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[]))?;
+    apply_opt(&mut conf, opt);
+
+    let config = applied_config(&conf);
+    Ok((
+        conf.handler.try_into()?,
+        conf.redirector.redirector_handler.try_into()?,
+        config,
+    ))
 }
 
 fn main() {
@@ -103,7 +163,6 @@ fn main() {
 
     let opt = Opt::parse();
 
-    #[allow(unused_mut)]
     let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
         Ok(conf) => conf,
         Err(err) => {
@@ -111,21 +170,91 @@ fn main() {
             Conf::default()
         }
     };
+    let startup_opt = apply_opt(&mut conf, opt);
 
-    #[cfg(feature = "ip-anonymization-top-level")]
-    conf.handler.anonymization.merge_with_opt(opt.anonymization);
-    #[cfg(feature = "common-log-top-level")]
-    conf.handler.log.merge_with_opt(opt.log);
-    #[cfg(feature = "compression-top-level")]
-    conf.handler.compression.merge_with_opt(opt.compression);
-    #[cfg(feature = "auth-top-level")]
-    conf.handler.auth.merge_with_opt(opt.auth);
-    #[cfg(feature = "static-files-top-level")]
-    conf.handler.static_files.merge_with_opt(opt.static_files);
+    #[cfg(any(
+        feature = "auth-per-host",
+        feature = "common-log-per-host",
+        feature = "compression-per-host",
+        feature = "headers-per-host",
+        feature = "ip-anonymization-per-host",
+        feature = "request-id-per-host",
+        feature = "rewrite-per-host",
+        feature = "response-per-host",
+        feature = "static-files-per-host",
+        feature = "upstream-per-host"
+    ))]
+    conf.startup.check_listener_names(
+        Some(&startup_opt),
+        conf.handler
+            .virtual_hosts
+            .vhosts
+            .values()
+            .chain(
+                conf.redirector
+                    .redirector_handler
+                    .virtual_hosts
+                    .vhosts
+                    .values(),
+            )
+            .flat_map(|host| host.listeners.iter()),
+    );
 
-    let server = match DefaultApp::<Handler>::from_conf(conf.handler)
-        .and_then(|app| conf.startup.into_server(app, Some(opt.startup)))
+    let mut admin = AdminApi::default();
+    admin.set_config(applied_config(&conf));
+
+    #[cfg(feature = "metrics-top-level")]
+    let metrics_service = conf.handler.metrics.to_service();
+
+    let app = match DefaultApp::<Handler>::from_conf(conf.handler) {
+        Ok(app) => app,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    let redirector_app = match DefaultApp::<Handler>::from_conf(conf.redirector.redirector_handler)
     {
+        Ok(app) => app,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    let reloader = app.reloader();
+    let redirector_reloader = redirector_app.reloader();
+    admin.set_reload(move || {
+        let (handler, redirector_handler, config) = reload_handler()?;
+        reloader.reload(handler);
+        redirector_reloader.reload(redirector_handler);
+        Ok(config)
+    });
+
+    #[cfg(any(feature = "common-log-top-level", feature = "common-log-per-host"))]
+    admin.add_command("/reopen-logs", || {
+        common_log_module::reopen_log_files()?;
+        Ok("Log files reopened\n".to_owned())
+    });
+
+    #[cfg(any(feature = "upstream-top-level", feature = "upstream-per-host"))]
+    admin.add_query("/upstreams", || {
+        Ok(
+            upstream_module::check_upstream_health(std::time::Duration::from_secs(2))
+                .into_iter()
+                .map(|health| format!("{health}\n"))
+                .collect(),
+        )
+    });
+
+    #[allow(unused_mut)]
+    let mut server = match conf.startup.into_server_with_redirector(
+        app,
+        redirector_app,
+        Some(startup_opt),
+        admin,
+    ) {
         Ok(server) => server,
         Err(err) => {
             error!("{err}");
@@ -133,5 +262,10 @@ fn main() {
         }
     };
 
+    #[cfg(feature = "metrics-top-level")]
+    if let Some(service) = metrics_service {
+        server.add_service(service);
+    }
+
     server.run_forever();
 }
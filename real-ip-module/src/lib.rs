@@ -0,0 +1,595 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::trace;
+use pandora_module_utils::pingora::{Error, SessionWrapper, SocketAddr};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Header containing the client address
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RealIpHeader {
+    /// `X-Forwarded-For` header, a comma-separated list of addresses
+    #[default]
+    XForwardedFor,
+    /// `X-Real-IP` header, a single address
+    XRealIp,
+    /// `Forwarded` header as defined in RFC 7239
+    Forwarded,
+}
+
+/// Real IP configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RealIpConf {
+    /// Networks of the proxies trusted to provide the client address, the module is disabled if
+    /// this list is empty
+    pub real_ip_trusted: OneOrMany<IpNet>,
+
+    /// If `true`, clients connecting via Unix sockets are trusted to provide the client address
+    pub real_ip_trust_unix: bool,
+
+    /// The header to take the client address from
+    pub real_ip_header: RealIpHeader,
+}
+
+/// Real IP module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RealIpHandler {
+    conf: RealIpConf,
+}
+
+impl TryFrom<RealIpConf> for RealIpHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: RealIpConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+/// Parses an address with an optional port like `192.0.2.1`, `192.0.2.1:1234`, `2001:db8::1` or
+/// `[2001:db8::1]:1234`.
+fn parse_address(value: &str) -> Option<std::net::SocketAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some((canonical_ip(ip), 0).into());
+    }
+    if let Some(ip) = value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+    {
+        return ip
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| (canonical_ip(ip), 0).into());
+    }
+    value
+        .parse::<std::net::SocketAddr>()
+        .ok()
+        .map(|addr| (canonical_ip(addr.ip()), addr.port()).into())
+}
+
+/// Splits a `Forwarded` header value at the given separator, ignoring separators within quoted
+/// strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (pos, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted && c == separator {
+            result.push(&value[start..pos]);
+            start = pos + c.len_utf8();
+        }
+    }
+    result.push(&value[start..]);
+    result
+}
+
+/// Extracts the `for` parameter from an element of the `Forwarded` header
+fn forwarded_for(element: &str) -> Option<&str> {
+    split_unquoted(element, ';').into_iter().find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("for") {
+            let value = value.trim();
+            Some(
+                value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+impl RealIpHandler {
+    fn is_trusted(&self, addr: &SocketAddr) -> bool {
+        match addr {
+            SocketAddr::Inet(addr) => {
+                let ip = canonical_ip(addr.ip());
+                self.conf
+                    .real_ip_trusted
+                    .iter()
+                    .any(|net| net.contains(&ip))
+            }
+            SocketAddr::Unix(_) => self.conf.real_ip_trust_unix,
+        }
+    }
+
+    /// Collects the address chain from the request headers, `None` entries cannot be parsed.
+    fn address_chain(&self, session: &impl SessionWrapper) -> Vec<Option<std::net::SocketAddr>> {
+        let headers = &session.req_header().headers;
+        let values = |name: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        match self.conf.real_ip_header {
+            RealIpHeader::XForwardedFor => values("X-Forwarded-For")
+                .iter()
+                .map(|value| parse_address(value))
+                .collect(),
+            RealIpHeader::XRealIp => headers
+                .get("X-Real-IP")
+                .map(|value| value.to_str().ok().and_then(parse_address))
+                .into_iter()
+                .collect(),
+            // Elements without a `for` parameter result in `None`, so that processing stops there
+            RealIpHeader::Forwarded => headers
+                .get_all("Forwarded")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| split_unquoted(value, ','))
+                .map(|element| forwarded_for(element).and_then(parse_address))
+                .collect(),
+        }
+    }
+
+    fn real_client_addr(&self, session: &impl SessionWrapper) -> Option<SocketAddr> {
+        if !self.is_trusted(session.client_addr()?) {
+            return None;
+        }
+
+        let mut result = None;
+        for addr in self.address_chain(session).into_iter().rev() {
+            let Some(addr) = addr.map(SocketAddr::Inet) else {
+                break;
+            };
+
+            let trusted = self.is_trusted(&addr);
+            result = Some(addr);
+            if !trusted {
+                break;
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl RequestFilter for RealIpHandler {
+    type Conf = RealIpConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn early_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if self.conf.real_ip_trusted.is_empty() && !self.conf.real_ip_trust_unix {
+            return Ok(());
+        }
+
+        if let Some(addr) = self.real_client_addr(session) {
+            trace!("setting client address to {addr}");
+            session.set_client_addr(addr);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, ErrorType, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::DefaultApp;
+    use std::os::unix::net::SocketAddr as UnixSocketAddr;
+    use std::str::FromStr;
+    use test_log::test;
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct IPAddressConf {
+        ip_address: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct IPAddressHandler {
+        ip_address: String,
+    }
+
+    #[async_trait]
+    impl RequestFilter for IPAddressHandler {
+        type Conf = IPAddressConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn early_request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            if self.ip_address.starts_with('/') {
+                session.set_client_addr(SocketAddr::Unix(
+                    UnixSocketAddr::from_pathname(&self.ip_address).unwrap(),
+                ));
+            } else {
+                session.set_client_addr(SocketAddr::Inet(
+                    (IpAddr::from_str(&self.ip_address).unwrap(), 8000).into(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl TryFrom<IPAddressConf> for IPAddressHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: IPAddressConf) -> Result<Self, Self::Error> {
+            Ok(Self {
+                ip_address: conf.ip_address,
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct Handler {
+        address: IPAddressHandler,
+        real_ip: RealIpHandler,
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<Handler> {
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    async fn make_session(headers: &[(&str, &str)]) -> Session {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            header.append_header(String::from(*name), *value).unwrap();
+        }
+        create_test_session(header).await
+    }
+
+    async fn client_addr(app: &mut DefaultApp<Handler>, headers: &[(&str, &str)]) -> SocketAddr {
+        let session = make_session(headers).await;
+        let mut result = app.handle_request(session).await;
+        assert_eq!(
+            result.err().as_ref().map(|err| &err.etype),
+            Some(&ErrorType::HTTPStatus(404))
+        );
+        let addr = result.session().client_addr().unwrap().clone();
+        addr
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        SocketAddr::Inet(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            parse_address("192.0.2.1"),
+            Some("192.0.2.1:0".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address(" 192.0.2.1:1234 "),
+            Some("192.0.2.1:1234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("2001:db8::1"),
+            Some("[2001:db8::1]:0".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("[2001:db8::1]"),
+            Some("[2001:db8::1]:0".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("[2001:db8::1]:1234"),
+            Some("[2001:db8::1]:1234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("::ffff:192.0.2.1"),
+            Some("192.0.2.1:0".parse().unwrap())
+        );
+        assert_eq!(parse_address("unknown"), None);
+        assert_eq!(parse_address("_hidden"), None);
+        assert_eq!(parse_address(""), None);
+
+        assert_eq!(forwarded_for("for=192.0.2.1"), Some("192.0.2.1"));
+        assert_eq!(
+            forwarded_for("proto=https; For=\"[2001:db8::1]:1234\";by=10.0.0.1"),
+            Some("[2001:db8::1]:1234")
+        );
+        assert_eq!(forwarded_for("by=10.0.0.1"), None);
+        assert_eq!(
+            forwarded_for("host=\"a;for=192.0.2.1\";for=192.0.2.2"),
+            Some("192.0.2.2")
+        );
+
+        assert_eq!(
+            split_unquoted("for=192.0.2.1, for=\"x,\\\",y\", by=a", ','),
+            ["for=192.0.2.1", " for=\"x,\\\",y\"", " by=a"]
+        );
+        assert_eq!(split_unquoted("", ','), [""]);
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() {
+        let mut app = make_app(
+            r#"
+                ip_address: 127.0.0.1
+            "#,
+        );
+
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            addr("127.0.0.1:8000")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn untrusted_peer() {
+        let mut app = make_app(
+            r#"
+                ip_address: 192.0.2.2
+                real_ip_trusted: 127.0.0.0/8
+            "#,
+        );
+
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            addr("192.0.2.2:8000")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn unix_peer() {
+        let unix_addr = || SocketAddr::Unix(UnixSocketAddr::from_pathname("/run/proxy").unwrap());
+
+        // Unix sockets aren't trusted by default
+        let mut app = make_app(
+            r#"
+                ip_address: /run/proxy
+                real_ip_trusted: 127.0.0.0/8
+            "#,
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            unix_addr()
+        );
+
+        let mut app = make_app(
+            r#"
+                ip_address: /run/proxy
+                real_ip_trust_unix: true
+            "#,
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            addr("192.0.2.1:0")
+        );
+        assert_eq!(client_addr(&mut app, &[]).await, unix_addr());
+    }
+
+    #[test(tokio::test)]
+    async fn x_forwarded_for() {
+        let mut app = make_app(
+            r#"
+                ip_address: 10.0.0.2
+                real_ip_trusted: [10.0.0.0/8, "2001:db8::/32"]
+            "#,
+        );
+
+        // No header
+        assert_eq!(client_addr(&mut app, &[]).await, addr("10.0.0.2:8000"));
+
+        // Single address
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            addr("192.0.2.1:0")
+        );
+
+        // Trusted proxies are skipped, spoofed addresses are ignored
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[("X-Forwarded-For", "192.0.2.1, 198.51.100.7, 10.0.0.5")]
+            )
+            .await,
+            addr("198.51.100.7:0")
+        );
+
+        // Multiple headers are combined
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[
+                    ("X-Forwarded-For", "192.0.2.1"),
+                    ("X-Forwarded-For", "[2001:db8::1]:1234"),
+                ]
+            )
+            .await,
+            addr("192.0.2.1:0")
+        );
+
+        // All addresses are trusted, leftmost one is used
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "10.0.0.7, 10.0.0.5")]).await,
+            addr("10.0.0.7:0")
+        );
+
+        // Processing stops at invalid entries
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[("X-Forwarded-For", "192.0.2.1, garbage, 10.0.0.5")]
+            )
+            .await,
+            addr("10.0.0.5:0")
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "garbage")]).await,
+            addr("10.0.0.2:8000")
+        );
+
+        // Other headers are ignored
+        assert_eq!(
+            client_addr(&mut app, &[("X-Real-IP", "192.0.2.1")]).await,
+            addr("10.0.0.2:8000")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn x_real_ip() {
+        let mut app = make_app(
+            r#"
+                ip_address: ::ffff:127.0.0.1
+                real_ip_trusted: 127.0.0.1/32
+                real_ip_header: x-real-ip
+            "#,
+        );
+
+        assert_eq!(
+            client_addr(&mut app, &[("X-Real-IP", "2001:db8::1")]).await,
+            addr("[2001:db8::1]:0")
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("X-Real-IP", "garbage")]).await,
+            addr("[::ffff:127.0.0.1]:8000")
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("X-Forwarded-For", "192.0.2.1")]).await,
+            addr("[::ffff:127.0.0.1]:8000")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn forwarded() {
+        let mut app = make_app(
+            r#"
+                ip_address: 10.0.0.2
+                real_ip_trusted: 10.0.0.0/8
+                real_ip_header: forwarded
+            "#,
+        );
+
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[(
+                    "Forwarded",
+                    "for=192.0.2.1, for=\"[2001:db8::1]:1234\";proto=https, for=10.0.0.5"
+                )]
+            )
+            .await,
+            addr("[2001:db8::1]:1234")
+        );
+
+        // Elements without a for parameter stop processing
+        assert_eq!(
+            client_addr(&mut app, &[("Forwarded", "for=192.0.2.1, proto=https")]).await,
+            addr("10.0.0.2:8000")
+        );
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[("Forwarded", "for=192.0.2.1"), ("Forwarded", "proto=https")]
+            )
+            .await,
+            addr("10.0.0.2:8000")
+        );
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[("Forwarded", "for=192.0.2.1, by=10.0.0.6, for=10.0.0.5")]
+            )
+            .await,
+            addr("10.0.0.5:0")
+        );
+
+        // Commas within quoted strings don't separate elements
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[(
+                    "Forwarded",
+                    "for=192.0.2.1, for=198.51.100.7;host=\"a, for=10.0.0.5\""
+                )]
+            )
+            .await,
+            addr("198.51.100.7:0")
+        );
+
+        // Obfuscated identifiers stop processing
+        assert_eq!(
+            client_addr(
+                &mut app,
+                &[("Forwarded", "for=192.0.2.1, for=_hidden, for=10.0.0.5")]
+            )
+            .await,
+            addr("10.0.0.5:0")
+        );
+        assert_eq!(
+            client_addr(&mut app, &[("Forwarded", "for=unknown")]).await,
+            addr("10.0.0.2:8000")
+        );
+    }
+}
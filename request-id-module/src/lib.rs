@@ -0,0 +1,366 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+
+use async_trait::async_trait;
+use http::{HeaderName, HeaderValue};
+use log::{debug, trace};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpModule, HttpModuleBuilder, HttpModules, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::{DeserializeMap, RequestFilter};
+use serde::de::{Deserialize, Deserializer, Unexpected};
+use std::any::Any;
+use std::fmt::Write;
+
+/// Maximal length of a request ID accepted from the client
+const MAX_ID_LENGTH: usize = 128;
+
+/// Number of random bytes in a generated request ID
+const ID_BYTES: usize = 16;
+
+fn deserialize_header_name<'de, D>(deserializer: D) -> Result<HeaderName, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let name = String::deserialize(deserializer)?;
+    HeaderName::try_from(&name)
+        .map_err(|_| D::Error::invalid_value(Unexpected::Str(&name), &"HTTP header name"))
+}
+
+/// Request ID configuration
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RequestIdConf {
+    /// If `true`, an ID will be assigned to each request. This ID is passed on to upstream
+    /// servers and sent back to the client.
+    pub request_id_enabled: bool,
+
+    /// Name of the HTTP header carrying the request ID
+    #[pandora(deserialize_with = "deserialize_header_name")]
+    pub request_id_header: HeaderName,
+
+    /// If `true`, a request ID sent by the client will be used instead of generating a new one.
+    ///
+    /// This should only be enabled if the server is only reachable through a trusted proxy that
+    /// sets this header.
+    pub request_id_trust_incoming: bool,
+}
+
+impl Default for RequestIdConf {
+    fn default() -> Self {
+        Self {
+            request_id_enabled: false,
+            request_id_header: HeaderName::from_static("x-request-id"),
+            request_id_trust_incoming: false,
+        }
+    }
+}
+
+struct RequestIdHttpModuleBuilder {}
+
+impl HttpModuleBuilder for RequestIdHttpModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(RequestIdHttpModule { header: None })
+    }
+}
+
+struct RequestIdHttpModule {
+    header: Option<(HeaderName, HeaderValue)>,
+}
+
+#[async_trait]
+impl HttpModule for RequestIdHttpModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        if let Some((name, value)) = &self.header {
+            resp.insert_header(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Request ID module handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestIdHandler {
+    conf: RequestIdConf,
+}
+
+impl TryFrom<RequestIdConf> for RequestIdHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: RequestIdConf) -> Result<Self, Self::Error> {
+        Ok(Self { conf })
+    }
+}
+
+fn is_valid_id(id: &[u8]) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() && *byte != b'"' && *byte != b'\\')
+}
+
+fn generate_id() -> Result<String, Box<Error>> {
+    let mut bytes = [0; ID_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|err| {
+        Error::because(
+            ErrorType::InternalError,
+            "failed generating random request ID",
+            err,
+        )
+    })?;
+
+    let mut id = String::with_capacity(ID_BYTES * 2);
+    for byte in bytes {
+        let _ = write!(id, "{byte:02x}");
+    }
+    Ok(id)
+}
+
+#[async_trait]
+impl RequestFilter for RequestIdHandler {
+    type Conf = RequestIdConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    fn init_downstream_modules(modules: &mut HttpModules) {
+        modules.add_module(Box::new(RequestIdHttpModuleBuilder {}));
+    }
+
+    async fn early_request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if !self.conf.request_id_enabled {
+            return Ok(());
+        }
+
+        let name = &self.conf.request_id_header;
+        let incoming = if self.conf.request_id_trust_incoming {
+            session
+                .req_header()
+                .headers
+                .get(name)
+                .filter(|value| is_valid_id(value.as_bytes()))
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        } else {
+            None
+        };
+
+        let id = if let Some(id) = incoming {
+            trace!("using request ID {id} supplied by client");
+            id
+        } else {
+            let id = generate_id()?;
+            debug!("assigned request ID {id}");
+            id
+        };
+
+        let value = HeaderValue::try_from(&id)
+            .map_err(|err| Error::because(ErrorType::InternalError, "invalid request ID", err))?;
+        session
+            .req_header_mut()
+            .insert_header(name.clone(), value.clone())?;
+        if let Some(module) = session
+            .downstream_modules_ctx
+            .get_mut::<RequestIdHttpModule>()
+        {
+            module.header = Some((name.clone(), value));
+        }
+        session.set_request_id(id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{create_test_session, RequestHeader, Session};
+    use pandora_module_utils::FromYaml;
+    use startup_module::{AppResult, DefaultApp};
+    use test_log::test;
+    use upstream_module::UpstreamHandler;
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct Handler {
+        request_id: RequestIdHandler,
+        upstream: UpstreamHandler,
+    }
+
+    fn make_app(conf: &str) -> DefaultApp<Handler> {
+        DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(format!(
+                "upstream: http://127.0.0.1\n{conf}"
+            ))
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        )
+    }
+
+    async fn make_session(request_id: Option<&str>) -> Session {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(request_id) = request_id {
+            header.insert_header("X-Request-ID", request_id).unwrap();
+        }
+        create_test_session(header).await
+    }
+
+    async fn handle_request(app: &mut DefaultApp<Handler>, session: Session) -> AppResult {
+        app.handle_request_with_upstream(session, |session, _| {
+            let mut header = ResponseHeader::build(200, None)?;
+            if let Some(value) = session.req_header().headers.get("X-Request-ID") {
+                header.insert_header("X-Upstream-Request-ID", value)?;
+            }
+            Ok(header)
+        })
+        .await
+    }
+
+    fn response_header(result: &mut AppResult, name: &str) -> Option<String> {
+        result
+            .session()
+            .response_written()
+            .and_then(|header| header.headers.get(name))
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    fn assert_request_id(result: &mut AppResult, expected: Option<&str>) -> String {
+        assert!(result.err().is_none());
+
+        let id = result.session().request_id().map(|id| id.to_owned());
+        let id = id.expect("request ID should be set");
+        if let Some(expected) = expected {
+            assert_eq!(id, expected);
+        } else {
+            assert_eq!(id.len(), ID_BYTES * 2);
+            assert!(id.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        }
+
+        assert_eq!(
+            response_header(result, "X-Upstream-Request-ID").as_ref(),
+            Some(&id)
+        );
+        assert_eq!(response_header(result, "X-Request-ID").as_ref(), Some(&id));
+        id
+    }
+
+    #[test(tokio::test)]
+    async fn unconfigured() {
+        let mut app = make_app("");
+
+        let session = make_session(None).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().request_id(), None);
+        assert_eq!(response_header(&mut result, "X-Request-ID"), None);
+
+        let session = make_session(Some("abc")).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().request_id(), None);
+        assert_eq!(
+            response_header(&mut result, "X-Upstream-Request-ID").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn generated() {
+        let mut app = make_app("request_id_enabled: true");
+
+        let session = make_session(None).await;
+        let mut result = handle_request(&mut app, session).await;
+        let id1 = assert_request_id(&mut result, None);
+
+        let session = make_session(None).await;
+        let mut result = handle_request(&mut app, session).await;
+        let id2 = assert_request_id(&mut result, None);
+
+        assert_ne!(id1, id2);
+    }
+
+    #[test(tokio::test)]
+    async fn untrusted_incoming() {
+        let mut app = make_app("request_id_enabled: true");
+
+        let session = make_session(Some("abc")).await;
+        let mut result = handle_request(&mut app, session).await;
+        let id = assert_request_id(&mut result, None);
+        assert_ne!(id, "abc");
+    }
+
+    #[test(tokio::test)]
+    async fn trusted_incoming() {
+        let mut app = make_app("request_id_enabled: true\nrequest_id_trust_incoming: true");
+
+        let session = make_session(Some("abc-123")).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert_request_id(&mut result, Some("abc-123"));
+
+        // Missing or invalid IDs are replaced
+        let session = make_session(None).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert_request_id(&mut result, None);
+
+        let session = make_session(Some("abc 123")).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert_request_id(&mut result, None);
+
+        let session = make_session(Some(&"a".repeat(MAX_ID_LENGTH + 1))).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert_request_id(&mut result, None);
+    }
+
+    #[test(tokio::test)]
+    async fn custom_header() {
+        let mut app = make_app("request_id_enabled: true\nrequest_id_header: X-Correlation-ID");
+
+        let session = make_session(Some("abc")).await;
+        let mut result = handle_request(&mut app, session).await;
+        assert!(result.err().is_none());
+
+        let id = result.session().request_id().unwrap().to_owned();
+        assert_eq!(id.len(), ID_BYTES * 2);
+        assert_eq!(
+            response_header(&mut result, "X-Correlation-ID").as_ref(),
+            Some(&id)
+        );
+        assert_eq!(
+            response_header(&mut result, "X-Upstream-Request-ID").as_deref(),
+            Some("abc")
+        );
+    }
+}
@@ -0,0 +1,1438 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ACME client obtaining and renewing certificates
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, error, info, warn};
+use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Extension, X509Name, X509ReqBuilder, X509};
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pandora_module_utils::{DeserializeMap, OneOrMany};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::{background_service, BackgroundService};
+use pingora::services::Service;
+use pingora::utils::CertKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::configuration::CertKeyConf;
+
+const ACME_ERR: ErrorType = ErrorType::Custom("AcmeError");
+
+/// ALPN protocol identifier of the tls-alpn-01 challenge
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Path prefix of http-01 challenge requests
+pub(crate) const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Let’s Encrypt production directory
+const DEFAULT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Certificates expiring within this number of days are renewed
+const RENEW_DAYS: u32 = 30;
+
+/// How often to check whether certificates need renewing
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long to wait before retrying after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Interval between checks of authorization and order status
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of status checks before giving up on an authorization or order
+const POLL_ATTEMPTS: usize = 60;
+
+/// Challenge type used to prove control over a server name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum AcmeChallenge {
+    /// http-01 challenge, answered by the TLS redirector
+    #[serde(rename = "http-01")]
+    Http01,
+    /// tls-alpn-01 challenge, answered on the TLS addresses
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// ACME configuration
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AcmeConf {
+    /// Certificates to be obtained, each entry is a server name or a list of server names to be
+    /// covered by one certificate
+    pub names: OneOrMany<OneOrMany<String>>,
+
+    /// URL of the ACME directory, Let’s Encrypt production directory by default
+    pub directory: Option<String>,
+
+    /// Contact URLs for the ACME account, e.g. `mailto:admin@example.com`
+    pub contact: OneOrMany<String>,
+
+    /// Directory to store the account key and certificates in
+    pub state_dir: Option<PathBuf>,
+
+    /// Additional CA certificate to trust when connecting to the ACME server
+    pub ca_file: Option<PathBuf>,
+
+    /// Challenge type, `http-01` if the TLS redirector is configured and `tls-alpn-01` otherwise
+    pub challenge: Option<AcmeChallenge>,
+
+    /// Agreement to the terms of service of the certificate authority, required to register an
+    /// account
+    pub accept_terms: bool,
+}
+
+/// Certificates obtained via ACME and pending challenges
+#[derive(Default)]
+pub(crate) struct AcmeState {
+    /// Server name to use for clients not indicating a server name
+    default_name: String,
+
+    /// Obtained certificates by server name
+    certificates: RwLock<HashMap<String, CertKey>>,
+
+    /// Key authorizations of pending http-01 challenges by token
+    http_challenges: RwLock<HashMap<String, String>>,
+
+    /// Certificates of pending tls-alpn-01 challenges by server name
+    tls_alpn_challenges: RwLock<HashMap<String, CertKey>>,
+}
+
+impl std::fmt::Debug for AcmeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcmeState")
+            .field("default_name", &self.default_name)
+            .finish()
+    }
+}
+
+impl AcmeState {
+    /// Returns the certificate for the given server name if one was obtained
+    pub(crate) fn certificate(&self, name: &str) -> Option<CertKey> {
+        self.certificates
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    /// Returns the certificate to be used if the client didn’t indicate a known server name
+    pub(crate) fn default_certificate(&self) -> Option<CertKey> {
+        self.certificate(&self.default_name)
+    }
+
+    /// Returns the key authorization for a pending http-01 challenge
+    pub(crate) fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_challenges
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(token)
+            .cloned()
+    }
+
+    /// Returns the certificate for a pending tls-alpn-01 challenge
+    pub(crate) fn tls_alpn_challenge(&self, name: &str) -> Option<CertKey> {
+        self.tls_alpn_challenges
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    fn install(&self, names: &[String], cert: CertKey) {
+        let mut certificates = self
+            .certificates
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        for name in names {
+            certificates.insert(name.clone(), cert.clone());
+        }
+    }
+
+    fn needs_renewal(&self, names: &[String]) -> bool {
+        let Some(cert) = self.certificate(&names[0]) else {
+            return true;
+        };
+
+        let expiring = Asn1Time::days_from_now(RENEW_DAYS)
+            .map(|limit| cert.leaf().not_after() < limit)
+            .unwrap_or(true);
+        if expiring {
+            return true;
+        }
+
+        // Server names might have been added to the configuration
+        let covered = cert
+            .leaf()
+            .subject_alt_names()
+            .map(|alt_names| {
+                alt_names
+                    .iter()
+                    .filter_map(|name| name.dnsname())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        !names.iter().all(|name| covered.contains(name))
+    }
+}
+
+#[cfg(test)]
+impl AcmeState {
+    /// Creates a state with a pending http-01 challenge
+    pub(crate) fn with_http_challenge(token: &str, key_authorization: &str) -> Self {
+        let state = Self::default();
+        state
+            .http_challenges
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(token.to_owned(), key_authorization.to_owned());
+        state
+    }
+}
+
+/// Removes pending challenges from the state once dropped
+struct PendingChallenges<'a> {
+    state: &'a AcmeState,
+    tokens: Vec<String>,
+    names: Vec<String>,
+}
+
+impl<'a> PendingChallenges<'a> {
+    fn new(state: &'a AcmeState) -> Self {
+        Self {
+            state,
+            tokens: Vec::new(),
+            names: Vec::new(),
+        }
+    }
+
+    fn add_http(&mut self, token: &str, key_authorization: String) {
+        self.state
+            .http_challenges
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(token.to_owned(), key_authorization);
+        self.tokens.push(token.to_owned());
+    }
+
+    fn add_tls_alpn(&mut self, name: &str, cert: CertKey) {
+        self.state
+            .tls_alpn_challenges
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(name.to_owned(), cert);
+        self.names.push(name.to_owned());
+    }
+}
+
+impl Drop for PendingChallenges<'_> {
+    fn drop(&mut self) {
+        let mut http_challenges = self
+            .state
+            .http_challenges
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        for token in &self.tokens {
+            http_challenges.remove(token);
+        }
+
+        let mut tls_alpn_challenges = self
+            .state
+            .tls_alpn_challenges
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        for name in &self.names {
+            tls_alpn_challenges.remove(name);
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn crypto_error(context: &'static str) -> impl FnOnce(ErrorStack) -> Box<Error> {
+    move |err| Error::because(ACME_ERR, context, err)
+}
+
+fn new_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+fn server_name(name: &str) -> Result<X509Name, ErrorStack> {
+    let mut builder = X509Name::builder()?;
+    builder.append_entry_by_nid(Nid::COMMONNAME, name)?;
+    Ok(builder.build())
+}
+
+/// Produces the JWK representation of the public key, members in lexicographic order as required
+/// for the thumbprint (RFC 7638)
+fn jwk(key: &PKey<Private>) -> Result<String, ErrorStack> {
+    let key = key.ec_key()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    key.public_key()
+        .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)?;
+    Ok(format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        base64(&x.to_vec_padded(32)?),
+        base64(&y.to_vec_padded(32)?)
+    ))
+}
+
+/// Produces an ES256 signature in the format required by JWS (RFC 7518 section 3.4)
+fn sign(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(data)?;
+    let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+    let mut result = signature.r().to_vec_padded(32)?;
+    result.extend_from_slice(&signature.s().to_vec_padded(32)?);
+    Ok(result)
+}
+
+/// Creates a certificate signing request for the given server names
+fn create_csr(names: &[String], key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_pubkey(key)?;
+    let subject = server_name(&names[0])?;
+    builder.set_subject_name(&subject)?;
+
+    let mut alt_names = SubjectAlternativeName::new();
+    for name in names {
+        alt_names.dns(name);
+    }
+    let mut extensions = Stack::new()?;
+    extensions.push(alt_names.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+
+    builder.sign(key, MessageDigest::sha256())?;
+    builder.build().to_der()
+}
+
+/// Creates the self-signed certificate answering a tls-alpn-01 challenge (RFC 8737)
+fn tls_alpn_certificate(name: &str, key_authorization: &str) -> Result<CertKey, ErrorStack> {
+    let key = new_key()?;
+    let subject = server_name(name)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    builder.set_serial_number(Asn1Integer::from_bn(&serial)?.as_ref())?;
+    builder.set_subject_name(&subject)?;
+    builder.set_issuer_name(&subject)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+
+    let alt_names = SubjectAlternativeName::new()
+        .dns(name)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_names)?;
+
+    // id-pe-acmeIdentifier extension containing the key authorization digest as OCTET STRING
+    let digest = hash(MessageDigest::sha256(), key_authorization.as_bytes())?;
+    let mut value = vec![0x04, digest.len() as u8];
+    value.extend_from_slice(&digest);
+    let oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.31")?;
+    let value = Asn1OctetString::new_from_bytes(&value)?;
+    builder.append_extension(X509Extension::new_from_der(&oid, true, &value)?)?;
+
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok(CertKey::new(vec![builder.build()], key))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(default)]
+    r#type: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    r#type: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug)]
+struct AcmeResponse {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl AcmeResponse {
+    fn json<T: DeserializeOwned>(&self) -> Result<T, Box<Error>> {
+        serde_json::from_slice(&self.body)
+            .map_err(|err| Error::because(ACME_ERR, "failed parsing ACME server response", err))
+    }
+}
+
+/// A session with the ACME server, using a registered account
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: PKey<Private>,
+    jwk: String,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(
+        http: reqwest::Client,
+        directory_url: &str,
+        key: PKey<Private>,
+        contact: &[String],
+    ) -> Result<Self, Box<Error>> {
+        let directory = http
+            .get(directory_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| {
+                Error::because(
+                    ACME_ERR,
+                    format!("failed retrieving ACME directory {directory_url}"),
+                    err,
+                )
+            })?
+            .json()
+            .await
+            .map_err(|err| Error::because(ACME_ERR, "failed parsing ACME directory", err))?;
+
+        let jwk = jwk(&key).map_err(crypto_error("failed encoding account key"))?;
+        let thumbprint = base64(
+            &hash(MessageDigest::sha256(), jwk.as_bytes())
+                .map_err(crypto_error("failed hashing account key"))?,
+        );
+
+        let mut client = Self {
+            http,
+            directory,
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if !contact.is_empty() {
+            account["contact"] = json!(contact);
+        }
+        let url = client.directory.new_account.clone();
+        let response = client.post(&url, Some(&account)).await?;
+        client.kid =
+            Some(response.location.ok_or_else(|| {
+                Error::explain(ACME_ERR, "ACME server didn't return an account URL")
+            })?);
+
+        Ok(client)
+    }
+
+    async fn new_nonce(&self) -> Result<String, Box<Error>> {
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|err| Error::because(ACME_ERR, "failed requesting ACME nonce", err))?;
+        response
+            .headers()
+            .get("Replay-Nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_owned)
+            .ok_or_else(|| Error::explain(ACME_ERR, "ACME server didn't return a nonce"))
+    }
+
+    fn sign_request(
+        &self,
+        url: &str,
+        payload: Option<&Value>,
+        nonce: &str,
+    ) -> Result<String, Box<Error>> {
+        let key = match &self.kid {
+            Some(kid) => format!(r#""kid":{}"#, json!(kid)),
+            None => format!(r#""jwk":{}"#, self.jwk),
+        };
+        let protected = base64(
+            format!(
+                r#"{{"alg":"ES256",{key},"nonce":{},"url":{}}}"#,
+                json!(nonce),
+                json!(url)
+            )
+            .as_bytes(),
+        );
+        // POST-as-GET requests have an empty payload
+        let payload = payload
+            .map(|payload| base64(payload.to_string().as_bytes()))
+            .unwrap_or_default();
+        let signature = sign(&self.key, format!("{protected}.{payload}").as_bytes())
+            .map_err(crypto_error("failed signing ACME request"))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64(&signature),
+        })
+        .to_string())
+    }
+
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<AcmeResponse, Box<Error>> {
+        let mut attempts = 0;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let body = self.sign_request(url, payload, &nonce)?;
+
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| {
+                    Error::because(ACME_ERR, format!("ACME request to {url} failed"), err)
+                })?;
+
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned)
+            };
+            self.nonce = header("Replay-Nonce");
+            let location = header("Location");
+            let status = response.status();
+            let body = response.bytes().await.map_err(|err| {
+                Error::because(ACME_ERR, format!("ACME request to {url} failed"), err)
+            })?;
+
+            if status.is_success() {
+                return Ok(AcmeResponse {
+                    location,
+                    body: body.to_vec(),
+                });
+            }
+
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or_default();
+            if problem.r#type == "urn:ietf:params:acme:error:badNonce" && attempts < 3 {
+                attempts += 1;
+                continue;
+            }
+
+            return Err(Error::explain(
+                ACME_ERR,
+                format!(
+                    "ACME request to {url} failed with status {status}: {}",
+                    problem.detail
+                ),
+            ));
+        }
+    }
+
+    async fn authorize(
+        &mut self,
+        url: &str,
+        challenge_type: AcmeChallenge,
+        pending: &mut PendingChallenges<'_>,
+    ) -> Result<(), Box<Error>> {
+        let authorization: Authorization = self.post(url, None).await?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let name = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == challenge_type.name())
+            .ok_or_else(|| {
+                Error::explain(
+                    ACME_ERR,
+                    format!(
+                        "ACME server didn't offer {} challenge for {name}",
+                        challenge_type.name()
+                    ),
+                )
+            })?;
+
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+        match challenge_type {
+            AcmeChallenge::Http01 => pending.add_http(&challenge.token, key_authorization),
+            AcmeChallenge::TlsAlpn01 => pending.add_tls_alpn(
+                &name,
+                tls_alpn_certificate(&name, &key_authorization)
+                    .map_err(crypto_error("failed creating tls-alpn-01 certificate"))?,
+            ),
+        }
+
+        debug!(
+            "responding to {} challenge for {name}",
+            challenge_type.name()
+        );
+        self.post(&challenge.url, Some(&json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let authorization: Authorization = self.post(url, None).await?.json()?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => {
+                    let detail = authorization
+                        .challenges
+                        .iter()
+                        .filter_map(|challenge| challenge.error.as_ref())
+                        .map(|error| error.detail.as_str())
+                        .next()
+                        .unwrap_or_default();
+                    return Err(Error::explain(
+                        ACME_ERR,
+                        format!("authorization for {name} is {status}: {detail}"),
+                    ));
+                }
+            }
+        }
+
+        Err(Error::explain(
+            ACME_ERR,
+            format!("timed out waiting for authorization of {name}"),
+        ))
+    }
+
+    /// Orders a certificate for the given server names, returns the PEM-encoded certificate chain
+    /// and private key.
+    async fn obtain(
+        &mut self,
+        names: &[String],
+        challenge_type: AcmeChallenge,
+        state: &AcmeState,
+    ) -> Result<(Vec<u8>, PKey<Private>), Box<Error>> {
+        let identifiers = names
+            .iter()
+            .map(|name| json!({"type": "dns", "value": name}))
+            .collect::<Vec<_>>();
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({"identifiers": identifiers})))
+            .await?;
+        let order_url = response
+            .location
+            .clone()
+            .ok_or_else(|| Error::explain(ACME_ERR, "ACME server didn't return an order URL"))?;
+        let mut order: Order = response.json()?;
+
+        {
+            let mut pending = PendingChallenges::new(state);
+            for url in &order.authorizations {
+                self.authorize(url, challenge_type, &mut pending).await?;
+            }
+        }
+
+        let key = new_key().map_err(crypto_error("failed generating private key"))?;
+        let csr = create_csr(names, &key).map_err(crypto_error("failed creating CSR"))?;
+        order = self
+            .post(&order.finalize, Some(&json!({"csr": base64(&csr)})))
+            .await?
+            .json()?;
+
+        let mut attempts = 0;
+        let certificate = loop {
+            match order.status.as_str() {
+                "valid" => {
+                    if let Some(certificate) = order.certificate {
+                        break certificate;
+                    }
+                }
+                "pending" | "ready" | "processing" => {}
+                status => {
+                    return Err(Error::explain(
+                        ACME_ERR,
+                        format!(
+                            "order is {status}: {}",
+                            order.error.map(|error| error.detail).unwrap_or_default()
+                        ),
+                    ))
+                }
+            }
+
+            attempts += 1;
+            if attempts > POLL_ATTEMPTS {
+                return Err(Error::explain(
+                    ACME_ERR,
+                    "timed out waiting for the certificate to be issued",
+                ));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+            order = self.post(&order_url, None).await?.json()?;
+        };
+
+        let chain = self.post(&certificate, None).await?.body;
+        Ok((chain, key))
+    }
+}
+
+fn file_error(path: &Path, action: &str, err: std::io::Error) -> Box<Error> {
+    Error::because(
+        ACME_ERR,
+        format!("failed {action} file {}", path.display()),
+        err,
+    )
+}
+
+/// Writes data to a temporary file next to `path`, readable by the current user only. Returns the
+/// path of the temporary file.
+fn write_temp_file(path: &Path, data: &[u8]) -> Result<PathBuf, Box<Error>> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .and_then(|mut file| file.write_all(data))
+        .map_err(|err| file_error(&temp_path, "writing", err))?;
+    Ok(temp_path)
+}
+
+/// Moves a temporary file produced by [`write_temp_file`] to its final location
+fn commit_file(temp_path: &Path, path: &Path) -> Result<(), Box<Error>> {
+    std::fs::rename(temp_path, path).map_err(|err| file_error(path, "writing", err))
+}
+
+/// Writes a file atomically, readable by the current user only
+fn write_file(path: &Path, data: &[u8]) -> Result<(), Box<Error>> {
+    commit_file(&write_temp_file(path, data)?, path)
+}
+
+/// Stores a certificate along with its private key. Both are written to temporary files first,
+/// the certificate is only replaced after the key so that a certificate file is never paired with
+/// an older key. If the process is interrupted in between, the stored certificate won’t match the
+/// key and will be obtained again.
+fn store_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+    chain: &[u8],
+    key: &PKey<Private>,
+) -> Result<(), Box<Error>> {
+    let key_temp = write_temp_file(
+        key_path,
+        &key.private_key_to_pem_pkcs8()
+            .map_err(crypto_error("failed encoding private key"))?,
+    )?;
+    let cert_temp = match write_temp_file(cert_path, chain) {
+        Ok(path) => path,
+        Err(err) => {
+            let _ = std::fs::remove_file(&key_temp);
+            return Err(err);
+        }
+    };
+
+    commit_file(&key_temp, key_path)?;
+    commit_file(&cert_temp, cert_path)
+}
+
+#[derive(Debug)]
+struct AcmeTask {
+    names: Vec<Vec<String>>,
+    directory: String,
+    contact: Vec<String>,
+    state_dir: PathBuf,
+    challenge: AcmeChallenge,
+    http: reqwest::Client,
+    state: Arc<AcmeState>,
+}
+
+impl AcmeTask {
+    fn cert_paths(&self, names: &[String]) -> (PathBuf, PathBuf) {
+        (
+            self.state_dir.join(format!("{}.crt", names[0])),
+            self.state_dir.join(format!("{}.key", names[0])),
+        )
+    }
+
+    fn account_key(&self) -> Result<PKey<Private>, Box<Error>> {
+        let path = self.state_dir.join("account.key");
+        match std::fs::read(&path) {
+            Ok(data) => PKey::private_key_from_pem(&data)
+                .map_err(crypto_error("failed parsing ACME account key")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("generating new ACME account key");
+                let key = new_key().map_err(crypto_error("failed generating account key"))?;
+                write_file(
+                    &path,
+                    &key.private_key_to_pem_pkcs8()
+                        .map_err(crypto_error("failed encoding account key"))?,
+                )?;
+                Ok(key)
+            }
+            Err(err) => Err(file_error(&path, "reading", err)),
+        }
+    }
+
+    /// Loads certificates obtained previously
+    fn load_certificates(&self) {
+        for names in &self.names {
+            let (cert_path, key_path) = self.cert_paths(names);
+            if !cert_path.exists() {
+                continue;
+            }
+
+            let conf = CertKeyConf {
+                cert_path: Some(cert_path),
+                key_path: Some(key_path),
+                ..Default::default()
+            };
+            match conf.to_certificate() {
+                Ok(cert) => self.state.install(names, cert),
+                Err(err) => warn!("Ignoring stored certificate for {}: {err}", names[0]),
+            }
+        }
+    }
+
+    async fn renew(&self) -> Result<(), Box<Error>> {
+        let due = self
+            .names
+            .iter()
+            .filter(|names| self.state.needs_renewal(names))
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut client = AcmeClient::new(
+            self.http.clone(),
+            &self.directory,
+            self.account_key()?,
+            &self.contact,
+        )
+        .await?;
+
+        let mut result = Ok(());
+        for names in due {
+            info!("obtaining certificate for {}", names.join(", "));
+            let obtained = match client.obtain(names, self.challenge, &self.state).await {
+                Ok(obtained) => obtained,
+                Err(err) => {
+                    error!("Failed obtaining certificate for {}: {err}", names[0]);
+                    result = Err(err);
+                    continue;
+                }
+            };
+
+            let (chain, key) = obtained;
+            if let Err(err) = self.install(names, &chain, key) {
+                error!("Failed installing certificate for {}: {err}", names[0]);
+                result = Err(err);
+                continue;
+            }
+            info!("installed new certificate for {}", names.join(", "));
+        }
+        result
+    }
+
+    /// Stores an obtained certificate and starts using it
+    fn install(
+        &self,
+        names: &[String],
+        chain: &[u8],
+        key: PKey<Private>,
+    ) -> Result<(), Box<Error>> {
+        let certs = X509::stack_from_pem(chain)
+            .map_err(crypto_error("failed parsing certificate chain"))?;
+        if certs.is_empty() {
+            return Err(Error::explain(
+                ACME_ERR,
+                "ACME server returned an empty certificate chain",
+            ));
+        }
+
+        let (cert_path, key_path) = self.cert_paths(names);
+        store_certificate(&cert_path, &key_path, chain, &key)?;
+        self.state.install(names, CertKey::new(certs, key));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AcmeTask {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            let interval = match self.renew().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(err) => {
+                    error!("ACME certificate renewal failed: {err}");
+                    RETRY_INTERVAL
+                }
+            };
+
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+    }
+}
+
+/// Creates the ACME state shared with TLS and redirector code and the background service
+/// obtaining certificates.
+pub(crate) fn create_acme_service(
+    conf: &AcmeConf,
+    challenge: AcmeChallenge,
+) -> Result<(Arc<AcmeState>, impl Service + 'static), Box<Error>> {
+    if !conf.accept_terms {
+        return Err(Error::explain(
+            ACME_ERR,
+            "tls.acme.accept_terms setting is required to agree to the terms of service of the certificate authority",
+        ));
+    }
+
+    let state_dir = conf.state_dir.clone().ok_or_else(|| {
+        Error::explain(
+            ACME_ERR,
+            "tls.acme.state_dir setting is required for ACME support",
+        )
+    })?;
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&state_dir)
+        .map_err(|err| {
+            Error::because(
+                ACME_ERR,
+                format!("failed creating directory {}", state_dir.display()),
+                err,
+            )
+        })?;
+
+    let names = conf
+        .names
+        .iter()
+        .map(|names| names.iter().map(|name| name.to_ascii_lowercase()).collect())
+        .collect::<Vec<Vec<String>>>();
+    for name in names.iter().flatten() {
+        if name.is_empty() || name.starts_with("*.") || name.contains(['/', '\\']) {
+            return Err(Error::explain(
+                ACME_ERR,
+                format!("server name {name:?} isn't supported by ACME client"),
+            ));
+        }
+    }
+    if names.iter().any(|names| names.is_empty()) {
+        return Err(Error::explain(
+            ACME_ERR,
+            "empty list of server names in tls.acme.names",
+        ));
+    }
+
+    let mut http = reqwest::Client::builder().timeout(Duration::from_secs(30));
+    if let Some(ca_file) = &conf.ca_file {
+        let ca = std::fs::read(ca_file).map_err(|err| file_error(ca_file, "reading", err))?;
+        let ca = reqwest::Certificate::from_pem(&ca).map_err(|err| {
+            Error::because(
+                ACME_ERR,
+                format!("failed parsing CA certificate {}", ca_file.display()),
+                err,
+            )
+        })?;
+        http = http.add_root_certificate(ca);
+    }
+    let http = http
+        .build()
+        .map_err(|err| Error::because(ACME_ERR, "failed creating HTTP client", err))?;
+
+    let state = Arc::new(AcmeState {
+        default_name: names
+            .first()
+            .and_then(|names| names.first())
+            .cloned()
+            .unwrap_or_default(),
+        ..Default::default()
+    });
+
+    let task = AcmeTask {
+        names,
+        directory: conf
+            .directory
+            .clone()
+            .unwrap_or_else(|| DEFAULT_DIRECTORY.to_owned()),
+        contact: conf.contact.iter().cloned().collect(),
+        state_dir,
+        challenge,
+        http,
+        state: state.clone(),
+    };
+    task.load_certificates();
+
+    Ok((state, background_service("ACME", task)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::x509::{X509NameBuilder, X509Req};
+    use std::sync::Mutex;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn signature() {
+        let key = new_key().unwrap();
+        let signature = sign(&key, b"data").unwrap();
+        assert_eq!(signature.len(), 64);
+
+        let jwk: Value = serde_json::from_str(&jwk(&key).unwrap()).unwrap();
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["crv"], "P-256");
+        assert_eq!(
+            URL_SAFE_NO_PAD
+                .decode(jwk["x"].as_str().unwrap())
+                .unwrap()
+                .len(),
+            32
+        );
+
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        let digest = hash(MessageDigest::sha256(), b"data").unwrap();
+        assert!(signature.verify(&digest, &key.ec_key().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn csr() {
+        let key = new_key().unwrap();
+        let names = ["example.com".to_owned(), "www.example.com".to_owned()];
+        let csr = X509Req::from_der(&create_csr(&names, &key).unwrap()).unwrap();
+        assert!(csr.verify(&key).unwrap());
+
+        let extensions = csr.extensions().unwrap();
+        assert_eq!(extensions.len(), 1);
+    }
+
+    #[test]
+    fn tls_alpn() {
+        let cert = tls_alpn_certificate("example.com", "token.thumbprint").unwrap();
+        let leaf = cert.leaf();
+        let names = leaf
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|name| name.dnsname().map(str::to_owned))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["example.com"]);
+
+        let der = leaf.to_der().unwrap();
+        let mut expected = vec![0x04, 0x20];
+        expected.extend_from_slice(&hash(MessageDigest::sha256(), b"token.thumbprint").unwrap());
+        assert!(der.windows(expected.len()).any(|window| window == expected));
+    }
+
+    #[test]
+    fn state() {
+        let state = AcmeState {
+            default_name: "example.com".to_owned(),
+            ..Default::default()
+        };
+        let names = ["example.com".to_owned(), "www.example.com".to_owned()];
+        assert!(state.needs_renewal(&names));
+        assert!(state.default_certificate().is_none());
+
+        let cert = tls_alpn_certificate("example.com", "").unwrap();
+        {
+            let mut pending = PendingChallenges::new(&state);
+            pending.add_http("token", "token.thumbprint".to_owned());
+            pending.add_tls_alpn("example.com", cert.clone());
+            assert_eq!(
+                state.http_challenge("token").as_deref(),
+                Some("token.thumbprint")
+            );
+            assert!(state.tls_alpn_challenge("example.com").is_some());
+        }
+        assert!(state.http_challenge("token").is_none());
+        assert!(state.tls_alpn_challenge("example.com").is_none());
+
+        state.install(&names, cert);
+        assert!(state.certificate("www.example.com").is_some());
+        assert!(state.default_certificate().is_some());
+
+        // Certificate expires within a day and doesn't cover www.example.com
+        assert!(state.needs_renewal(&names));
+    }
+
+    /// Issues a certificate for a CSR, signed by a throwaway CA
+    fn issue_certificate(csr: &[u8]) -> Vec<u8> {
+        let csr = X509Req::from_der(csr).unwrap();
+        let ca_key = new_key().unwrap();
+        let mut issuer = X509NameBuilder::new().unwrap();
+        issuer.append_entry_by_text("CN", "Test CA").unwrap();
+        let issuer = issuer.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&Asn1Integer::from_bn(&BigNum::from_u32(1).unwrap()).unwrap())
+            .unwrap();
+        builder.set_subject_name(csr.subject_name()).unwrap();
+        builder.set_issuer_name(&issuer).unwrap();
+        builder.set_pubkey(&csr.public_key().unwrap()).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+            .unwrap();
+        for extension in csr.extensions().unwrap() {
+            builder.append_extension(extension).unwrap();
+        }
+        builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+        builder.build().to_pem().unwrap()
+    }
+
+    /// Decodes the payload of a JWS request
+    fn payload(body: &[u8]) -> Value {
+        let body: Value = serde_json::from_slice(body).unwrap();
+        let payload = URL_SAFE_NO_PAD
+            .decode(body["payload"].as_str().unwrap())
+            .unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    /// Responds to a request to the stub ACME server, returns status, Location header and body
+    fn acme_response(
+        base: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        issued: &Mutex<HashMap<String, Vec<u8>>>,
+    ) -> (u16, Option<String>, Vec<u8>) {
+        let json = |value: Value| value.to_string().into_bytes();
+        match (method, path) {
+            ("GET", "/directory") => (
+                200,
+                None,
+                json(json!({
+                    "newNonce": format!("{base}/nonce"),
+                    "newAccount": format!("{base}/account"),
+                    "newOrder": format!("{base}/order"),
+                })),
+            ),
+            ("HEAD", "/nonce") => (200, None, Vec::new()),
+            ("POST", "/account") => (201, Some(format!("{base}/account/1")), json(json!({}))),
+            ("POST", "/order") => {
+                let identifiers = payload(body)["identifiers"].clone();
+                assert_eq!(identifiers.as_array().unwrap().len(), 1);
+                assert_eq!(identifiers[0]["type"], "dns");
+                let name = identifiers[0]["value"].as_str().unwrap();
+                (
+                    201,
+                    Some(format!("{base}/order/{name}")),
+                    json(json!({
+                        "status": "pending",
+                        "authorizations": [format!("{base}/authz/{name}")],
+                        "finalize": format!("{base}/finalize/{name}"),
+                    })),
+                )
+            }
+            ("POST", path) if path.starts_with("/authz/") => (
+                200,
+                None,
+                json(json!({
+                    "status": "valid",
+                    "identifier": {"type": "dns", "value": &path[7..]},
+                })),
+            ),
+            ("POST", path) if path.starts_with("/finalize/") => {
+                let name = &path[10..];
+                let csr = URL_SAFE_NO_PAD
+                    .decode(payload(body)["csr"].as_str().unwrap())
+                    .unwrap();
+                // Names starting with bad. get an unusable certificate chain
+                let chain = if name.starts_with("bad.") {
+                    b"garbage".to_vec()
+                } else {
+                    issue_certificate(&csr)
+                };
+                issued.lock().unwrap().insert(name.to_owned(), chain);
+                (
+                    200,
+                    None,
+                    json(json!({
+                        "status": "valid",
+                        "finalize": format!("{base}/finalize/{name}"),
+                        "certificate": format!("{base}/cert/{name}"),
+                    })),
+                )
+            }
+            ("POST", path) if path.starts_with("/cert/") => (
+                200,
+                None,
+                issued.lock().unwrap().get(&path[6..]).unwrap().clone(),
+            ),
+            _ => (404, None, Vec::new()),
+        }
+    }
+
+    /// Handles a single HTTP/1.1 request on the connection and closes it
+    async fn serve_connection(
+        mut connection: TcpStream,
+        base: &str,
+        issued: &Mutex<HashMap<String, Vec<u8>>>,
+    ) {
+        let mut data = Vec::new();
+        let header_end = loop {
+            let mut buffer = [0; 4096];
+            let len = connection.read(&mut buffer).await.unwrap();
+            assert!(len > 0, "connection closed before request was complete");
+            data.extend_from_slice(&buffer[..len]);
+            if let Some(pos) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let head = String::from_utf8(data[..header_end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap().split(' ');
+        let method = request_line.next().unwrap().to_owned();
+        let path = request_line.next().unwrap().to_owned();
+        let content_length = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+            .unwrap_or(0);
+        while data.len() < header_end + content_length {
+            let mut buffer = [0; 4096];
+            let len = connection.read(&mut buffer).await.unwrap();
+            assert!(len > 0, "connection closed before request was complete");
+            data.extend_from_slice(&buffer[..len]);
+        }
+        let body = &data[header_end..header_end + content_length];
+
+        let (status, location, body) = acme_response(base, &method, &path, body, issued);
+        let mut response = format!(
+            "HTTP/1.1 {status} Stub\r\nReplay-Nonce: nonce\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        if let Some(location) = location {
+            response.push_str(&format!("Location: {location}\r\n"));
+        }
+        response.push_str("\r\n");
+        connection.write_all(response.as_bytes()).await.unwrap();
+        if method != "HEAD" {
+            connection.write_all(&body).await.unwrap();
+        }
+        connection.shutdown().await.unwrap();
+    }
+
+    /// Starts a stub ACME server, returns its directory URL
+    async fn stub_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let directory = format!("{base}/directory");
+        let issued = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(async move {
+            loop {
+                let (connection, _) = listener.accept().await.unwrap();
+                let base = base.clone();
+                let issued = issued.clone();
+                tokio::spawn(async move { serve_connection(connection, &base, &issued).await });
+            }
+        });
+        directory
+    }
+
+    #[test(tokio::test)]
+    async fn renewal() {
+        let state_dir = std::env::temp_dir().join(format!("acme-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        std::fs::create_dir_all(&state_dir).unwrap();
+
+        let names = vec!["example.com".to_owned()];
+        let task = AcmeTask {
+            names: vec![names.clone()],
+            directory: stub_server().await,
+            contact: vec!["mailto:admin@example.com".to_owned()],
+            state_dir: state_dir.clone(),
+            challenge: AcmeChallenge::Http01,
+            http: reqwest::Client::builder().no_proxy().build().unwrap(),
+            state: Default::default(),
+        };
+
+        assert!(task.state.needs_renewal(&names));
+        task.renew().await.unwrap();
+        assert!(!task.state.needs_renewal(&names));
+        let installed = task.state.certificate("example.com").unwrap();
+
+        // Certificate and key are stored without leftover temporary files
+        let mut files = std::fs::read_dir(&state_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["account.key", "example.com.crt", "example.com.key"]);
+
+        // Stored certificate matches the key and the installed certificate
+        let (cert_path, key_path) = task.cert_paths(&names);
+        let stored = CertKeyConf {
+            cert_path: Some(cert_path),
+            key_path: Some(key_path),
+            ..Default::default()
+        }
+        .to_certificate()
+        .unwrap();
+        assert_eq!(
+            stored.leaf().to_der().unwrap(),
+            installed.leaf().to_der().unwrap()
+        );
+
+        // Nothing to do if the certificate is current
+        task.renew().await.unwrap();
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn renewal_failure() {
+        let state_dir =
+            std::env::temp_dir().join(format!("acme-failure-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        std::fs::create_dir_all(&state_dir).unwrap();
+
+        let names = ["example.com", "bad.example.com", "example.net"]
+            .map(|name| vec![name.to_owned()])
+            .to_vec();
+        let task = AcmeTask {
+            names: names.clone(),
+            directory: stub_server().await,
+            contact: Vec::new(),
+            state_dir: state_dir.clone(),
+            challenge: AcmeChallenge::Http01,
+            http: reqwest::Client::builder().no_proxy().build().unwrap(),
+            state: Default::default(),
+        };
+
+        // A bad certificate chain doesn't prevent other certificates from being installed
+        assert!(task.renew().await.is_err());
+        assert!(!task.state.needs_renewal(&names[0]));
+        assert!(task.state.needs_renewal(&names[1]));
+        assert!(!task.state.needs_renewal(&names[2]));
+        assert!(task.state.certificate("bad.example.com").is_none());
+        assert!(!task.cert_paths(&names[1]).0.exists());
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+
+    #[test]
+    fn terms_of_service() {
+        let conf = AcmeConf {
+            names: vec![vec!["example.com".to_owned()].into()].into(),
+            state_dir: Some(std::env::temp_dir()),
+            ..Default::default()
+        };
+        let Err(err) = create_acme_service(&conf, AcmeChallenge::Http01) else {
+            panic!("terms of service should be required");
+        };
+        assert!(err.to_string().contains("accept_terms"));
+
+        let conf = AcmeConf {
+            accept_terms: true,
+            ..conf
+        };
+        assert!(create_acme_service(&conf, AcmeChallenge::Http01).is_ok());
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Admin API service
+
+use async_trait::async_trait;
+use http::{header, Method, Response, StatusCode};
+use log::{info, warn};
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::apps::http_app::ServeHttp;
+use pingora::protocols::http::ServerSession;
+use pingora::services::listening::Service;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::configuration::{AdminConf, ListenAddr};
+
+const ADMIN_CONF_ERR: ErrorType = ErrorType::Custom("AdminConfigError");
+
+type AdminCallback = Arc<dyn Fn() -> Result<String, Box<Error>> + Send + Sync>;
+
+/// Endpoints provided by the admin API
+///
+/// The `/status` and `/config` endpoints are always available. Additional endpoints can be added
+/// via [`AdminApi::add_query`] and [`AdminApi::add_command`].
+#[derive(Default)]
+pub struct AdminApi {
+    config: String,
+    queries: BTreeMap<String, AdminCallback>,
+    commands: BTreeMap<String, AdminCallback>,
+}
+
+impl Debug for AdminApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminApi")
+            .field("queries", &self.queries.keys())
+            .field("commands", &self.commands.keys())
+            .finish()
+    }
+}
+
+impl AdminApi {
+    /// Sets the configuration text returned by the `/config` endpoint.
+    pub fn set_config(&mut self, config: String) {
+        self.config = config;
+    }
+
+    /// Adds an endpoint to be called with the GET method. The callback produces the response text.
+    pub fn add_query<F>(&mut self, path: impl Into<String>, callback: F)
+    where
+        F: Fn() -> Result<String, Box<Error>> + Send + Sync + 'static,
+    {
+        self.queries.insert(path.into(), Arc::new(callback));
+    }
+
+    /// Adds an endpoint to be called with the POST method. The callback produces the response
+    /// text.
+    pub fn add_command<F>(&mut self, path: impl Into<String>, callback: F)
+    where
+        F: Fn() -> Result<String, Box<Error>> + Send + Sync + 'static,
+    {
+        self.commands.insert(path.into(), Arc::new(callback));
+    }
+
+    /// Adds the `/reload` endpoint. The callback should apply the new configuration and return
+    /// its text representation, this will be returned by the `/config` endpoint from then on.
+    pub fn set_reload<F>(&mut self, callback: F)
+    where
+        F: Fn() -> Result<String, Box<Error>> + Send + Sync + 'static,
+    {
+        self.commands
+            .insert("/reload".to_owned(), Arc::new(callback));
+    }
+}
+
+pub(crate) struct AdminApp {
+    token: Option<String>,
+    started: Instant,
+    config: Arc<Mutex<String>>,
+    queries: BTreeMap<String, AdminCallback>,
+    commands: BTreeMap<String, AdminCallback>,
+}
+
+impl AdminApp {
+    fn new(conf: &AdminConf, api: AdminApi) -> Self {
+        let config = Arc::new(Mutex::new(api.config));
+        let mut commands = api.commands;
+        if let Some(reload) = commands.remove("/reload") {
+            let config = config.clone();
+            commands.insert(
+                "/reload".to_owned(),
+                Arc::new(move || {
+                    let new_config = reload()?;
+                    *config.lock().unwrap_or_else(|err| err.into_inner()) = new_config;
+                    info!("configuration reloaded via admin API");
+                    Ok("Configuration reloaded\n".to_owned())
+                }),
+            );
+        }
+
+        Self {
+            token: conf.token.clone(),
+            started: Instant::now(),
+            config,
+            queries: api.queries,
+            commands,
+        }
+    }
+
+    fn is_authorized(&self, authorization: Option<&[u8]>) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+
+        let Some(provided) = authorization.and_then(|value| value.strip_prefix(b"Bearer ")) else {
+            return false;
+        };
+
+        // Compare without exiting early so that the timing doesn’t reveal the token
+        provided.len() == token.len()
+            && provided
+                .iter()
+                .zip(token.as_bytes())
+                .fold(0, |result, (a, b)| result | (a ^ b))
+                == 0
+    }
+
+    fn status(&self) -> String {
+        let mut status = format!(
+            "pid: {}\nuptime: {}s\n",
+            std::process::id(),
+            self.started.elapsed().as_secs()
+        );
+        for path in self.queries.keys() {
+            status.push_str(&format!("query: {path}\n"));
+        }
+        for path in self.commands.keys() {
+            status.push_str(&format!("command: {path}\n"));
+        }
+        status
+    }
+
+    async fn handle(
+        &self,
+        method: &Method,
+        path: &str,
+        authorization: Option<&[u8]>,
+    ) -> (StatusCode, String) {
+        if !self.is_authorized(authorization) {
+            return (StatusCode::UNAUTHORIZED, "Unauthorized\n".to_owned());
+        }
+
+        let (callback, allowed) = match path {
+            "/status" => return (StatusCode::OK, self.status()),
+            "/config" => {
+                let config = self.config.lock().unwrap_or_else(|err| err.into_inner());
+                return (StatusCode::OK, format!("{config}\n"));
+            }
+            _ => {
+                if let Some(callback) = self.queries.get(path) {
+                    (callback, Method::GET)
+                } else if let Some(callback) = self.commands.get(path) {
+                    (callback, Method::POST)
+                } else {
+                    return (StatusCode::NOT_FOUND, "Not Found\n".to_owned());
+                }
+            }
+        };
+
+        if method != allowed {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method Not Allowed\n".to_owned(),
+            );
+        }
+
+        // Callbacks are allowed to block, run them outside of the async context
+        let callback = callback.clone();
+        match tokio::task::spawn_blocking(move || callback()).await {
+            Ok(Ok(text)) => (StatusCode::OK, text),
+            Ok(Err(err)) => {
+                warn!("admin API request to {path} failed: {err}");
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n"))
+            }
+            Err(err) => {
+                warn!("admin API request to {path} panicked: {err}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error\n".to_owned(),
+                )
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let header = http_session.req_header();
+        let method = header.method.clone();
+        let path = header.uri.path().to_owned();
+        let authorization = header
+            .headers
+            .get(header::AUTHORIZATION)
+            .map(|value| value.as_bytes().to_owned());
+
+        let (status, text) = self.handle(&method, &path, authorization.as_deref()).await;
+        let body = text.into_bytes();
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain;charset=utf-8")
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body)
+            .unwrap()
+    }
+}
+
+/// Produces the listening address of the admin API if configured
+pub(crate) fn admin_listen_addr(conf: &AdminConf) -> Option<ListenAddr> {
+    let mut addr = ListenAddr::from(conf.listen.as_deref()?);
+    if addr.unix_path().is_some() {
+        // Only the user running the server should be able to connect
+        addr.mode = Some(0o600);
+    }
+    Some(addr)
+}
+
+pub(crate) fn create_admin_service(
+    conf: &AdminConf,
+    api: AdminApi,
+) -> Result<Option<Service<AdminApp>>, Box<Error>> {
+    let Some(addr) = admin_listen_addr(conf) else {
+        return Ok(None);
+    };
+
+    let mut service = Service::new("Admin API".to_owned(), AdminApp::new(conf, api));
+    if addr.unix_path().is_none() {
+        let listen = &addr.addr;
+        let addr: SocketAddr = listen.parse().map_err(|err| {
+            Error::because(
+                ADMIN_CONF_ERR,
+                format!("failed parsing admin API address {listen}"),
+                err,
+            )
+        })?;
+        if !addr.ip().is_loopback() {
+            return Err(Error::explain(
+                ADMIN_CONF_ERR,
+                format!("admin API address {listen} isn't a loopback address"),
+            ));
+        }
+    }
+    service.add_address(addr.to_server_address());
+
+    Ok(Some(service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn make_app(token: Option<&str>) -> AdminApp {
+        let mut api = AdminApi::default();
+        api.set_config("config 1".to_owned());
+        api.add_query("/query", || Ok("query result\n".to_owned()));
+        api.add_command("/command", || Ok("command result\n".to_owned()));
+        api.add_command("/failing", || {
+            Err(Error::explain(ErrorType::InternalError, "command failed"))
+        });
+        api.set_reload(|| Ok("config 2".to_owned()));
+
+        AdminApp::new(
+            &AdminConf {
+                listen: None,
+                token: token.map(|token| token.to_owned()),
+            },
+            api,
+        )
+    }
+
+    #[test(tokio::test)]
+    async fn endpoints() {
+        let app = make_app(None);
+
+        let (status, text) = app.handle(&Method::GET, "/status", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(text.contains("uptime: "));
+        assert!(text.contains("query: /query\n"));
+        assert!(text.contains("command: /reload\n"));
+
+        assert_eq!(
+            app.handle(&Method::GET, "/query", None).await,
+            (StatusCode::OK, "query result\n".to_owned())
+        );
+        assert_eq!(
+            app.handle(&Method::POST, "/query", None).await.0,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        assert_eq!(
+            app.handle(&Method::POST, "/command", None).await,
+            (StatusCode::OK, "command result\n".to_owned())
+        );
+        assert_eq!(
+            app.handle(&Method::GET, "/command", None).await.0,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+
+        assert_eq!(
+            app.handle(&Method::POST, "/failing", None).await.0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        assert_eq!(
+            app.handle(&Method::GET, "/unknown", None).await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn reload() {
+        let app = make_app(None);
+
+        assert_eq!(
+            app.handle(&Method::GET, "/config", None).await,
+            (StatusCode::OK, "config 1\n".to_owned())
+        );
+        assert_eq!(
+            app.handle(&Method::POST, "/reload", None).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            app.handle(&Method::GET, "/config", None).await,
+            (StatusCode::OK, "config 2\n".to_owned())
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn token() {
+        let app = make_app(Some("secret"));
+
+        assert_eq!(
+            app.handle(&Method::GET, "/status", None).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.handle(&Method::GET, "/status", Some(b"Bearer wrong"))
+                .await
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.handle(&Method::GET, "/status", Some(b"secret")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            app.handle(&Method::GET, "/status", Some(b"Bearer secret"))
+                .await
+                .0,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn listen_address() {
+        let conf = AdminConf {
+            listen: Some("127.0.0.1:8081".to_owned()),
+            token: None,
+        };
+        assert!(create_admin_service(&conf, AdminApi::default())
+            .unwrap()
+            .is_some());
+
+        let conf = AdminConf {
+            listen: Some("192.0.2.1:8081".to_owned()),
+            token: None,
+        };
+        assert!(create_admin_service(&conf, AdminApi::default()).is_err());
+
+        let conf = AdminConf {
+            listen: Some("192.0.2.1:8081".to_owned()),
+            token: Some("secret".to_owned()),
+        };
+        assert!(create_admin_service(&conf, AdminApi::default()).is_err());
+
+        let conf = AdminConf {
+            listen: Some("[::1]:8081".to_owned()),
+            token: Some("secret".to_owned()),
+        };
+        assert!(create_admin_service(&conf, AdminApi::default())
+            .unwrap()
+            .is_some());
+
+        let conf = AdminConf {
+            listen: Some("localhost".to_owned()),
+            token: None,
+        };
+        assert!(create_admin_service(&conf, AdminApi::default()).is_err());
+
+        let conf = AdminConf {
+            listen: None,
+            token: None,
+        };
+        assert!(create_admin_service(&conf, AdminApi::default())
+            .unwrap()
+            .is_none());
+    }
+}
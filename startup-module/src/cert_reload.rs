@@ -0,0 +1,332 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reloading of changed certificate files
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pandora_module_utils::pingora::Error;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::{background_service, BackgroundService};
+use pingora::services::Service;
+use pingora::utils::CertKey;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::configuration::{CertKeyConf, TLS_CONF_ERR};
+use crate::ocsp::{OcspStaple, RETRY_INTERVAL};
+use crate::server_name::{certificate_names, wildcard_name};
+
+/// Interval to check certificate files for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Identifies a particular version of a file: modification time, size and inode
+type FileStamp = Option<(SystemTime, u64, u64)>;
+
+fn file_stamp(path: Option<&Path>) -> FileStamp {
+    // This follows symlinks, so replacing the link target is recognized as well
+    let metadata = path?.metadata().ok()?;
+    Some((metadata.modified().ok()?, metadata.len(), metadata.ino()))
+}
+
+/// A loaded certificate along with the data derived from it
+#[derive(Debug)]
+struct LoadedCert {
+    cert: CertKey,
+    names: Vec<String>,
+    ocsp: Option<OcspStaple>,
+    ocsp_refresh: Instant,
+}
+
+/// A certificate/key combination which is replaced when the files change
+#[derive(Debug)]
+pub(crate) struct ReloadableCert {
+    conf: CertKeyConf,
+    description: String,
+    stamps: Mutex<[FileStamp; 3]>,
+    cert: RwLock<LoadedCert>,
+}
+
+impl ReloadableCert {
+    /// Loads the certificate initially, `description` is used in error messages
+    pub(crate) fn new(conf: CertKeyConf, description: String) -> Result<Self, Box<Error>> {
+        let stamps = Mutex::new(Self::stamps(&conf));
+        let cert = RwLock::new(Self::load(&conf, &description)?);
+        Ok(Self {
+            conf,
+            description,
+            stamps,
+            cert,
+        })
+    }
+
+    fn stamps(conf: &CertKeyConf) -> [FileStamp; 3] {
+        [
+            file_stamp(conf.cert_path.as_deref()),
+            file_stamp(conf.key_path.as_deref()),
+            file_stamp(conf.ocsp_path.as_deref()),
+        ]
+    }
+
+    /// Loads the certificate along with the server names it is valid for and the OCSP response
+    fn load(conf: &CertKeyConf, description: &str) -> Result<LoadedCert, Box<Error>> {
+        let cert = conf.to_certificate()?;
+        let names = certificate_names(cert.leaf());
+
+        let ocsp = match &conf.ocsp_path {
+            Some(path) => {
+                let response = std::fs::read(path).map_err(|err| {
+                    Error::because(
+                        TLS_CONF_ERR,
+                        format!("failed reading file {}", path.display()),
+                        err,
+                    )
+                })?;
+
+                // An outdated response shouldn’t prevent the certificate from being used
+                OcspStaple::new(&cert, response)
+                    .map_err(|err| {
+                        warn!(
+                            "Not stapling OCSP response {} to {description}: {err}",
+                            path.display()
+                        )
+                    })
+                    .ok()
+            }
+            None => None,
+        };
+
+        Ok(LoadedCert {
+            cert,
+            names,
+            ocsp,
+            ocsp_refresh: Instant::now(),
+        })
+    }
+
+    /// Returns the description used in log messages
+    pub(crate) fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn loaded(&self) -> RwLockReadGuard<'_, LoadedCert> {
+        self.cert.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns the currently active certificate
+    pub(crate) fn get(&self) -> CertKey {
+        self.loaded().cert.clone()
+    }
+
+    /// Returns the currently active certificate along with the OCSP response to be stapled
+    pub(crate) fn get_stapled(&self) -> (CertKey, Option<Vec<u8>>) {
+        let loaded = self.loaded();
+        let ocsp = loaded
+            .ocsp
+            .as_ref()
+            .and_then(|ocsp| ocsp.response())
+            .map(|response| response.to_vec());
+        (loaded.cert.clone(), ocsp)
+    }
+
+    /// Checks whether the currently active certificate is valid for a server name. With `wildcard`
+    /// set, only wildcard names are considered, otherwise only exact matches.
+    pub(crate) fn matches(&self, name: &str, wildcard: bool) -> bool {
+        let name = if wildcard {
+            match wildcard_name(name) {
+                Some(name) => name,
+                None => return false,
+            }
+        } else {
+            name.to_owned()
+        };
+
+        self.loaded().names.contains(&name)
+    }
+
+    /// Checks whether a fresh OCSP response should be requested from the responder. This is never
+    /// the case if the response is configured explicitly.
+    pub(crate) fn ocsp_refresh_due(&self) -> bool {
+        self.conf.ocsp_path.is_none() && Instant::now() >= self.loaded().ocsp_refresh
+    }
+
+    /// Stores a fetched OCSP response for the certificate it has been requested for. If `None` is
+    /// passed, fetching failed and the current response is kept until it expires.
+    pub(crate) fn set_ocsp(&self, cert: &CertKey, ocsp: Option<OcspStaple>) {
+        let mut loaded = self.cert.write().unwrap_or_else(|err| err.into_inner());
+        if loaded.cert.leaf() != cert.leaf() {
+            // Certificate has been reloaded meanwhile, the response doesn’t apply to it
+            return;
+        }
+
+        match ocsp {
+            Some(ocsp) => {
+                loaded.ocsp_refresh = ocsp.refresh();
+                loaded.ocsp = Some(ocsp);
+            }
+            None => loaded.ocsp_refresh = Instant::now() + RETRY_INTERVAL,
+        }
+    }
+
+    /// Reloads the certificate if the files changed, keeping the current one if loading fails.
+    /// Returns `true` if the certificate was replaced.
+    fn reload(&self, force: bool) -> bool {
+        let stamps = Self::stamps(&self.conf);
+        {
+            let mut current = self.stamps.lock().unwrap_or_else(|err| err.into_inner());
+            if !force && *current == stamps {
+                return false;
+            }
+
+            // Remember the state even if loading fails, the error shouldn’t be reported repeatedly
+            *current = stamps;
+        }
+
+        match Self::load(&self.conf, &self.description) {
+            Ok(cert) => {
+                *self.cert.write().unwrap_or_else(|err| err.into_inner()) = cert;
+                info!("reloaded {}", self.description);
+                true
+            }
+            Err(err) => {
+                error!(
+                    "Failed reloading {}, keeping the previous certificate: {err}",
+                    self.description
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Reloads the certificates, unconditionally if `force` is `true` or if the files changed
+/// otherwise.
+pub(crate) fn reload_certificates(certs: &[Arc<ReloadableCert>], force: bool) {
+    for cert in certs {
+        cert.reload(force);
+    }
+}
+
+#[derive(Debug)]
+struct CertReloadTask {
+    certs: Vec<Arc<ReloadableCert>>,
+    /// If `false`, `SIGHUP` signals are handled elsewhere
+    handle_hangup: bool,
+}
+
+#[async_trait]
+impl BackgroundService for CertReloadTask {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = if self.handle_hangup {
+            signal(SignalKind::hangup())
+                .map_err(|err| warn!("Failed registering for SIGHUP signal: {err}"))
+                .ok()
+        } else {
+            None
+        };
+
+        loop {
+            let force = tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(CHECK_INTERVAL) => false,
+                Some(_) = async {
+                    match &mut hangup {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => true,
+            };
+
+            reload_certificates(&self.certs, force);
+        }
+    }
+}
+
+/// Creates a service checking certificate files for changes regularly and on `SIGHUP` signal
+/// unless `handle_hangup` is `false`
+pub(crate) fn create_reload_service(
+    certs: Vec<Arc<ReloadableCert>>,
+    handle_hangup: bool,
+) -> impl Service + 'static {
+    background_service(
+        "Certificate reloading",
+        CertReloadTask {
+            certs,
+            handle_hangup,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cert-reload-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn copy_test_file(dir: &Path, source: &str, target: &str) {
+        let source = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("pandora-web-server")
+            .join("config")
+            .join(source);
+        std::fs::copy(source, dir.join(target)).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let dir = test_dir();
+        copy_test_file(&dir, "cert_localhost.pem", "cert.pem");
+        copy_test_file(&dir, "key_localhost.pem", "key.pem");
+
+        let cert = ReloadableCert::new(
+            CertKeyConf {
+                cert_path: Some(dir.join("cert.pem")),
+                key_path: Some(dir.join("key.pem")),
+                ..Default::default()
+            },
+            "test certificate".to_owned(),
+        )
+        .unwrap();
+        let original = cert.get().leaf().to_der().unwrap();
+        assert!(cert.matches("localhost", false));
+        assert!(!cert.reload(false));
+
+        // Broken file should keep the original certificate
+        std::fs::write(dir.join("cert.pem"), "garbage").unwrap();
+        assert!(!cert.reload(false));
+        assert_eq!(cert.get().leaf().to_der().unwrap(), original);
+
+        // Certificate not matching the key should be rejected as well
+        copy_test_file(&dir, "cert_example.com.pem", "cert.pem");
+        assert!(!cert.reload(true));
+        assert_eq!(cert.get().leaf().to_der().unwrap(), original);
+
+        copy_test_file(&dir, "key_example.com.pem", "key.pem");
+        assert!(cert.reload(true));
+        assert_ne!(cert.get().leaf().to_der().unwrap(), original);
+        assert!(cert.matches("example.com", false));
+        assert!(!cert.matches("localhost", false));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
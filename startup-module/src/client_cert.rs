@@ -0,0 +1,203 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS client certificate verification
+
+use log::warn;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509NameRef, X509Ref, X509VerifyResult, X509};
+use pandora_module_utils::pingora::{ClientCertificate, Error};
+use pingora::tls::ssl::{SslRef, SslVerifyMode};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::configuration::TLS_CONF_ERR;
+
+/// Client certificate verification mode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientVerify {
+    /// Clients have to present a valid certificate
+    #[default]
+    Required,
+    /// Clients may present a certificate, it is validated if present
+    Optional,
+}
+
+/// Client certificate verification settings for a particular certificate
+#[derive(Debug, PartialEq)]
+pub(crate) struct ClientAuth {
+    ca_certs: Vec<X509>,
+    verify: ClientVerify,
+}
+
+impl ClientAuth {
+    /// Loads the CA bundle used to verify client certificates
+    pub(crate) fn new(ca_path: &Path, verify: ClientVerify) -> Result<Self, Box<Error>> {
+        let data = std::fs::read(ca_path).map_err(|err| {
+            Error::because(
+                TLS_CONF_ERR,
+                format!("failed reading file {}", ca_path.display()),
+                err,
+            )
+        })?;
+        let ca_certs = X509::stack_from_pem(&data).map_err(|err| {
+            Error::because(
+                TLS_CONF_ERR,
+                format!("failed parsing CA certificates in {}", ca_path.display()),
+                err,
+            )
+        })?;
+        if ca_certs.is_empty() {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                format!("no CA certificates found in {}", ca_path.display()),
+            ));
+        }
+
+        Ok(Self { ca_certs, verify })
+    }
+
+    /// Requests a client certificate during the TLS handshake
+    pub(crate) fn apply(&self, ssl: &mut SslRef) {
+        let result = (|| {
+            let mut store = X509StoreBuilder::new()?;
+            let mut names = Stack::new()?;
+            for cert in &self.ca_certs {
+                store.add_cert(cert.clone())?;
+                names.push(cert.subject_name().to_owned()?)?;
+            }
+            ssl.set_verify_cert_store(store.build())?;
+            ssl.set_client_ca_list(names);
+            Ok::<_, openssl::error::ErrorStack>(())
+        })();
+        if let Err(err) = result {
+            // Without the CA store no certificate will verify, so required verification fails
+            warn!("Failed setting up client certificate verification: {err}");
+        }
+
+        let mode = match self.verify {
+            ClientVerify::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            ClientVerify::Optional => SslVerifyMode::PEER,
+        };
+        ssl.set_verify(mode);
+    }
+}
+
+fn name_entry(name: &X509NameRef, nid: Nid) -> Option<String> {
+    let entry = name.entries_by_nid(nid).next()?;
+    entry.data().as_utf8().ok().map(|data| data.to_string())
+}
+
+fn certificate_details(cert: &X509Ref) -> ClientCertificate {
+    let subject = cert
+        .subject_name()
+        .entries()
+        .map(|entry| {
+            let key = entry
+                .object()
+                .nid()
+                .short_name()
+                .map(str::to_owned)
+                .unwrap_or_else(|_| entry.object().to_string());
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|data| data.to_string())
+                .unwrap_or_default();
+            format!("{key}={}", escape_value(&value))
+        })
+        .collect::<Vec<_>>();
+
+    let subject_alt_names = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().or(name.email()).or(name.uri()))
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let fingerprint = cert
+        .digest(MessageDigest::sha256())
+        .map(|digest| digest.iter().map(|b| format!("{b:02x}")).collect())
+        .unwrap_or_default();
+
+    ClientCertificate {
+        // RFC 4514 lists the most specific entry first
+        subject: subject.into_iter().rev().collect::<Vec<_>>().join(","),
+        common_name: name_entry(cert.subject_name(), Nid::COMMONNAME),
+        subject_alt_names,
+        fingerprint,
+    }
+}
+
+/// Escapes special characters in a distinguished name value as required by RFC 4514
+fn escape_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && matches!(c, ' ' | '#'))
+            || (i == value.chars().count() - 1 && c == ' ');
+        if special {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Extracts the details of the verified client certificate of a TLS connection. With session
+/// resumption, the certificate verified during the original handshake is returned.
+pub(crate) fn client_certificate(ssl: &SslRef) -> Option<ClientCertificate> {
+    let cert = ssl.peer_certificate()?;
+    (ssl.verify_result() == X509VerifyResult::OK).then(|| certificate_details(&cert))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn test_cert() -> X509 {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("pandora-web-server")
+            .join("config")
+            .join("cert_example.com.pem");
+        X509::from_pem(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn details() {
+        let cert = test_cert();
+        let details = certificate_details(&cert);
+        assert_eq!(details.common_name.as_deref(), Some("example.com"));
+        assert!(details.subject.starts_with("CN=example.com"));
+        assert_eq!(details.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_value("Example, Inc."), "Example\\, Inc.");
+        assert_eq!(escape_value("#1 "), "\\#1\\ ");
+        assert_eq!(escape_value("a=b"), "a\\=b");
+    }
+}
@@ -14,27 +14,49 @@
 
 use async_trait::async_trait;
 use clap::Parser;
+use ipnet::IpNet;
+use log::warn;
 use pandora_module_utils::pingora::{
     http_proxy_service, Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt,
 };
-use pandora_module_utils::{DeserializeMap, OneOrMany};
-use pingora::listeners::{TcpSocketOptions, TlsAccept, TlsSettings};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter};
+use pingora::apps::HttpServerOptions;
+use pingora::listeners::{ServerAddress, TcpSocketOptions, TlsAccept, TlsSettings};
+use pingora::proxy::{http_proxy_service_with_name, HttpProxy};
+use pingora::services::listening::Service as ListeningService;
 use pingora::services::Service;
 use pingora::tls::ext::ssl_add_chain_cert;
 use pingora::tls::{
     ext::{ssl_use_certificate, ssl_use_private_key},
     pkey::PKey,
-    ssl::{NameType, SslRef},
+    ssl::{NameType, SslOptions, SslRef, SslSessionCacheMode},
     x509::X509,
 };
 use pingora::utils::CertKey;
-use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::de::{Deserialize, Deserializer, MapAccess, Unexpected, Visitor};
 use std::collections::HashMap;
-use std::fs::read;
+use std::fs::{read, Permissions};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::redirector::create_redirector;
+use crate::acme::{create_acme_service, AcmeChallenge, AcmeConf, AcmeState, ACME_TLS_ALPN};
+use crate::admin::{admin_listen_addr, create_admin_service, AdminApi};
+use crate::cert_reload::{create_reload_service, ReloadableCert};
+use crate::client_cert::{ClientAuth, ClientVerify};
+use crate::hsts::{hsts_header, HstsModuleBuilder};
+use crate::listener::ListenerNames;
+use crate::ocsp::{create_ocsp_service, enable_stapling};
+use crate::proxy_protocol::add_listen_addr;
+use crate::redirector::{create_redirector, NoHandler};
+use crate::server_name::{normalize_name, wildcard_name};
+use crate::systemd::{
+    bind_tcp_sockets, claim_activated_sockets, create_notify_service, passed_sockets,
+    SocketActivation,
+};
+use crate::tls_options::TlsOptions;
+use crate::unix_socket::bind_unix_sockets;
+use crate::DefaultApp;
 
 pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 
@@ -57,6 +79,50 @@ pub struct StartupOpt {
     pub conf: Option<Vec<String>>,
 }
 
+/// Unix file mode, written as an octal number string like `"660"`
+struct FileMode(u32);
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        let mode = String::deserialize(deserializer)?;
+        let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+        u32::from_str_radix(digits, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(Self)
+            .ok_or_else(|| {
+                D::Error::invalid_value(Unexpected::Str(&mode), &"octal file mode like \"660\"")
+            })
+    }
+}
+
+/// User or group, either a name or a numeric ID
+struct UserOrGroup(String);
+
+impl<'de> Deserialize<'de> for UserOrGroup {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Name(String),
+            Id(u32),
+        }
+
+        Ok(Self(match Value::deserialize(deserializer)? {
+            Value::Name(name) => name,
+            Value::Id(id) => id.to_string(),
+        }))
+    }
+}
+
 /// Address for the server to listen on
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ListenAddr {
@@ -68,29 +134,87 @@ pub struct ListenAddr {
     /// This required TLS configuration to be present.
     pub tls: bool,
 
+    /// If `true`, HTTP/2 will be enabled for this address.
+    ///
+    /// With TLS, HTTP/2 is negotiated via ALPN and HTTP/1.1 remains available. Without TLS, only
+    /// HTTP/2 with prior knowledge (h2c) will be accepted.
+    pub http2: bool,
+
+    /// TLS protocol settings for this address, overriding the global ones in the TLS configuration
+    pub tls_options: Option<TlsOptions>,
+
     /// Determines whether listening on IPv6 `[::]` address should accept IPv4 connections as well.
     ///
     /// If set, the IPV6_V6ONLY flag will be set accordingly for the socket. Otherwise the system
     /// default will be used.
     pub ipv6_only: Option<bool>,
+
+    /// Permissions of a Unix socket, written as an octal number string like `"660"` in the
+    /// configuration file
+    ///
+    /// If not set, only the owner and group of the socket can connect (`"660"`). For sockets
+    /// passed in by systemd, the mode set by systemd is kept then.
+    pub mode: Option<u32>,
+
+    /// Owner of a Unix socket, either a user name or a numeric user ID
+    pub owner: Option<String>,
+
+    /// Group of a Unix socket, either a group name or a numeric group ID
+    pub group: Option<String>,
+
+    /// If `true`, connections are expected to start with a PROXY protocol header (version 1 or 2)
+    /// indicating the actual client address.
+    pub proxy_protocol: bool,
+
+    /// IP ranges that connections with a PROXY protocol header are accepted from, e.g.
+    /// `10.0.0.0/8`
+    ///
+    /// Connections from other addresses are processed without expecting a PROXY protocol header.
+    /// This setting is ignored for Unix sockets.
+    pub proxy_protocol_trusted: Vec<IpNet>,
+
+    /// If `true`, PROXY protocol headers are accepted from all clients connecting to a Unix
+    /// socket. This setting is required to enable PROXY protocol for Unix sockets.
+    pub proxy_protocol_trust_unix: bool,
+
+    /// Name of the socket passed in by systemd to be used for this address
+    /// (`FileDescriptorName=` setting of the socket unit)
+    ///
+    /// If not set, a socket bound to the address will be used if systemd passed one in.
+    pub socket_name: Option<String>,
+
+    /// Name of this listening address, used to restrict handlers to specific listeners
+    pub name: Option<String>,
 }
 
 impl ListenAddr {
-    pub(crate) fn to_socket_options(&self) -> Option<TcpSocketOptions> {
+    /// Returns the socket path if this is a Unix socket address like `unix:/run/pandora.sock`
+    pub fn unix_path(&self) -> Option<&str> {
+        self.addr.strip_prefix("unix:")
+    }
+
+    fn to_socket_options(&self) -> Option<TcpSocketOptions> {
         self.ipv6_only.map(|ipv6_only| {
             let mut options = TcpSocketOptions::default();
             options.ipv6_only = Some(ipv6_only);
             options
         })
     }
+
+    pub(crate) fn to_server_address(&self) -> ServerAddress {
+        if let Some(path) = self.unix_path() {
+            ServerAddress::Uds(path.to_owned(), self.mode.map(Permissions::from_mode))
+        } else {
+            ServerAddress::Tcp(self.addr.clone(), self.to_socket_options())
+        }
+    }
 }
 
 impl From<String> for ListenAddr {
     fn from(value: String) -> Self {
         Self {
             addr: value,
-            tls: false,
-            ipv6_only: None,
+            ..Default::default()
         }
     }
 }
@@ -145,10 +269,30 @@ impl<'de> Deserialize<'de> for ListenAddr {
                 const ADDR_FIELD: &str = "addr";
                 const IPV6_ONLY_FIELD: &str = "ipv6_only";
                 const TLS_FIELD: &str = "tls";
+                const HTTP2_FIELD: &str = "http2";
+                const TLS_OPTIONS_FIELD: &str = "tls_options";
+                const MODE_FIELD: &str = "mode";
+                const OWNER_FIELD: &str = "owner";
+                const GROUP_FIELD: &str = "group";
+                const PROXY_PROTOCOL_FIELD: &str = "proxy_protocol";
+                const PROXY_PROTOCOL_TRUSTED_FIELD: &str = "proxy_protocol_trusted";
+                const PROXY_PROTOCOL_TRUST_UNIX_FIELD: &str = "proxy_protocol_trust_unix";
+                const SOCKET_NAME_FIELD: &str = "socket_name";
+                const NAME_FIELD: &str = "name";
 
                 let mut addr = None;
                 let mut tls = None;
+                let mut http2 = None;
+                let mut tls_options = None;
                 let mut ipv6_only = None;
+                let mut mode = None;
+                let mut owner = None;
+                let mut group = None;
+                let mut proxy_protocol = None;
+                let mut proxy_protocol_trusted = None;
+                let mut proxy_protocol_trust_unix = None;
+                let mut socket_name = None;
+                let mut name = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         ADDR_FIELD => {
@@ -169,10 +313,89 @@ impl<'de> Deserialize<'de> for ListenAddr {
                             }
                             tls = Some(map.next_value()?);
                         }
+                        HTTP2_FIELD => {
+                            if http2.is_some() {
+                                return Err(A::Error::duplicate_field(HTTP2_FIELD));
+                            }
+                            http2 = Some(map.next_value()?);
+                        }
+                        TLS_OPTIONS_FIELD => {
+                            if tls_options.is_some() {
+                                return Err(A::Error::duplicate_field(TLS_OPTIONS_FIELD));
+                            }
+                            tls_options = Some(map.next_value()?);
+                        }
+                        MODE_FIELD => {
+                            if mode.is_some() {
+                                return Err(A::Error::duplicate_field(MODE_FIELD));
+                            }
+                            mode = Some(map.next_value::<FileMode>()?.0);
+                        }
+                        OWNER_FIELD => {
+                            if owner.is_some() {
+                                return Err(A::Error::duplicate_field(OWNER_FIELD));
+                            }
+                            owner = Some(map.next_value::<UserOrGroup>()?.0);
+                        }
+                        GROUP_FIELD => {
+                            if group.is_some() {
+                                return Err(A::Error::duplicate_field(GROUP_FIELD));
+                            }
+                            group = Some(map.next_value::<UserOrGroup>()?.0);
+                        }
+                        PROXY_PROTOCOL_FIELD => {
+                            if proxy_protocol.is_some() {
+                                return Err(A::Error::duplicate_field(PROXY_PROTOCOL_FIELD));
+                            }
+                            proxy_protocol = Some(map.next_value()?);
+                        }
+                        PROXY_PROTOCOL_TRUSTED_FIELD => {
+                            if proxy_protocol_trusted.is_some() {
+                                return Err(A::Error::duplicate_field(
+                                    PROXY_PROTOCOL_TRUSTED_FIELD,
+                                ));
+                            }
+                            proxy_protocol_trusted =
+                                Some(map.next_value::<OneOrMany<IpNet>>()?.into());
+                        }
+                        PROXY_PROTOCOL_TRUST_UNIX_FIELD => {
+                            if proxy_protocol_trust_unix.is_some() {
+                                return Err(A::Error::duplicate_field(
+                                    PROXY_PROTOCOL_TRUST_UNIX_FIELD,
+                                ));
+                            }
+                            proxy_protocol_trust_unix = Some(map.next_value()?);
+                        }
+                        SOCKET_NAME_FIELD => {
+                            if socket_name.is_some() {
+                                return Err(A::Error::duplicate_field(SOCKET_NAME_FIELD));
+                            }
+                            socket_name = Some(map.next_value()?);
+                        }
+                        NAME_FIELD => {
+                            if name.is_some() {
+                                return Err(A::Error::duplicate_field(NAME_FIELD));
+                            }
+                            name = Some(map.next_value()?);
+                        }
                         other => {
                             return Err(A::Error::unknown_field(
                                 other,
-                                &[ADDR_FIELD, IPV6_ONLY_FIELD, TLS_FIELD],
+                                &[
+                                    ADDR_FIELD,
+                                    IPV6_ONLY_FIELD,
+                                    TLS_FIELD,
+                                    HTTP2_FIELD,
+                                    TLS_OPTIONS_FIELD,
+                                    MODE_FIELD,
+                                    OWNER_FIELD,
+                                    GROUP_FIELD,
+                                    PROXY_PROTOCOL_FIELD,
+                                    PROXY_PROTOCOL_TRUSTED_FIELD,
+                                    PROXY_PROTOCOL_TRUST_UNIX_FIELD,
+                                    SOCKET_NAME_FIELD,
+                                    NAME_FIELD,
+                                ],
                             ))
                         }
                     }
@@ -184,6 +407,16 @@ impl<'de> Deserialize<'de> for ListenAddr {
                         addr,
                         ipv6_only,
                         tls,
+                        http2: http2.unwrap_or(false),
+                        tls_options,
+                        mode,
+                        owner,
+                        group,
+                        proxy_protocol: proxy_protocol.unwrap_or(false),
+                        proxy_protocol_trusted: proxy_protocol_trusted.unwrap_or_default(),
+                        proxy_protocol_trust_unix: proxy_protocol_trust_unix.unwrap_or(false),
+                        socket_name,
+                        name,
                     })
                 } else {
                     Err(A::Error::missing_field(ADDR_FIELD))
@@ -204,6 +437,25 @@ pub struct CertKeyConf {
 
     /// Path to the private key file
     pub key_path: Option<PathBuf>,
+
+    /// Path to the CA certificates used to verify client certificates, enables client
+    /// certificate authentication
+    pub client_ca_path: Option<PathBuf>,
+
+    /// Client certificate verification mode, `required` or `optional`
+    pub client_verify: ClientVerify,
+
+    /// Path to a DER-encoded OCSP response to be stapled to the certificate
+    pub ocsp_path: Option<PathBuf>,
+}
+
+impl CertKeyConf {
+    fn client_auth(&self) -> Result<Option<Arc<ClientAuth>>, Box<Error>> {
+        self.client_ca_path
+            .as_ref()
+            .map(|path| ClientAuth::new(path, self.client_verify).map(Arc::new))
+            .transpose()
+    }
 }
 
 impl CertKeyConf {
@@ -218,8 +470,8 @@ impl CertKeyConf {
         })
     }
 
-    fn into_certificate(self) -> Result<CertKey, Box<Error>> {
-        if let (Some(cert_path), Some(key_path)) = (self.cert_path, self.key_path) {
+    pub(crate) fn to_certificate(&self) -> Result<CertKey, Box<Error>> {
+        if let (Some(cert_path), Some(key_path)) = (&self.cert_path, &self.key_path) {
             const END_MARKER: &[u8] = b"-----END CERTIFICATE-----";
             let mut certs = Vec::new();
             let cert_data = Self::read_file(cert_path)?;
@@ -250,6 +502,17 @@ impl CertKeyConf {
             let key = PKey::private_key_from_pem(&Self::read_file(key_path)?)
                 .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing private key", err))?;
 
+            let matches = certs[0]
+                .public_key()
+                .map(|public_key| public_key.public_eq(&key))
+                .unwrap_or(false);
+            if !matches {
+                return Err(Error::explain(
+                    TLS_CONF_ERR,
+                    "private key doesn't match the certificate",
+                ));
+            }
+
             Ok(CertKey::new(certs, key))
         } else {
             Err(Error::explain(
@@ -272,6 +535,8 @@ pub struct TlsRedirectorConf {
     /// or a host name an port combination, e.g. `example.com:8433` to redirect to
     /// `https://example.com:8433/`. No path should be specified, it will be copied from the
     /// original request.
+    ///
+    /// If empty, requests are redirected to the server name they were sent to.
     pub redirect_to: String,
 
     /// Server names mapped to their respective redirect target
@@ -279,17 +544,29 @@ pub struct TlsRedirectorConf {
     /// If the requested name is not found in the list or the request didn’t contain a server name,
     /// the default redirect target will be used.
     pub redirect_by_name: HashMap<OneOrMany<String>, String>,
+
+    /// Port of the HTTPS server used when redirecting to the requested server name
+    ///
+    /// If not set, the port of the first TLS address is used.
+    pub https_port: Option<u16>,
 }
 
 impl TlsRedirectorConf {
-    fn to_redirector(
+    fn to_redirector<H>(
         &self,
+        app: DefaultApp<H>,
         server_conf: &Arc<ServerConf>,
-    ) -> Result<Option<impl Service + 'static>, Box<Error>> {
+        https_port: Option<u16>,
+        acme: Option<Arc<AcmeState>>,
+    ) -> Result<Vec<Box<dyn Service>>, Box<Error>>
+    where
+        H: RequestFilter + Send + Sync + 'static,
+        H::CTX: Send + Sync,
+    {
         if self.listen.is_empty() {
-            Ok(None)
+            Ok(Vec::new())
         } else {
-            create_redirector(self, server_conf).map(Some)
+            create_redirector(self, app, server_conf, https_port, acme)
         }
     }
 }
@@ -301,56 +578,263 @@ pub struct TlsConf {
     #[pandora(flatten)]
     pub default: CertKeyConf,
 
-    /// Certificate/key combinations for particular server names
+    /// Certificate/key combinations for particular server names, wildcard names like
+    /// `*.example.com` are supported
     pub server_names: HashMap<OneOrMany<String>, CertKeyConf>,
 
+    /// Certificate/key combinations used for the server names listed in the certificates
+    pub certificates: OneOrMany<CertKeyConf>,
+
     /// HTTP to HTTPS redirector settings
     pub redirector: TlsRedirectorConf,
+
+    /// Settings for obtaining certificates via ACME
+    pub acme: AcmeConf,
+
+    /// If `true`, OCSP responses will be requested from the responder listed in the certificate
+    /// and stapled, unless `ocsp_path` is configured for the certificate
+    pub ocsp_stapling: bool,
+
+    /// If set, responses to requests received via TLS will include a `Strict-Transport-Security`
+    /// header with this `max-age` value in seconds
+    pub hsts_max_age: Option<u64>,
+
+    /// If `true`, the `Strict-Transport-Security` header will apply to subdomains as well
+    pub hsts_include_subdomains: bool,
+
+    /// TLS protocol settings, can be overridden per address
+    #[pandora(flatten)]
+    pub options: TlsOptions,
 }
 
 impl TlsConf {
-    fn into_callbacks(self) -> Result<TlsAcceptCallbacks, Box<Error>> {
-        let mut certificates = HashMap::with_capacity(self.server_names.len() + 1);
-        for (names, conf) in self.server_names.into_iter() {
-            let cert = conf.into_certificate().map_err(|err| {
+    /// Determines the challenge type to be used by the ACME client
+    fn acme_challenge(&self) -> Result<AcmeChallenge, Box<Error>> {
+        match self.acme.challenge {
+            Some(AcmeChallenge::Http01) if self.redirector.listen.is_empty() => {
+                Err(Error::explain(
+                    TLS_CONF_ERR,
+                    "http-01 ACME challenge requires tls.redirector.listen setting",
+                ))
+            }
+            Some(challenge) => Ok(challenge),
+            None if self.redirector.listen.is_empty() => Ok(AcmeChallenge::TlsAlpn01),
+            None => Ok(AcmeChallenge::Http01),
+        }
+    }
+
+    fn into_callbacks(
+        self,
+        acme: Option<Arc<AcmeState>>,
+    ) -> Result<TlsAcceptCallbacks, Box<Error>> {
+        let load = |conf: CertKeyConf, description: String| {
+            let client_auth = conf.client_auth()?;
+            let cert = ReloadableCert::new(conf, description.clone()).map_err(|err| {
                 Error::because(
                     TLS_CONF_ERR,
-                    format!(
-                        "failed setting up certificate/key for server names {}",
-                        names.join(", ")
-                    ),
+                    format!("failed setting up {description}"),
                     err,
                 )
             })?;
+            Ok::<_, Box<Error>>(CertEntry {
+                cert: Arc::new(cert),
+                client_auth,
+            })
+        };
+
+        let mut certificates = HashMap::with_capacity(self.server_names.len());
+        for (names, conf) in self.server_names.into_iter() {
+            let names = names
+                .iter()
+                .map(|name| normalize_name(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            let entry = load(
+                conf,
+                format!("certificate/key for server names {}", names.join(", ")),
+            )?;
             for name in names {
-                certificates.insert(name, cert.clone());
+                certificates.insert(name, entry.clone());
             }
         }
-        let cert = self.default.into_certificate().map_err(|err| {
-            Error::because(
-                TLS_CONF_ERR,
-                "failed setting up default certificate/key",
-                err,
-            )
-        })?;
-        certificates.insert(String::new(), cert);
-        Ok(TlsAcceptCallbacks { certificates })
+
+        let san_certificates = self
+            .certificates
+            .into_iter()
+            .map(|conf| {
+                let description = format!(
+                    "certificate {}",
+                    conf.cert_path.as_deref().unwrap_or(Path::new("")).display()
+                );
+                load(conf, description)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // With ACME the default certificate is optional, ACME certificates can be used instead
+        let default_configured =
+            self.default.cert_path.is_some() || self.default.key_path.is_some();
+        let default_client_auth = self.default.client_auth()?;
+        let default_certificate = if acme.is_none() || default_configured {
+            let cert = ReloadableCert::new(self.default, "default certificate/key".to_owned())
+                .map_err(|err| {
+                    Error::because(
+                        TLS_CONF_ERR,
+                        "failed setting up default certificate/key",
+                        err,
+                    )
+                })?;
+            Some(Arc::new(cert))
+        } else {
+            None
+        };
+
+        Ok(TlsAcceptCallbacks {
+            certificates,
+            san_certificates,
+            default_certificate,
+            default_client_auth,
+            acme,
+        })
     }
 }
 
+/// A certificate along with the client certificate verification settings configured for it
+#[derive(Debug, Clone)]
+struct CertEntry {
+    cert: Arc<ReloadableCert>,
+    client_auth: Option<Arc<ClientAuth>>,
+}
+
 #[derive(Debug, Clone)]
 struct TlsAcceptCallbacks {
-    certificates: HashMap<String, CertKey>,
+    certificates: HashMap<String, CertEntry>,
+    san_certificates: Vec<CertEntry>,
+    default_certificate: Option<Arc<ReloadableCert>>,
+    default_client_auth: Option<Arc<ClientAuth>>,
+    acme: Option<Arc<AcmeState>>,
+}
+
+impl TlsAcceptCallbacks {
+    fn is_acme_challenge(&self, ssl: &SslRef) -> bool {
+        self.acme.is_some() && ssl.selected_alpn_protocol() == Some(ACME_TLS_ALPN)
+    }
+
+    /// Finds the certificate configured for a server name. Explicitly configured server names take
+    /// precedence over names listed in certificates, exact matches over wildcards.
+    fn entry(&self, name: &str) -> Option<&CertEntry> {
+        let name = name.to_ascii_lowercase();
+        self.certificates
+            .get(&name)
+            .or_else(|| wildcard_name(&name).and_then(|wildcard| self.certificates.get(&wildcard)))
+            .or_else(|| {
+                self.san_certificates
+                    .iter()
+                    .find(|entry| entry.cert.matches(&name, false))
+            })
+            .or_else(|| {
+                self.san_certificates
+                    .iter()
+                    .find(|entry| entry.cert.matches(&name, true))
+            })
+    }
+
+    /// Determines client certificate verification settings, names without their own certificate
+    /// use the default settings
+    fn client_auth(&self, ssl: &SslRef) -> Option<&ClientAuth> {
+        if self.is_acme_challenge(ssl) {
+            return None;
+        }
+
+        self.client_auth_for_name(ssl.servername(NameType::HOST_NAME))
+    }
+
+    /// Checks whether client certificates are requested for any server names
+    fn has_client_auth(&self) -> bool {
+        self.default_client_auth.is_some()
+            || self
+                .certificates
+                .values()
+                .chain(&self.san_certificates)
+                .any(|entry| entry.client_auth.is_some())
+    }
+
+    fn client_auth_for_name(&self, name: Option<&str>) -> Option<&ClientAuth> {
+        match name.and_then(|name| self.entry(name)) {
+            Some(entry) => entry.client_auth.as_deref(),
+            None => self.default_client_auth.as_deref(),
+        }
+    }
+
+    /// Checks whether connections on the same listener can be subject to different client
+    /// certificate verification settings. This is the case if server names use different settings
+    /// or client certificates are requested but tls-alpn-01 challenges are accepted without them.
+    fn has_mixed_client_auth(&self) -> bool {
+        self.has_client_auth()
+            && (self.acme.is_some()
+                || self
+                    .certificates
+                    .values()
+                    .chain(&self.san_certificates)
+                    .any(|entry| entry.client_auth != self.default_client_auth))
+    }
+
+    /// Returns all certificates loaded from files, each one listed only once
+    fn reloadable_certificates(&self) -> Vec<Arc<ReloadableCert>> {
+        let mut result: Vec<Arc<ReloadableCert>> = Vec::new();
+        let all = self
+            .certificates
+            .values()
+            .chain(self.san_certificates.iter())
+            .map(|entry| &entry.cert)
+            .chain(self.default_certificate.iter());
+        for cert in all {
+            if !result.iter().any(|existing| Arc::ptr_eq(existing, cert)) {
+                result.push(cert.clone());
+            }
+        }
+        result
+    }
+
+    /// Selects the certificate along with the OCSP response to be stapled
+    fn certificate(&self, ssl: &SslRef) -> Option<(CertKey, Option<Vec<u8>>)> {
+        let name = ssl.servername(NameType::HOST_NAME);
+
+        if let Some(acme) = &self.acme {
+            if self.is_acme_challenge(ssl) {
+                // tls-alpn-01 challenge, only the challenge certificate should be used here
+                return name
+                    .and_then(|name| acme.tls_alpn_challenge(name))
+                    .map(|cert| (cert, None));
+            }
+        }
+
+        name.and_then(|name| {
+            self.entry(name)
+                .map(|entry| entry.cert.get_stapled())
+                .or_else(|| {
+                    self.acme
+                        .as_ref()
+                        .and_then(|acme| acme.certificate(&name.to_ascii_lowercase()))
+                        .map(|cert| (cert, None))
+                })
+        })
+        .or_else(|| {
+            self.default_certificate
+                .as_ref()
+                .map(|cert| cert.get_stapled())
+        })
+        .or_else(|| {
+            self.acme
+                .as_ref()
+                .and_then(|acme| acme.default_certificate())
+                .map(|cert| (cert, None))
+        })
+    }
 }
 
 #[async_trait]
 impl TlsAccept for TlsAcceptCallbacks {
     async fn certificate_callback(&self, ssl: &mut SslRef) {
-        let cert = ssl
-            .servername(NameType::HOST_NAME)
-            .and_then(|name| self.certificates.get(name))
-            .or_else(|| self.certificates.get(""));
-        if let Some(cert) = cert {
+        if let Some((cert, ocsp)) = self.certificate(ssl) {
             // Errors are unexpected here, these should only occur if a certificate has been set
             // already or private key and certificate don’t match. Ok to panic then.
             ssl_use_certificate(ssl, cert.leaf()).unwrap();
@@ -358,10 +842,41 @@ impl TlsAccept for TlsAcceptCallbacks {
                 ssl_add_chain_cert(ssl, intermediate).unwrap();
             }
             ssl_use_private_key(ssl, cert.key()).unwrap();
+
+            if let Some(ocsp) = ocsp {
+                if let Err(err) = ssl.set_ocsp_status(&ocsp) {
+                    warn!("Failed stapling OCSP response: {err}");
+                }
+            }
+        }
+
+        if let Some(auth) = self.client_auth(ssl) {
+            auth.apply(ssl);
         }
     }
 }
 
+/// Admin API configuration
+#[derive(Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AdminConf {
+    /// Address to listen on, either a loopback IP address and port combination like
+    /// `127.0.0.1:8081` or a Unix socket path prefixed with `unix:`
+    pub listen: Option<String>,
+
+    /// Token to be sent by the clients in the `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for AdminConf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Make sure the token doesn’t show up when the configuration is printed out
+        f.debug_struct("AdminConf")
+            .field("listen", &self.listen)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
 /// Configuration settings of the startup module
 #[derive(Debug, Default, PartialEq, Eq, DeserializeMap)]
 pub struct StartupConf {
@@ -371,6 +886,12 @@ pub struct StartupConf {
     /// TLS configuration for the server
     pub tls: TlsConf,
 
+    /// If `true`, sockets passed in by systemd will be used for the listening addresses
+    pub socket_activation: bool,
+
+    /// Admin API configuration
+    pub admin: AdminConf,
+
     /// Pingora’s default server configuration options
     #[pandora(flatten)]
     pub server: ServerConf,
@@ -378,11 +899,142 @@ pub struct StartupConf {
 
 impl StartupConf {
     /// Sets up a server with the given configuration and command line options
+    ///
+    /// Addresses with h2c (HTTP/2 without TLS) require a separate app instance, use
+    /// [`StartupConf::into_server_with_h2c`] for these. The admin API isn’t supported, use
+    /// [`StartupConf::into_server_with_admin`] if `admin.listen` is configured.
     pub fn into_server<SV>(self, app: SV, opt: Option<StartupOpt>) -> Result<Server, Box<Error>>
     where
         SV: ProxyHttp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
     {
+        self.build_server(app, None, DefaultApp::new(NoHandler), opt, None)
+    }
+
+    /// Sets up a server with the given configuration and command line options, `h2c_app` will
+    /// handle requests to addresses with h2c (HTTP/2 without TLS).
+    ///
+    /// Pingora can only enable h2c for an entire service, so these addresses need a separate app
+    /// instance. The admin API isn’t supported, use [`StartupConf::into_server_with_admin`] if
+    /// `admin.listen` is configured.
+    pub fn into_server_with_h2c<SV>(
+        self,
+        app: SV,
+        h2c_app: SV,
+        opt: Option<StartupOpt>,
+    ) -> Result<Server, Box<Error>>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        self.build_server(app, Some(h2c_app), DefaultApp::new(NoHandler), opt, None)
+    }
+
+    /// Sets up a server with the given configuration and command line options, the admin API
+    /// will provide the endpoints configured in `admin`.
+    ///
+    /// The app will be cloned if some addresses require a separate service, e.g. for h2c.
+    pub fn into_server_with_admin<SV>(
+        self,
+        app: SV,
+        opt: Option<StartupOpt>,
+        admin: AdminApi,
+    ) -> Result<Server, Box<Error>>
+    where
+        SV: ProxyHttp + Clone + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
+        self.into_server_with_redirector(app, DefaultApp::new(NoHandler), opt, admin)
+    }
+
+    /// Sets up a server with the given configuration and command line options, the admin API
+    /// will provide the endpoints configured in `admin`.
+    ///
+    /// The TLS redirector will pass requests to `redirector_app` first and only redirect the
+    /// requests it leaves unhandled. The app will be cloned if some addresses require a separate
+    /// service, e.g. for h2c.
+    pub fn into_server_with_redirector<SV, H>(
+        self,
+        app: SV,
+        redirector_app: DefaultApp<H>,
+        opt: Option<StartupOpt>,
+        admin: AdminApi,
+    ) -> Result<Server, Box<Error>>
+    where
+        SV: ProxyHttp + Clone + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+        H: RequestFilter + Send + Sync + 'static,
+        H::CTX: Send + Sync,
+    {
+        let h2c_app = self.has_h2c_addr(&opt).then(|| app.clone());
+        self.build_server(app, h2c_app, redirector_app, opt, Some(admin))
+    }
+
+    /// Produces a warning for each of the names that doesn’t match the `name` setting of any
+    /// listening address, e.g. names that virtual hosts are restricted to.
+    pub fn check_listener_names(
+        &self,
+        opt: Option<&StartupOpt>,
+        names: impl IntoIterator<Item = impl AsRef<str>>,
+    ) {
+        let listen = match opt.and_then(|opt| opt.listen.as_ref()) {
+            Some(listen) => &listen[..],
+            None => &self.listen[..],
+        };
+        for name in names {
+            let name = name.as_ref();
+            let known = listen
+                .iter()
+                .chain(&self.tls.redirector.listen)
+                .any(|addr| addr.name.as_deref() == Some(name));
+            if !known {
+                warn!("No listening address is named {name}, check the listeners setting");
+            }
+        }
+    }
+
+    /// Checks whether any of the listening addresses uses h2c.
+    fn has_h2c_addr(&self, opt: &Option<StartupOpt>) -> bool {
+        let is_h2c = |addr: &ListenAddr| !addr.tls && addr.http2;
+        match opt.as_ref().and_then(|opt| opt.listen.as_ref()) {
+            Some(listen) => listen.iter().any(is_h2c),
+            None => self.listen.iter().any(is_h2c),
+        }
+    }
+
+    /// Sets up the server, `h2c_app` is required if any addresses use h2c and `admin` if the
+    /// admin API is configured.
+    fn build_server<SV, H>(
+        self,
+        app: SV,
+        h2c_app: Option<SV>,
+        redirector_app: DefaultApp<H>,
+        opt: Option<StartupOpt>,
+        admin: Option<AdminApi>,
+    ) -> Result<Server, Box<Error>>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+        H: RequestFilter + Send + Sync + 'static,
+        H::CTX: Send + Sync,
+    {
+        if self.admin.listen.is_some() && admin.is_none() {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "the admin API requires its endpoints, use StartupConf::into_server_with_admin()",
+            ));
+        }
+
+        // Systemd variables are removed, this has to happen before any threads are started
+        let passed_sockets = self.socket_activation.then(passed_sockets);
+
+        // Validate the admin API configuration before binding any sockets
+        let admin_service = match admin {
+            Some(admin) => create_admin_service(&self.admin, admin)?,
+            None => None,
+        };
+        let admin_listen = admin_listen_addr(&self.admin);
+
         let opt = opt.unwrap_or_default();
 
         let mut listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
@@ -404,39 +1056,349 @@ impl StartupConf {
         );
         server.bootstrap();
 
+        if let Some(passed_sockets) = passed_sockets {
+            claim_activated_sockets(
+                passed_sockets,
+                &listen
+                    .iter()
+                    .chain(&self.tls.redirector.listen)
+                    .chain(&admin_listen)
+                    .collect::<Vec<_>>(),
+            )?;
+        }
+        // The redirector only runs if there are TLS addresses
+        let redirector_listen = if listen.iter().any(|addr| addr.tls) {
+            &self.tls.redirector.listen[..]
+        } else {
+            &[]
+        };
+        // Listening services signal readiness once they took over their sockets, so all sockets
+        // need to be bound here already.
+        let all_listen = listen
+            .iter()
+            .chain(redirector_listen)
+            .chain(&admin_listen)
+            .collect::<Vec<_>>();
+        bind_unix_sockets(&all_listen)?;
+        bind_tcp_sockets(&all_listen)?;
+
         let mut service = http_proxy_service(&server.configuration, app);
+        let mut names = ListenerNames::default();
         for addr in &listen {
-            if addr.tls {
+            if addr.tls || addr.http2 {
                 continue;
             }
 
-            if let Some(socket_options) = addr.to_socket_options() {
-                service.add_tcp_with_settings(&addr.addr, socket_options);
-            } else {
-                service.add_tcp(&addr.addr);
+            if let Some(relay) = add_listen_addr(&mut service, addr, None, &mut names)? {
+                server.add_service(SocketActivation::new(relay));
             }
         }
 
+        // Pingora can only enable h2c for an entire service, so these addresses need their own
+        if listen.iter().any(|addr| !addr.tls && addr.http2) {
+            let Some(h2c_app) = h2c_app else {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    "listening addresses with h2c require a separate app, use StartupConf::into_server_with_h2c()",
+                ));
+            };
+            let mut h2c_service = h2c_service(&server.configuration, h2c_app);
+            let mut h2c_names = ListenerNames::default();
+
+            for addr in &listen {
+                if addr.tls || !addr.http2 {
+                    continue;
+                }
+
+                if let Some(relay) = add_listen_addr(&mut h2c_service, addr, None, &mut h2c_names)?
+                {
+                    server.add_service(SocketActivation::new(relay));
+                }
+            }
+            h2c_names.add_to_service(&mut h2c_service);
+            server.add_service(SocketActivation::new(h2c_service));
+        }
+
+        let mut reloadable_certs = Vec::new();
         if listen.iter().any(|addr| addr.tls) {
-            if let Some(redirector) = self.tls.redirector.to_redirector(&server.configuration)? {
-                server.add_service(redirector);
+            let mut acme = None;
+            let mut tls_alpn_challenge = false;
+            if !self.tls.acme.names.is_empty() {
+                let challenge = self.tls.acme_challenge()?;
+                tls_alpn_challenge = challenge == AcmeChallenge::TlsAlpn01;
+
+                let (state, acme_service) = create_acme_service(&self.tls.acme, challenge)?;
+                server.add_service(acme_service);
+                acme = Some(state);
+            }
+
+            // Redirect to the port of the first TLS address unless configured otherwise
+            let https_port = listen
+                .iter()
+                .filter(|addr| addr.tls && addr.unix_path().is_none())
+                .find_map(|addr| addr.addr.rsplit_once(':')?.1.parse().ok());
+            server.add_services(
+                self.tls
+                    .redirector
+                    .to_redirector(
+                        redirector_app,
+                        &server.configuration,
+                        https_port,
+                        acme.clone(),
+                    )?
+                    .into_iter()
+                    .map(SocketActivation::wrap)
+                    .collect(),
+            );
+
+            if let Some(max_age) = self.tls.hsts_max_age {
+                let header = hsts_header(max_age, self.tls.hsts_include_subdomains);
+                if let Some(proxy) = service.app_logic_mut() {
+                    proxy
+                        .downstream_modules
+                        .add_module(Box::new(HstsModuleBuilder::new(header)));
+                }
             }
 
-            let tls_callbacks = self.tls.into_callbacks()?;
+            let tls_options = self.tls.options.clone();
+            let ocsp_stapling = self.tls.ocsp_stapling;
+            let tls_callbacks = self.tls.into_callbacks(acme)?;
+            reloadable_certs = tls_callbacks.reloadable_certificates();
+            if ocsp_stapling {
+                server.add_service(create_ocsp_service(
+                    tls_callbacks.reloadable_certificates(),
+                )?);
+            }
             for addr in &listen {
                 if !addr.tls {
                     continue;
                 }
 
-                service.add_tls_with_settings(
-                    &addr.addr,
-                    addr.to_socket_options(),
-                    TlsSettings::with_callbacks(Box::new(tls_callbacks.clone()))?,
-                );
+                if addr.http2 && tls_callbacks.has_client_auth() {
+                    warn!(
+                        "HTTP/2 won't be offered on address {} for server names requesting client certificates",
+                        addr.addr
+                    );
+                }
+
+                let mut tls = TlsSettings::with_callbacks(Box::new(tls_callbacks.clone()))?;
+                let callbacks = tls_callbacks.clone();
+                tls_options
+                    .merge(addr.tls_options.as_ref())
+                    .apply(&mut tls, addr.http2, tls_alpn_challenge, move |ssl| {
+                        callbacks.client_auth(ssl).is_some()
+                    })
+                    .map_err(|err| {
+                        Error::because(
+                            TLS_CONF_ERR,
+                            format!("failed applying TLS settings for address {}", addr.addr),
+                            err,
+                        )
+                    })?;
+                enable_stapling(&mut tls)?;
+                if tls_callbacks.has_mixed_client_auth() {
+                    // A session is looked up before the server name is known, so it could be
+                    // resumed for a server name with different client certificate settings.
+                    tls.set_options(SslOptions::NO_TICKET);
+                    tls.set_session_cache_mode(SslSessionCacheMode::OFF);
+                }
+                if let Some(relay) = add_listen_addr(&mut service, addr, Some(tls), &mut names)? {
+                    server.add_service(SocketActivation::new(relay));
+                }
             }
         }
-        server.add_service(service);
+        names.add_to_service(&mut service);
+        server.add_service(SocketActivation::new(service));
+
+        if let Some(admin_service) = admin_service {
+            server.add_service(SocketActivation::new(admin_service));
+        }
+
+        // With systemd notifications, certificates are reloaded by the notification service on
+        // SIGHUP so that reloading can be reported.
+        let notify_service = create_notify_service(reloadable_certs.clone());
+        let handle_hangup = notify_service.is_none();
+        if let Some(notify_service) = notify_service {
+            server.add_service(notify_service);
+        }
+        if !reloadable_certs.is_empty() {
+            server.add_service(create_reload_service(reloadable_certs, handle_hangup));
+        }
 
         Ok(server)
     }
 }
+
+/// Creates a proxy service expecting HTTP/2 connections without TLS (h2c).
+fn h2c_service<SV>(conf: &Arc<ServerConf>, app: SV) -> ListeningService<HttpProxy<SV>>
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    <SV as ProxyHttp>::CTX: Send + Sync,
+{
+    let mut service = http_proxy_service_with_name(conf, app, "Pingora HTTP Proxy Service (h2c)");
+    if let Some(proxy) = service.app_logic_mut() {
+        let mut options = HttpServerOptions::default();
+        options.h2c = true;
+        proxy.server_options = Some(options);
+    }
+    service
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::{header, Request, StatusCode};
+    use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
+    use pandora_module_utils::RequestFilterResult;
+    use pingora::server::ShutdownWatch;
+    use test_log::test;
+    use tokio::net::UnixStream;
+    use tokio::sync::watch;
+
+    #[derive(Debug)]
+    struct Handler;
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let host = session
+                .get_header(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or_default()
+                .to_owned();
+            let text = format!("{:?} {host}", session.req_header().version);
+
+            let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+            header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(text.into()), true).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn h2c() {
+        let path = std::env::temp_dir().join(format!("pandora-h2c-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut service = h2c_service(&Arc::new(ServerConf::default()), DefaultApp::new(Handler));
+        service.add_uds(path.to_str().unwrap(), None);
+
+        let (shutdown_sender, shutdown): (_, ShutdownWatch) = watch::channel(false);
+        let server = tokio::spawn(async move { service.start_service(None, shutdown).await });
+
+        let mut stream = None;
+        for _ in 0..50 {
+            if let Ok(connection) = UnixStream::connect(&path).await {
+                stream = Some(connection);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        let (mut client, connection) = h2::client::handshake(stream.unwrap()).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("http://localhost/").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            text.extend_from_slice(&chunk);
+        }
+        assert_eq!(text, b"HTTP/2.0 localhost");
+
+        shutdown_sender.send(true).unwrap();
+        server.await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn client_auth_names() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let callbacks = |client_ca: bool| {
+            let mut entry = CertKeyConf {
+                cert_path: Some(testdata.join("ocsp_cert.pem")),
+                key_path: Some(testdata.join("ocsp_key.pem")),
+                ..Default::default()
+            };
+            let mut conf = TlsConf {
+                default: entry.clone(),
+                ..Default::default()
+            };
+            if client_ca {
+                entry.client_ca_path = Some(testdata.join("ocsp_cert.pem"));
+            }
+            conf.server_names
+                .insert(vec!["secure.example.com".to_owned()].into(), entry);
+            conf.into_callbacks(None).unwrap()
+        };
+
+        assert!(!callbacks(false).has_client_auth());
+
+        let callbacks = callbacks(true);
+        assert!(callbacks.has_client_auth());
+        assert!(callbacks
+            .client_auth_for_name(Some("secure.example.com"))
+            .is_some());
+        assert!(callbacks
+            .client_auth_for_name(Some("public.example.com"))
+            .is_none());
+    }
+
+    #[test]
+    fn mixed_client_auth() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let entry = |client_ca: bool| CertKeyConf {
+            cert_path: Some(testdata.join("ocsp_cert.pem")),
+            key_path: Some(testdata.join("ocsp_key.pem")),
+            client_ca_path: client_ca.then(|| testdata.join("ocsp_cert.pem")),
+            ..Default::default()
+        };
+        let callbacks = |default_ca: bool, name_ca: bool| {
+            let mut conf = TlsConf {
+                default: entry(default_ca),
+                ..Default::default()
+            };
+            conf.server_names
+                .insert(vec!["secure.example.com".to_owned()].into(), entry(name_ca));
+            conf.into_callbacks(None).unwrap()
+        };
+
+        assert!(!callbacks(false, false).has_mixed_client_auth());
+        assert!(!callbacks(true, true).has_mixed_client_auth());
+        assert!(callbacks(false, true).has_mixed_client_auth());
+        assert!(callbacks(true, false).has_mixed_client_auth());
+    }
+
+    #[test]
+    fn admin_requires_endpoints() {
+        let conf = || StartupConf {
+            admin: AdminConf {
+                listen: Some("127.0.0.1:8081".to_owned()),
+                token: None,
+            },
+            ..Default::default()
+        };
+        assert!(conf().into_server(DefaultApp::new(Handler), None).is_err());
+        assert!(conf()
+            .into_server_with_h2c(DefaultApp::new(Handler), DefaultApp::new(Handler), None)
+            .is_err());
+    }
+}
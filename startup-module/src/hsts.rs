@@ -0,0 +1,160 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP Strict Transport Security
+//!
+//! Browsers ignore the `Strict-Transport-Security` header on plain HTTP responses and RFC 6797
+//! section 7.2 forbids sending it there. The header is added by a downstream module of the main
+//! service instead, this module is only enabled for requests received via TLS.
+
+use async_trait::async_trait;
+use http::{header, HeaderValue};
+use pandora_module_utils::pingora::{Error, ResponseHeader, Session};
+use pingora::modules::http::{HttpModule, HttpModuleBuilder};
+use std::any::Any;
+
+/// Produces the value of the `Strict-Transport-Security` header
+pub(crate) fn hsts_header(max_age: u64, include_subdomains: bool) -> HeaderValue {
+    let value = if include_subdomains {
+        format!("max-age={max_age}; includeSubDomains")
+    } else {
+        format!("max-age={max_age}")
+    };
+    HeaderValue::try_from(value).expect("header value should be valid")
+}
+
+pub(crate) struct HstsModuleBuilder {
+    header: HeaderValue,
+}
+
+impl HstsModuleBuilder {
+    pub(crate) fn new(header: HeaderValue) -> Self {
+        Self { header }
+    }
+}
+
+impl HttpModuleBuilder for HstsModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(HstsModule {
+            header: self.header.clone(),
+            enabled: false,
+        })
+    }
+}
+
+struct HstsModule {
+    header: HeaderValue,
+    enabled: bool,
+}
+
+#[async_trait]
+impl HttpModule for HstsModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<(), Box<Error>> {
+        // A header produced by the upstream server takes precedence
+        if self.enabled && !resp.headers.contains_key(header::STRICT_TRANSPORT_SECURITY) {
+            resp.insert_header(header::STRICT_TRANSPORT_SECURITY, &self.header)?;
+        }
+        Ok(())
+    }
+}
+
+/// Enables the `Strict-Transport-Security` header for the response if HSTS is configured and the
+/// request was received via TLS.
+pub(crate) fn enable_hsts(session: &mut Session) {
+    let tls = session
+        .digest()
+        .is_some_and(|digest| digest.ssl_digest.is_some());
+    if !tls {
+        return;
+    }
+
+    if let Some(module) = session.downstream_modules_ctx.get_mut::<HstsModule>() {
+        module.enabled = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pingora::modules::http::HttpModules;
+    use test_log::test;
+
+    async fn response(enabled: bool, upstream: Option<&str>) -> ResponseHeader {
+        let mut modules = HttpModules::new();
+        modules.add_module(Box::new(HstsModuleBuilder::new(hsts_header(3600, true))));
+        let mut ctx = modules.build_ctx();
+        ctx.get_mut::<HstsModule>().unwrap().enabled = enabled;
+
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        if let Some(upstream) = upstream {
+            header
+                .insert_header(header::STRICT_TRANSPORT_SECURITY, upstream)
+                .unwrap();
+        }
+        ctx.response_header_filter(&mut header, false)
+            .await
+            .unwrap();
+        header
+    }
+
+    #[test]
+    fn header() {
+        assert_eq!(hsts_header(3600, false), "max-age=3600");
+        assert_eq!(
+            hsts_header(31536000, true),
+            "max-age=31536000; includeSubDomains"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn module() {
+        // Plain HTTP responses don't get the header
+        let header = response(false, None).await;
+        assert!(header
+            .headers
+            .get(header::STRICT_TRANSPORT_SECURITY)
+            .is_none());
+
+        let header = response(true, None).await;
+        assert_eq!(
+            header
+                .headers
+                .get(header::STRICT_TRANSPORT_SECURITY)
+                .unwrap(),
+            "max-age=3600; includeSubDomains"
+        );
+
+        let header = response(true, Some("max-age=60")).await;
+        assert_eq!(
+            header
+                .headers
+                .get(header::STRICT_TRANSPORT_SECURITY)
+                .unwrap(),
+            "max-age=60"
+        );
+    }
+}
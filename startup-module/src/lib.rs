@@ -14,24 +14,47 @@
 
 #![doc = include_str!("../README.md")]
 
+mod acme;
+mod admin;
+mod cert_reload;
+mod client_cert;
 mod configuration;
+mod hsts;
+mod listener;
+mod ocsp;
+mod proxy_protocol;
 mod redirector;
+mod server_name;
+mod systemd;
+mod tls_options;
+mod unix_socket;
 
+pub use acme::{AcmeChallenge, AcmeConf};
+pub use admin::AdminApi;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
+use client_cert::client_certificate;
+pub use client_cert::ClientVerify;
 pub use configuration::{
-    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    AdminConf, CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
 };
-use http::Extensions;
+use hsts::enable_hsts;
+use http::{header, Extensions, Version};
+use listener::listener_name;
 use pandora_module_utils::pingora::{
     Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
 };
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use pingora::modules::http::HttpModules;
+use pingora::protocols::Digest;
 use pingora::ErrorType;
+use proxy_protocol::relayed_client_addr;
 use std::borrow::Cow;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, RwLock};
+pub use tls_options::{TlsOptions, TlsVersion};
 
 struct NoDebug<T> {
     inner: T,
@@ -114,19 +137,43 @@ impl AppResult {
 /// `logging` phases. All processing will be delegated to the respective `RequestFilter` methods.
 #[derive(Debug)]
 pub struct DefaultApp<H> {
-    handler: H,
+    handler: Arc<RwLock<Arc<H>>>,
     capture_body: bool,
 }
 
+impl<H> Clone for DefaultApp<H> {
+    fn clone(&self) -> Self {
+        // The handler is shared, so that reloading it affects all copies
+        Self {
+            handler: self.handler.clone(),
+            capture_body: self.capture_body,
+        }
+    }
+}
+
 impl<H> DefaultApp<H> {
     /// Creates a new app from a [`RequestFilter`] instance.
     pub fn new(handler: H) -> Self {
         Self {
-            handler,
+            handler: Arc::new(RwLock::new(Arc::new(handler))),
             capture_body: false,
         }
     }
 
+    /// Returns a reloader that can be used to replace the handler while the app is running.
+    pub fn reloader(&self) -> HandlerReloader<H> {
+        HandlerReloader {
+            handler: self.handler.clone(),
+        }
+    }
+
+    fn handler(&self) -> Arc<H> {
+        self.handler
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
     /// Creates a new app from a [`RequestFilter`] configuration.
     ///
     /// Any errors occurring when converting configuration to handler will be passed on.
@@ -143,7 +190,7 @@ impl<H> DefaultApp<H> {
     /// Upon successful completion, `evaluate_result` callback is called to validate the session.
     pub async fn handle_request(&mut self, session: Session) -> AppResult
     where
-        H: RequestFilter + Sync,
+        H: RequestFilter + Send + Sync + 'static,
         H::CTX: Send + Sync,
     {
         self.handle_request_with_upstream(session, |_, _| {
@@ -168,7 +215,7 @@ impl<H> DefaultApp<H> {
     ) -> AppResult
     where
         C: Fn(&mut Session, Box<HttpPeer>) -> Result<ResponseHeader, Box<Error>>,
-        H: RequestFilter + Sync,
+        H: RequestFilter + Send + Sync + 'static,
         H::CTX: Send + Sync,
     {
         let mut modules = HttpModules::new();
@@ -227,17 +274,144 @@ impl<H> DefaultApp<H> {
     }
 }
 
+impl<H> DefaultApp<H>
+where
+    H: RequestFilter + Send + Sync + 'static,
+    H::CTX: Send,
+{
+    /// Runs the request filter of the handler. Unlike [`ProxyHttp::request_filter`] this
+    /// distinguishes between handled and unhandled requests.
+    pub(crate) async fn handler_request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut <Self as ProxyHttp>::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+        ctx.instance
+            .request_filter(&mut session, &mut ctx.handler)
+            .await
+    }
+}
+
+/// Allows replacing the handler of a [`DefaultApp`] instance, e.g. after configuration changes
+#[derive(Debug)]
+pub struct HandlerReloader<H> {
+    handler: Arc<RwLock<Arc<H>>>,
+}
+
+impl<H> HandlerReloader<H> {
+    /// Replaces the handler. Requests already being processed will continue using the old
+    /// handler, the new handler will be used for all new requests.
+    pub fn reload(&self, handler: H) {
+        *self.handler.write().unwrap_or_else(|err| err.into_inner()) = Arc::new(handler);
+    }
+}
+
 /// Context for the default app
 #[derive(Debug, Clone)]
 pub struct DefaultCtx<C> {
     extensions: Extensions,
+    /// Handler instance processing the request, kept for the entire request even if the handler
+    /// is reloaded in the meantime.
+    instance: Arc<dyn RequestPhases<C>>,
     handler: C,
 }
 
+/// The request processing phases of a [`RequestFilter`] for a particular context type. This allows
+/// [`DefaultCtx`] to hold the handler instance without naming the handler type.
+#[async_trait]
+trait RequestPhases<C: Send>: Send + Sync {
+    async fn early_request_filter(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut C,
+    ) -> Result<(), Box<Error>>;
+
+    async fn request_filter(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut C,
+    ) -> Result<RequestFilterResult, Box<Error>>;
+
+    async fn upstream_peer(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut C,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>>;
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        ctx: &mut C,
+    ) -> Result<(), Box<Error>>;
+
+    async fn logging(&self, session: &mut SessionWrapperImpl<'_>, e: Option<&Error>, ctx: &mut C);
+}
+
+impl<C> Debug for dyn RequestPhases<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestPhases").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<H> RequestPhases<H::CTX> for H
+where
+    H: RequestFilter + Send + Sync,
+    H::CTX: Send,
+{
+    async fn early_request_filter(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut H::CTX,
+    ) -> Result<(), Box<Error>> {
+        RequestFilter::early_request_filter(self, session, ctx).await
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut H::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        RequestFilter::request_filter(self, session, ctx).await
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        ctx: &mut H::CTX,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        RequestFilter::upstream_peer(self, session, ctx).await
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        ctx: &mut H::CTX,
+    ) -> Result<(), Box<Error>> {
+        RequestFilter::connected_to_upstream(self, session, reused, peer, fd, ctx).await
+    }
+
+    async fn logging(
+        &self,
+        session: &mut SessionWrapperImpl<'_>,
+        e: Option<&Error>,
+        ctx: &mut H::CTX,
+    ) {
+        RequestFilter::logging(self, session, e, ctx).await
+    }
+}
+
 #[async_trait]
 impl<H> ProxyHttp for DefaultApp<H>
 where
-    H: RequestFilter + Sync,
+    H: RequestFilter + Send + Sync + 'static,
     H::CTX: Send,
 {
     type CTX = DefaultCtx<<H as RequestFilter>::CTX>;
@@ -245,6 +419,7 @@ where
     fn new_ctx(&self) -> Self::CTX {
         Self::CTX {
             extensions: Extensions::new(),
+            instance: self.handler(),
             handler: H::new_ctx(),
         }
     }
@@ -258,8 +433,41 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<Error>> {
+        enable_hsts(session);
+
+        let listener = listener_name(session);
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
-        self.handler
+        if let Some(name) = listener {
+            session.set_listener(name);
+        }
+        if let Some(addr) = relayed_client_addr(session.client_addr()) {
+            session.set_client_addr(addr);
+        }
+
+        // Pingora only exposes the connection for HTTP/1, HTTP/2 isn’t negotiated if client
+        // certificates are requested.
+        let certificate = session
+            .as_downstream()
+            .stream()
+            .and_then(|stream| stream.get_ssl())
+            .and_then(client_certificate);
+        if let Some(certificate) = certificate {
+            session.set_client_certificate(certificate);
+        }
+
+        // HTTP/2 requests usually indicate the host name via :authority pseudo-header only, add
+        // the Host header so that modules don’t need to distinguish.
+        if session.req_header().version == Version::HTTP_2
+            && session.get_header(header::HOST).is_none()
+        {
+            if let Some(authority) = session.uri().authority().map(|a| a.as_str().to_owned()) {
+                session
+                    .req_header_mut()
+                    .insert_header(header::HOST, authority)?;
+            }
+        }
+
+        ctx.instance
             .early_request_filter(&mut session, &mut ctx.handler)
             .await
     }
@@ -269,12 +477,7 @@ where
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<bool, Box<Error>> {
-        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
-        Ok(self
-            .handler
-            .request_filter(&mut session, &mut ctx.handler)
-            .await?
-            == RequestFilterResult::ResponseSent)
+        Ok(self.handler_request_filter(session, ctx).await? == RequestFilterResult::ResponseSent)
     }
 
     async fn upstream_peer(
@@ -283,8 +486,8 @@ where
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>, Box<Error>> {
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
-        let result = self
-            .handler
+        let result = ctx
+            .instance
             .upstream_peer(&mut session, &mut ctx.handler)
             .await?;
         if let Some(result) = result {
@@ -294,9 +497,24 @@ where
         }
     }
 
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
+        ctx.instance
+            .connected_to_upstream(&mut session, reused, peer, fd, &mut ctx.handler)
+            .await
+    }
+
     async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
         let mut session = SessionWrapperImpl::new(session, &mut ctx.extensions, self.capture_body);
-        self.handler
+        ctx.instance
             .logging(&mut session, e, &mut ctx.handler)
             .await
     }
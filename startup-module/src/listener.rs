@@ -0,0 +1,205 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Named listening addresses
+//!
+//! Each service knows the names of its own listening addresses. These are passed to the requests
+//! via a downstream module, keyed by the address the listening socket is actually bound to.
+
+use async_trait::async_trait;
+use pandora_module_utils::pingora::{Session, SocketAddr};
+use pingora::modules::http::{HttpModule, HttpModuleBuilder};
+use pingora::proxy::HttpProxy;
+use pingora::services::listening::Service as ListeningService;
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::configuration::ListenAddr;
+use crate::systemd::listening_socket_addr;
+
+/// Names of the listening addresses of a service, keyed by the address the server is actually
+/// bound to
+#[derive(Debug, Default)]
+pub(crate) struct ListenerNames {
+    inet: HashMap<std::net::SocketAddr, String>,
+    unix: HashMap<PathBuf, String>,
+}
+
+impl ListenerNames {
+    /// Remembers the name of a listening address. TCP sockets have to be bound already.
+    ///
+    /// `target` is the internal socket the server listens on if it differs from the configured
+    /// address, e.g. for PROXY protocol relays.
+    pub(crate) fn register(&mut self, addr: &ListenAddr, target: Option<&Path>) {
+        let Some(name) = &addr.name else {
+            return;
+        };
+
+        if let Some(target) = target {
+            self.unix.insert(target.into(), name.clone());
+        } else if let Some(path) = addr.unix_path() {
+            self.unix.insert(path.into(), name.clone());
+        } else if let Some(bound) = listening_socket_addr(addr.to_server_address().as_ref()) {
+            let bound = std::net::SocketAddr::new(canonical_ip(bound.ip()), bound.port());
+            self.inet.insert(bound, name.clone());
+        }
+    }
+
+    /// Makes the names available to the requests received by the service.
+    pub(crate) fn add_to_service<SV>(self, service: &mut ListeningService<HttpProxy<SV>>) {
+        if self.inet.is_empty() && self.unix.is_empty() {
+            return;
+        }
+
+        if let Some(proxy) = service.app_logic_mut() {
+            proxy
+                .downstream_modules
+                .add_module(Box::new(ListenerNamesModuleBuilder {
+                    names: Arc::new(self),
+                }));
+        }
+    }
+
+    fn lookup(&self, server_addr: &SocketAddr) -> Option<&str> {
+        match server_addr {
+            SocketAddr::Inet(server_addr) => {
+                let port = server_addr.port();
+                let find = |ip| self.inet.get(&std::net::SocketAddr::new(ip, port));
+                find(canonical_ip(server_addr.ip()))
+                    // Address might be bound to 0.0.0.0 or [::]
+                    .or_else(|| find(IpAddr::V4(Ipv4Addr::UNSPECIFIED)))
+                    .or_else(|| find(IpAddr::V6(Ipv6Addr::UNSPECIFIED)))
+            }
+            SocketAddr::Unix(server_addr) => self.unix.get(server_addr.as_pathname()?),
+        }
+        .map(String::as_str)
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses into IPv4 addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    }
+}
+
+struct ListenerNamesModuleBuilder {
+    names: Arc<ListenerNames>,
+}
+
+impl HttpModuleBuilder for ListenerNamesModuleBuilder {
+    fn init(&self) -> Box<dyn HttpModule + Sync + Send> {
+        Box::new(ListenerNamesModule {
+            names: self.names.clone(),
+        })
+    }
+}
+
+struct ListenerNamesModule {
+    names: Arc<ListenerNames>,
+}
+
+#[async_trait]
+impl HttpModule for ListenerNamesModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Looks up the name of the listening address a request was received on.
+pub(crate) fn listener_name(session: &Session) -> Option<String> {
+    let module = session
+        .downstream_modules_ctx
+        .get::<ListenerNamesModule>()?;
+    module
+        .names
+        .lookup(session.server_addr()?)
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::systemd::bind_tcp_sockets;
+
+    fn named(addr: &str, name: &str) -> ListenAddr {
+        ListenAddr {
+            name: Some(name.to_owned()),
+            ..addr.into()
+        }
+    }
+
+    fn inet(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::Inet(std::net::SocketAddr::new(ip.parse().unwrap(), port))
+    }
+
+    fn bound_port(addr: &ListenAddr) -> u16 {
+        bind_tcp_sockets(&[addr]).unwrap();
+        listening_socket_addr(&addr.addr).unwrap().port()
+    }
+
+    #[test]
+    fn lookup() {
+        let internal = named("127.0.0.3:0", "internal");
+        let internal_port = bound_port(&internal);
+        let public = named("0.0.0.0:0", "public");
+        let public_port = bound_port(&public);
+
+        let mut names = ListenerNames::default();
+        names.register(&internal, None);
+        names.register(&public, None);
+        names.register(&"127.0.0.1:18081".into(), None);
+        // Not bound, so there is no address to match
+        names.register(&named("127.0.0.1:18083", "unbound"), None);
+        names.register(
+            &named("0.0.0.0:18082", "relayed"),
+            Some(Path::new("/tmp/relay/1.sock")),
+        );
+
+        assert_eq!(
+            names.lookup(&inet("127.0.0.3", internal_port)),
+            Some("internal")
+        );
+        assert_eq!(
+            names.lookup(&inet("::ffff:127.0.0.3", internal_port)),
+            Some("internal")
+        );
+        assert_eq!(names.lookup(&inet("127.0.0.4", internal_port)), None);
+        assert_eq!(
+            names.lookup(&inet("192.0.2.1", public_port)),
+            Some("public")
+        );
+        assert_eq!(names.lookup(&inet("127.0.0.1", 18081)), None);
+        assert_eq!(names.lookup(&inet("127.0.0.1", 18082)), None);
+        assert_eq!(names.lookup(&inet("127.0.0.1", 18083)), None);
+        assert_eq!(
+            names.lookup(&SocketAddr::Unix(
+                std::os::unix::net::SocketAddr::from_pathname("/tmp/relay/1.sock").unwrap()
+            )),
+            Some("relayed")
+        );
+    }
+}
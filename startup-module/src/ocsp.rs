@@ -0,0 +1,388 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OCSP stapling
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use openssl::asn1::{Asn1GeneralizedTimeRef, Asn1Time};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::listeners::TlsSettings;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::{background_service, BackgroundService};
+use pingora::services::Service;
+use pingora::utils::CertKey;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cert_reload::ReloadableCert;
+use crate::configuration::TLS_CONF_ERR;
+
+const OCSP_ERR: ErrorType = ErrorType::Custom("OcspError");
+
+/// Interval to check whether OCSP responses need refreshing
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay before retrying if fetching an OCSP response failed
+pub(crate) const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Tolerated clock difference when checking OCSP response validity, in seconds
+const CLOCK_SKEW: u32 = 5 * 60;
+
+/// Textual month representation used by OpenSSL
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A verified OCSP response to be stapled to a certificate
+#[derive(Debug, Clone)]
+pub(crate) struct OcspStaple {
+    response: Vec<u8>,
+    refresh: Instant,
+    expires: Instant,
+}
+
+impl OcspStaple {
+    /// Verifies an OCSP response for the certificate. The response has to be signed by the
+    /// certificate issuer or a responder delegated by it and indicate a good status.
+    pub(crate) fn new(cert: &CertKey, response: Vec<u8>) -> Result<Self, Box<Error>> {
+        let issuer = cert.intermediates().first().ok_or_else(|| {
+            Error::explain(
+                OCSP_ERR,
+                "certificate chain has to contain the issuer certificate for OCSP stapling",
+            )
+        })?;
+
+        let parsed = OcspResponse::from_der(&response)
+            .map_err(|err| Error::because(OCSP_ERR, "failed parsing OCSP response", err))?;
+        if parsed.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(Error::explain(
+                OCSP_ERR,
+                format!(
+                    "OCSP responder returned error status {}",
+                    parsed.status().as_raw()
+                ),
+            ));
+        }
+        let basic = parsed
+            .basic()
+            .map_err(|err| Error::because(OCSP_ERR, "failed parsing OCSP response", err))?;
+
+        (|| {
+            // The issuer is trusted even though it usually isn't a root certificate
+            let mut store = X509StoreBuilder::new()?;
+            store.add_cert(issuer.clone())?;
+            store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+            let mut certs = Stack::new()?;
+            certs.push(issuer.clone())?;
+            basic.verify(&certs, &store.build(), OcspFlag::TRUST_OTHER)
+        })()
+        .map_err(|err| Error::because(OCSP_ERR, "OCSP response signature is invalid", err))?;
+
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), cert.leaf(), issuer)
+            .map_err(|err| Error::because(OCSP_ERR, "failed creating certificate ID", err))?;
+        let status = basic.find_status(&id).ok_or_else(|| {
+            Error::explain(OCSP_ERR, "OCSP response doesn't contain certificate status")
+        })?;
+        if status.status != OcspCertStatus::GOOD {
+            return Err(Error::explain(
+                OCSP_ERR,
+                format!("OCSP certificate status is {}", status.status.as_raw()),
+            ));
+        }
+        status
+            .check_validity(CLOCK_SKEW, None)
+            .map_err(|err| Error::because(OCSP_ERR, "OCSP response is outdated", err))?;
+
+        let remaining = time_until(status.next_update)
+            .ok_or_else(|| Error::explain(OCSP_ERR, "failed determining OCSP response validity"))?;
+        let now = Instant::now();
+        Ok(Self {
+            response,
+            // Refresh halfway through the validity period, leaving plenty of time for retries
+            refresh: now + remaining / 2,
+            expires: now + remaining,
+        })
+    }
+
+    /// Returns the DER-encoded response unless it expired
+    pub(crate) fn response(&self) -> Option<&[u8]> {
+        if Instant::now() < self.expires {
+            Some(&self.response)
+        } else {
+            None
+        }
+    }
+
+    /// Time when a fresh response should be fetched
+    pub(crate) fn refresh(&self) -> Instant {
+        self.refresh
+    }
+}
+
+/// Determines how long until a time in an OCSP response, `None` if it is in the past
+fn time_until(time: &Asn1GeneralizedTimeRef) -> Option<Duration> {
+    // Only the printed representation is accessible, e.g. `Mar  1 12:00:00 2030 GMT`
+    let printed = time.to_string();
+    let mut parts = printed.split_whitespace();
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let time = parts.next()?.split('.').next()?.replace(':', "");
+    let year: u32 = parts.next()?.parse().ok()?;
+
+    let time = Asn1Time::from_str(&format!("{year:04}{month:02}{day:02}{time}Z")).ok()?;
+    let diff = Asn1Time::days_from_now(0).ok()?.diff(&time).ok()?;
+    let seconds = i64::from(diff.days) * 24 * 60 * 60 + i64::from(diff.secs);
+    u64::try_from(seconds).ok().map(Duration::from_secs)
+}
+
+/// Requests the status of the certificate from an OCSP responder
+pub(crate) async fn fetch_response(
+    http: &reqwest::Client,
+    cert: &CertKey,
+    url: &str,
+) -> Result<OcspStaple, Box<Error>> {
+    let issuer = cert.intermediates().first().ok_or_else(|| {
+        Error::explain(
+            OCSP_ERR,
+            "certificate chain has to contain the issuer certificate for OCSP stapling",
+        )
+    })?;
+    let request = (|| {
+        let mut request = OcspRequest::new()?;
+        request.add_id(OcspCertId::from_cert(
+            MessageDigest::sha1(),
+            cert.leaf(),
+            issuer,
+        )?)?;
+        request.to_der()
+    })()
+    .map_err(|err: ErrorStack| Error::because(OCSP_ERR, "failed creating OCSP request", err))?;
+
+    let response = http
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/ocsp-request")
+        .body(request)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| {
+            Error::because(
+                OCSP_ERR,
+                format!("failed querying OCSP responder {url}"),
+                err,
+            )
+        })?
+        .bytes()
+        .await
+        .map_err(|err| {
+            Error::because(
+                OCSP_ERR,
+                format!("failed querying OCSP responder {url}"),
+                err,
+            )
+        })?;
+
+    OcspStaple::new(cert, response.to_vec())
+}
+
+/// Makes the server send OCSP responses set in the certificate callback
+pub(crate) fn enable_stapling(tls: &mut TlsSettings) -> Result<(), Box<Error>> {
+    tls.set_status_callback(|ssl| Ok(ssl.ocsp_status().is_some()))
+        .map_err(|err| Error::because(TLS_CONF_ERR, "failed enabling OCSP stapling", err))
+}
+
+#[derive(Debug)]
+struct OcspTask {
+    http: reqwest::Client,
+    certs: Vec<Arc<ReloadableCert>>,
+}
+
+impl OcspTask {
+    async fn refresh(&self, cert: &ReloadableCert) {
+        if !cert.ocsp_refresh_due() {
+            return;
+        }
+
+        let current = cert.get();
+        let url = current
+            .leaf()
+            .ocsp_responders()
+            .ok()
+            .and_then(|responders| responders.iter().next().map(|url| url.to_string()));
+        let Some(url) = url else {
+            debug!(
+                "no OCSP responder listed in {}, not stapling",
+                cert.description()
+            );
+            cert.set_ocsp(&current, None);
+            return;
+        };
+
+        match fetch_response(&self.http, &current, &url).await {
+            Ok(staple) => {
+                info!("refreshed OCSP response for {}", cert.description());
+                cert.set_ocsp(&current, Some(staple));
+            }
+            Err(err) => {
+                warn!(
+                    "Failed refreshing OCSP response for {}: {err}",
+                    cert.description()
+                );
+                cert.set_ocsp(&current, None);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for OcspTask {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            for cert in &self.certs {
+                self.refresh(cert).await;
+            }
+
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+/// Creates a service fetching OCSP responses for the certificates and refreshing them before
+/// they expire
+pub(crate) fn create_ocsp_service(
+    certs: Vec<Arc<ReloadableCert>>,
+) -> Result<impl Service + 'static, Box<Error>> {
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|err| Error::because(OCSP_ERR, "failed creating HTTP client", err))?;
+    Ok(background_service(
+        "OCSP stapling",
+        OcspTask { http, certs },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::pkey::PKey;
+    use openssl::x509::X509;
+    use std::path::PathBuf;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn test_file(name: &str) -> Vec<u8> {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name);
+        std::fs::read(path).unwrap()
+    }
+
+    fn test_cert() -> CertKey {
+        CertKey::new(
+            X509::stack_from_pem(&test_file("ocsp_cert.pem")).unwrap(),
+            PKey::private_key_from_pem(&test_file("ocsp_key.pem")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn verification() {
+        let cert = test_cert();
+        let staple = OcspStaple::new(&cert, test_file("ocsp_response.der")).unwrap();
+        assert_eq!(
+            staple.response(),
+            Some(test_file("ocsp_response.der").as_slice())
+        );
+        assert!(staple.refresh() > Instant::now() + Duration::from_secs(365 * 24 * 60 * 60));
+
+        // Response doesn't belong to this certificate
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("pandora-web-server")
+            .join("config");
+        let other = CertKey::new(
+            vec![
+                X509::from_pem(&std::fs::read(path.join("cert_localhost.pem")).unwrap()).unwrap(),
+                cert.intermediates()[0].clone(),
+            ],
+            cert.key().clone(),
+        );
+        assert!(OcspStaple::new(&other, test_file("ocsp_response.der")).is_err());
+
+        // Issuer certificate is required
+        let no_issuer = CertKey::new(vec![cert.leaf().clone()], cert.key().clone());
+        assert!(OcspStaple::new(&no_issuer, test_file("ocsp_response.der")).is_err());
+
+        assert!(OcspStaple::new(&cert, b"garbage".to_vec()).is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn fetching() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let responder = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+
+            let response = test_file("ocsp_response.der");
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/ocsp-response\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n",
+                        response.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let staple = fetch_response(&reqwest::Client::new(), &test_cert(), &url)
+            .await
+            .unwrap();
+        assert_eq!(
+            staple.response(),
+            Some(test_file("ocsp_response.der").as_slice())
+        );
+
+        let request = responder.await.unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\n"));
+        assert!(request
+            .to_ascii_lowercase()
+            .contains("content-type: application/ocsp-request"));
+    }
+}
@@ -0,0 +1,776 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PROXY protocol support for listening addresses
+//!
+//! Pingora doesn’t allow looking at incoming data before its TLS or HTTP processing takes over.
+//! So addresses with PROXY protocol enabled are served by a separate Pingora service without TLS.
+//! It processes the PROXY protocol header and passes on remaining data to an internal Unix socket
+//! that the actual service listens on. The relay’s end of the internal connection is bound to a
+//! unique socket name, the client address is kept in memory under that name while the connection
+//! exists. `DefaultApp` will look it up and apply it to the session.
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::debug;
+use nix::unistd::geteuid;
+use once_cell::sync::{Lazy, OnceCell};
+use pandora_module_utils::pingora::{Error, ErrorType, SocketAddr};
+use pingora::apps::ServerApp;
+use pingora::listeners::{ServerAddress, TlsSettings};
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
+use pingora::services::listening::Service as ListeningService;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::fs::DirBuilder;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::configuration::ListenAddr;
+use crate::listener::ListenerNames;
+use crate::systemd::add_listening_socket;
+
+const PROXY_CONF_ERR: ErrorType = ErrorType::Custom("ProxyProtocolConfigError");
+const PROXY_ERR: ErrorType = ErrorType::Custom("ProxyProtocolError");
+
+/// How long to wait for the PROXY protocol header to arrive
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal length of a PROXY protocol version 1 header, including the terminating CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Prefix of a PROXY protocol version 1 header
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Signature starting a PROXY protocol version 2 header
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the fixed part of a PROXY protocol version 2 header
+const V2_HEADER_LENGTH: usize = 16;
+
+/// Connection backlog of the internal sockets
+const LISTEN_BACKLOG: i32 = 65535;
+
+/// How long to keep retrying if the backlog of the internal socket is full
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal delay between connection attempts to the internal socket
+const MAX_CONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximal length of a socket path, the size of `sun_path` minus the terminating null byte
+const MAX_SOCKET_PATH_LENGTH: usize = 107;
+
+/// Maximal length of an internal socket name: a 64-bit counter value and the `.sock` suffix
+const MAX_SOCKET_NAME_LENGTH: usize = 20 + 5;
+
+/// Directory containing the internal sockets, only accessible to the user the server runs as
+static RELAY_DIRECTORY: OnceCell<PathBuf> = OnceCell::new();
+
+/// Counter making the names of internal sockets unique
+static SOCKET_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Client addresses of relayed connections, keyed by the counter value in the relay’s socket name
+static RELAYED_ADDRS: Lazy<Mutex<HashMap<u64, SocketAddr>>> = Lazy::new(Default::default);
+
+/// Creates the directory for internal sockets if necessary.
+fn relay_directory() -> std::io::Result<&'static Path> {
+    RELAY_DIRECTORY
+        .get_or_try_init(|| {
+            let path = std::env::temp_dir().join(format!("pandora-relay-{}", std::process::id()));
+
+            // Remove leftovers of an earlier process with the same ID, e.g. in a container
+            if let Ok(metadata) = path.symlink_metadata() {
+                if metadata.is_dir() && metadata.uid() == geteuid().as_raw() {
+                    std::fs::remove_dir_all(&path)?;
+                }
+            }
+
+            // Checking once here makes sure that all socket names will fit into sun_path
+            if path.as_os_str().len() + 1 + MAX_SOCKET_NAME_LENGTH > MAX_SOCKET_PATH_LENGTH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "path {} is too long for Unix sockets, set TMPDIR to a shorter path",
+                        path.display()
+                    ),
+                ));
+            }
+
+            DirBuilder::new().mode(0o700).create(&path)?;
+            Ok(path)
+        })
+        .map(PathBuf::as_path)
+}
+
+/// Produces a new unique path for an internal socket, the name is a number with an optional
+/// suffix. The number is returned as well.
+fn internal_socket_path(suffix: &str) -> std::io::Result<(u64, PathBuf)> {
+    let id = SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = relay_directory()?.join(format!("{id}{suffix}"));
+    Ok((id, path))
+}
+
+/// Looks up the actual client address if the connection has been relayed. It is stored under
+/// the name of the socket that the relay connected from.
+pub(crate) fn relayed_client_addr(client_addr: Option<&SocketAddr>) -> Option<SocketAddr> {
+    let Some(SocketAddr::Unix(client_addr)) = client_addr else {
+        return None;
+    };
+    let path = client_addr.as_pathname()?;
+    if path.parent()? != RELAY_DIRECTORY.get()? {
+        return None;
+    }
+
+    let id: u64 = path.file_name()?.to_str()?.parse().ok()?;
+    RELAYED_ADDRS.lock().unwrap().get(&id).cloned()
+}
+
+/// Client address registered for a relayed connection, removed again when dropped
+#[derive(Debug)]
+struct RelayedAddr(u64);
+
+impl RelayedAddr {
+    fn register(id: u64, client_addr: SocketAddr) -> Self {
+        RELAYED_ADDRS.lock().unwrap().insert(id, client_addr);
+        Self(id)
+    }
+}
+
+impl Drop for RelayedAddr {
+    fn drop(&mut self) {
+        RELAYED_ADDRS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Result of parsing the PROXY protocol header
+#[derive(Debug, PartialEq, Eq)]
+enum Header {
+    /// More data is required to parse the header
+    Incomplete,
+
+    /// Header has been parsed
+    Complete {
+        /// Client address if the header indicates one
+        source: Option<std::net::SocketAddr>,
+
+        /// Header length
+        length: usize,
+    },
+}
+
+fn invalid_header(context: &'static str) -> Box<Error> {
+    Error::explain(PROXY_ERR, context)
+}
+
+fn parse_v1(data: &[u8]) -> Result<Header, Box<Error>> {
+    let Some(end) = data.windows(2).position(|window| window == b"\r\n") else {
+        return if data.len() < V1_MAX_LENGTH {
+            Ok(Header::Incomplete)
+        } else {
+            Err(invalid_header("PROXY protocol header too long"))
+        };
+    };
+    if end + 2 > V1_MAX_LENGTH {
+        return Err(invalid_header("PROXY protocol header too long"));
+    }
+
+    let line = std::str::from_utf8(&data[V1_PREFIX.len()..end])
+        .map_err(|_| invalid_header("PROXY protocol header isn't valid UTF-8"))?;
+    let mut parts = line.split(' ');
+    let source = match parts.next() {
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let mut next = || {
+                parts
+                    .next()
+                    .ok_or_else(|| invalid_header("PROXY protocol header is missing fields"))
+            };
+            let ip: IpAddr = next()?
+                .parse()
+                .map_err(|_| invalid_header("invalid source address in PROXY protocol header"))?;
+            let _: IpAddr = next()?.parse().map_err(|_| {
+                invalid_header("invalid destination address in PROXY protocol header")
+            })?;
+            let port: u16 = next()?
+                .parse()
+                .map_err(|_| invalid_header("invalid source port in PROXY protocol header"))?;
+            let _: u16 = next()?
+                .parse()
+                .map_err(|_| invalid_header("invalid destination port in PROXY protocol header"))?;
+            if parts.next().is_some() {
+                return Err(invalid_header("unexpected data in PROXY protocol header"));
+            }
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid_header(
+                    "source address in PROXY protocol header doesn't match protocol",
+                ));
+            }
+            Some(std::net::SocketAddr::new(ip, port))
+        }
+        Some("UNKNOWN") => None,
+        _ => {
+            return Err(invalid_header(
+                "unsupported protocol in PROXY protocol header",
+            ))
+        }
+    };
+
+    Ok(Header::Complete {
+        source,
+        length: end + 2,
+    })
+}
+
+fn parse_v2(data: &[u8]) -> Result<Header, Box<Error>> {
+    if data.len() < V2_HEADER_LENGTH {
+        return Ok(Header::Incomplete);
+    }
+
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported PROXY protocol version"));
+    }
+
+    let family_protocol = data[13];
+    let length = V2_HEADER_LENGTH + usize::from(u16::from_be_bytes([data[14], data[15]]));
+    if data.len() < length {
+        return Ok(Header::Incomplete);
+    }
+
+    let addresses = &data[V2_HEADER_LENGTH..length];
+    let source = match (version_command & 0x0F, family_protocol) {
+        // LOCAL command, e.g. health checks by the proxy itself
+        (0x0, _) => None,
+        // PROXY command, TCP over IPv4
+        (0x1, 0x11) if addresses.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addresses[0..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(std::net::SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        // PROXY command, TCP over IPv6
+        (0x1, 0x21) if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[0..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(std::net::SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        (0x1, 0x11 | 0x21) => {
+            return Err(invalid_header("PROXY protocol header is missing addresses"));
+        }
+        // PROXY command with an unsupported protocol, use connection address
+        (0x1, _) => None,
+        _ => return Err(invalid_header("unsupported PROXY protocol command")),
+    };
+
+    Ok(Header::Complete { source, length })
+}
+
+/// Parses a PROXY protocol header of either version at the start of the data
+fn parse_header(data: &[u8]) -> Result<Header, Box<Error>> {
+    if data.starts_with(V2_SIGNATURE) {
+        parse_v2(data)
+    } else if data.starts_with(V1_PREFIX) {
+        parse_v1(data)
+    } else if V2_SIGNATURE.starts_with(data) || V1_PREFIX.starts_with(data) {
+        Ok(Header::Incomplete)
+    } else {
+        Err(invalid_header(
+            "connection doesn't start with a PROXY protocol header",
+        ))
+    }
+}
+
+fn is_trusted(trusted: &[IpNet], trust_unix: bool, peer: &SocketAddr) -> bool {
+    match peer {
+        SocketAddr::Inet(addr) => {
+            let ip = match addr.ip() {
+                IpAddr::V6(ip) => ip
+                    .to_ipv4_mapped()
+                    .map(IpAddr::V4)
+                    .unwrap_or(IpAddr::V6(ip)),
+                ip => ip,
+            };
+            trusted.iter().any(|net| net.contains(&ip))
+        }
+        SocketAddr::Unix(_) => trust_unix,
+    }
+}
+
+/// Connects to the internal socket, binding the connection to a unique name first. The client
+/// address is registered under this name for as long as the returned guard is kept.
+async fn connect(
+    target: &Path,
+    client_addr: SocketAddr,
+) -> std::io::Result<(UnixStream, RelayedAddr)> {
+    let (id, path) = internal_socket_path("")?;
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(&path)?)?;
+
+    // The socket keeps its name when the file system entry is removed, nobody needs to connect
+    // to it.
+    let _ = std::fs::remove_file(&path);
+    socket.set_nonblocking(true)?;
+    let relayed_addr = RelayedAddr::register(id, client_addr);
+
+    // Connecting to a Unix socket completes immediately unless its backlog is full. Retry then,
+    // the connection shouldn’t be dropped just because the server is busy.
+    let target = SockAddr::unix(target)?;
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let mut delay = Duration::from_millis(1);
+    loop {
+        match socket.connect(&target) {
+            Ok(()) => break,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(err);
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_CONNECT_DELAY);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let stream = UnixStream::from_std(std::os::unix::net::UnixStream::from(socket))?;
+    Ok((stream, relayed_addr))
+}
+
+async fn relay<S>(
+    mut stream: S,
+    peer: &SocketAddr,
+    trusted: bool,
+    target: &Path,
+) -> Result<(), Box<Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = Vec::with_capacity(V1_MAX_LENGTH);
+    let mut client_addr = peer.clone();
+    if trusted {
+        loop {
+            match parse_header(&buffer)? {
+                Header::Incomplete => {
+                    let read = tokio::time::timeout(HEADER_TIMEOUT, stream.read_buf(&mut buffer))
+                        .await
+                        .map_err(|err| {
+                            Error::because(PROXY_ERR, "timed out waiting for PROXY header", err)
+                        })?
+                        .map_err(|err| {
+                            Error::because(ErrorType::ReadError, "failed reading PROXY header", err)
+                        })?;
+                    if read == 0 {
+                        return Err(invalid_header(
+                            "connection closed before PROXY protocol header was received",
+                        ));
+                    }
+                }
+                Header::Complete { source, length } => {
+                    if let Some(source) = source {
+                        client_addr = SocketAddr::Inet(source);
+                    }
+                    buffer.drain(..length);
+                    break;
+                }
+            }
+        }
+    }
+
+    let (mut upstream, _relayed_addr) = connect(target, client_addr).await.map_err(|err| {
+        Error::because(
+            ErrorType::ConnectError,
+            format!("failed connecting to {}", target.display()),
+            err,
+        )
+    })?;
+
+    upstream
+        .write_all(&buffer)
+        .await
+        .map_err(|err| Error::because(ErrorType::WriteError, "failed relaying data", err))?;
+    copy_bidirectional(&mut stream, &mut upstream)
+        .await
+        .map_err(|err| Error::because(ErrorType::ReadError, "failed relaying data", err))?;
+    Ok(())
+}
+
+/// App relaying connections to addresses with PROXY protocol enabled
+#[derive(Debug)]
+pub(crate) struct ProxyProtocolRelay {
+    trusted: Vec<IpNet>,
+    trust_unix: bool,
+    target: PathBuf,
+}
+
+#[async_trait]
+impl ServerApp for ProxyProtocolRelay {
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let Some(peer) = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().cloned())
+        else {
+            debug!("Dropping connection with unknown peer address");
+            return None;
+        };
+
+        let trusted = is_trusted(&self.trusted, self.trust_unix, &peer);
+        if let Err(err) = relay(stream, &peer, trusted, &self.target).await {
+            debug!("Relaying connection from {peer} failed: {err}");
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        let _ = std::fs::remove_file(&self.target);
+    }
+}
+
+/// Binds the internal socket that relayed connections are passed on to
+fn bind_internal(path: &Path) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Adds an address to the listening service.
+///
+/// If PROXY protocol is enabled for the address, the service will listen on an internal Unix
+/// socket instead. A relay service listening on the address is returned then which needs to be
+/// added to the server. The name of the address is added to `names`.
+pub(crate) fn add_listen_addr<A>(
+    service: &mut ListeningService<A>,
+    addr: &ListenAddr,
+    tls: Option<TlsSettings>,
+    names: &mut ListenerNames,
+) -> Result<Option<ListeningService<ProxyProtocolRelay>>, Box<Error>> {
+    if !addr.proxy_protocol {
+        names.register(addr, None);
+        service
+            .endpoints()
+            .add_endpoint(addr.to_server_address(), tls);
+        return Ok(None);
+    }
+
+    if addr.unix_path().is_some() && !addr.proxy_protocol_trust_unix {
+        return Err(Error::explain(
+            PROXY_CONF_ERR,
+            format!(
+                "proxy_protocol_trust_unix setting is required to enable PROXY protocol for address {}",
+                addr.addr
+            ),
+        ));
+    }
+    if addr.unix_path().is_none() && addr.proxy_protocol_trusted.is_empty() {
+        return Err(Error::explain(
+            PROXY_CONF_ERR,
+            format!(
+                "proxy_protocol_trusted setting is required to enable PROXY protocol for address {}",
+                addr.addr
+            ),
+        ));
+    }
+
+    // The internal socket is bound right away and handed over to Pingora, so that no other
+    // process can take its place.
+    let (socket, target) = internal_socket_path(".sock")
+        .and_then(|(_, target)| Ok((bind_internal(&target)?, target)))
+        .map_err(|err| {
+            Error::because(
+                PROXY_CONF_ERR,
+                format!("failed creating internal socket for address {}", addr.addr),
+                err,
+            )
+        })?;
+    let target_str = target.to_str().ok_or_else(|| {
+        Error::explain(
+            PROXY_CONF_ERR,
+            format!("path {} isn't valid UTF-8", target.display()),
+        )
+    })?;
+    add_listening_socket(target_str.to_owned(), socket);
+
+    names.register(addr, Some(&target));
+    service
+        .endpoints()
+        .add_endpoint(ServerAddress::Uds(target_str.to_owned(), None), tls);
+
+    let mut relay = ListeningService::new(
+        format!("PROXY protocol relay {}", addr.addr),
+        ProxyProtocolRelay {
+            trusted: addr.proxy_protocol_trusted.clone(),
+            trust_unix: addr.proxy_protocol_trust_unix,
+            target,
+        },
+    );
+    relay
+        .endpoints()
+        .add_endpoint(addr.to_server_address(), None);
+    Ok(Some(relay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn complete(source: Option<&str>, length: usize) -> Header {
+        Header::Complete {
+            source: source.map(|source| source.parse().unwrap()),
+            length,
+        }
+    }
+
+    #[test]
+    fn v1() {
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            parse_header(header).unwrap(),
+            complete(Some("192.0.2.1:56324"), 42)
+        );
+        for length in 0..42 {
+            assert_eq!(parse_header(&header[..length]).unwrap(), Header::Incomplete);
+        }
+
+        assert_eq!(
+            parse_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            complete(Some("[2001:db8::1]:56324"), 46)
+        );
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n").unwrap(),
+            complete(None, 15)
+        );
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN 192.0.2.1 192.0.2.2 56324 443\r\n").unwrap(),
+            complete(None, 45)
+        );
+
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443 1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 563240 443\r\n").is_err());
+        assert!(parse_header(b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 443\r\n").is_err());
+        assert!(parse_header(&[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(20)).is_err());
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB]);
+        header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert_eq!(
+            parse_header(&header).unwrap(),
+            complete(Some("192.0.2.1:56324"), 28)
+        );
+        for length in 0..28 {
+            assert_eq!(parse_header(&header[..length]).unwrap(), Header::Incomplete);
+        }
+
+        // IPv6 with additional TLV data
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 40]);
+        header.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&[0xDC, 0x04, 0x01, 0xBB, 0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            parse_header(&header).unwrap(),
+            complete(Some("[2001:db8::1]:56324"), 56)
+        );
+
+        // LOCAL command
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(parse_header(&header).unwrap(), complete(None, 16));
+
+        // Unix socket addresses are ignored
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x31, 0, 216]);
+        header.extend_from_slice(&[0; 216]);
+        assert_eq!(parse_header(&header).unwrap(), complete(None, 232));
+
+        // Addresses missing
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(parse_header(&header).is_err());
+
+        // Wrong version
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(parse_header(&header).is_err());
+    }
+
+    #[test]
+    fn trusted() {
+        let trusted = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ];
+        let inet = |addr: &str| SocketAddr::Inet(addr.parse().unwrap());
+        assert!(is_trusted(&trusted, false, &inet("10.1.2.3:1234")));
+        assert!(is_trusted(&trusted, false, &inet("[::ffff:10.1.2.3]:1234")));
+        assert!(is_trusted(&trusted, false, &inet("[2001:db8::1]:1234")));
+        assert!(!is_trusted(&trusted, false, &inet("192.0.2.1:1234")));
+        assert!(!is_trusted(&trusted, false, &inet("[2001:db9::1]:1234")));
+        assert!(!is_trusted(&[], false, &inet("10.1.2.3:1234")));
+        assert!(!is_trusted(&[], true, &inet("10.1.2.3:1234")));
+
+        let unix = SocketAddr::Unix(
+            std::os::unix::net::SocketAddr::from_pathname("/run/pandora.sock").unwrap(),
+        );
+        assert!(!is_trusted(&trusted, false, &unix));
+        assert!(is_trusted(&[], true, &unix));
+    }
+
+    fn internal_listener() -> (tokio::net::UnixListener, PathBuf) {
+        let (_, target) = internal_socket_path(".sock").unwrap();
+        let listener = tokio::net::UnixListener::bind(&target).unwrap();
+        (listener, target)
+    }
+
+    fn relayed(addr: &tokio::net::unix::SocketAddr) -> Option<SocketAddr> {
+        // Tokio’s address type cannot be converted, resolve it via the path
+        let addr = std::os::unix::net::SocketAddr::from_pathname(addr.as_pathname()?).ok()?;
+        relayed_client_addr(Some(&SocketAddr::Unix(addr)))
+    }
+
+    #[test(tokio::test)]
+    async fn relays_connections() {
+        let (listener, target) = internal_listener();
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let peer = SocketAddr::Inet("10.0.0.1:1234".parse().unwrap());
+        let relay = tokio::spawn(async move { relay(server, &peer, true, &target).await });
+
+        client
+            .write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nHi")
+            .await
+            .unwrap();
+
+        let (mut connection, relay_addr) = listener.accept().await.unwrap();
+        assert_eq!(
+            relayed(&relay_addr),
+            Some(SocketAddr::Inet("192.0.2.1:56324".parse().unwrap()))
+        );
+
+        // The file system entry is removed once connected
+        assert!(!relay_addr.as_pathname().unwrap().exists());
+
+        // The client address is only stored while the connection exists
+        let relay_addr = relay_addr.as_pathname().unwrap().to_owned();
+
+        let mut buffer = [0; 2];
+        connection.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"Hi");
+
+        connection.write_all(b"Hello").await.unwrap();
+        drop(connection);
+        let mut buffer = Vec::new();
+        client.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"Hello");
+
+        drop(client);
+        relay.await.unwrap().unwrap();
+
+        let relay_addr = std::os::unix::net::SocketAddr::from_pathname(relay_addr).unwrap();
+        assert_eq!(
+            relayed_client_addr(Some(&SocketAddr::Unix(relay_addr))),
+            None
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn full_backlog() {
+        let (_, target) = internal_socket_path(".sock").unwrap();
+        let listener = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+        listener.bind(&SockAddr::unix(&target).unwrap()).unwrap();
+        listener.listen(0).unwrap();
+
+        // Fill the backlog until connecting fails
+        let mut pending = Vec::new();
+        loop {
+            let socket = Socket::new(Domain::UNIX, Type::STREAM, None).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            match socket.connect(&SockAddr::unix(&target).unwrap()) {
+                Ok(()) => pending.push(socket),
+                Err(err) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+                    break;
+                }
+            }
+        }
+
+        let peer = SocketAddr::Inet("192.0.2.1:1234".parse().unwrap());
+        let connection = tokio::spawn(async move { connect(&target, peer).await });
+
+        // Connecting succeeds once the backlog is processed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connection.is_finished());
+        let _accepted = listener.accept().unwrap();
+        assert!(connection.await.unwrap().is_ok());
+    }
+
+    #[test(tokio::test)]
+    async fn untrusted_connections() {
+        let (listener, target) = internal_listener();
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let peer = SocketAddr::Inet("192.0.2.3:1234".parse().unwrap());
+        let relay = tokio::spawn({
+            let peer = peer.clone();
+            async move { relay(server, &peer, false, &target).await }
+        });
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        let (mut connection, relay_addr) = listener.accept().await.unwrap();
+        assert_eq!(relayed(&relay_addr), Some(peer));
+
+        let mut buffer = [0; 16];
+        connection.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"GET / HTTP/1.1\r\n");
+
+        drop(connection);
+        drop(client);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn foreign_addresses() {
+        let inet = SocketAddr::Inet("192.0.2.1:1234".parse().unwrap());
+        assert_eq!(relayed_client_addr(Some(&inet)), None);
+
+        let unix = std::os::unix::net::SocketAddr::from_pathname("/tmp/1").unwrap();
+        assert_eq!(relayed_client_addr(Some(&SocketAddr::Unix(unix))), None);
+
+        // Unknown connection in the relay directory
+        let (_, path) = internal_socket_path("").unwrap();
+        let unix = std::os::unix::net::SocketAddr::from_pathname(path).unwrap();
+        assert_eq!(relayed_client_addr(Some(&SocketAddr::Unix(unix))), None);
+    }
+}
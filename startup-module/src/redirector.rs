@@ -13,60 +13,156 @@
 // limitations under the License.
 
 use async_trait::async_trait;
+use http::uri::Authority;
 use http::{header, Method, StatusCode};
 use pandora_module_utils::pingora::{
-    Error, ErrorType, HttpPeer, ProxyHttp, ResponseHeader, ServerConf, Session,
+    Error, ErrorType, HttpModules, HttpPeer, ProxyHttp, ResponseHeader, ServerConf, Session,
+    SessionWrapper,
 };
 use pandora_module_utils::standard_response::response_text;
+use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pingora::protocols::Digest;
 use pingora::{proxy::http_proxy_service, services::Service};
 use std::collections::HashMap;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 
+use crate::acme::{AcmeState, ACME_CHALLENGE_PATH};
 use crate::configuration::{TlsRedirectorConf, TLS_CONF_ERR};
+use crate::listener::ListenerNames;
+use crate::proxy_protocol::add_listen_addr;
+use crate::DefaultApp;
 
-struct RedirectorApp {
-    redirect_to: String,
-    redirect_by_name: HashMap<String, String>,
-}
+/// Handler used by the redirector if no handler chain is configured, leaves all requests to the
+/// redirect
+#[derive(Debug)]
+pub(crate) struct NoHandler;
 
 #[async_trait]
-impl ProxyHttp for RedirectorApp {
+impl RequestFilter for NoHandler {
+    type Conf = ();
     type CTX = ();
-    fn new_ctx(&self) -> Self::CTX {}
+    fn new_ctx() -> Self::CTX {}
 
     async fn request_filter(
         &self,
-        session: &mut Session,
+        _session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
-    ) -> Result<bool, Box<Error>> {
-        let status = StatusCode::PERMANENT_REDIRECT;
-        let text = response_text(status);
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        Ok(RequestFilterResult::Unhandled)
+    }
+}
+
+/// Determines the redirect targets
+#[derive(Debug, Default)]
+struct RedirectTarget {
+    redirect_to: String,
+    redirect_by_name: HashMap<String, String>,
+    https_port: Option<u16>,
+}
+
+impl RedirectTarget {
+    /// Produces the redirect location for a request to the given host, `None` if no target can be
+    /// determined.
+    fn location(&self, host: Option<&str>, path_and_query: &str) -> Option<String> {
+        let authority = host.and_then(|host| host.parse::<Authority>().ok());
+        let server_name = authority
+            .as_ref()
+            .map(|authority| authority.host().to_ascii_lowercase());
+
+        let target = if let Some(target) = server_name
+            .as_ref()
+            .and_then(|name| self.redirect_by_name.get(name))
+        {
+            target.clone()
+        } else if !self.redirect_to.is_empty() {
+            self.redirect_to.clone()
+        } else {
+            // Redirect to the requested server name, on the port of the HTTPS server
+            match (server_name?, self.https_port) {
+                (name, Some(port)) if port != 443 => format!("{name}:{port}"),
+                (name, _) => name,
+            }
+        };
+
+        Some(format!("https://{target}{path_and_query}"))
+    }
+}
+
+struct RedirectorApp<H> {
+    app: DefaultApp<H>,
+    target: RedirectTarget,
+    acme: Option<Arc<AcmeState>>,
+}
+
+impl<H> RedirectorApp<H> {
+    /// Responds to an ACME http-01 challenge if the request is for a pending one
+    async fn acme_challenge(&self, session: &mut Session) -> Result<bool, Box<Error>> {
+        let Some(acme) = &self.acme else {
+            return Ok(false);
+        };
+        let Some(token) = session
+            .req_header()
+            .uri
+            .path()
+            .strip_prefix(ACME_CHALLENGE_PATH)
+        else {
+            return Ok(false);
+        };
+        let Some(key_authorization) = acme.http_challenge(token) else {
+            return Ok(false);
+        };
+
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+        header.append_header(header::CONTENT_LENGTH, key_authorization.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "text/plain")?;
+
+        let send_body = session.req_header().method != Method::HEAD;
+        session
+            .write_response_header(Box::new(header), !send_body)
+            .await?;
+
+        if send_body {
+            session
+                .write_response_body(Some(key_authorization.into()), true)
+                .await?;
+        }
+
+        Ok(true)
+    }
 
-        let server_name = session
+    /// Redirects the request to HTTPS
+    async fn redirect(&self, session: &mut Session) -> Result<(), Box<Error>> {
+        let host = session
             .get_header(header::HOST)
             .and_then(|host| host.to_str().ok())
-            .map(|host| host.split_once(':').map(|(host, _)| host).unwrap_or(host))
-            .or_else(|| session.req_header().uri.host());
-        let target = server_name
-            .and_then(|host| self.redirect_by_name.get(host))
-            .unwrap_or(&self.redirect_to);
-
-        let mut header = ResponseHeader::build(status, Some(4))?;
-        header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
-        header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
-        header.append_header(
-            header::LOCATION,
-            format!(
-                "https://{}{}",
-                target,
+            .or_else(|| {
                 session
                     .req_header()
                     .uri
-                    .path_and_query()
-                    .map(|p| p.as_str())
-                    .unwrap_or_default()
-            ),
-        )?;
+                    .authority()
+                    .map(|authority| authority.as_str())
+            });
+        let path_and_query = session
+            .req_header()
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or_default();
+        let Some(location) = self.target.location(host, path_and_query) else {
+            return Err(Error::explain(
+                ErrorType::HTTPStatus(400),
+                "no server name to redirect to",
+            ));
+        };
+
+        let status = StatusCode::PERMANENT_REDIRECT;
+        let text = response_text(status);
+
+        let mut header = ResponseHeader::build(status, Some(3))?;
+        header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "text/html;charset=utf-8")?;
+        header.append_header(header::LOCATION, location)?;
 
         let send_body = session.req_header().method != Method::HEAD;
         session
@@ -77,41 +173,116 @@ impl ProxyHttp for RedirectorApp {
             session.write_response_body(Some(text.into()), true).await?;
         }
 
-        Ok(true)
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<H> ProxyHttp for RedirectorApp<H>
+where
+    H: RequestFilter + Send + Sync + 'static,
+    H::CTX: Send + Sync,
+{
+    type CTX = <DefaultApp<H> as ProxyHttp>::CTX;
+
+    fn new_ctx(&self) -> Self::CTX {
+        self.app.new_ctx()
+    }
+
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        self.app.init_downstream_modules(modules);
+    }
+
+    async fn early_request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app.early_request_filter(session, ctx).await
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        if self.acme_challenge(session).await? {
+            return Ok(true);
+        }
+
+        match self.app.handler_request_filter(session, ctx).await? {
+            RequestFilterResult::ResponseSent => Ok(true),
+            // Response will be produced by the upstream server
+            RequestFilterResult::Handled => Ok(false),
+            RequestFilterResult::Unhandled => {
+                self.redirect(session).await?;
+                Ok(true)
+            }
+        }
     }
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
-        _ctx: &mut Self::CTX,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>, Box<Error>> {
-        Err(Error::new(ErrorType::HTTPStatus(404)))
+        self.app.upstream_peer(session, ctx).await
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .connected_to_upstream(session, reused, peer, fd, digest, ctx)
+            .await
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        self.app.logging(session, e, ctx).await
     }
 }
 
-pub(crate) fn create_redirector(
+/// Creates the redirector service, along with any PROXY protocol relays required for it
+///
+/// Requests are passed to the app first, only requests it leaves unhandled are redirected. If
+/// ACME state is passed in, the redirector will also respond to http-01 challenges.
+/// `https_port` is the port of the HTTPS server if known.
+pub(crate) fn create_redirector<H>(
     conf: &TlsRedirectorConf,
+    app: DefaultApp<H>,
     server_conf: &Arc<ServerConf>,
-) -> Result<impl Service + 'static, Box<Error>> {
-    if conf.redirect_to.is_empty() {
-        return Err(Error::explain(
-            TLS_CONF_ERR,
-            "tls.redirector.redirect_to setting has to be specified for TLS redirector",
-        ));
-    }
-
+    https_port: Option<u16>,
+    acme: Option<Arc<AcmeState>>,
+) -> Result<Vec<Box<dyn Service>>, Box<Error>>
+where
+    H: RequestFilter + Send + Sync + 'static,
+    H::CTX: Send + Sync,
+{
     let mut redirect_by_name = HashMap::new();
     for (names, target) in &conf.redirect_by_name {
         for name in names {
-            redirect_by_name.insert(name.clone(), target.clone());
+            redirect_by_name.insert(name.to_ascii_lowercase(), target.clone());
         }
     }
 
     let app = RedirectorApp {
-        redirect_to: conf.redirect_to.clone(),
-        redirect_by_name,
+        app,
+        target: RedirectTarget {
+            redirect_to: conf.redirect_to.clone(),
+            redirect_by_name,
+            https_port: conf.https_port.or(https_port),
+        },
+        acme,
     };
     let mut service = http_proxy_service(server_conf, app);
+    let mut services: Vec<Box<dyn Service>> = Vec::new();
+    let mut names = ListenerNames::default();
 
     for addr in &conf.listen {
         if addr.tls {
@@ -121,12 +292,266 @@ pub(crate) fn create_redirector(
             ));
         }
 
-        if let Some(socket_options) = addr.to_socket_options() {
-            service.add_tcp_with_settings(&addr.addr, socket_options);
-        } else {
-            service.add_tcp(&addr.addr);
+        if addr.tls_options.is_some() {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                "tls.redirector.listen setting cannot contain addresses with TLS options",
+            ));
+        }
+
+        if addr.http2 {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                "tls.redirector.listen setting cannot contain any HTTP/2 addresses",
+            ));
+        }
+
+        if let Some(relay) = add_listen_addr(&mut service, addr, None, &mut names)? {
+            services.push(Box::new(relay));
         }
     }
+    names.add_to_service(&mut service);
+
+    services.push(Box::new(service));
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pingora::server::ShutdownWatch;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+    use tokio::sync::watch;
+
+    /// Handler answering requests to `/health` and `/.well-known/`, requests to `/proxied` are
+    /// passed on to the upstream server if any, other requests are left to the redirect
+    #[derive(Debug)]
+    struct Handler {
+        upstream: Option<std::net::SocketAddr>,
+    }
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let path = session.uri().path();
+            if path == "/proxied" && self.upstream.is_some() {
+                return Ok(RequestFilterResult::Handled);
+            }
+            if path != "/health" && !path.starts_with("/.well-known/") {
+                return Ok(RequestFilterResult::Unhandled);
+            }
 
-    Ok(service)
+            let text = "handled";
+            let mut header = ResponseHeader::build(StatusCode::OK, Some(1))?;
+            header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session.write_response_body(Some(text.into()), true).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+            Ok(self
+                .upstream
+                .map(|addr| Box::new(HttpPeer::new(addr, false, String::new()))))
+        }
+    }
+
+    /// Runs an upstream server responding with `proxied` to a single request
+    async fn upstream_server() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(len > 0);
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\nproxied",
+                )
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    /// Runs a redirector on a Unix socket and sends a request for `path` to it, returns the
+    /// response with the header names lowercased.
+    async fn redirector_response(
+        upstream: Option<std::net::SocketAddr>,
+        acme: Option<Arc<AcmeState>>,
+        path: &str,
+    ) -> String {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let socket_path = std::env::temp_dir().join(format!(
+            "redirector-test-{}-{}.sock",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&socket_path);
+
+        let conf = TlsRedirectorConf {
+            listen: vec![format!("unix:{}", socket_path.display()).into()].into(),
+            redirect_to: "example.com".to_owned(),
+            ..Default::default()
+        };
+        let mut services = create_redirector(
+            &conf,
+            DefaultApp::new(Handler { upstream }),
+            &Arc::new(ServerConf::default()),
+            None,
+            acme,
+        )
+        .unwrap();
+        let mut service = services.pop().unwrap();
+        let (_shutdown_sender, shutdown): (_, ShutdownWatch) = watch::channel(false);
+        let server = tokio::spawn(async move { service.start_service(None, shutdown).await });
+
+        let mut connection = None;
+        for _ in 0..50 {
+            if let Ok(stream) = UnixStream::connect(&socket_path).await {
+                connection = Some(stream);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let mut connection = connection.unwrap();
+        connection
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        connection.read_to_end(&mut response).await.unwrap();
+
+        server.abort();
+        let _ = std::fs::remove_file(&socket_path);
+
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        format!("{}\r\n\r\n{body}", head.to_ascii_lowercase())
+    }
+
+    #[test(tokio::test)]
+    async fn handled_by_chain() {
+        let response = redirector_response(None, None, "/health").await;
+        assert!(response.starts_with("http/1.1 200 "), "{response}");
+        assert!(response.ends_with("\r\n\r\nhandled"), "{response}");
+    }
+
+    #[test(tokio::test)]
+    async fn handled_by_upstream() {
+        let upstream = upstream_server().await;
+        let response = redirector_response(Some(upstream), None, "/proxied").await;
+        assert!(response.starts_with("http/1.1 200 "), "{response}");
+        assert!(response.ends_with("\r\n\r\nproxied"), "{response}");
+
+        // Without an upstream server the request is left to the redirect
+        let response = redirector_response(None, None, "/proxied").await;
+        assert!(response.starts_with("http/1.1 308 "), "{response}");
+    }
+
+    #[test(tokio::test)]
+    async fn unhandled_redirected() {
+        let response = redirector_response(None, None, "/path?query").await;
+        assert!(response.starts_with("http/1.1 308 "), "{response}");
+        assert!(
+            response.contains("\r\nlocation: https://example.com/path?query\r\n"),
+            "{response}"
+        );
+        // HSTS headers are only sent via TLS
+        assert!(
+            !response.contains("\r\nstrict-transport-security:"),
+            "{response}"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn acme_challenge_first() {
+        let acme = Arc::new(AcmeState::with_http_challenge("token", "token.thumbprint"));
+
+        let response = redirector_response(
+            None,
+            Some(acme.clone()),
+            "/.well-known/acme-challenge/token",
+        )
+        .await;
+        assert!(response.starts_with("http/1.1 200 "), "{response}");
+        assert!(response.ends_with("\r\n\r\ntoken.thumbprint"), "{response}");
+
+        // Unknown tokens are left to the handler chain
+        let response =
+            redirector_response(None, Some(acme), "/.well-known/acme-challenge/other").await;
+        assert!(response.ends_with("\r\n\r\nhandled"), "{response}");
+    }
+
+    #[test]
+    fn redirect_location() {
+        let mut target = RedirectTarget {
+            redirect_to: "example.com".into(),
+            redirect_by_name: [("example.net".into(), "www.example.net:8443".into())].into(),
+            https_port: Some(8443),
+        };
+
+        assert_eq!(
+            target
+                .location(Some("localhost:8080"), "/path?query")
+                .as_deref(),
+            Some("https://example.com/path?query")
+        );
+        assert_eq!(
+            target.location(Some("Example.NET"), "/").as_deref(),
+            Some("https://www.example.net:8443/")
+        );
+        assert_eq!(
+            target.location(None, "/").as_deref(),
+            Some("https://example.com/")
+        );
+
+        target.redirect_to.clear();
+        assert_eq!(
+            target.location(Some("localhost:8080"), "/path").as_deref(),
+            Some("https://localhost:8443/path")
+        );
+        assert_eq!(
+            target.location(Some("[::1]:8080"), "/").as_deref(),
+            Some("https://[::1]:8443/")
+        );
+        assert_eq!(target.location(None, "/"), None);
+
+        target.https_port = Some(443);
+        assert_eq!(
+            target.location(Some("localhost:8080"), "/").as_deref(),
+            Some("https://localhost/")
+        );
+        target.https_port = None;
+        assert_eq!(
+            target.location(Some("localhost"), "/").as_deref(),
+            Some("https://localhost/")
+        );
+    }
 }
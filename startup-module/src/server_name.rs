@@ -0,0 +1,106 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server name matching for certificate selection
+
+use pandora_module_utils::pingora::Error;
+use pingora::tls::nid::Nid;
+use pingora::tls::x509::X509Ref;
+
+use crate::configuration::TLS_CONF_ERR;
+
+/// Produces the wildcard name covering a server name, e.g. `*.example.com` for `www.example.com`.
+///
+/// Wildcards only cover a single label, so there is no wildcard covering `example.com`.
+pub(crate) fn wildcard_name(name: &str) -> Option<String> {
+    let (label, parent) = name.split_once('.')?;
+    if label.is_empty() || !parent.contains('.') {
+        None
+    } else {
+        Some(format!("*.{parent}"))
+    }
+}
+
+/// Normalizes a configured server name, making sure wildcards are only used as the first label
+pub(crate) fn normalize_name(name: &str) -> Result<String, Box<Error>> {
+    let name = name.to_ascii_lowercase();
+    let valid = match name.strip_prefix("*.") {
+        Some(parent) => !parent.contains('*') && parent.contains('.'),
+        None => !name.contains('*'),
+    };
+
+    if valid {
+        Ok(name)
+    } else {
+        Err(Error::explain(
+            TLS_CONF_ERR,
+            format!("invalid server name {name}, wildcards are only supported like *.example.com"),
+        ))
+    }
+}
+
+/// Determines the server names a certificate is valid for: DNS names from the subject
+/// alternative names or the common name if there are none.
+pub(crate) fn certificate_names(cert: &X509Ref) -> Vec<String> {
+    let mut names = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname())
+                .map(|name| name.to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if names.is_empty() {
+        names.extend(
+            cert.subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .filter_map(|entry| entry.data().as_utf8().ok())
+                .map(|name| name.to_string().to_ascii_lowercase()),
+        );
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert_eq!(
+            wildcard_name("www.example.com").as_deref(),
+            Some("*.example.com")
+        );
+        assert_eq!(
+            wildcard_name("a.b.example.com").as_deref(),
+            Some("*.b.example.com")
+        );
+        assert_eq!(wildcard_name("example.com"), None);
+        assert_eq!(wildcard_name("localhost"), None);
+        assert_eq!(wildcard_name(".example.com"), None);
+    }
+
+    #[test]
+    fn normalization() {
+        assert_eq!(normalize_name("Example.COM").unwrap(), "example.com");
+        assert_eq!(normalize_name("*.Example.com").unwrap(), "*.example.com");
+        assert!(normalize_name("*.com").is_err());
+        assert!(normalize_name("www.*.example.com").is_err());
+        assert!(normalize_name("*www.example.com").is_err());
+        assert!(normalize_name("*.*.example.com").is_err());
+    }
+}
@@ -0,0 +1,555 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! systemd integration: socket activation and service manager notifications
+//!
+//! Pingora looks up listening sockets in a table of file descriptors before binding its own, this
+//! is meant for sockets inherited during a graceful upgrade. Listening services are wrapped here
+//! so that sockets passed in by systemd or bound in advance are added to this table before the
+//! service starts. All listening sockets are bound before the server starts, so that the server
+//! accepts connections on all addresses once the service manager is notified.
+
+use async_trait::async_trait;
+use listenfd::ListenFd;
+use log::{debug, info, warn};
+use nix::time::{clock_gettime, ClockId};
+use once_cell::sync::Lazy;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::services::background::{background_service, BackgroundService};
+use pingora::services::Service;
+use socket2::{Domain, Socket, Type};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::net::ToSocketAddrs;
+use std::os::fd::IntoRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+
+use crate::cert_reload::{reload_certificates, ReloadableCert};
+use crate::configuration::ListenAddr;
+
+const SYSTEMD_CONF_ERR: ErrorType = ErrorType::Custom("SystemdConfigError");
+
+/// Backlog of the listening sockets, same as the one used by Pingora
+const LISTEN_BACKLOG: i32 = 65535;
+
+/// A socket passed in by systemd along with its name (`FileDescriptorName=` setting)
+#[derive(Debug)]
+pub(crate) struct ActivatedSocket {
+    name: String,
+    socket: Socket,
+}
+
+/// Sockets matched to listening addresses or bound before the server starts, keyed by the address
+/// as Pingora sees it
+static CLAIMED_SOCKETS: Lazy<Mutex<HashMap<String, Socket>>> = Lazy::new(Default::default);
+
+/// Number of listening services which haven’t taken over their sockets yet
+static PENDING_SERVICES: AtomicUsize = AtomicUsize::new(0);
+
+/// Notified whenever a listening service took over its sockets
+static SERVICE_STARTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Takes over the sockets listed in the `LISTEN_FDS` environment variable. This removes the
+/// environment variables, so it has to be called before any threads are started.
+pub(crate) fn passed_sockets() -> Vec<ActivatedSocket> {
+    // This variable isn’t processed by listenfd and shouldn’t be inherited by child processes
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    std::env::remove_var("LISTEN_FDNAMES");
+    let mut names = names.split(':');
+
+    let mut fds = ListenFd::from_env();
+    (0..fds.len())
+        .filter_map(|index| {
+            let name = names.next().unwrap_or_default().to_owned();
+            let socket = match fds.take_tcp_listener(index) {
+                Ok(listener) => listener.map(Socket::from),
+                Err(_) => match fds.take_unix_listener(index) {
+                    Ok(listener) => listener.map(Socket::from),
+                    Err(err) => {
+                        warn!("Ignoring socket {name} passed in by systemd: {err}");
+                        None
+                    }
+                },
+            };
+            let socket = socket?;
+
+            // Sockets inherited from systemd are blocking, Tokio expects non-blocking ones
+            if let Err(err) = socket.set_nonblocking(true) {
+                warn!("Ignoring socket {name} passed in by systemd: {err}");
+                return None;
+            }
+            Some(ActivatedSocket { name, socket })
+        })
+        .collect()
+}
+
+/// Checks whether a socket is bound to the given address
+fn matches_address(socket: &Socket, addr: &ListenAddr) -> bool {
+    let Ok(local) = socket.local_addr() else {
+        return false;
+    };
+
+    if let Some(path) = addr.unix_path() {
+        local.as_pathname() == Some(Path::new(path))
+    } else if let Some(local) = local.as_socket() {
+        addr.addr
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.any(|addr| addr == local))
+    } else {
+        false
+    }
+}
+
+/// Matches the sockets passed in by systemd to listening addresses, either by the `socket_name`
+/// setting or by the address the socket is bound to. Addresses without a matching socket will be
+/// bound as usual.
+pub(crate) fn claim_activated_sockets(
+    mut passed: Vec<ActivatedSocket>,
+    addrs: &[&ListenAddr],
+) -> Result<(), Box<Error>> {
+    let mut claimed = CLAIMED_SOCKETS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+
+    for addr in addrs {
+        let index = if let Some(name) = &addr.socket_name {
+            Some(
+                passed
+                    .iter()
+                    .position(|socket| socket.name == *name)
+                    .ok_or_else(|| {
+                        Error::explain(
+                            SYSTEMD_CONF_ERR,
+                            format!("systemd didn't pass a socket named {name}"),
+                        )
+                    })?,
+            )
+        } else {
+            passed
+                .iter()
+                .position(|socket| matches_address(&socket.socket, addr))
+        };
+
+        let Some(index) = index else {
+            debug!("no socket passed in by systemd for address {}", addr.addr);
+            continue;
+        };
+
+        let is_unix = passed[index]
+            .socket
+            .domain()
+            .is_ok_and(|domain| domain == Domain::UNIX);
+        if is_unix != addr.unix_path().is_some() {
+            return Err(Error::explain(
+                SYSTEMD_CONF_ERR,
+                format!(
+                    "socket passed in by systemd for address {} has a different type",
+                    addr.addr
+                ),
+            ));
+        }
+
+        let socket = passed.remove(index);
+        info!(
+            "using socket {} passed in by systemd for address {}",
+            socket.name, addr.addr
+        );
+        claimed.insert(addr.to_server_address().as_ref().to_owned(), socket.socket);
+    }
+
+    for socket in passed.iter() {
+        warn!(
+            "Socket {} passed in by systemd doesn't match any listening address",
+            socket.name
+        );
+    }
+    Ok(())
+}
+
+/// Adds a socket bound before the server starts, it will be used by the listening service for the
+/// address.
+pub(crate) fn add_listening_socket(addr: String, socket: Socket) {
+    CLAIMED_SOCKETS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(addr, socket);
+}
+
+/// Checks whether a socket has been passed in by systemd or bound in advance for the address.
+pub(crate) fn has_listening_socket(addr: &str) -> bool {
+    CLAIMED_SOCKETS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .contains_key(addr)
+}
+
+/// Returns the local address of the socket passed in by systemd or bound in advance for the
+/// address.
+pub(crate) fn listening_socket_addr(addr: &str) -> Option<std::net::SocketAddr> {
+    CLAIMED_SOCKETS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(addr)?
+        .local_addr()
+        .ok()?
+        .as_socket()
+}
+
+/// Binds a TCP socket the same way Pingora would, to the first address the host name resolves to
+fn bind_tcp(addr: &ListenAddr) -> std::io::Result<Socket> {
+    let sock_addr = addr
+        .addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"))?;
+
+    let socket = Socket::new(Domain::for_address(sock_addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if let Some(ipv6_only) = addr.ipv6_only {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&sock_addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds the TCP addresses before the server starts, Pingora will take over the listening
+/// sockets. Listening services only signal readiness once they took over their sockets, so
+/// Pingora mustn’t bind any sockets itself.
+pub(crate) fn bind_tcp_sockets(addrs: &[&ListenAddr]) -> Result<(), Box<Error>> {
+    for addr in addrs {
+        if addr.unix_path().is_some() {
+            continue;
+        }
+
+        let key = addr.to_server_address().as_ref().to_owned();
+        if has_listening_socket(&key) {
+            continue;
+        }
+
+        let socket = bind_tcp(addr).map_err(|err| {
+            Error::because(
+                ErrorType::BindError,
+                format!("failed binding address {}", addr.addr),
+                err,
+            )
+        })?;
+        add_listening_socket(key, socket);
+    }
+    Ok(())
+}
+
+/// A listening service using sockets passed in by systemd
+pub(crate) struct SocketActivation {
+    inner: Box<dyn Service>,
+}
+
+impl SocketActivation {
+    pub(crate) fn new(inner: impl Service + 'static) -> Self {
+        PENDING_SERVICES.fetch_add(1, Ordering::AcqRel);
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+
+    pub(crate) fn wrap(inner: Box<dyn Service>) -> Box<dyn Service> {
+        PENDING_SERVICES.fetch_add(1, Ordering::AcqRel);
+        Box::new(Self { inner })
+    }
+}
+
+/// Waits until all listening services took over their sockets
+async fn services_started() {
+    loop {
+        // Notifications are only received by futures created before, create it before checking
+        let started = SERVICE_STARTED.notified();
+        if PENDING_SERVICES.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        started.await;
+    }
+}
+
+#[async_trait]
+impl Service for SocketActivation {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        if let Some(fds) = &fds {
+            // All services share the table, the first one to start moves all sockets there. The
+            // table is locked first so that no service starts listening before that.
+            let mut table = fds.lock().await;
+            let sockets = std::mem::take(
+                &mut *CLAIMED_SOCKETS
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner()),
+            );
+            for (addr, socket) in sockets {
+                // Sockets inherited during a graceful upgrade take precedence
+                if table.get(&addr).is_none() {
+                    table.add(addr, socket.into_raw_fd());
+                }
+            }
+        }
+
+        PENDING_SERVICES.fetch_sub(1, Ordering::AcqRel);
+        SERVICE_STARTED.notify_waiters();
+
+        self.inner.start_service(fds, shutdown).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+/// Sends a state notification to the service manager listening on `socket_path`
+fn notify_to(socket_path: &OsStr, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = socket_path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+    } else {
+        socket.send_to(state.as_bytes(), socket_path)?;
+    }
+    Ok(())
+}
+
+/// Sends a state notification to the service manager if `NOTIFY_SOCKET` is set
+fn notify(state: &str) {
+    if let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = notify_to(&socket_path, state) {
+            warn!("Failed notifying service manager: {err}");
+        }
+    }
+}
+
+/// Produces the reload notification, systemd expects the current monotonic time along with it
+fn reloading_state() -> String {
+    match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+        Ok(now) => format!(
+            "RELOADING=1\nMONOTONIC_USEC={}",
+            now.tv_sec() * 1_000_000 + now.tv_nsec() / 1_000
+        ),
+        Err(_) => "RELOADING=1".to_owned(),
+    }
+}
+
+/// Determines the interval for watchdog pings, half of the configured watchdog timeout
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(usec) / 2)
+    }
+}
+
+#[derive(Debug)]
+struct NotifyTask {
+    /// Certificates to be reloaded on `SIGHUP` signal
+    certs: Vec<Arc<ReloadableCert>>,
+}
+
+#[async_trait]
+impl BackgroundService for NotifyTask {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = signal(SignalKind::hangup())
+            .map_err(|err| warn!("Failed registering for SIGHUP signal: {err}"))
+            .ok();
+        let watchdog = watchdog_interval();
+
+        // The server is only ready once all listening services took over their sockets
+        tokio::select! {
+            _ = shutdown.changed() => {
+                notify("STOPPING=1");
+                return;
+            }
+            _ = services_started() => {}
+        }
+
+        // The process might have been forked by now, so the main process ID is passed along
+        notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    notify("STOPPING=1");
+                    break;
+                }
+                _ = async {
+                    match watchdog {
+                        Some(interval) => tokio::time::sleep(interval).await,
+                        None => std::future::pending().await,
+                    }
+                } => notify("WATCHDOG=1"),
+                Some(_) = async {
+                    match &mut hangup {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    // Certificates are reloaded here so that the service manager is only notified
+                    // once reloading is complete. Log files are reopened by the logging module
+                    // independently.
+                    notify(&reloading_state());
+                    let certs = self.certs.clone();
+                    if let Err(err) = tokio::task::spawn_blocking(move || {
+                        reload_certificates(&certs, true)
+                    })
+                    .await
+                    {
+                        warn!("Failed reloading certificates: {err}");
+                    }
+                    notify("READY=1");
+                }
+            }
+        }
+    }
+}
+
+/// Creates a service sending notifications to the service manager if the `NOTIFY_SOCKET`
+/// environment variable is set. This service will also reload the certificates on `SIGHUP` signal.
+pub(crate) fn create_notify_service(
+    certs: Vec<Arc<ReloadableCert>>,
+) -> Option<impl Service + 'static> {
+    std::env::var_os("NOTIFY_SOCKET")
+        .map(|_| background_service("systemd notifications", NotifyTask { certs }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use test_log::test;
+    use tokio::sync::watch;
+
+    #[derive(Debug)]
+    struct NoTask;
+
+    #[async_trait]
+    impl BackgroundService for NoTask {
+        async fn start(&self, _shutdown: ShutdownWatch) {}
+    }
+
+    #[test]
+    fn address_matching() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let socket = Socket::from(listener);
+
+        assert!(matches_address(
+            &socket,
+            &format!("127.0.0.1:{port}").into()
+        ));
+        assert!(!matches_address(
+            &socket,
+            &format!("127.0.0.2:{port}").into()
+        ));
+        assert!(!matches_address(
+            &socket,
+            &format!("unix:/tmp/{port}.sock").into()
+        ));
+
+        let path = std::env::temp_dir().join(format!("systemd-test-{}.sock", std::process::id()));
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let socket = Socket::from(listener);
+        assert!(matches_address(
+            &socket,
+            &format!("unix:{}", path.display()).into()
+        ));
+        assert!(!matches_address(&socket, &"unix:/tmp/other.sock".into()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tcp_binding() {
+        bind_tcp_sockets(&[&"127.0.0.1:0".into()]).unwrap();
+        assert!(has_listening_socket("127.0.0.1:0"));
+        let local = CLAIMED_SOCKETS.lock().unwrap()["127.0.0.1:0"]
+            .local_addr()
+            .unwrap()
+            .as_socket()
+            .unwrap();
+        std::net::TcpStream::connect(local).unwrap();
+
+        // Addresses with a socket already are skipped
+        bind_tcp_sockets(&[&"127.0.0.1:0".into()]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(bind_tcp_sockets(&[&addr.as_str().into()]).is_err());
+        assert!(!has_listening_socket(&addr));
+    }
+
+    #[test]
+    fn notifications() {
+        let path = std::env::temp_dir().join(format!("notify-test-{}.sock", std::process::id()));
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let state = reloading_state();
+        assert!(state.starts_with("RELOADING=1\nMONOTONIC_USEC="));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn readiness() {
+        let mut service = SocketActivation::new(background_service("test", NoTask));
+
+        let timeout = Duration::from_millis(100);
+        assert!(tokio::time::timeout(timeout, services_started())
+            .await
+            .is_err());
+
+        let (_sender, shutdown) = watch::channel(false);
+        service.start_service(None, shutdown).await;
+        assert!(tokio::time::timeout(timeout, services_started())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS protocol settings
+
+use pandora_module_utils::pingora::Error;
+use pandora_module_utils::{DeserializeMap, OneOrMany};
+use pingora::listeners::TlsSettings;
+use pingora::tls::ssl::{select_next_proto, AlpnError, SslOptions, SslRef, SslVersion};
+use serde::de::{Deserialize, Deserializer, Unexpected};
+
+use crate::acme::ACME_TLS_ALPN;
+use crate::configuration::TLS_CONF_ERR;
+
+/// ALPN identifier of HTTP/2
+const ALPN_H2: &str = "h2";
+
+/// ALPN identifier of HTTP/1.1
+const ALPN_HTTP1: &str = "http/1.1";
+
+/// TLS protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    Tls10,
+    /// TLS 1.1
+    Tls11,
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+impl TlsVersion {
+    fn to_ssl_version(self) -> SslVersion {
+        match self {
+            Self::Tls10 => SslVersion::TLS1,
+            Self::Tls11 => SslVersion::TLS1_1,
+            Self::Tls12 => SslVersion::TLS1_2,
+            Self::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+
+        // Unquoted versions like 1.2 are numbers in YAML
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            String(String),
+            Number(f64),
+        }
+
+        let value = match Value::deserialize(deserializer)? {
+            Value::String(value) => value,
+            Value::Number(value) => value.to_string(),
+        };
+        match value.strip_prefix("TLSv").unwrap_or(&value) {
+            "1" | "1.0" => Ok(Self::Tls10),
+            "1.1" => Ok(Self::Tls11),
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => Err(D::Error::invalid_value(
+                Unexpected::Str(&value),
+                &"TLS version like \"1.2\"",
+            )),
+        }
+    }
+}
+
+/// TLS protocol settings, configured globally and overridable per address
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct TlsOptions {
+    /// Minimal TLS version to accept, e.g. `1.2`
+    pub min_version: Option<TlsVersion>,
+
+    /// Maximal TLS version to accept, e.g. `1.3`
+    pub max_version: Option<TlsVersion>,
+
+    /// Cipher list for TLS 1.2 and below in OpenSSL format, e.g.
+    /// `ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256`
+    pub ciphers: Option<String>,
+
+    /// TLS 1.3 cipher suites in OpenSSL format, e.g. `TLS_AES_256_GCM_SHA384:TLS_AES_128_GCM_SHA256`
+    pub cipher_suites: Option<String>,
+
+    /// Supported curves (groups) for key exchange, e.g. `X25519` or `P-256`
+    pub curves: Option<OneOrMany<String>>,
+
+    /// If `false`, no session tickets will be issued to clients
+    pub session_tickets: Option<bool>,
+
+    /// ALPN protocols to be offered in order of preference, `h2` and `http/1.1` are supported
+    pub alpn: Option<OneOrMany<String>>,
+}
+
+impl TlsOptions {
+    /// Produces the settings for an address, with settings present in `overrides` taking
+    /// precedence.
+    pub(crate) fn merge(&self, overrides: Option<&Self>) -> Self {
+        let Some(overrides) = overrides else {
+            return self.clone();
+        };
+
+        Self {
+            min_version: overrides.min_version.or(self.min_version),
+            max_version: overrides.max_version.or(self.max_version),
+            ciphers: overrides.ciphers.clone().or_else(|| self.ciphers.clone()),
+            cipher_suites: overrides
+                .cipher_suites
+                .clone()
+                .or_else(|| self.cipher_suites.clone()),
+            curves: overrides.curves.clone().or_else(|| self.curves.clone()),
+            session_tickets: overrides.session_tickets.or(self.session_tickets),
+            alpn: overrides.alpn.clone().or_else(|| self.alpn.clone()),
+        }
+    }
+
+    /// Produces ALPN protocols in wire format
+    fn alpn_protocols(&self, http2: bool, acme_challenge: bool) -> Result<Vec<u8>, Box<Error>> {
+        let protocols = match &self.alpn {
+            Some(alpn) => alpn.iter().map(String::as_str).collect(),
+            None if http2 => vec![ALPN_H2, ALPN_HTTP1],
+            None => vec![ALPN_HTTP1],
+        };
+
+        let mut result = Vec::new();
+        if acme_challenge {
+            result.push(ACME_TLS_ALPN.len() as u8);
+            result.extend_from_slice(ACME_TLS_ALPN);
+        }
+        for protocol in protocols {
+            if protocol != ALPN_H2 && protocol != ALPN_HTTP1 {
+                return Err(Error::explain(
+                    TLS_CONF_ERR,
+                    format!(
+                        "unsupported ALPN protocol {protocol}, only h2 and http/1.1 are supported"
+                    ),
+                ));
+            }
+            result.push(protocol.len() as u8);
+            result.extend_from_slice(protocol.as_bytes());
+        }
+        Ok(result)
+    }
+
+    /// Applies the settings to a listener. `http2` is the listener’s HTTP/2 flag, `acme_challenge`
+    /// indicates whether tls-alpn-01 challenges should be accepted.
+    ///
+    /// `client_auth` callback determines whether a client certificate will be requested for the
+    /// connection. HTTP/2 isn’t negotiated for these connections, Pingora only exposes the client
+    /// certificate of HTTP/1 connections.
+    pub(crate) fn apply(
+        &self,
+        tls: &mut TlsSettings,
+        http2: bool,
+        acme_challenge: bool,
+        client_auth: impl Fn(&SslRef) -> bool + Send + Sync + 'static,
+    ) -> Result<(), Box<Error>> {
+        if let (Some(min_version), Some(max_version)) = (self.min_version, self.max_version) {
+            if min_version > max_version {
+                return Err(Error::explain(
+                    TLS_CONF_ERR,
+                    "min_version setting cannot be larger than max_version",
+                ));
+            }
+        }
+
+        // Library defaults should stay in place for versions that aren’t configured
+        if let Some(min_version) = self.min_version {
+            tls.set_min_proto_version(Some(min_version.to_ssl_version()))
+                .map_err(|err| {
+                    Error::because(TLS_CONF_ERR, "failed setting minimal TLS version", err)
+                })?;
+        }
+        if let Some(max_version) = self.max_version {
+            tls.set_max_proto_version(Some(max_version.to_ssl_version()))
+                .map_err(|err| {
+                    Error::because(TLS_CONF_ERR, "failed setting maximal TLS version", err)
+                })?;
+        }
+
+        if let Some(ciphers) = &self.ciphers {
+            tls.set_cipher_list(ciphers).map_err(|err| {
+                Error::because(TLS_CONF_ERR, format!("invalid cipher list {ciphers}"), err)
+            })?;
+        }
+
+        if let Some(cipher_suites) = &self.cipher_suites {
+            tls.set_ciphersuites(cipher_suites).map_err(|err| {
+                Error::because(
+                    TLS_CONF_ERR,
+                    format!("invalid TLS 1.3 cipher suites {cipher_suites}"),
+                    err,
+                )
+            })?;
+        }
+
+        if let Some(curves) = &self.curves {
+            let curves = curves.join(":");
+            tls.set_groups_list(&curves).map_err(|err| {
+                Error::because(TLS_CONF_ERR, format!("invalid curve list {curves}"), err)
+            })?;
+        }
+
+        if self.session_tickets == Some(false) {
+            tls.set_options(SslOptions::NO_TICKET);
+        }
+
+        if self.alpn.is_some() || acme_challenge || http2 {
+            let protocols = self.alpn_protocols(http2, acme_challenge)?;
+            let http1_protocols = without_h2(&protocols);
+            tls.set_alpn_select_callback(move |ssl, alpn_in| {
+                let protocols = if client_auth(ssl) {
+                    &http1_protocols
+                } else {
+                    &protocols
+                };
+                select_next_proto(protocols, alpn_in).ok_or(AlpnError::NOACK)
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes HTTP/2 from a list of ALPN protocols in wire format
+fn without_h2(protocols: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut remaining = protocols;
+    while let Some((len, rest)) = remaining.split_first() {
+        let (protocol, rest) = rest.split_at(usize::from(*len).min(rest.len()));
+        if protocol != ALPN_H2.as_bytes() {
+            result.push(*len);
+            result.extend_from_slice(protocol);
+        }
+        remaining = rest;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::FromYaml;
+
+    #[test]
+    fn parsing() {
+        let options = TlsOptions::from_yaml(
+            r#"
+min_version: 1.2
+max_version: "TLSv1.3"
+curves: [X25519, P-256]
+session_tickets: false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.min_version, Some(TlsVersion::Tls12));
+        assert_eq!(options.max_version, Some(TlsVersion::Tls13));
+        assert_eq!(options.session_tickets, Some(false));
+
+        assert!(TlsOptions::from_yaml("min_version: 2.0").is_err());
+    }
+
+    #[test]
+    fn merging() {
+        let global = TlsOptions {
+            min_version: Some(TlsVersion::Tls12),
+            ciphers: Some("HIGH".to_owned()),
+            ..Default::default()
+        };
+        let overrides = TlsOptions {
+            min_version: Some(TlsVersion::Tls13),
+            ..Default::default()
+        };
+
+        assert_eq!(global.merge(None), global);
+        let merged = global.merge(Some(&overrides));
+        assert_eq!(merged.min_version, Some(TlsVersion::Tls13));
+        assert_eq!(merged.ciphers.as_deref(), Some("HIGH"));
+    }
+
+    #[test]
+    fn alpn() {
+        let options = TlsOptions::default();
+        assert_eq!(
+            options.alpn_protocols(true, false).unwrap(),
+            b"\x02h2\x08http/1.1"
+        );
+        assert_eq!(
+            options.alpn_protocols(false, true).unwrap(),
+            b"\x0aacme-tls/1\x08http/1.1"
+        );
+
+        let options = TlsOptions {
+            alpn: Some(vec!["http/1.1".to_owned()].into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.alpn_protocols(true, false).unwrap(),
+            b"\x08http/1.1"
+        );
+
+        let options = TlsOptions {
+            alpn: Some(vec!["spdy/3".to_owned()].into()),
+            ..Default::default()
+        };
+        assert!(options.alpn_protocols(false, false).is_err());
+
+        assert_eq!(without_h2(b"\x02h2\x08http/1.1"), b"\x08http/1.1");
+        assert_eq!(
+            without_h2(b"\x0aacme-tls/1\x02h2\x08http/1.1"),
+            b"\x0aacme-tls/1\x08http/1.1"
+        );
+        assert_eq!(without_h2(b"\x02h2"), b"");
+    }
+
+    #[test]
+    fn validation() {
+        let apply = |options: TlsOptions| {
+            let mut tls = TlsSettings::with_callbacks(Box::new(NoCallbacks)).unwrap();
+            options.apply(&mut tls, false, false, |_| false)
+        };
+
+        assert!(apply(TlsOptions {
+            min_version: Some(TlsVersion::Tls12),
+            ciphers: Some("ECDHE-RSA-AES128-GCM-SHA256".to_owned()),
+            curves: Some(vec!["X25519".to_owned()].into()),
+            ..Default::default()
+        })
+        .is_ok());
+
+        assert!(apply(TlsOptions {
+            min_version: Some(TlsVersion::Tls13),
+            max_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        })
+        .is_err());
+
+        assert!(apply(TlsOptions {
+            ciphers: Some("NO-SUCH-CIPHER".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+
+        assert!(apply(TlsOptions {
+            curves: Some(vec!["no-such-curve".to_owned()].into()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    struct NoCallbacks;
+
+    #[async_trait::async_trait]
+    impl pingora::listeners::TlsAccept for NoCallbacks {}
+}
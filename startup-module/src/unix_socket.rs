@@ -0,0 +1,235 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ownership of Unix sockets
+
+use log::debug;
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use pandora_module_utils::pingora::{Error, ErrorType};
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::fs::{remove_file, set_permissions, symlink_metadata, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use crate::configuration::ListenAddr;
+use crate::systemd::{add_listening_socket, has_listening_socket};
+
+const SOCKET_CONF_ERR: ErrorType = ErrorType::Custom("UnixSocketConfigError");
+
+/// Backlog of the listening sockets, same as the one used by Pingora
+const LISTEN_BACKLOG: i32 = 65535;
+
+/// Default permissions of a Unix socket, only owner and group can connect
+const DEFAULT_MODE: u32 = 0o660;
+
+fn resolve_user(user: &str) -> Result<Uid, Box<Error>> {
+    if let Ok(id) = user.parse() {
+        return Ok(Uid::from_raw(id));
+    }
+
+    match User::from_name(user) {
+        Ok(Some(user)) => Ok(user.uid),
+        Ok(None) => Err(Error::explain(
+            SOCKET_CONF_ERR,
+            format!("unknown user {user}"),
+        )),
+        Err(err) => Err(Error::because(
+            SOCKET_CONF_ERR,
+            format!("failed looking up user {user}"),
+            err,
+        )),
+    }
+}
+
+fn resolve_group(group: &str) -> Result<Gid, Box<Error>> {
+    if let Ok(id) = group.parse() {
+        return Ok(Gid::from_raw(id));
+    }
+
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid),
+        Ok(None) => Err(Error::explain(
+            SOCKET_CONF_ERR,
+            format!("unknown group {group}"),
+        )),
+        Err(err) => Err(Error::because(
+            SOCKET_CONF_ERR,
+            format!("failed looking up group {group}"),
+            err,
+        )),
+    }
+}
+
+/// Binds a Unix socket, ownership and permissions are changed before it starts listening so that
+/// no client can connect before access is restricted. The file system entry has to be changed
+/// here, changing the ownership of the socket file descriptor wouldn’t affect it.
+///
+/// A stale socket at the path is removed, any other file results in an error.
+fn bind(path: &Path, mode: u32, owner: Option<Uid>, group: Option<Gid>) -> std::io::Result<Socket> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            remove_file(path)?;
+            debug!("removed stale Unix socket {}", path.display());
+        }
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    set_permissions(path, Permissions::from_mode(mode))?;
+    if owner.is_some() || group.is_some() {
+        chown(path, owner, group)?;
+    }
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds all Unix sockets before the server starts, Pingora will take over the listening sockets.
+/// Pingora itself would make the sockets accessible to all users and remove any file at the
+/// path. Sockets passed in by systemd exist already, only the configured mode and ownership are
+/// applied to these.
+pub(crate) fn bind_unix_sockets(addrs: &[&ListenAddr]) -> Result<(), Box<Error>> {
+    for addr in addrs {
+        let Some(path) = addr.unix_path() else {
+            continue;
+        };
+
+        let owner = addr.owner.as_deref().map(resolve_user).transpose()?;
+        let group = addr.group.as_deref().map(resolve_group).transpose()?;
+        let key = addr.to_server_address().as_ref().to_owned();
+        if has_listening_socket(&key) {
+            // Without an explicit mode, the one set by systemd (`SocketMode=`) is kept
+            if let Some(mode) = addr.mode {
+                set_permissions(path, Permissions::from_mode(mode)).map_err(|err| {
+                    Error::because(
+                        SOCKET_CONF_ERR,
+                        format!("failed changing permissions of Unix socket {path}"),
+                        err,
+                    )
+                })?;
+            }
+            if owner.is_some() || group.is_some() {
+                chown(path, owner, group).map_err(|err| {
+                    Error::because(
+                        SOCKET_CONF_ERR,
+                        format!("failed changing ownership of Unix socket {path}"),
+                        err,
+                    )
+                })?;
+            }
+            continue;
+        }
+
+        let socket = bind(
+            Path::new(path),
+            addr.mode.unwrap_or(DEFAULT_MODE),
+            owner,
+            group,
+        )
+        .map_err(|err| {
+            Error::because(
+                SOCKET_CONF_ERR,
+                format!("failed binding Unix socket {path}"),
+                err,
+            )
+        })?;
+        add_listening_socket(key, socket);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn resolve() {
+        assert_eq!(resolve_user("0").unwrap(), Uid::from_raw(0));
+        assert_eq!(resolve_user("root").unwrap(), Uid::from_raw(0));
+        assert!(resolve_user("no-such-user-hopefully").is_err());
+
+        assert_eq!(resolve_group("0").unwrap(), Gid::from_raw(0));
+        assert!(resolve_group("no-such-group-hopefully").is_err());
+    }
+
+    #[test]
+    fn bind_socket() {
+        let path = std::env::temp_dir().join(format!("ownership-test-{}.sock", std::process::id()));
+
+        // Stale socket files are replaced
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+
+        let uid = nix::unistd::geteuid();
+        let socket = bind(&path, 0o600, Some(uid), None).unwrap();
+        let metadata = path.metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(metadata.uid(), uid.as_raw());
+
+        // The socket is listening already
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+
+        drop(socket);
+        remove_file(&path).unwrap();
+
+        // Other files aren't removed
+        std::fs::write(&path, "data").unwrap();
+        let err = bind(&path, 0o600, None, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data");
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn default_mode() {
+        let path = std::env::temp_dir().join(format!("mode-test-{}.sock", std::process::id()));
+        let addr = ListenAddr::from(format!("unix:{}", path.display()));
+        bind_unix_sockets(&[&addr]).unwrap();
+
+        let metadata = path.metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, DEFAULT_MODE);
+        assert!(has_listening_socket(addr.unix_path().unwrap()));
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn passed_socket_mode() {
+        let path = std::env::temp_dir().join(format!("passed-test-{}.sock", std::process::id()));
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        set_permissions(&path, Permissions::from_mode(0o666)).unwrap();
+
+        let mut addr = ListenAddr::from(format!("unix:{}", path.display()));
+        addr.mode = Some(0o600);
+        add_listening_socket(
+            addr.to_server_address().as_ref().to_owned(),
+            Socket::from(listener),
+        );
+        bind_unix_sockets(&[&addr]).unwrap();
+
+        let metadata = path.metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        remove_file(path).unwrap();
+    }
+}
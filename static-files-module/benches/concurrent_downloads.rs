@@ -0,0 +1,216 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures the throughput of many concurrent large downloads on a runtime with few worker
+//! threads. While the downloads are running, a timer task measures how long the worker threads
+//! are unavailable for other tasks. File reads blocking the worker threads result in low
+//! throughput and large timer delays.
+//!
+//! Run with `cargo bench -p static-files-module`. Set `BENCH_ROOT` environment
+//! variable to a directory on the file system to be tested, the system’s temporary directory is
+//! used by default.
+//!
+//! For comparison, the downloads are also performed with the previous implementation reading
+//! files on the worker threads directly.
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use http::{header, StatusCode};
+use pandora_module_utils::pingora::{
+    create_test_session, Error, RequestHeader, ResponseHeader, SessionWrapper,
+};
+use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use startup_module::DefaultApp;
+use static_files_module::{StaticFilesConf, StaticFilesHandler};
+use std::cmp::min;
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+const BUFFER_SIZE: usize = 64 * 1024;
+const CONCURRENCY: usize = 16;
+const WORKER_THREADS: usize = 2;
+const ITERATIONS: usize = 5;
+const TICK: Duration = Duration::from_millis(1);
+
+fn create_root() -> PathBuf {
+    let root = std::env::var_os("BENCH_ROOT")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("static-files-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+
+    let data = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for i in 0..CONCURRENCY {
+        std::fs::write(root.join(format!("file{i}.bin")), &data).unwrap();
+    }
+    root
+}
+
+/// Writes a file as a Pingora session response, reading it on the current thread. This was the
+/// implementation before file operations moved to the blocking thread pool.
+async fn blocking_file_response(
+    session: &mut impl SessionWrapper,
+    path: &Path,
+    size: usize,
+) -> Result<(), Box<Error>> {
+    let mut file = std::fs::File::open(path).unwrap();
+
+    let mut remaining = size;
+    while remaining > 0 {
+        let mut buf = BytesMut::zeroed(min(remaining, BUFFER_SIZE));
+        let len = file.read(buf.as_mut()).unwrap();
+        assert!(
+            len > 0,
+            "file ended with {remaining} bytes left to be written"
+        );
+
+        buf.truncate(len);
+        session.write_response_body(Some(buf.into()), false).await?;
+        remaining -= len;
+    }
+
+    session.write_response_body(None, true).await
+}
+
+/// Handler serving files with the previous blocking implementation
+#[derive(Debug, Clone)]
+struct BlockingHandler {
+    root: PathBuf,
+}
+
+#[async_trait]
+impl RequestFilter for BlockingHandler {
+    type Conf = ();
+    type CTX = ();
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let path = self.root.join(session.uri().path().trim_start_matches('/'));
+        let size = path.metadata().unwrap().len();
+
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(1))?;
+        header.append_header(header::CONTENT_LENGTH, size.to_string())?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        blocking_file_response(session, &path, size as usize).await?;
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+async fn download<H>(handler: H, path: String) -> usize
+where
+    H: RequestFilter + Send + Sync + 'static,
+    H::CTX: Send + Sync,
+{
+    let mut app = DefaultApp::new(handler);
+    let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+    let session = create_test_session(header).await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    result.body().len()
+}
+
+/// Runs all downloads concurrently, returns the time taken, the number of bytes downloaded and
+/// the maximal timer delay.
+async fn run<F>(download: impl Fn(String) -> F) -> (Duration, usize, Duration)
+where
+    F: Future<Output = usize> + Send + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let timer = {
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            let mut max_delay = Duration::ZERO;
+            while !stop.load(Ordering::Relaxed) {
+                let before = Instant::now();
+                tokio::time::sleep(TICK).await;
+                max_delay = max_delay.max(before.elapsed().saturating_sub(TICK));
+            }
+            max_delay
+        })
+    };
+
+    let start = Instant::now();
+    let downloads = (0..CONCURRENCY)
+        .map(|i| tokio::spawn(download(format!("/file{i}.bin"))))
+        .collect::<Vec<_>>();
+    let mut bytes = 0;
+    for download in downloads {
+        bytes += download.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    (elapsed, bytes, timer.await.unwrap())
+}
+
+/// Prints the results of an iteration
+fn report(iteration: usize, (elapsed, bytes, max_delay): (Duration, usize, Duration)) {
+    assert_eq!(bytes, FILE_SIZE * CONCURRENCY);
+    println!(
+        "iteration {iteration}: {:.1} MiB/s, timer delayed by up to {:.1} ms",
+        bytes as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64(),
+        max_delay.as_secs_f64() * 1000.0
+    );
+}
+
+fn main() {
+    let root = create_root();
+    let blocking_handler = BlockingHandler { root: root.clone() };
+    let handler: StaticFilesHandler = StaticFilesConf {
+        root: Some(root.clone()),
+        ..Default::default()
+    }
+    .try_into()
+    .unwrap();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(WORKER_THREADS)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!(
+        "{CONCURRENCY} concurrent downloads of {} MiB files, {WORKER_THREADS} worker threads",
+        FILE_SIZE / 1024 / 1024
+    );
+
+    println!("previous implementation (blocking reads):");
+    for iteration in 1..=ITERATIONS {
+        report(
+            iteration,
+            runtime.block_on(run(|path| download(blocking_handler.clone(), path))),
+        );
+    }
+
+    println!("current implementation:");
+    for iteration in 1..=ITERATIONS {
+        report(
+            iteration,
+            runtime.block_on(run(|path| download(handler.clone(), path))),
+        );
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,104 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parsing of `Accept` and `Accept-Encoding` HTTP headers
+
+use std::str::FromStr;
+
+/// Parses an element of `Accept` or `Accept-Encoding` HTTP header like `image/webp;q=0.8` into a
+/// value/quality pair. Quality values are multiplied by 1000, so the default is 1000.
+fn parse_element(element: &str) -> (&str, u16) {
+    let mut params = element.split(';');
+    let value = params.next().unwrap_or_default().trim();
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, param_value)) = param.split_once('=') {
+            if name.trim() == "q" {
+                if let Ok(param_value) = f64::from_str(param_value.trim()) {
+                    quality = (param_value * 1000.0) as u16;
+                }
+            }
+        }
+    }
+    (value, quality)
+}
+
+/// Splits an `Accept` or `Accept-Encoding` HTTP header into value/quality pairs, see
+/// [`parse_element`]. Empty elements are skipped.
+pub(crate) fn parse(header: &str) -> impl Iterator<Item = (&str, u16)> {
+    header
+        .split(',')
+        .map(parse_element)
+        .filter(|(value, _)| !value.is_empty())
+}
+
+/// Determines the quality value that the `Accept` header assigns to a MIME type, taking the most
+/// specific matching media range. The second value is the specificity of the match: 3 for an
+/// exact match, 2 for `type/*`, 1 for `*/*` and 0 if nothing matched.
+pub(crate) fn mime_quality(accept: &str, mime: &str) -> (u16, u8) {
+    let (type_, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut result = (0, 0);
+    for (range, quality) in parse(accept) {
+        let specificity = if range.eq_ignore_ascii_case(mime) {
+            3
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|range| range.eq_ignore_ascii_case(type_))
+        {
+            2
+        } else if range == "*/*" {
+            1
+        } else {
+            continue;
+        };
+
+        if specificity > result.1 {
+            result = (quality, specificity);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse("").collect::<Vec<_>>(), Vec::new());
+        assert_eq!(
+            parse("gzip, br;q=0.5 ,, identity; q = 0 ,*;q=0.25;level=1").collect::<Vec<_>>(),
+            vec![("gzip", 1000), ("br", 500), ("identity", 0), ("*", 250)]
+        );
+        assert_eq!(
+            parse("text/html;q=invalid").collect::<Vec<_>>(),
+            vec![("text/html", 1000)]
+        );
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(mime_quality("", "text/html"), (0, 0));
+        assert_eq!(mime_quality("*/*;q=0.1", "text/html"), (100, 1));
+        assert_eq!(
+            mime_quality("text/*;q=0.5, */*;q=0.1", "text/html"),
+            (500, 2)
+        );
+        assert_eq!(
+            mime_quality("TEXT/HTML;q=0.8, text/*;q=0.5, */*", "text/html"),
+            (800, 3)
+        );
+        assert_eq!(mime_quality("text/plain, image/*", "text/html"), (0, 0));
+    }
+}
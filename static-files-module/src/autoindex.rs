@@ -0,0 +1,393 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Directory listings
+
+use http::{header, method::Method, status::StatusCode};
+use httpdate::fmt_http_date;
+use maud::{html, DOCTYPE};
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper};
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::accept::mime_quality;
+use crate::storage::Storage;
+
+/// Characters to be escaped in a file name when used as URI path segment
+const SEGMENT_ESC_CHARSET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// A directory entry to be listed
+#[derive(Debug)]
+pub(crate) struct Entry {
+    name: OsString,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn display_name(&self) -> String {
+        self.name.to_string_lossy().into_owned()
+    }
+
+    fn href(&self, base: &str) -> String {
+        let mut href = base.to_owned();
+        href.push_str(
+            &percent_encode(self.name.as_encoded_bytes(), SEGMENT_ESC_CHARSET).to_string(),
+        );
+        if self.is_dir {
+            href.push('/');
+        }
+        href
+    }
+}
+
+/// Reads the entries of a directory. Entries that cannot be accessed like broken symbolic links
+/// are skipped.
+pub(crate) fn read_entries(
+    storage: &dyn Storage,
+    path: &Path,
+    show_hidden: bool,
+) -> std::io::Result<Vec<Entry>> {
+    Ok(storage
+        .read_dir(path)?
+        .into_iter()
+        .filter(|(name, _)| show_hidden || !name.as_encoded_bytes().starts_with(b"."))
+        .map(|(name, info)| Entry {
+            name,
+            is_dir: info.is_dir,
+            size: info.size,
+            modified: info.modified,
+        })
+        .collect())
+}
+
+/// Property to sort the directory entries by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// Sort order requested via `sort` and `order` query parameters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Sorting {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Sorting {
+    /// Extracts the sort order from the query string, e.g. `sort=size&order=desc`
+    pub(crate) fn from_query(query: Option<&str>) -> Self {
+        let mut result = Self::default();
+        for param in query.unwrap_or_default().split('&') {
+            match param.split_once('=') {
+                Some(("sort", "name")) => result.key = SortKey::Name,
+                Some(("sort", "size")) => result.key = SortKey::Size,
+                Some(("sort", "modified")) => result.key = SortKey::Modified,
+                Some(("order", "asc")) => result.descending = false,
+                Some(("order", "desc")) => result.descending = true,
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Produces the query string of the link sorting by the given key. If the entries are already
+    /// sorted by this key, the link will reverse the order.
+    fn link(&self, key: SortKey) -> String {
+        let descending = self.key == key && !self.descending;
+        format!(
+            "?sort={}&order={}",
+            key.name(),
+            if descending { "desc" } else { "asc" }
+        )
+    }
+
+    /// Sorts the entries, directories are always listed first.
+    pub(crate) fn sort(&self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            }
+            .then_with(|| a.name.cmp(&b.name));
+            let ordering = if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            b.is_dir.cmp(&a.is_dir).then(ordering)
+        });
+    }
+}
+
+/// Checks whether the `Accept` header prefers JSON over HTML. With equal quality values, JSON is
+/// preferred if it is listed more specifically, e.g. `application/json, */*`.
+pub(crate) fn prefers_json(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        let json = mime_quality(accept, "application/json");
+        json.0 > 0 && json > mime_quality(accept, "text/html")
+    })
+}
+
+/// Formats a file size for display
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{size} B");
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{value:.1} {unit}")
+}
+
+/// Produces the HTML page listing the directory entries.
+///
+/// `base` is the URI of the directory, `parent` the URI of its parent directory if it should be
+/// linked.
+pub(crate) fn render_html(
+    base: &str,
+    parent: Option<&str>,
+    entries: &[Entry],
+    sorting: &Sorting,
+) -> String {
+    let title = format!("Index of {base}");
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                title {
+                    (title)
+                }
+            }
+
+            body {
+                h1 {
+                    (title)
+                }
+
+                table {
+                    thead {
+                        tr {
+                            th {
+                                a href=(sorting.link(SortKey::Name)) { "Name" }
+                            }
+                            th {
+                                a href=(sorting.link(SortKey::Size)) { "Size" }
+                            }
+                            th {
+                                a href=(sorting.link(SortKey::Modified)) { "Last modified" }
+                            }
+                        }
+                    }
+
+                    tbody {
+                        @if let Some(parent) = parent {
+                            tr {
+                                td {
+                                    a href=(parent) { "../" }
+                                }
+                                td { "-" }
+                                td {}
+                            }
+                        }
+
+                        @for entry in entries {
+                            tr {
+                                td {
+                                    a href=(entry.href(base)) {
+                                        (entry.display_name())
+                                        @if entry.is_dir {
+                                            "/"
+                                        }
+                                    }
+                                }
+                                td {
+                                    @if entry.is_dir {
+                                        "-"
+                                    } @else {
+                                        (format_size(entry.size))
+                                    }
+                                }
+                                td {
+                                    @if let Some(modified) = entry.modified {
+                                        (fmt_http_date(modified))
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .into()
+}
+
+/// A directory entry as serialized into JSON
+#[derive(Debug, Serialize)]
+struct JsonEntry {
+    name: String,
+    href: String,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+}
+
+/// Produces the JSON array listing the directory entries.
+pub(crate) fn render_json(base: &str, entries: &[Entry]) -> Result<String, Box<Error>> {
+    let entries = entries
+        .iter()
+        .map(|entry| JsonEntry {
+            name: entry.display_name(),
+            href: entry.href(base),
+            type_: if entry.is_dir { "directory" } else { "file" },
+            size: if entry.is_dir { None } else { Some(entry.size) },
+            modified: entry.modified.map(fmt_http_date),
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string(&entries).map_err(|err| {
+        Error::because(
+            ErrorType::InternalError,
+            "failed serializing directory listing",
+            err,
+        )
+    })
+}
+
+/// Sends the directory listing as response.
+pub(crate) async fn listing_response(
+    session: &mut impl SessionWrapper,
+    content_type: &str,
+    text: String,
+) -> Result<(), Box<Error>> {
+    let mut header = ResponseHeader::build(StatusCode::OK, Some(4))?;
+    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+    header.append_header(header::CONTENT_TYPE, content_type)?;
+    header.append_header(header::CACHE_CONTROL, "no-cache")?;
+    header.append_header(header::VARY, "Accept")?;
+
+    let send_body = session.req_header().method != Method::HEAD;
+    session
+        .write_response_header(Box::new(header), !send_body)
+        .await?;
+
+    if send_body {
+        session.write_response_body(Some(text.into()), true).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_preference() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("application/json, text/plain, */*")));
+        assert!(prefers_json(Some("application/*, text/html;q=0.5")));
+        assert!(!prefers_json(Some("application/json;q=0.5, text/html")));
+        assert!(!prefers_json(Some("application/json;q=0, */*")));
+    }
+
+    #[test]
+    fn sorting() {
+        let entry = |name: &str, is_dir, size| Entry {
+            name: name.into(),
+            is_dir,
+            size,
+            modified: None,
+        };
+        let names = |entries: &[Entry]| entries.iter().map(Entry::display_name).collect::<Vec<_>>();
+
+        let mut entries = vec![
+            entry("b.txt", false, 1),
+            entry("dir", true, 4096),
+            entry("a.txt", false, 3),
+            entry("c.txt", false, 2),
+        ];
+
+        Sorting::from_query(None).sort(&mut entries);
+        assert_eq!(names(&entries), ["dir", "a.txt", "b.txt", "c.txt"]);
+
+        let sorting = Sorting::from_query(Some("sort=size&order=desc"));
+        sorting.sort(&mut entries);
+        assert_eq!(names(&entries), ["dir", "a.txt", "c.txt", "b.txt"]);
+        assert_eq!(sorting.link(SortKey::Size), "?sort=size&order=asc");
+        assert_eq!(sorting.link(SortKey::Name), "?sort=name&order=asc");
+
+        let sorting = Sorting::from_query(Some("sort=name&order=desc&x=y"));
+        sorting.sort(&mut entries);
+        assert_eq!(names(&entries), ["dir", "c.txt", "b.txt", "a.txt"]);
+
+        let sorting = Sorting::from_query(Some("sort=name"));
+        assert_eq!(sorting.link(SortKey::Name), "?sort=name&order=desc");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory cache for resolved paths, file metadata and contents of small files
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::metadata::Metadata;
+use crate::mime_types::MimeTypes;
+use crate::storage::Storage;
+
+/// Estimated memory use of a cache entry in addition to paths and file contents
+const ENTRY_OVERHEAD: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    /// URI path
+    Uri(String),
+    /// File path along with the original path for pre-compressed files
+    File(PathBuf, Option<PathBuf>),
+}
+
+impl Key {
+    fn size(&self) -> usize {
+        match self {
+            Self::Uri(uri) => uri.len(),
+            Self::File(path, orig_path) => {
+                path.as_os_str().len() + orig_path.as_ref().map_or(0, |p| p.as_os_str().len())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    /// File path a URI path resolved to
+    Path(PathBuf),
+    /// File metadata if the file exists and contents if the file is small enough
+    File(Result<CachedFile, ErrorKind>),
+}
+
+impl Value {
+    fn size(&self) -> usize {
+        match self {
+            Self::Path(path) => path.as_os_str().len(),
+            Self::File(Ok(file)) => file.contents.as_ref().map_or(0, Bytes::len),
+            Self::File(Err(_)) => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    expires: Instant,
+    size: usize,
+    value: Value,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Keys in insertion order, entries replaced in the meantime have a different ID
+    queue: VecDeque<(Key, u64)>,
+    size: usize,
+    next_id: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.size -= entry.size;
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((key, id)) = self.queue.pop_front() {
+            if self.map.get(&key).is_some_and(|entry| entry.id == id) {
+                self.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn read_contents(storage: &dyn Storage, path: &Path) -> Result<Vec<u8>, Error> {
+    let mut contents = Vec::new();
+    storage.open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// File metadata and contents if the file is small enough
+#[derive(Debug, Clone)]
+pub(crate) struct CachedFile {
+    pub(crate) meta: Arc<Metadata>,
+    pub(crate) contents: Option<Bytes>,
+}
+
+/// A bounded in-memory cache, entries are evicted in the order they were added
+pub(crate) struct FileCache {
+    max_size: usize,
+    max_file_size: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl std::fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileCache")
+            .field("max_size", &self.max_size)
+            .field("max_file_size", &self.max_file_size)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for FileCache {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_size == other.max_size
+            && self.max_file_size == other.max_file_size
+            && self.ttl == other.ttl
+    }
+}
+
+impl Eq for FileCache {}
+
+impl FileCache {
+    /// Creates a new cache with the given total size, maximal size of files to keep in memory
+    /// and expiration time of the entries.
+    pub(crate) fn new(max_size: usize, max_file_size: usize, ttl: Duration) -> Self {
+        Self {
+            max_size,
+            max_file_size,
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    fn get(&self, key: &Key) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        let entry = entries.map.get(key)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(key);
+            return None;
+        }
+        Some(entry.value.clone())
+    }
+
+    fn insert(&self, key: Key, value: Value) {
+        let size = ENTRY_OVERHEAD + key.size() + value.size();
+        if size > self.max_size {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|err| err.into_inner());
+        entries.remove(&key);
+        while entries.size + size > self.max_size {
+            if !entries.evict_oldest() {
+                break;
+            }
+        }
+
+        // Drop queue items for replaced entries before the queue grows too large
+        if entries.queue.len() > 2 * entries.map.len() + 16 {
+            let Entries { map, queue, .. } = &mut *entries;
+            queue.retain(|(key, id)| map.get(key).is_some_and(|entry| entry.id == *id));
+        }
+
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.size += size;
+        entries.queue.push_back((key.clone(), id));
+        entries.map.insert(
+            key,
+            Entry {
+                id,
+                expires: Instant::now() + self.ttl,
+                size,
+                value,
+            },
+        );
+    }
+
+    /// Resolves a URI path to a file path. Unless the cache contains the result already, `resolve`
+    /// is called to do the actual work. Only successful results are cached.
+    pub(crate) fn resolve_uri(
+        &self,
+        uri: &str,
+        resolve: impl FnOnce() -> Result<PathBuf, Error>,
+    ) -> Result<PathBuf, Error> {
+        let key = Key::Uri(uri.to_owned());
+        if let Some(Value::Path(path)) = self.get(&key) {
+            return Ok(path);
+        }
+
+        let path = resolve()?;
+        self.insert(key, Value::Path(path.clone()));
+        Ok(path)
+    }
+
+    /// Checks whether the path points to a regular file. Negative results are cached.
+    pub(crate) fn is_file(
+        &self,
+        storage: &dyn Storage,
+        path: &Path,
+        orig_path: Option<&Path>,
+    ) -> bool {
+        let key = Key::File(path.to_path_buf(), orig_path.map(Path::to_path_buf));
+        if let Some(Value::File(file)) = self.get(&key) {
+            return file.is_ok();
+        }
+
+        if storage.is_file(path) {
+            true
+        } else {
+            self.insert(key, Value::File(Err(ErrorKind::NotFound)));
+            false
+        }
+    }
+
+    /// Retrieves file metadata and, for files no larger than the maximal file size, file
+    /// contents. See [`Metadata::with_mime_types`] for the meaning of the parameters.
+    pub(crate) async fn file(
+        &self,
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        orig_path: Option<&Path>,
+        mime_types: &Arc<MimeTypes>,
+    ) -> Result<CachedFile, Error> {
+        let key = Key::File(path.to_path_buf(), orig_path.map(Path::to_path_buf));
+        if let Some(Value::File(file)) = self.get(&key) {
+            return file.map_err(Error::from);
+        }
+
+        let max_file_size = self.max_file_size as u64;
+        let storage = storage.clone();
+        let mime_types = mime_types.clone();
+        let path = path.to_path_buf();
+        let orig_path = orig_path.map(Path::to_path_buf);
+        let file = tokio::task::spawn_blocking(move || {
+            let meta = Metadata::with_mime_types(
+                storage.as_ref(),
+                &path,
+                orig_path.as_deref(),
+                &mime_types,
+            )?;
+            let contents = if meta.size <= max_file_size {
+                // File might have changed since metadata was retrieved, don’t cache it then.
+                read_contents(storage.as_ref(), &path)
+                    .ok()
+                    .filter(|contents| contents.len() as u64 == meta.size)
+                    .map(Bytes::from)
+            } else {
+                None
+            };
+            Ok(CachedFile {
+                meta: Arc::new(meta),
+                contents,
+            })
+        })
+        .await
+        .unwrap_or_else(|err| Err(Error::other(err)));
+
+        match &file {
+            Ok(file) => self.insert(key, Value::File(Ok(file.clone()))),
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::InvalidInput) => {
+                self.insert(key, Value::File(Err(err.kind())))
+            }
+            Err(_) => {}
+        }
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileSystem;
+
+    fn path(path: &str) -> PathBuf {
+        PathBuf::from(path)
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = FileCache::new(3 * ENTRY_OVERHEAD + 20, 0, Duration::from_secs(60));
+        let resolved = |uri: &str| cache.resolve_uri(uri, || Ok(path("/resolved"))).unwrap();
+        let failing = |uri: &str| cache.resolve_uri(uri, || Err(ErrorKind::NotFound.into()));
+
+        resolved("/a");
+        resolved("/b");
+        assert_eq!(failing("/a").unwrap(), path("/resolved"));
+        assert_eq!(failing("/b").unwrap(), path("/resolved"));
+
+        // Third entry doesn’t fit, oldest entry should be evicted
+        resolved("/c");
+        assert!(failing("/a").is_err());
+        assert_eq!(failing("/b").unwrap(), path("/resolved"));
+        assert_eq!(failing("/c").unwrap(), path("/resolved"));
+
+        // Failed resolution isn’t cached
+        assert!(failing("/d").is_err());
+        assert_eq!(failing("/b").unwrap(), path("/resolved"));
+    }
+
+    #[test]
+    fn expiration() {
+        let cache = FileCache::new(1024 * 1024, 0, Duration::ZERO);
+        cache.resolve_uri("/a", || Ok(path("/a"))).unwrap();
+        assert_eq!(
+            cache.resolve_uri("/a", || Ok(path("/b"))).unwrap(),
+            path("/b")
+        );
+    }
+
+    #[test]
+    fn negative_results() {
+        let cache = FileCache::new(1024 * 1024, 0, Duration::from_secs(60));
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("root");
+        let storage = FileSystem::new(&root).unwrap();
+
+        assert!(cache.is_file(&storage, &root.join("file.txt"), None));
+        assert!(!cache.is_file(&storage, &root.join("missing.txt"), None));
+        assert!(!cache.is_file(&storage, &root.join("subdir"), None));
+        assert_eq!(cache.entries.lock().unwrap().map.len(), 2);
+    }
+}
@@ -14,8 +14,9 @@
 
 //! Handles compression for a Pingora session, both static (precompressed files) and dynamic.
 
-use http::{header, status::StatusCode};
+use http::{header, status::StatusCode, HeaderMap};
 use pandora_module_utils::pingora::{Error, ResponseCompression, ResponseHeader, SessionWrapper};
+use std::future::Future;
 use std::path::{Path, PathBuf};
 
 use crate::compression_algorithm::{find_matches, CompressionAlgorithm};
@@ -46,17 +47,22 @@ impl<'a> Compression<'a> {
     }
 
     /// Checks whether the given path should be rewritten to a pre-compressed version of the file.
-    pub(crate) fn rewrite_path(
+    /// The `is_file` callback is used to check whether a pre-compressed file exists.
+    pub(crate) async fn rewrite_path<F>(
         &mut self,
-        session: &impl SessionWrapper,
+        headers: &HeaderMap,
         path: &Path,
-    ) -> Option<PathBuf> {
+        is_file: impl Fn(PathBuf) -> F,
+    ) -> Option<PathBuf>
+    where
+        F: Future<Output = bool>,
+    {
         if self.precompressed.is_empty() {
             return None;
         }
 
         let filename = path.file_name()?;
-        let requested = session.req_header().headers.get(header::ACCEPT_ENCODING)?;
+        let requested = headers.get(header::ACCEPT_ENCODING)?;
         let overlap = find_matches(requested.to_str().ok()?, self.precompressed);
 
         for algorithm in overlap {
@@ -66,7 +72,7 @@ impl<'a> Compression<'a> {
 
             let mut candidate_path = path.to_path_buf();
             candidate_path.set_file_name(candidate_name);
-            if candidate_path.is_file() {
+            if is_file(candidate_path.clone()).await {
                 self.precompressed_active = Some(algorithm);
                 return Some(candidate_path);
             }
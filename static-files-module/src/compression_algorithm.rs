@@ -19,6 +19,8 @@ use serde::Deserialize;
 use std::fmt::Display;
 use std::str::FromStr;
 
+use crate::accept;
+
 /// Represents a compression algorithm choice.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
 pub enum CompressionAlgorithm {
@@ -112,34 +114,13 @@ impl Display for UnsupportedCompressionAlgorithm {
     }
 }
 
-/// Parses an encoding specifier from `Accept-Encoding` HTTP header into an
-/// algorithm/quality pair.
-fn parse_encoding(encoding: &str) -> Option<(&str, u16)> {
-    let mut params = encoding.split(';');
-    let algorithm = params.next()?.trim();
-    let mut quality = 1000;
-    for param in params {
-        if let Some((name, value)) = param.split_once('=') {
-            if name.trim() == "q" {
-                if let Ok(value) = f64::from_str(value.trim()) {
-                    quality = (value * 1000.0) as u16;
-                }
-            }
-        }
-    }
-    Some((algorithm, quality))
-}
-
 /// Compares the requested encodings from `Accept-Encoding` HTTP header with a list of supported
 /// algorithms and returns any matches, sorted by the respective quality value.
 pub(crate) fn find_matches(
     requested: &str,
     supported: &[CompressionAlgorithm],
 ) -> Vec<CompressionAlgorithm> {
-    let mut requested = requested
-        .split(',')
-        .filter_map(parse_encoding)
-        .collect::<Vec<_>>();
+    let mut requested = accept::parse(requested).collect::<Vec<_>>();
     requested.sort_by_key(|(_, quality)| -(*quality as i32));
 
     let mut result = Vec::new();
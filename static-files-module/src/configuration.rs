@@ -19,10 +19,13 @@ use mime_guess::mime::FromStrError;
 use mime_guess::Mime;
 use pandora_module_utils::{DeserializeMap, OneOrMany};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::compression_algorithm::CompressionAlgorithm;
+use crate::image_format::ImageFormat;
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(try_from = "String")]
@@ -58,6 +61,38 @@ impl TryFrom<String> for MimeMatch {
     }
 }
 
+/// A MIME type like `application/wasm`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MimeType(pub Mime);
+
+impl FromStr for MimeType {
+    type Err = FromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl TryFrom<String> for MimeType {
+    type Error = FromStrError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Parses a command line value like `wasm=application/wasm`.
+fn parse_mime_mapping(value: &str) -> Result<(String, MimeType), String> {
+    let (key, mime) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXTENSION=TYPE or PATTERN=TYPE, got {value}"))?;
+    Ok((
+        key.to_owned(),
+        mime.parse().map_err(|err| format!("{err}"))?,
+    ))
+}
+
 /// Command line options of the static files module
 #[derive(Debug, Default, Parser)]
 pub struct StaticFilesOpt {
@@ -65,6 +100,15 @@ pub struct StaticFilesOpt {
     #[clap(short, long, value_parser = clap::value_parser!(OsString))]
     pub root: Option<PathBuf>,
 
+    /// Tar archive to serve files from instead of a root directory.
+    #[clap(long, value_parser = clap::value_parser!(OsString))]
+    pub archive: Option<PathBuf>,
+
+    /// Name of an archive embedded into the binary to serve files from instead of a root
+    /// directory.
+    #[clap(long)]
+    pub embedded: Option<String>,
+
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     #[clap(long)]
     pub canonicalize_uri: Option<bool>,
@@ -78,12 +122,36 @@ pub struct StaticFilesOpt {
     #[clap(long)]
     pub page_404: Option<String>,
 
+    /// URI path to try if the requested file doesn’t exist, `$uri` will be replaced by the
+    /// original URI path, e.g. `$uri.html` or `/index.html`. This command line flag can be
+    /// specified multiple times.
+    #[clap(long)]
+    pub try_files: Option<Vec<String>>,
+
+    /// URI path pattern, e.g. `/assets/*`. Matching requests will not use the `try_files`
+    /// fallbacks. This command line flag can be specified multiple times.
+    #[clap(long)]
+    pub try_files_exclude: Option<Vec<String>>,
+
+    /// Display a listing for directories without an index file.
+    #[clap(long)]
+    pub autoindex: Option<bool>,
+
+    /// Include hidden files (names starting with a dot) in directory listings.
+    #[clap(long)]
+    pub autoindex_hidden: Option<bool>,
+
     /// File extension to check when looking for pre-compressed versions of a file. This command
     /// line flag can be specified multiple times. Supported file extensions are gz (gzip),
     /// zz (zlib deflate), z (compress), br (Brotli), zst (Zstandard).
     #[clap(long, value_parser = clap::value_parser!(String))]
     pub precompressed: Option<Vec<CompressionAlgorithm>>,
 
+    /// Alternative image format to look for if supported by the client. This command line flag
+    /// can be specified multiple times. Supported formats are avif, webp, jxl (JPEG XL).
+    #[clap(long)]
+    pub image_formats: Option<Vec<ImageFormat>>,
+
     /// The character set to declare for text files.
     #[clap(long)]
     pub declare_charset: Option<String>,
@@ -92,6 +160,33 @@ pub struct StaticFilesOpt {
     /// specified multiple times.
     #[clap(long, value_parser = clap::value_parser!(String))]
     pub declare_charset_types: Option<Vec<MimeMatch>>,
+
+    /// MIME type for a file extension or file name pattern, e.g. `wasm=application/wasm` or
+    /// `*.min.js=text/javascript`. This command line flag can be specified multiple times.
+    #[clap(long, value_parser = parse_mime_mapping)]
+    pub mime_types: Option<Vec<(String, MimeType)>>,
+
+    /// MIME type for files where it cannot be determined from the file name.
+    #[clap(long)]
+    pub default_type: Option<MimeType>,
+
+    /// Maximal number of byte ranges allowed in a request. Requests with more ranges will receive
+    /// the full file.
+    #[clap(long)]
+    pub max_ranges: Option<usize>,
+
+    /// Maximal memory use of the in-memory cache for file paths, metadata and contents of small
+    /// files, in bytes. The default value 0 disables the cache.
+    #[clap(long)]
+    pub cache_size: Option<usize>,
+
+    /// Maximal size of files to keep in the in-memory cache, in bytes.
+    #[clap(long)]
+    pub cache_max_file_size: Option<usize>,
+
+    /// Time in seconds after which in-memory cache entries expire.
+    #[clap(long)]
+    pub cache_ttl: Option<u64>,
 }
 
 /// Configuration file settings of the static files module
@@ -100,6 +195,13 @@ pub struct StaticFilesConf {
     /// The root directory.
     pub root: Option<PathBuf>,
 
+    /// Tar archive to serve files from instead of a root directory.
+    pub archive: Option<PathBuf>,
+
+    /// Name of an archive embedded into the binary to serve files from instead of a root
+    /// directory.
+    pub embedded: Option<String>,
+
     /// Redirect /file%2e.txt to /file.txt and /dir to /dir/.
     pub canonicalize_uri: bool,
 
@@ -109,16 +211,55 @@ pub struct StaticFilesConf {
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
     pub page_404: Option<String>,
 
+    /// List of URI paths to try if the requested file doesn’t exist, `$uri` will be replaced by
+    /// the original URI path, e.g. `$uri.html` or `/index.html`.
+    pub try_files: OneOrMany<String>,
+
+    /// List of URI path patterns, e.g. `/assets/*`. Matching requests will not use the
+    /// `try_files` fallbacks.
+    pub try_files_exclude: OneOrMany<String>,
+
+    /// Display a listing for directories without an index file.
+    pub autoindex: bool,
+
+    /// Include hidden files (names starting with a dot) in directory listings.
+    pub autoindex_hidden: bool,
+
     /// List of file extensions to check when looking for pre-compressed versions of a file.
     /// Supported file extensions are gz (gzip), zz (zlib deflate), z (compress), br (Brotli),
     /// zst (Zstandard).
     pub precompressed: OneOrMany<CompressionAlgorithm>,
 
+    /// List of alternative image formats to look for if supported by the client, in the order of
+    /// preference. Supported formats are avif, webp, jxl (JPEG XL).
+    pub image_formats: OneOrMany<ImageFormat>,
+
     /// The character set to declare for text files.
     pub declare_charset: String,
 
     /// List of MIME types that the `declare_charset` setting should apply to.
     pub declare_charset_types: OneOrMany<MimeMatch>,
+
+    /// MIME types for file extensions like `wasm` or file name patterns like `*.min.js`,
+    /// overriding the built-in list.
+    pub mime_types: HashMap<String, MimeType>,
+
+    /// MIME type for files where it cannot be determined from the file name.
+    pub default_type: Option<MimeType>,
+
+    /// Maximal number of byte ranges allowed in a request. Requests with more ranges will receive
+    /// the full file.
+    pub max_ranges: usize,
+
+    /// Maximal memory use of the in-memory cache for file paths, metadata and contents of small
+    /// files, in bytes. The default value 0 disables the cache.
+    pub cache_size: usize,
+
+    /// Maximal size of files to keep in the in-memory cache, in bytes.
+    pub cache_max_file_size: usize,
+
+    /// Time in seconds after which in-memory cache entries expire.
+    pub cache_ttl: u64,
 }
 
 impl StaticFilesConf {
@@ -129,6 +270,14 @@ impl StaticFilesConf {
             self.root = opt.root;
         }
 
+        if opt.archive.is_some() {
+            self.archive = opt.archive;
+        }
+
+        if opt.embedded.is_some() {
+            self.embedded = opt.embedded;
+        }
+
         if let Some(canonicalize_uri) = opt.canonicalize_uri {
             self.canonicalize_uri = canonicalize_uri;
         }
@@ -141,10 +290,30 @@ impl StaticFilesConf {
             self.page_404 = opt.page_404;
         }
 
+        if let Some(try_files) = opt.try_files {
+            self.try_files = try_files.into();
+        }
+
+        if let Some(try_files_exclude) = opt.try_files_exclude {
+            self.try_files_exclude = try_files_exclude.into();
+        }
+
+        if let Some(autoindex) = opt.autoindex {
+            self.autoindex = autoindex;
+        }
+
+        if let Some(autoindex_hidden) = opt.autoindex_hidden {
+            self.autoindex_hidden = autoindex_hidden;
+        }
+
         if let Some(precompressed) = opt.precompressed {
             self.precompressed = precompressed.into();
         }
 
+        if let Some(image_formats) = opt.image_formats {
+            self.image_formats = image_formats.into();
+        }
+
         if let Some(declare_charset) = opt.declare_charset {
             self.declare_charset = declare_charset;
         }
@@ -152,6 +321,30 @@ impl StaticFilesConf {
         if let Some(declare_charset_types) = opt.declare_charset_types {
             self.declare_charset_types = declare_charset_types.into();
         }
+
+        if let Some(mime_types) = opt.mime_types {
+            self.mime_types.extend(mime_types);
+        }
+
+        if opt.default_type.is_some() {
+            self.default_type = opt.default_type;
+        }
+
+        if let Some(max_ranges) = opt.max_ranges {
+            self.max_ranges = max_ranges;
+        }
+
+        if let Some(cache_size) = opt.cache_size {
+            self.cache_size = cache_size;
+        }
+
+        if let Some(cache_max_file_size) = opt.cache_max_file_size {
+            self.cache_max_file_size = cache_max_file_size;
+        }
+
+        if let Some(cache_ttl) = opt.cache_ttl {
+            self.cache_ttl = cache_ttl;
+        }
     }
 }
 
@@ -159,12 +352,25 @@ impl Default for StaticFilesConf {
     fn default() -> Self {
         Self {
             root: None,
+            archive: None,
+            embedded: None,
             canonicalize_uri: true,
             index_file: Default::default(),
             page_404: None,
+            try_files: Default::default(),
+            try_files_exclude: Default::default(),
+            autoindex: false,
+            autoindex_hidden: false,
             precompressed: Default::default(),
+            image_formats: Default::default(),
             declare_charset: "utf-8".to_owned(),
             declare_charset_types: Default::default(),
+            mime_types: Default::default(),
+            default_type: None,
+            max_ranges: 16,
+            cache_size: 0,
+            cache_max_file_size: 64 * 1024,
+            cache_ttl: 60,
         }
     }
 }
@@ -14,62 +14,219 @@
 
 //! Writing files to Pingora session.
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use http::status::StatusCode;
 use log::error;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use std::cmp::min;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::multipart::MultipartRanges;
+use crate::storage::{FileData, Storage};
 
 const BUFFER_SIZE: usize = 64 * 1024;
 
-/// Writes a chunk of a file as a Pingora session response. The data will be passed through the
-/// compression handler first in case dynamic compression is enabled.
-pub(crate) async fn file_response(
-    session: &mut impl SessionWrapper,
-    path: &Path,
-    start: u64,
+/// Number of buffers that can be read ahead of the data being sent
+const READ_AHEAD: usize = 4;
+
+fn internal_error() -> Box<Error> {
+    Error::new(ErrorType::HTTPStatus(
+        StatusCode::INTERNAL_SERVER_ERROR.into(),
+    ))
+}
+
+/// Reads up to `READ_AHEAD` buffers of a file, starting at the given position and ending at `end`
+/// at most. Fewer buffers are returned if the file ends early.
+///
+/// This function performs blocking file operations and should run on the blocking thread pool.
+fn read_chunks(
+    file: &mut dyn FileData,
+    mut position: u64,
     end: u64,
+) -> std::io::Result<Vec<Bytes>> {
+    file.seek(SeekFrom::Start(position))?;
+
+    let mut buffers = Vec::with_capacity(READ_AHEAD);
+    while buffers.len() < READ_AHEAD && position <= end {
+        let mut buf = BytesMut::zeroed(min(end - position + 1, BUFFER_SIZE as u64) as usize);
+        let len = file.read(buf.as_mut())?;
+        if len == 0 {
+            break;
+        }
+        buf.truncate(len);
+        position += len as u64;
+        buffers.push(buf.freeze());
+    }
+    Ok(buffers)
+}
+
+/// Reads the given ranges of a file and passes the data on to the sender. Buffers never span
+/// multiple ranges.
+///
+/// Each batch of buffers is read by a separate task on the blocking thread pool, so that no
+/// blocking thread is occupied while waiting for the data to be sent.
+async fn read_ranges(
+    storage: Arc<dyn Storage>,
+    path: &Path,
+    ranges: &[(u64, u64)],
+    sender: &mpsc::Sender<Result<Bytes, Box<Error>>>,
 ) -> Result<(), Box<Error>> {
-    let mut file = File::open(path).map_err(|err| {
+    let mut file = {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || storage.open(&path))
+    }
+    .await
+    .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    .map_err(|err| {
         error!("failed opening file {path:?}: {err}");
-        Error::new(ErrorType::HTTPStatus(
-            StatusCode::INTERNAL_SERVER_ERROR.into(),
-        ))
+        internal_error()
     })?;
 
-    if start != 0 {
-        file.seek(SeekFrom::Start(start)).map_err(|err| {
-            error!("failed seeking in file {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
+    for &(start, end) in ranges {
+        let mut position = start;
+        while position <= end {
+            let (returned, result) = tokio::task::spawn_blocking(move || {
+                let result = read_chunks(file.as_mut(), position, end);
+                (file, result)
+            })
+            .await
+            .map_err(|err| {
+                error!("failed reading data from {path:?}: {err}");
+                internal_error()
+            })?;
+            file = returned;
+
+            let buffers = result.map_err(|err| {
+                error!("failed reading data from {path:?}: {err}");
+                internal_error()
+            })?;
+            if buffers.is_empty() {
+                error!(
+                    "file ended with {} bytes left to be written",
+                    end - position + 1
+                );
+                return Err(Error::new(ErrorType::ReadError));
+            }
+
+            for buf in buffers {
+                position += buf.len() as u64;
+                if sender.send(Ok(buf)).await.is_err() {
+                    // Receiver is gone, response has been aborted.
+                    return Ok(());
+                }
+            }
+        }
     }
 
-    let mut remaining = (end - start + 1) as usize;
-    while remaining > 0 {
-        let mut buf = BytesMut::zeroed(min(remaining, BUFFER_SIZE));
-        let len = file.read(buf.as_mut()).map_err(|err| {
-            error!("failed reading data from {path:?}: {err}");
-            Error::new(ErrorType::HTTPStatus(
-                StatusCode::INTERNAL_SERVER_ERROR.into(),
-            ))
-        })?;
+    Ok(())
+}
 
-        if len == 0 {
-            error!("file ended with {remaining} bytes left to be written");
-            return Err(Error::new(ErrorType::ReadError));
+/// Source of the data to be sent
+#[derive(Debug)]
+pub(crate) enum FileSource<'a> {
+    /// File to be read from a storage backend
+    Storage(&'a Arc<dyn Storage>, &'a Path),
+    /// File contents already in memory
+    Memory(Bytes),
+}
+
+/// Provides file data, either from memory or by reading it from storage on the blocking thread
+/// pool so that slow disks don’t stall the threads processing requests. In the latter case, reading
+/// continues while previously read data is being sent, up to `READ_AHEAD` buffers.
+enum FileReader {
+    Disk {
+        path: PathBuf,
+        receiver: mpsc::Receiver<Result<Bytes, Box<Error>>>,
+    },
+    Memory(Bytes),
+}
+
+impl FileReader {
+    /// Starts reading the given ranges of a file.
+    fn new(source: FileSource<'_>, ranges: Vec<(u64, u64)>) -> Self {
+        let (storage, path) = match source {
+            FileSource::Storage(storage, path) => (storage.clone(), path.to_path_buf()),
+            FileSource::Memory(contents) => return Self::Memory(contents),
+        };
+
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+        {
+            let path = path.clone();
+            tokio::spawn(async move {
+                if let Err(err) = read_ranges(storage, &path, &ranges, &sender).await {
+                    let _ = sender.send(Err(err)).await;
+                }
+            });
         }
+        Self::Disk { path, receiver }
+    }
 
-        buf.truncate(len);
-        session.write_response_body(Some(buf.into()), false).await?;
-        remaining -= len;
+    /// Writes the data of a range to the response body without finishing the response.
+    async fn write_range(
+        &mut self,
+        session: &mut impl SessionWrapper,
+        start: u64,
+        end: u64,
+    ) -> Result<(), Box<Error>> {
+        let (path, receiver) = match self {
+            Self::Disk { path, receiver } => (path, receiver),
+            Self::Memory(contents) => {
+                if end >= contents.len() as u64 {
+                    error!("range {start}-{end} is outside of cached file contents");
+                    return Err(Error::new(ErrorType::ReadError));
+                }
+                let data = contents.slice(start as usize..=end as usize);
+                return session.write_response_body(Some(data), false).await;
+            }
+        };
+
+        let mut remaining = end - start + 1;
+        while remaining > 0 {
+            let buf = receiver.recv().await.unwrap_or_else(|| {
+                error!("reading from file {path:?} stopped unexpectedly");
+                Err(Error::new(ErrorType::ReadError))
+            })?;
+            remaining = remaining.saturating_sub(buf.len() as u64);
+            session.write_response_body(Some(buf), false).await?;
+        }
+        Ok(())
     }
+}
 
+/// Writes a chunk of a file as a Pingora session response. The data will be passed through the
+/// compression handler first in case dynamic compression is enabled.
+pub(crate) async fn file_response(
+    session: &mut impl SessionWrapper,
+    source: FileSource<'_>,
+    start: u64,
+    end: u64,
+) -> Result<(), Box<Error>> {
+    let mut reader = FileReader::new(source, vec![(start, end)]);
+    reader.write_range(session, start, end).await?;
     session.write_response_body(None, true).await?;
 
     Ok(())
 }
+
+/// Writes multiple ranges of a file as a `multipart/byteranges` Pingora session response.
+pub(crate) async fn multipart_response(
+    session: &mut impl SessionWrapper,
+    source: FileSource<'_>,
+    multipart: &MultipartRanges,
+) -> Result<(), Box<Error>> {
+    let mut reader = FileReader::new(source, multipart.ranges().to_vec());
+    for (start, end) in multipart.ranges() {
+        session
+            .write_response_body(Some(multipart.part_header(*start, *end).into()), false)
+            .await?;
+        reader.write_range(session, *start, *end).await?;
+    }
+    session
+        .write_response_body(Some(multipart.trailer().into()), true)
+        .await?;
+
+    Ok(())
+}
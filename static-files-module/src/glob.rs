@@ -0,0 +1,83 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simple wildcard patterns
+
+/// Checks whether a string matches a wildcard pattern. `*` in the pattern matches any number of
+/// characters, `?` matches exactly one character, all other characters have to match literally.
+pub(crate) fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    let mut p = 0;
+    let mut v = 0;
+    // Position after the last `*` seen and the value position it has been matched up to
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, v));
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => {
+                if let Some((star_p, star_v)) = backtrack {
+                    // Let the last `*` consume one more character
+                    p = star_p;
+                    v = star_v + 1;
+                    backtrack = Some((star_p, v));
+                } else {
+                    return false;
+                }
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        assert!(glob_matches("/assets/*", "/assets/app.js"));
+        assert!(glob_matches("/assets/*", "/assets/"));
+        assert!(glob_matches("/assets/*", "/assets/js/app.js"));
+        assert!(!glob_matches("/assets/*", "/assets"));
+        assert!(!glob_matches("/assets/*", "/app/assets/app.js"));
+
+        assert!(glob_matches("*.js", "app.js"));
+        assert!(glob_matches("*.js", ".js"));
+        assert!(!glob_matches("*.js", "app.json"));
+        assert!(glob_matches("*.min.*", "app.min.js"));
+        assert!(glob_matches("*.min.*", "app.min.min.css"));
+        assert!(!glob_matches("*.min.*", "app.js"));
+
+        assert!(glob_matches("file?.txt", "file1.txt"));
+        assert!(glob_matches("file?.txt", "fileä.txt"));
+        assert!(!glob_matches("file?.txt", "file.txt"));
+        assert!(!glob_matches("file?.txt", "file12.txt"));
+
+        assert!(glob_matches("LICENSE", "LICENSE"));
+        assert!(!glob_matches("LICENSE", "LICENSE.txt"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "x"));
+    }
+}
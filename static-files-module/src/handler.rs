@@ -15,22 +15,33 @@
 //! Handler for the `request_filter` phase.
 
 use async_trait::async_trait;
-use http::{method::Method, status::StatusCode};
+use http::{header, method::Method, status::StatusCode};
 use log::{debug, info, warn};
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use pandora_module_utils::standard_response::{error_response, redirect_response};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use std::io::ErrorKind;
-use std::path::PathBuf;
-
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::autoindex::{
+    listing_response, prefers_json, read_entries, render_html, render_json, Sorting,
+};
+use crate::cache::{CachedFile, FileCache};
 use crate::compression::Compression;
 use crate::configuration::StaticFilesConf;
-use crate::file_writer::file_response;
+use crate::file_writer::{file_response, multipart_response, FileSource};
+use crate::glob::glob_matches;
+use crate::image_negotiation::ImageNegotiation;
 use crate::metadata::Metadata;
 use crate::mime_matcher::MimeMatcher;
-use crate::path::{path_to_uri, resolve_uri};
-use crate::range::{extract_range, Range};
-use crate::CompressionAlgorithm;
+use crate::mime_types::MimeTypes;
+use crate::multipart::MultipartRanges;
+use crate::range::{extract_ranges, Ranges};
+use crate::storage::{embedded_archive, FileSystem, SharedStorage, Storage};
+use crate::tar_archive::TarArchive;
+use crate::{CompressionAlgorithm, ImageFormat};
 
 const DEFAULT_TEXT_TYPES: &[&str] = &[
     "text/*",
@@ -44,13 +55,21 @@ const DEFAULT_TEXT_TYPES: &[&str] = &[
 /// Static Files module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StaticFilesHandler {
-    root: Option<PathBuf>,
+    storage: Option<SharedStorage>,
     canonicalize_uri: bool,
     index_file: Vec<String>,
     page_404: Option<String>,
+    try_files: Vec<String>,
+    try_files_exclude: Vec<String>,
+    autoindex: bool,
+    autoindex_hidden: bool,
     precompressed: Vec<CompressionAlgorithm>,
+    image_formats: Vec<ImageFormat>,
     declare_charset: String,
     declare_charset_matcher: MimeMatcher,
+    mime_types: Arc<MimeTypes>,
+    max_ranges: usize,
+    cache: Option<Arc<FileCache>>,
 }
 
 #[async_trait]
@@ -66,8 +85,8 @@ impl RequestFilter for StaticFilesHandler {
         session: &mut impl SessionWrapper,
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
-        let root = if let Some(root) = self.root.as_ref() {
-            root
+        let storage = if let Some(storage) = self.storage.as_ref() {
+            &storage.0
         } else {
             debug!("received request but static files handler is not configured, ignoring");
             return Ok(RequestFilterResult::Unhandled);
@@ -76,27 +95,33 @@ impl RequestFilter for StaticFilesHandler {
         let uri = session.uri();
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
-            Ok(path) => (path, false),
+        let (mut path, fallback, not_found) = match self.resolve_uri(uri.path(), storage).await {
+            Ok(path) => (path, false, false),
             Err(err) if err.kind() == ErrorKind::NotFound => {
                 debug!("canonicalizing resulted in NotFound error");
 
-                let path = self.page_404.as_ref().and_then(|page_404| {
-                    debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
-                        Ok(path) => Some(path),
-                        Err(err) => {
-                            warn!("Failed resolving error page {page_404}: {err}");
-                            None
+                if let Some(path) = self.try_files(uri.path(), storage).await {
+                    (path, true, false)
+                } else {
+                    let path = if let Some(page_404) = &self.page_404 {
+                        debug!("error page is {page_404}");
+                        match self.resolve_uri(page_404, storage).await {
+                            Ok(path) => Some(path),
+                            Err(err) => {
+                                warn!("Failed resolving error page {page_404}: {err}");
+                                None
+                            }
                         }
+                    } else {
+                        None
+                    };
+
+                    if let Some(path) = path {
+                        (path, true, true)
+                    } else {
+                        error_response(session, StatusCode::NOT_FOUND).await?;
+                        return Ok(RequestFilterResult::ResponseSent);
                     }
-                });
-
-                if let Some(path) = path {
-                    (path, true)
-                } else {
-                    error_response(session, StatusCode::NOT_FOUND).await?;
-                    return Ok(RequestFilterResult::ResponseSent);
                 }
             }
             Err(err) => {
@@ -125,20 +150,15 @@ impl RequestFilter for StaticFilesHandler {
 
         debug!("translated into file path {path:?}");
 
-        if self.canonicalize_uri && !not_found {
-            if let Some(mut canonical) = path_to_uri(&path, root) {
+        if self.canonicalize_uri && !fallback {
+            if let Some(mut canonical) = storage.path_to_uri(&path) {
                 if canonical != uri.path() {
                     if let Some(query) = uri.query() {
                         canonical.push('?');
                         canonical.push_str(query);
                     }
 
-                    if let Some(prefix) = session
-                        .original_uri()
-                        .path()
-                        .strip_suffix(uri.path())
-                        .filter(|p| !p.is_empty())
-                    {
+                    if let Some(prefix) = stripped_prefix(session) {
                         // A prefix has been removed from the original URI, insert it for the
                         // redirect.
                         canonical.insert_str(0, prefix);
@@ -150,10 +170,10 @@ impl RequestFilter for StaticFilesHandler {
             }
         }
 
-        if path.is_dir() {
+        if is_dir(storage, &path).await {
             for filename in &self.index_file {
                 let candidate = path.join(filename);
-                if candidate.is_file() {
+                if self.is_file(storage, &candidate, None).await {
                     debug!("using directory index file {filename}");
                     path = candidate;
                 }
@@ -173,17 +193,47 @@ impl RequestFilter for StaticFilesHandler {
             }
         }
 
+        if self.autoindex && !fallback && is_dir(storage, &path).await {
+            return self.directory_listing(session, storage, &path).await;
+        }
+
+        let mut image_negotiation = ImageNegotiation::new(&self.image_formats);
+        let path = if let Some(variant_path) = image_negotiation
+            .rewrite_path(
+                &session.req_header().headers,
+                &path,
+                &self.mime_types.guess(&path),
+                |candidate| async move { self.is_file(storage, &candidate, None).await },
+            )
+            .await
+        {
+            debug!("serving image variant {variant_path:?}");
+            variant_path
+        } else {
+            path
+        };
+
         let mut compression = Compression::new(session, &self.precompressed);
 
-        let (path, orig_path) =
-            if let Some(precompressed_path) = compression.rewrite_path(session, &path) {
-                (precompressed_path, Some(path))
-            } else {
-                (path, None)
-            };
+        let (path, orig_path) = if let Some(precompressed_path) = compression
+            .rewrite_path(&session.req_header().headers, &path, |candidate| {
+                let path = &path;
+                async move { self.is_file(storage, &candidate, Some(path)).await }
+            })
+            .await
+        {
+            (precompressed_path, Some(path))
+        } else {
+            (path, None)
+        };
 
-        let meta = match Metadata::from_path(&path, orig_path.as_ref()) {
-            Ok(meta) => meta,
+        let (meta, contents) = match self.file(storage, &path, orig_path.as_deref()).await {
+            Ok(file) => (file.meta, file.contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("file {path:?} no longer exists");
+                error_response(session, StatusCode::NOT_FOUND).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
             Err(err) if err.kind() == ErrorKind::InvalidInput => {
                 warn!("Path {path:?} is not a regular file, denying access");
                 error_response(session, StatusCode::FORBIDDEN).await?;
@@ -200,6 +250,7 @@ impl RequestFilter for StaticFilesHandler {
             debug!("If-Match/If-Unmodified-Since precondition failed");
             let header = meta.to_custom_header(StatusCode::PRECONDITION_FAILED)?;
             let header = compression.transform_header(session, header)?;
+            let header = image_negotiation.transform_header(header)?;
             session.write_response_header(header, true).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
@@ -208,6 +259,7 @@ impl RequestFilter for StaticFilesHandler {
             debug!("If-None-Match/If-Modified-Since check resulted in Not Modified");
             let header = meta.to_custom_header(StatusCode::NOT_MODIFIED)?;
             let header = compression.transform_header(session, header)?;
+            let header = image_negotiation.transform_header(header)?;
             session.write_response_header(header, true).await?;
             return Ok(RequestFilterResult::ResponseSent);
         }
@@ -218,17 +270,28 @@ impl RequestFilter for StaticFilesHandler {
             None
         };
 
-        let (mut header, start, end) = match extract_range(session, &meta) {
-            Some(Range::Valid(start, end)) => {
+        let (mut header, range, multipart) = match extract_ranges(session, &meta, self.max_ranges) {
+            Some(Ranges::Single(start, end)) => {
                 debug!("bytes range requested: {start}-{end}");
                 let header = meta.to_partial_content_header(charset, start, end)?;
                 let header = compression.transform_header(session, header)?;
-                (header, start, end)
+                let header = image_negotiation.transform_header(header)?;
+                (header, Some((start, end)), None)
+            }
+            Some(Ranges::Multiple(ranges)) => {
+                debug!("multiple bytes ranges requested: {ranges:?}");
+                let multipart =
+                    MultipartRanges::new(ranges, meta.content_type(charset), meta.size)?;
+                let header = meta.to_multipart_header(&multipart)?;
+                let header = compression.transform_header(session, header)?;
+                let header = image_negotiation.transform_header(header)?;
+                (header, None, Some(multipart))
             }
-            Some(Range::OutOfBounds) => {
+            Some(Ranges::OutOfBounds) => {
                 debug!("requested bytes range is out of bounds");
                 let header = meta.to_not_satisfiable_header(charset)?;
                 let header = compression.transform_header(session, header)?;
+                let header = image_negotiation.transform_header(header)?;
                 session.write_response_header(header, true).await?;
                 return Ok(RequestFilterResult::ResponseSent);
             }
@@ -236,7 +299,10 @@ impl RequestFilter for StaticFilesHandler {
                 // Range is either missing or cannot be parsed, produce the entire file.
                 let header = meta.to_response_header(charset)?;
                 let header = compression.transform_header(session, header)?;
-                (header, 0, meta.size - 1)
+                let header = image_negotiation.transform_header(header)?;
+                // Empty files have no range to be read.
+                let range = meta.size.checked_sub(1).map(|end| (0, end));
+                (header, range, None)
             }
         };
 
@@ -250,7 +316,207 @@ impl RequestFilter for StaticFilesHandler {
         if send_body {
             // sendfile would be nice but not currently possible within pingora-proxy (see
             // https://github.com/cloudflare/pingora/issues/160)
-            file_response(session, &path, start, end).await?;
+            let source = if let Some(contents) = contents {
+                FileSource::Memory(contents)
+            } else {
+                FileSource::Storage(storage, &path)
+            };
+            if let Some(multipart) = multipart {
+                multipart_response(session, source, &multipart).await?;
+            } else if let Some((start, end)) = range {
+                file_response(session, source, start, end).await?;
+            } else {
+                session.write_response_body(None, true).await?;
+            }
+        }
+        Ok(RequestFilterResult::ResponseSent)
+    }
+}
+
+/// Checks on the blocking thread pool whether a path points to a directory.
+async fn is_dir(storage: &Arc<dyn Storage>, path: &Path) -> bool {
+    let storage = storage.clone();
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || storage.is_dir(&path))
+        .await
+        .unwrap_or(false)
+}
+
+/// Determines the prefix removed from the original URI, e.g. by the `strip_prefix` setting of the
+/// Virtual Hosts module.
+fn stripped_prefix(session: &impl SessionWrapper) -> Option<&str> {
+    session
+        .original_uri()
+        .path()
+        .strip_suffix(session.uri().path())
+        .filter(|p| !p.is_empty())
+}
+
+impl StaticFilesHandler {
+    /// Replaces the storage backend files are served from, e.g. by a custom [`Storage`]
+    /// implementation.
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = Some(SharedStorage(storage));
+    }
+
+    /// Resolves a URI path to a file path on the blocking thread pool, using the cache if
+    /// enabled.
+    async fn resolve_uri(
+        &self,
+        uri: &str,
+        storage: &Arc<dyn Storage>,
+    ) -> Result<PathBuf, std::io::Error> {
+        let cache = self.cache.clone();
+        let storage = storage.clone();
+        let uri = uri.to_owned();
+        tokio::task::spawn_blocking(move || {
+            if let Some(cache) = &cache {
+                cache.resolve_uri(&uri, || storage.resolve_uri(&uri))
+            } else {
+                storage.resolve_uri(&uri)
+            }
+        })
+        .await
+        .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+    }
+
+    /// Looks for the first of the `try_files` fallbacks for a URI path that points to a regular
+    /// file.
+    async fn try_files(&self, uri: &str, storage: &Arc<dyn Storage>) -> Option<PathBuf> {
+        if self.try_files.is_empty() {
+            return None;
+        }
+
+        if let Some(pattern) = self
+            .try_files_exclude
+            .iter()
+            .find(|pattern| glob_matches(pattern, uri))
+        {
+            debug!("URI path {uri} matches try_files exclusion {pattern}, not trying fallbacks");
+            return None;
+        }
+
+        for candidate in &self.try_files {
+            let candidate = candidate.replace("$uri", uri);
+            if candidate == uri {
+                // Already known not to exist
+                continue;
+            }
+
+            match self.resolve_uri(&candidate, storage).await {
+                Ok(path) => {
+                    if self.is_file(storage, &path, None).await {
+                        debug!("using fallback {candidate}");
+                        return Some(path);
+                    }
+                    debug!("fallback {candidate} isn’t a regular file");
+                }
+                Err(err) => debug!("failed resolving fallback {candidate}: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Checks on the blocking thread pool whether a path points to a regular file, using the
+    /// cache if enabled.
+    async fn is_file(
+        &self,
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        orig_path: Option<&Path>,
+    ) -> bool {
+        let cache = self.cache.clone();
+        let storage = storage.clone();
+        let path = path.to_path_buf();
+        let orig_path = orig_path.map(Path::to_path_buf);
+        tokio::task::spawn_blocking(move || {
+            if let Some(cache) = &cache {
+                cache.is_file(storage.as_ref(), &path, orig_path.as_deref())
+            } else {
+                storage.is_file(&path)
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Retrieves file metadata, using the cache if enabled. File contents are only available
+    /// for files stored in the cache.
+    async fn file(
+        &self,
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        orig_path: Option<&Path>,
+    ) -> Result<CachedFile, std::io::Error> {
+        if let Some(cache) = &self.cache {
+            cache.file(storage, path, orig_path, &self.mime_types).await
+        } else {
+            Ok(CachedFile {
+                meta: Arc::new(
+                    Metadata::with_mime_types_async(storage, path, orig_path, &self.mime_types)
+                        .await?,
+                ),
+                contents: None,
+            })
+        }
+    }
+
+    /// Produces a listing of the directory contents.
+    async fn directory_listing(
+        &self,
+        session: &mut impl SessionWrapper,
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        let entries = {
+            let storage = storage.clone();
+            let path = path.to_path_buf();
+            let show_hidden = self.autoindex_hidden;
+            tokio::task::spawn_blocking(move || read_entries(storage.as_ref(), &path, show_hidden))
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+        };
+        let mut entries = match entries {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                debug!("reading directory {path:?} resulted in PermissionDenied error");
+                error_response(session, StatusCode::FORBIDDEN).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+            Err(err) => {
+                warn!("failed reading directory {path:?}: {err}");
+                error_response(session, StatusCode::INTERNAL_SERVER_ERROR).await?;
+                return Ok(RequestFilterResult::ResponseSent);
+            }
+        };
+
+        let sorting = Sorting::from_query(session.uri().query());
+        sorting.sort(&mut entries);
+
+        let prefix = stripped_prefix(session).unwrap_or_default().to_owned();
+        let with_prefix = |uri: String| format!("{prefix}{uri}");
+        let base = with_prefix(storage.path_to_uri(path).unwrap_or_else(|| "/".to_owned()));
+        let parent = if path != storage.root() {
+            path.parent()
+                .and_then(|parent| storage.path_to_uri(parent))
+                .map(with_prefix)
+        } else {
+            None
+        };
+
+        let accept = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok());
+        if prefers_json(accept) {
+            debug!("producing JSON listing for directory {path:?}");
+            let text = render_json(&base, &entries)?;
+            listing_response(session, "application/json", text).await?;
+        } else {
+            debug!("producing HTML listing for directory {path:?}");
+            let text = render_html(&base, parent.as_deref(), &entries, &sorting);
+            listing_response(session, "text/html;charset=utf-8", text).await?;
         }
         Ok(RequestFilterResult::ResponseSent)
     }
@@ -260,18 +526,63 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
     type Error = Box<Error>;
 
     fn try_from(conf: StaticFilesConf) -> Result<Self, Self::Error> {
-        let root = if let Some(root) = conf.root {
-            Some(root.canonicalize().map_err(|err| {
+        let sources = [
+            conf.root.is_some(),
+            conf.archive.is_some(),
+            conf.embedded.is_some(),
+        ];
+        if sources.into_iter().filter(|source| *source).count() > 1 {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "Only one of root, archive and embedded settings can be specified",
+            ));
+        }
+
+        let storage: Option<Arc<dyn Storage>> = if let Some(root) = conf.root {
+            Some(Arc::new(FileSystem::new(&root).map_err(|err| {
                 Error::because(
                     ErrorType::InternalError,
                     format!("Failed accessing root path {:?}", root),
                     err,
                 )
-            })?)
+            })?))
+        } else if let Some(archive) = conf.archive {
+            Some(Arc::new(TarArchive::open(&archive).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Failed reading archive {:?}", archive),
+                    err,
+                )
+            })?))
+        } else if let Some(name) = conf.embedded {
+            let data = embedded_archive(&name).ok_or_else(|| {
+                Error::explain(
+                    ErrorType::InternalError,
+                    format!("No embedded archive named {name:?} has been registered"),
+                )
+            })?;
+            Some(Arc::new(TarArchive::from_static(data).map_err(|err| {
+                Error::because(
+                    ErrorType::InternalError,
+                    format!("Failed reading embedded archive {name:?}"),
+                    err,
+                )
+            })?))
         } else {
             None
         };
 
+        if let Some(entry) = conf
+            .try_files
+            .iter()
+            .find(|entry| !entry.starts_with('/') && !entry.starts_with("$uri"))
+        {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("try_files entry {entry:?} has to start with / or $uri"),
+            ));
+        }
+
         let mut declare_charset_matcher = MimeMatcher::new();
         if !conf.declare_charset_types.is_empty() {
             for mime in conf.declare_charset_types {
@@ -284,13 +595,30 @@ impl TryFrom<StaticFilesConf> for StaticFilesHandler {
         }
 
         Ok(Self {
-            root,
+            storage: storage.map(SharedStorage),
             canonicalize_uri: conf.canonicalize_uri,
             index_file: conf.index_file.into(),
             page_404: conf.page_404,
+            try_files: conf.try_files.into(),
+            try_files_exclude: conf.try_files_exclude.into(),
+            autoindex: conf.autoindex,
+            autoindex_hidden: conf.autoindex_hidden,
             precompressed: conf.precompressed.into(),
+            image_formats: conf.image_formats.into(),
             declare_charset: conf.declare_charset,
             declare_charset_matcher,
+            mime_types: Arc::new(MimeTypes::new(
+                conf.mime_types.into_iter().map(|(key, mime)| (key, mime.0)),
+                conf.default_type.map(|mime| mime.0),
+            )),
+            max_ranges: conf.max_ranges,
+            cache: (conf.cache_size > 0).then(|| {
+                Arc::new(FileCache::new(
+                    conf.cache_size,
+                    conf.cache_max_file_size,
+                    Duration::from_secs(conf.cache_ttl),
+                ))
+            }),
         })
     }
 }
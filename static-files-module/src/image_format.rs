@@ -0,0 +1,194 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handles image formats that can be negotiated via the `Accept` HTTP header.
+
+use serde::Deserialize;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::accept;
+
+/// Represents an alternative image format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub enum ImageFormat {
+    /// AV1 Image File Format
+    #[serde(rename = "avif")]
+    Avif,
+    /// WebP
+    #[serde(rename = "webp")]
+    Webp,
+    /// JPEG XL
+    #[serde(rename = "jxl")]
+    JpegXl,
+}
+
+impl ImageFormat {
+    /// Returns the file extension corresponding to the format.
+    pub fn ext(&self) -> &'static str {
+        match self {
+            Self::Avif => "avif",
+            Self::Webp => "webp",
+            Self::JpegXl => "jxl",
+        }
+    }
+
+    /// Determines the format corresponding to the file extension if any.
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        match ext {
+            "avif" => Some(Self::Avif),
+            "webp" => Some(Self::Webp),
+            "jxl" => Some(Self::JpegXl),
+            _ => None,
+        }
+    }
+
+    /// Returns the MIME type of the format as used in `Accept` HTTP header.
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Avif => "image/avif",
+            Self::Webp => "image/webp",
+            Self::JpegXl => "image/jxl",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = UnsupportedImageFormat;
+
+    /// Coverts a file extension into an image format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ImageFormat::from_ext(s).ok_or(UnsupportedImageFormat(s.to_owned()))
+    }
+}
+
+impl Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.ext())
+    }
+}
+
+/// The error type returned by `ImageFormat::from_str()`
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnsupportedImageFormat(String);
+
+impl Display for UnsupportedImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "Unsupported image format: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedImageFormat {}
+
+/// Compares the media ranges from `Accept` HTTP header with a list of supported image formats
+/// and returns any matches, sorted by the respective quality value. Only formats listed
+/// explicitly are considered, wildcards like `image/*` don’t indicate support for a particular
+/// format. Formats with the same quality value keep the order of the supported list.
+///
+/// Formats are only returned if the client prefers them over the original MIME type: their
+/// quality value has to be higher, or equal if the original type only matches a wildcard.
+pub(crate) fn find_matches(
+    requested: &str,
+    original: &str,
+    supported: &[ImageFormat],
+) -> Vec<ImageFormat> {
+    let original = accept::mime_quality(requested, original);
+    let requested = accept::parse(requested).collect::<Vec<_>>();
+
+    let mut result = supported
+        .iter()
+        .filter_map(|format| {
+            requested
+                .iter()
+                .find(|(mime, _)| mime.eq_ignore_ascii_case(format.mime()))
+                .filter(|(_, quality)| *quality > 0 && (*quality, 3) > original)
+                .map(|(_, quality)| (*format, *quality))
+        })
+        .collect::<Vec<_>>();
+    result.sort_by_key(|(_, quality)| -(*quality as i32));
+    result.into_iter().map(|(format, _)| format).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_matches() {
+        let supported = [ImageFormat::Avif, ImageFormat::Webp];
+
+        assert_eq!(find_matches("", "image/jpeg", &supported), Vec::new());
+        assert_eq!(find_matches("*/*", "image/jpeg", &supported), Vec::new());
+        assert_eq!(
+            find_matches("image/*, image/png, */*;q=0.8", "image/jpeg", &supported),
+            Vec::new()
+        );
+
+        assert_eq!(
+            find_matches(
+                "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8",
+                "image/jpeg",
+                &supported
+            ),
+            vec![ImageFormat::Avif, ImageFormat::Webp]
+        );
+
+        assert_eq!(
+            find_matches("image/webp, image/avif", "image/jpeg", &supported),
+            vec![ImageFormat::Avif, ImageFormat::Webp]
+        );
+
+        assert_eq!(
+            find_matches(
+                "image/avif;q=0.5, IMAGE/WEBP, image/jxl",
+                "image/jpeg",
+                &supported
+            ),
+            vec![ImageFormat::Webp, ImageFormat::Avif]
+        );
+
+        assert_eq!(
+            find_matches("image/avif;q=0, image/webp", "image/jpeg", &supported),
+            vec![ImageFormat::Webp]
+        );
+
+        // Original format preferred or equally preferred
+        assert_eq!(
+            find_matches("image/webp;q=0.1, image/jpeg", "image/jpeg", &supported),
+            Vec::new()
+        );
+        assert_eq!(
+            find_matches("image/webp, image/jpeg", "image/jpeg", &supported),
+            Vec::new()
+        );
+        assert_eq!(
+            find_matches(
+                "image/avif, image/webp;q=0.5, image/jpeg;q=0.8",
+                "image/jpeg",
+                &supported
+            ),
+            vec![ImageFormat::Avif]
+        );
+
+        // Explicitly listed formats win over wildcards with the same quality
+        assert_eq!(
+            find_matches("image/webp;q=0.5, image/*;q=0.5", "image/jpeg", &supported),
+            vec![ImageFormat::Webp]
+        );
+        assert_eq!(
+            find_matches("image/webp;q=0.5, */*", "image/jpeg", &supported),
+            Vec::new()
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selects alternative image formats for a Pingora session based on the `Accept` HTTP header.
+
+use http::{header, HeaderMap};
+use mime_guess::{mime, Mime};
+use pandora_module_utils::pingora::{Error, ResponseHeader};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use crate::image_format::{find_matches, ImageFormat};
+
+/// Encapsulates the image format negotiation state for the current session.
+pub(crate) struct ImageNegotiation<'a> {
+    formats: &'a [ImageFormat],
+    negotiated: bool,
+}
+
+impl<'a> ImageNegotiation<'a> {
+    /// Creates a new negotiation state supporting the given alternative image formats.
+    pub(crate) fn new(formats: &'a [ImageFormat]) -> Self {
+        Self {
+            formats,
+            negotiated: false,
+        }
+    }
+
+    /// Checks whether the given path should be rewritten to an alternative format of the image.
+    /// `mime` is the MIME type of the file requested, only images are considered. The `is_file`
+    /// callback is used to check whether a file in an alternative format exists, both
+    /// `photo.jpg.webp` and `photo.webp` are accepted for `photo.jpg`.
+    pub(crate) async fn rewrite_path<F>(
+        &mut self,
+        headers: &HeaderMap,
+        path: &Path,
+        mime: &Mime,
+        is_file: impl Fn(PathBuf) -> F,
+    ) -> Option<PathBuf>
+    where
+        F: Future<Output = bool>,
+    {
+        if self.formats.is_empty() || mime.type_() != mime::IMAGE {
+            return None;
+        }
+
+        // Response depends on the Accept header even if no alternative exists.
+        self.negotiated = true;
+
+        let filename = path.file_name()?;
+        let requested = headers.get(header::ACCEPT)?;
+        let overlap = find_matches(requested.to_str().ok()?, mime.essence_str(), self.formats);
+
+        for format in overlap {
+            if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case(format.ext()))
+            {
+                // This is already the preferred format
+                return None;
+            }
+
+            let mut candidate_name = filename.to_os_string();
+            candidate_name.push(".");
+            candidate_name.push(format.ext());
+
+            let mut candidate_path = path.to_path_buf();
+            candidate_path.set_file_name(candidate_name);
+            if is_file(candidate_path.clone()).await {
+                return Some(candidate_path);
+            }
+
+            candidate_path = path.with_extension(format.ext());
+            if is_file(candidate_path.clone()).await {
+                return Some(candidate_path);
+            }
+        }
+
+        None
+    }
+
+    /// Adds `Vary: Accept` HTTP header to the response if it depends on the `Accept` header.
+    pub(crate) fn transform_header(
+        &self,
+        mut header: Box<ResponseHeader>,
+    ) -> Result<Box<ResponseHeader>, Box<Error>> {
+        if self.negotiated {
+            header.append_header(header::VARY, "Accept")?;
+        }
+        Ok(header)
+    }
+}
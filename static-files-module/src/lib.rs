@@ -14,18 +14,29 @@
 
 #![doc = include_str!("../README.md")]
 
+mod accept;
+mod autoindex;
+mod cache;
 mod compression;
 mod compression_algorithm;
 mod configuration;
 mod file_writer;
+mod glob;
 mod handler;
+mod image_format;
+mod image_negotiation;
 pub mod metadata;
 mod mime_matcher;
+mod mime_types;
+mod multipart;
 pub mod path;
 pub mod range;
+pub mod storage;
+pub mod tar_archive;
 #[cfg(test)]
 mod tests;
 
 pub use compression_algorithm::{CompressionAlgorithm, UnsupportedCompressionAlgorithm};
 pub use configuration::{StaticFilesConf, StaticFilesOpt};
 pub use handler::StaticFilesHandler;
+pub use image_format::{ImageFormat, UnsupportedImageFormat};
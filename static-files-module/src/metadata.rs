@@ -20,8 +20,13 @@ use mime_guess::Mime;
 use pandora_module_utils::pingora::{ResponseHeader, SessionWrapper};
 use std::io::{Error, ErrorKind};
 use std::path::Path;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use crate::mime_types::MimeTypes;
+use crate::multipart::MultipartRanges;
+use crate::storage::{FileInfo, Storage};
+
 /// Helper wrapping file metadata information
 #[derive(Debug)]
 pub struct Metadata {
@@ -52,24 +57,81 @@ impl Metadata {
             return Err(ErrorKind::InvalidInput.into());
         }
 
-        let mime = mime_guess::from_path(orig_path.unwrap_or(path)).first_or_octet_stream();
-        let size = meta.len();
-        let modified = meta.modified().ok().map(fmt_http_date);
+        let info = FileInfo {
+            is_dir: false,
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        };
+        let mime = MimeTypes::default().guess(orig_path.unwrap_or(path).as_ref());
+        Ok(Self::from_info(&info, mime))
+    }
+
+    /// Collects the metadata for a file within a storage backend. If `orig_path` is present, it
+    /// will be used to determine the MIME type instead of `path`.
+    ///
+    /// This method will return any errors produced by [`Storage::info()`]. It will also result in
+    /// a [`ErrorKind::InvalidInput`] error if the path given points to a directory.
+    pub fn from_storage(
+        storage: &dyn Storage,
+        path: &Path,
+        orig_path: Option<&Path>,
+    ) -> Result<Self, Error> {
+        Self::with_mime_types(storage, path, orig_path, &MimeTypes::default())
+    }
+
+    /// Collects the metadata for a file like [`Metadata::from_storage`] does but uses custom
+    /// MIME type mappings.
+    pub(crate) fn with_mime_types(
+        storage: &dyn Storage,
+        path: &Path,
+        orig_path: Option<&Path>,
+        mime_types: &MimeTypes,
+    ) -> Result<Self, Error> {
+        let info = storage.info(path)?;
+
+        if info.is_dir {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        let mime = mime_types.guess(orig_path.unwrap_or(path));
+        Ok(Self::from_info(&info, mime))
+    }
+
+    /// Collects the metadata for a file like [`Metadata::with_mime_types`] does but performs the
+    /// storage access on the blocking thread pool.
+    pub(crate) async fn with_mime_types_async(
+        storage: &Arc<dyn Storage>,
+        path: &Path,
+        orig_path: Option<&Path>,
+        mime_types: &Arc<MimeTypes>,
+    ) -> Result<Self, Error> {
+        let storage = storage.clone();
+        let path = path.to_path_buf();
+        let orig_path = orig_path.map(Path::to_path_buf);
+        let mime_types = mime_types.clone();
+        tokio::task::spawn_blocking(move || {
+            Self::with_mime_types(storage.as_ref(), &path, orig_path.as_deref(), &mime_types)
+        })
+        .await
+        .unwrap_or_else(|err| Err(Error::other(err)))
+    }
+
+    fn from_info(info: &FileInfo, mime: Mime) -> Self {
+        let modified = info.modified.map(fmt_http_date);
         let etag = format!(
             "\"{:x}-{:x}\"",
-            meta.modified()
-                .ok()
+            info.modified
                 .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
                 .map_or(0, |duration| duration.as_secs()),
-            meta.len()
+            info.size
         );
 
-        Ok(Self {
+        Self {
             mime,
-            size,
+            size: info.size,
             modified,
             etag,
-        })
+        }
     }
 
     /// Checks `If-Match` and `If-Unmodified-Since` headers of the request to determine whether
@@ -122,20 +184,22 @@ impl Metadata {
         }
     }
 
+    /// Produces the value of the `Content-Type` header for the file.
+    pub(crate) fn content_type(&self, charset: Option<&str>) -> String {
+        if let Some(charset) = charset {
+            format!("{};charset={charset}", self.mime.as_ref())
+        } else {
+            self.mime.as_ref().to_owned()
+        }
+    }
+
     #[inline(always)]
     fn add_content_type(
         &self,
         header: &mut ResponseHeader,
         charset: Option<&str>,
     ) -> Result<(), Box<pandora_module_utils::pingora::Error>> {
-        if let Some(charset) = charset {
-            header.append_header(
-                header::CONTENT_TYPE,
-                format!("{};charset={charset}", self.mime.as_ref()),
-            )?;
-        } else {
-            header.append_header(header::CONTENT_TYPE, self.mime.as_ref())?;
-        }
+        header.append_header(header::CONTENT_TYPE, self.content_type(charset))?;
         Ok(())
     }
 
@@ -182,6 +246,22 @@ impl Metadata {
         Ok(Box::new(header))
     }
 
+    /// Produces a `206 Partial Content` response for a `multipart/byteranges` response body and
+    /// adds headers according to file metadata.
+    pub(crate) fn to_multipart_header(
+        &self,
+        multipart: &MultipartRanges,
+    ) -> Result<Box<ResponseHeader>, Box<pandora_module_utils::pingora::Error>> {
+        let mut header = ResponseHeader::build(StatusCode::PARTIAL_CONTENT, Some(8))?;
+        header.append_header(
+            header::CONTENT_LENGTH,
+            multipart.content_length().to_string(),
+        )?;
+        header.append_header(header::CONTENT_TYPE, multipart.content_type())?;
+        self.add_etag(&mut header)?;
+        Ok(Box::new(header))
+    }
+
     /// Produces a `416 Range Not Satisfiable` response and adds headers according to file
     /// metadata.
     pub(crate) fn to_not_satisfiable_header(
@@ -0,0 +1,150 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Determining MIME types from file names
+
+use mime_guess::Mime;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::glob::glob_matches;
+
+/// Determines the MIME type of a file, custom mappings taking precedence over the built-in list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MimeTypes {
+    /// Lower-case file extensions
+    extensions: HashMap<String, Mime>,
+    /// File name patterns, more specific (longer) patterns first
+    patterns: Vec<(String, Mime)>,
+    default: Mime,
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        Self::new(Vec::new(), None)
+    }
+}
+
+impl MimeTypes {
+    /// Creates a new instance from custom mappings. Keys without wildcards or dots are file
+    /// extensions (a leading dot is allowed), these also match file names without an extension
+    /// like `LICENSE`. All other keys are patterns for the file name.
+    /// `default` is used if the MIME type cannot be determined otherwise.
+    pub(crate) fn new(
+        mappings: impl IntoIterator<Item = (String, Mime)>,
+        default: Option<Mime>,
+    ) -> Self {
+        let mut extensions = HashMap::new();
+        let mut patterns = Vec::new();
+        for (key, mime) in mappings {
+            let extension = key.strip_prefix('.').unwrap_or(&key);
+            if extension.contains(['*', '?', '.']) {
+                patterns.push((key, mime));
+            } else {
+                extensions.insert(extension.to_ascii_lowercase(), mime);
+            }
+        }
+        patterns.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        Self {
+            extensions,
+            patterns,
+            default: default.unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM),
+        }
+    }
+
+    /// Determines the MIME type for a path.
+    pub(crate) fn guess(&self, path: &Path) -> Mime {
+        let name = path.file_name().and_then(|name| name.to_str());
+        if let Some((_, mime)) = name.and_then(|name| {
+            self.patterns
+                .iter()
+                .find(|(pattern, _)| glob_matches(pattern, name))
+        }) {
+            return mime.clone();
+        }
+
+        // Without an extension, the complete file name is looked up
+        let extension = match path.extension() {
+            Some(extension) => extension.to_str(),
+            None => name,
+        };
+        if let Some(mime) =
+            extension.and_then(|extension| self.extensions.get(&extension.to_ascii_lowercase()))
+        {
+            return mime.clone();
+        }
+
+        mime_guess::from_path(path)
+            .first()
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mime(mime: &str) -> Mime {
+        mime.parse().unwrap()
+    }
+
+    #[test]
+    fn guessing() {
+        let types = MimeTypes::default();
+        assert_eq!(types.guess(Path::new("/a/file.txt")), mime("text/plain"));
+        assert_eq!(
+            types.guess(Path::new("/a/LICENSE")),
+            mime("application/octet-stream")
+        );
+
+        let types = MimeTypes::new(
+            [
+                ("wasm".to_owned(), mime("application/wasm")),
+                (".MJS".to_owned(), mime("text/javascript")),
+                ("txt".to_owned(), mime("text/markdown")),
+                ("README".to_owned(), mime("text/markdown")),
+                ("*.min.*".to_owned(), mime("application/x-minified")),
+                ("app.min.*".to_owned(), mime("application/x-app")),
+                (
+                    "manifest.webmanifest".to_owned(),
+                    mime("application/manifest+json"),
+                ),
+            ],
+            Some(mime("text/plain")),
+        );
+        assert_eq!(
+            types.guess(Path::new("/a/b.wasm")),
+            mime("application/wasm")
+        );
+        assert_eq!(types.guess(Path::new("/a/b.mjs")), mime("text/javascript"));
+        assert_eq!(types.guess(Path::new("/a/b.TXT")), mime("text/markdown"));
+        assert_eq!(
+            types.guess(Path::new("/a/lib.min.js")),
+            mime("application/x-minified")
+        );
+        assert_eq!(
+            types.guess(Path::new("/a/app.min.js")),
+            mime("application/x-app")
+        );
+        assert_eq!(
+            types.guess(Path::new("/manifest.webmanifest")),
+            mime("application/manifest+json")
+        );
+        assert_eq!(types.guess(Path::new("/a/b.css")), mime("text/css"));
+        assert_eq!(types.guess(Path::new("/a/LICENSE")), mime("text/plain"));
+        assert_eq!(types.guess(Path::new("/a/README")), mime("text/markdown"));
+        assert_eq!(types.guess(Path::new("/a/readme")), mime("text/markdown"));
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layout of `multipart/byteranges` responses
+
+use pandora_module_utils::pingora::{Error, ErrorType};
+use std::fmt::Write;
+
+/// Number of random bytes in the multipart boundary
+const BOUNDARY_BYTES: usize = 16;
+
+/// A `multipart/byteranges` response body consisting of multiple byte ranges of a file
+#[derive(Debug)]
+pub(crate) struct MultipartRanges {
+    boundary: String,
+    content_type: String,
+    size: u64,
+    ranges: Vec<(u64, u64)>,
+}
+
+impl MultipartRanges {
+    /// Creates a new multipart response body for the given ranges of a file. `content_type` is
+    /// the full `Content-Type` header value of the file, `size` the file size.
+    pub(crate) fn new(
+        ranges: Vec<(u64, u64)>,
+        content_type: String,
+        size: u64,
+    ) -> Result<Self, Box<Error>> {
+        let mut bytes = [0; BOUNDARY_BYTES];
+        getrandom::getrandom(&mut bytes).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                "failed generating random multipart boundary",
+                err,
+            )
+        })?;
+
+        let mut boundary = String::with_capacity(BOUNDARY_BYTES * 2);
+        for byte in bytes {
+            let _ = write!(boundary, "{byte:02x}");
+        }
+
+        Ok(Self::with_boundary(ranges, content_type, size, boundary))
+    }
+
+    fn with_boundary(
+        ranges: Vec<(u64, u64)>,
+        content_type: String,
+        size: u64,
+        boundary: String,
+    ) -> Self {
+        Self {
+            boundary,
+            content_type,
+            size,
+            ranges,
+        }
+    }
+
+    /// Value of the `Content-Type` header for the response
+    pub(crate) fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// The byte ranges to be sent
+    pub(crate) fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+
+    /// Produces the delimiter and headers preceding the data of a range
+    pub(crate) fn part_header(&self, start: u64, end: u64) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {start}-{end}/{}\r\n\r\n",
+            self.boundary, self.content_type, self.size
+        )
+    }
+
+    /// Produces the delimiter closing the response body
+    pub(crate) fn trailer(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Calculates the total length of the response body
+    pub(crate) fn content_length(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|(start, end)| self.part_header(*start, *end).len() as u64 + end - start + 1)
+            .sum::<u64>()
+            + self.trailer().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let multipart = MultipartRanges::with_boundary(
+            vec![(0, 1), (5, 9)],
+            "text/plain".to_owned(),
+            10,
+            "xyz".to_owned(),
+        );
+        assert_eq!(
+            multipart.content_type(),
+            "multipart/byteranges; boundary=xyz"
+        );
+
+        let body = format!(
+            "{}01{}56789{}",
+            multipart.part_header(0, 1),
+            multipart.part_header(5, 9),
+            multipart.trailer()
+        );
+        assert_eq!(
+            body,
+            "\r\n--xyz\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--xyz\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-9/10\r\n\r\n56789\
+             \r\n--xyz--\r\n"
+        );
+        assert_eq!(multipart.content_length(), body.len() as u64);
+
+        let multipart = MultipartRanges::new(vec![], "text/plain".to_owned(), 10).unwrap();
+        assert_eq!(multipart.boundary.len(), BOUNDARY_BYTES * 2);
+    }
+}
@@ -69,7 +69,12 @@ pub fn resolve_uri(uri_path: &str, root: &Path) -> Result<PathBuf, Error> {
 /// This will return `None` for paths outside the root directory.
 pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
     let rel_path = path.strip_prefix(root).ok()?;
+    Some(relative_path_to_uri(rel_path, path.is_dir()))
+}
 
+/// Calculates the canonical URI path for a path relative to the root directory. Directory URIs
+/// end with a slash.
+pub(crate) fn relative_path_to_uri(rel_path: &Path, is_dir: bool) -> String {
     let mut uri = String::from('/');
     for component in rel_path.components() {
         uri.push_str(
@@ -77,8 +82,8 @@ pub fn path_to_uri(path: &Path, root: &Path) -> Option<String> {
         );
         uri.push('/');
     }
-    if !path.is_dir() && uri.len() > 1 {
+    if !is_dir && uri.len() > 1 {
         uri.pop();
     }
-    Some(uri)
+    uri
 }
@@ -16,11 +16,12 @@
 
 use http::header;
 use pandora_module_utils::pingora::SessionWrapper;
+use std::cmp::max;
 use std::str::FromStr;
 
 use crate::metadata::Metadata;
 
-/// Represents the result of parsing the `Range` HTTP header.
+/// Represents the result of parsing the `Range` HTTP header with a single range.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Range {
     /// A valid range with the given start and end bounds
@@ -30,24 +31,29 @@ pub enum Range {
 }
 
 impl Range {
-    /// Parses the value of a `Range` HTTP header. The file size is required to resolve ranges
-    /// specified relative to the end of file and to recognize out of bounds ranges. Ranges that
-    /// cannot be parsed (unexpected format) will result in `None`.
-    pub fn parse(range: &str, file_size: u64) -> Option<Self> {
-        let (units, range) = range.split_once('=')?;
-        if units != "bytes" {
-            return None;
-        }
+    /// Parses a single range specifier like `0-499`. Ranges that cannot be parsed will result in
+    /// `None`.
+    fn parse_spec(spec: &str, file_size: u64) -> Option<Self> {
+        let (start, end) = spec.split_once('-')?;
+        let Some(last) = file_size.checked_sub(1) else {
+            // Check that the range can be parsed but nothing is satisfiable for an empty file
+            if !start.trim().is_empty() {
+                u64::from_str(start.trim()).ok()?;
+            }
+            if !end.trim().is_empty() {
+                u64::from_str(end.trim()).ok()?;
+            }
+            return Some(Self::OutOfBounds);
+        };
 
-        let (start, end) = range.trim().split_once('-')?;
-        let (start, end) = if start.is_empty() {
+        let (start, end) = if start.trim().is_empty() {
             let len = u64::from_str(end.trim()).ok()?;
             if len > file_size {
                 return Some(Self::OutOfBounds);
             }
-            (file_size - len, file_size - 1)
-        } else if end.is_empty() {
-            (u64::from_str(start.trim()).ok()?, file_size - 1)
+            (file_size - len, last)
+        } else if end.trim().is_empty() {
+            (u64::from_str(start.trim()).ok()?, last)
         } else {
             (
                 u64::from_str(start.trim()).ok()?,
@@ -61,6 +67,83 @@ impl Range {
             Some(Self::Valid(start, end))
         }
     }
+
+    /// Parses the value of a `Range` HTTP header. The file size is required to resolve ranges
+    /// specified relative to the end of file and to recognize out of bounds ranges. Ranges that
+    /// cannot be parsed (unexpected format) will result in `None`.
+    ///
+    /// Note: Multiple ranges are only supported if they can be merged into a single range,
+    /// otherwise the result is `None`. Use [`Ranges::parse`] to support multiple ranges.
+    pub fn parse(range: &str, file_size: u64) -> Option<Self> {
+        match Ranges::parse(range, file_size)? {
+            Ranges::Single(start, end) => Some(Self::Valid(start, end)),
+            Ranges::Multiple(_) => None,
+            Ranges::OutOfBounds => Some(Self::OutOfBounds),
+        }
+    }
+}
+
+/// Represents the result of parsing the `Range` HTTP header, potentially listing multiple ranges.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Ranges {
+    /// A single valid range with the given start and end bounds
+    Single(u64, u64),
+    /// Multiple valid ranges, sorted and with overlapping or adjacent ranges merged
+    Multiple(Vec<(u64, u64)>),
+    /// None of the ranges are within the file’s boundaries
+    OutOfBounds,
+}
+
+impl Ranges {
+    /// Parses the value of a `Range` HTTP header. The file size is required to resolve ranges
+    /// specified relative to the end of file and to recognize out of bounds ranges. Ranges that
+    /// cannot be parsed (unexpected format) will result in `None`.
+    ///
+    /// If multiple ranges are given, ranges outside of the file’s boundaries are ignored. Only if
+    /// none of the ranges is valid the result will be `OutOfBounds`. Overlapping and adjacent
+    /// ranges are merged, so that the result might be a single range.
+    pub fn parse(range: &str, file_size: u64) -> Option<Self> {
+        let (units, range) = range.split_once('=')?;
+        if units != "bytes" {
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+        let mut seen_spec = false;
+        for spec in range.split(',').map(str::trim) {
+            // Empty list elements are allowed by the HTTP specification
+            if spec.is_empty() {
+                continue;
+            }
+            seen_spec = true;
+
+            if let Range::Valid(start, end) = Range::parse_spec(spec, file_size)? {
+                ranges.push((start, end));
+            }
+        }
+
+        if !seen_spec {
+            return None;
+        }
+
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                    *last_end = max(*last_end, end);
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+
+        match merged.as_slice() {
+            [] => Some(Self::OutOfBounds),
+            [(start, end)] => Some(Self::Single(*start, *end)),
+            _ => Some(Self::Multiple(merged)),
+        }
+    }
 }
 
 /// This processes the `Range` and `If-Range` request headers to produce the requested byte range
@@ -69,8 +152,26 @@ impl Range {
 /// `Range` header missing, using some unsupported format or overruled by `If-Range` header will
 /// all result in `None` being returned.
 ///
-/// Note: Multiple ranges are not supported.
+/// Note: Multiple ranges are not supported, use [`extract_ranges`] for that.
 pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<Range> {
+    match extract_ranges(session, meta, 1)? {
+        Ranges::Single(start, end) => Some(Range::Valid(start, end)),
+        Ranges::Multiple(_) => None,
+        Ranges::OutOfBounds => Some(Range::OutOfBounds),
+    }
+}
+
+/// This processes the `Range` and `If-Range` request headers to produce the requested byte ranges
+/// if any.
+///
+/// `Range` header missing, using some unsupported format or overruled by `If-Range` header will
+/// all result in `None` being returned. The same is true if the `Range` header lists more than
+/// `max_ranges` ranges.
+pub fn extract_ranges(
+    session: &impl SessionWrapper,
+    meta: &Metadata,
+    max_ranges: usize,
+) -> Option<Ranges> {
     let headers = &session.req_header().headers;
     if let Some(value) = headers
         .get(header::IF_RANGE)
@@ -89,7 +190,15 @@ pub fn extract_range(session: &impl SessionWrapper, meta: &Metadata) -> Option<R
     let value = headers.get(header::RANGE)?;
     let value = value.to_str().ok()?;
 
-    Range::parse(value, meta.size)
+    let count = value
+        .split(',')
+        .filter(|spec| !spec.trim().is_empty())
+        .count();
+    if count > max_ranges {
+        return None;
+    }
+
+    Ranges::parse(value, meta.size)
 }
 
 #[cfg(test)]
@@ -254,4 +363,138 @@ mod tests {
         let mut result = process_session(session).await;
         assert_eq!(extract_range(&result.session(), &metadata()), None);
     }
+
+    #[test(tokio::test)]
+    async fn multiple_ranges_extracted() {
+        let session = make_session("bytes=1-2,5-6").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Multiple(vec![(1, 2), (5, 6)]))
+        );
+
+        // Ranges are sorted, out of bounds ranges ignored
+        let session = make_session("bytes=-10, 2000-, 5-6").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Multiple(vec![(5, 6), (990, 999)]))
+        );
+
+        // Overlapping and adjacent ranges are merged
+        let session = make_session("bytes=1-2,3-4,0-1").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Single(0, 4))
+        );
+
+        let session = make_session("bytes=1-10,5-6,20-30,25-").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Multiple(vec![(1, 10), (20, 999)]))
+        );
+
+        let session = make_session("bytes=1000-,-2000").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::OutOfBounds)
+        );
+
+        // Any invalid range invalidates the header
+        let session = make_session("bytes=1-2,x-4").await;
+        let mut result = process_session(session).await;
+        assert_eq!(extract_ranges(&result.session(), &metadata(), 16), None);
+
+        // Too many ranges should be treated like no Range header
+        let session = make_session("bytes=1-2,5-6,9-10").await;
+        let mut result = process_session(session).await;
+        assert_eq!(extract_ranges(&result.session(), &metadata(), 2), None);
+    }
+
+    #[test(tokio::test)]
+    async fn single_range_only() {
+        let session = make_session("bytes=0-499").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_range(&result.session(), &metadata()),
+            Some(Range::Valid(0, 499))
+        );
+
+        let session = make_session("bytes=1000-").await;
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_range(&result.session(), &metadata()),
+            Some(Range::OutOfBounds)
+        );
+
+        let session = make_session("bytes=1-2,5-6").await;
+        let mut result = process_session(session).await;
+        assert_eq!(extract_range(&result.session(), &metadata()), None);
+
+        assert_eq!(
+            Range::parse("bytes=1-2,3-4", 1000),
+            Some(Range::Valid(1, 4))
+        );
+        assert_eq!(Range::parse("bytes=1-2,5-6", 1000), None);
+        assert_eq!(Range::parse("bytes=-5", 0), Some(Range::OutOfBounds));
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(Ranges::parse("bytes=0-", 0), Some(Ranges::OutOfBounds));
+        assert_eq!(Ranges::parse("bytes=-5", 0), Some(Ranges::OutOfBounds));
+        assert_eq!(Ranges::parse("bytes=x-", 0), None);
+    }
+
+    #[test(tokio::test)]
+    async fn if_range_multiple() {
+        let mut session = make_session("bytes=0-9,20-29").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Range", "\"abc\"")
+            .unwrap();
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Multiple(vec![(0, 9), (20, 29)]))
+        );
+
+        let mut session = make_session("bytes=0-9,20-29").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Range", "\"xyz\"")
+            .unwrap();
+        let mut result = process_session(session).await;
+        assert_eq!(extract_ranges(&result.session(), &metadata(), 16), None);
+
+        let mut session = make_session("bytes=0-9,20-29").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Range", "Fri, 15 May 2015 15:34:21 GMT")
+            .unwrap();
+        let mut result = process_session(session).await;
+        assert_eq!(
+            extract_ranges(&result.session(), &metadata(), 16),
+            Some(Ranges::Multiple(vec![(0, 9), (20, 29)]))
+        );
+
+        let mut session = make_session("bytes=0-9,20-29").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Range", "Thu, 01 Jan 1970 00:00:00 GMT")
+            .unwrap();
+        let mut result = process_session(session).await;
+        assert_eq!(extract_ranges(&result.session(), &metadata(), 16), None);
+
+        let mut session = make_session("bytes=0-9,20-29").await;
+        session
+            .req_header_mut()
+            .insert_header("If-Range", "bogus")
+            .unwrap();
+        let mut result = process_session(session).await;
+        assert_eq!(extract_ranges(&result.session(), &metadata(), 16), None);
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backends providing the files to be served
+
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::path::{path_to_uri, relative_path_to_uri, resolve_uri};
+
+/// Information on a file or directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// `true` for directories, `false` for regular files
+    pub is_dir: bool,
+    /// File size in bytes
+    pub size: u64,
+    /// Last modified time if known
+    pub modified: Option<SystemTime>,
+}
+
+/// File contents that can be read starting at any position
+pub trait FileData: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> FileData for T {}
+
+/// A storage backend providing the files to be served.
+///
+/// Paths are absolute paths within the storage, starting with the root path. Storage methods
+/// are allowed to block, the Static Files module calls methods accessing file data and metadata on
+/// the blocking thread pool.
+pub trait Storage: Debug + Send + Sync {
+    /// Path of the root directory
+    fn root(&self) -> &Path;
+
+    /// Resolves the path from a URI. The errors should match the ones produced by
+    /// [`resolve_uri`].
+    fn resolve_uri(&self, uri_path: &str) -> Result<PathBuf, Error>;
+
+    /// Retrieves information on a file or directory. Other file types should result in
+    /// [`ErrorKind::InvalidInput`].
+    fn info(&self, path: &Path) -> Result<FileInfo, Error>;
+
+    /// Lists the entries of a directory. Entries that cannot be accessed should be skipped.
+    fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, FileInfo)>, Error>;
+
+    /// Opens a file for reading.
+    fn open(&self, path: &Path) -> Result<Box<dyn FileData>, Error>;
+
+    /// Checks whether the path points to a directory.
+    fn is_dir(&self, path: &Path) -> bool {
+        self.info(path).is_ok_and(|info| info.is_dir)
+    }
+
+    /// Checks whether the path points to a regular file.
+    fn is_file(&self, path: &Path) -> bool {
+        self.info(path).is_ok_and(|info| !info.is_dir)
+    }
+
+    /// Calculates the canonical URI path for a path, see [`path_to_uri`].
+    fn path_to_uri(&self, path: &Path) -> Option<String> {
+        let rel_path = path.strip_prefix(self.root()).ok()?;
+        Some(relative_path_to_uri(rel_path, self.is_dir(path)))
+    }
+}
+
+/// Storage serving files from a directory on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSystem {
+    root: PathBuf,
+}
+
+impl FileSystem {
+    /// Creates a new storage for the given root directory.
+    pub fn new(root: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            root: root.canonicalize()?,
+        })
+    }
+}
+
+impl Storage for FileSystem {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn resolve_uri(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        resolve_uri(uri_path, &self.root)
+    }
+
+    fn info(&self, path: &Path) -> Result<FileInfo, Error> {
+        // Follows symbolic links
+        let meta = path.metadata()?;
+        if !meta.is_file() && !meta.is_dir() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        Ok(FileInfo {
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, FileInfo)>, Error> {
+        let mut entries = Vec::new();
+        for entry in path.read_dir()? {
+            let entry = entry?;
+            if let Ok(info) = self.info(&entry.path()) {
+                entries.push((entry.file_name(), info));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn FileData>, Error> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn path_to_uri(&self, path: &Path) -> Option<String> {
+        path_to_uri(path, &self.root)
+    }
+}
+
+/// Shared reference to a storage backend. Two references are considered equal if they point to
+/// the same storage instance.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedStorage(pub(crate) Arc<dyn Storage>);
+
+impl PartialEq for SharedStorage {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+}
+
+impl Eq for SharedStorage {}
+
+/// Archives embedded into the binary
+static EMBEDDED_ARCHIVES: Mutex<Vec<(String, &'static [u8])>> = Mutex::new(Vec::new());
+
+/// Registers a tar archive embedded into the binary under the given name. The `embedded` setting
+/// can then refer to this name to serve files from this archive. This needs to be called before
+/// the configuration is loaded, e.g. with the archive produced by a build script:
+///
+/// ```rust,ignore
+/// static_files_module::storage::register_embedded_archive(
+///     "site",
+///     include_bytes!(concat!(env!("OUT_DIR"), "/site.tar")),
+/// );
+/// ```
+pub fn register_embedded_archive(name: impl Into<String>, data: &'static [u8]) {
+    let name = name.into();
+    let mut archives = EMBEDDED_ARCHIVES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    archives.retain(|(existing, _)| *existing != name);
+    archives.push((name, data));
+}
+
+/// Looks up an embedded archive by its name.
+pub(crate) fn embedded_archive(name: &str) -> Option<&'static [u8]> {
+    EMBEDDED_ARCHIVES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .find(|(existing, _)| existing == name)
+        .map(|(_, data)| *data)
+}
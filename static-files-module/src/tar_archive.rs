@@ -0,0 +1,634 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage backend serving files from a tar archive
+
+use percent_encoding::percent_decode_str;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::storage::{FileData, FileInfo, Storage};
+
+const BLOCK_SIZE: u64 = 512;
+
+#[derive(Debug, Clone)]
+enum Source {
+    File(Arc<File>),
+    Static(&'static [u8]),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Position of the file data within the archive, `None` for directories
+    offset: Option<u64>,
+    info: FileInfo,
+}
+
+/// Overrides for the next archive entry from GNU long name or PAX extended headers
+#[derive(Debug, Default)]
+struct Overrides {
+    path: Option<String>,
+    size: Option<u64>,
+    mtime: Option<u64>,
+}
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Extracts a NUL-terminated string from a header field.
+fn header_string(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Parses a numeric header field, either octal or GNU base-256 encoding.
+fn header_number(field: &[u8]) -> Result<u64, Error> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        let mut result = u64::from(field[0] & 0x7F);
+        for byte in &field[1..] {
+            result = result
+                .checked_mul(256)
+                .and_then(|result| result.checked_add(u64::from(*byte)))
+                .ok_or_else(|| invalid_data("numeric field in tar header too large"))?;
+        }
+        Ok(result)
+    } else {
+        let value = header_string(field);
+        let value = value.trim_matches(|c| c == ' ' || c == '\0');
+        if value.is_empty() {
+            Ok(0)
+        } else {
+            u64::from_str_radix(value, 8)
+                .map_err(|_| invalid_data("invalid numeric field in tar header"))
+        }
+    }
+}
+
+fn verify_checksum(header: &[u8; BLOCK_SIZE as usize]) -> Result<(), Error> {
+    let expected = header_number(&header[148..156])?;
+    let mut unsigned = 0u64;
+    let mut signed = 0i64;
+    for (i, byte) in header.iter().enumerate() {
+        let byte = if (148..156).contains(&i) { b' ' } else { *byte };
+        unsigned += u64::from(byte);
+        signed += i64::from(byte as i8);
+    }
+
+    if unsigned == expected || u64::try_from(signed).is_ok_and(|signed| signed == expected) {
+        Ok(())
+    } else {
+        Err(invalid_data("invalid tar header checksum"))
+    }
+}
+
+/// Parses the records of a PAX extended header like `30 mtime=1715509624.123456789\n`.
+fn parse_pax(data: &[u8], overrides: &mut Overrides) {
+    let mut data = data;
+    while let Some(space) = data.iter().position(|b| *b == b' ') {
+        let Some(len) = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| usize::from_str(len).ok())
+            .filter(|len| *len > space && *len <= data.len())
+        else {
+            break;
+        };
+
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(eq) = record.iter().position(|b| *b == b'=') {
+            let value = String::from_utf8_lossy(&record[eq + 1..]);
+            match &record[..eq] {
+                b"path" => overrides.path = Some(value.into_owned()),
+                b"size" => overrides.size = u64::from_str(&value).ok(),
+                b"mtime" => {
+                    overrides.mtime = value
+                        .split('.')
+                        .next()
+                        .and_then(|value| u64::from_str(value).ok())
+                }
+                _ => {}
+            }
+        }
+        data = &data[len..];
+    }
+}
+
+/// Converts a path within the archive into an absolute path, `None` for the root directory and
+/// paths that should be ignored.
+fn normalize(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from("/");
+    for component in name.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => path.push(component),
+        }
+    }
+    (path.parent().is_some()).then_some(path)
+}
+
+fn read_data(reader: &mut impl Read, size: u64) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 == size {
+        Ok(data)
+    } else {
+        Err(ErrorKind::UnexpectedEof.into())
+    }
+}
+
+fn add_entry(entries: &mut BTreeMap<PathBuf, Entry>, path: PathBuf, entry: Entry) {
+    let modified = entry.info.modified;
+    for ancestor in path.ancestors().skip(1) {
+        if entries.contains_key(ancestor) {
+            break;
+        }
+        entries.insert(
+            ancestor.to_path_buf(),
+            Entry {
+                offset: None,
+                info: FileInfo {
+                    is_dir: true,
+                    size: 0,
+                    modified,
+                },
+            },
+        );
+    }
+    entries.insert(path, entry);
+}
+
+/// Calculates the position of the next header following an entry with the given size.
+fn next_offset(data_offset: u64, size: u64) -> Result<u64, Error> {
+    size.div_ceil(BLOCK_SIZE)
+        .checked_mul(BLOCK_SIZE)
+        .and_then(|size| size.checked_add(data_offset))
+        .ok_or_else(|| invalid_data("tar entry size too large"))
+}
+
+/// Reads the list of entries from the archive.
+fn parse(reader: &mut (impl Read + Seek)) -> Result<BTreeMap<PathBuf, Entry>, Error> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut entries = BTreeMap::new();
+    entries.insert(
+        PathBuf::from("/"),
+        Entry {
+            offset: None,
+            info: FileInfo {
+                is_dir: true,
+                size: 0,
+                modified: None,
+            },
+        },
+    );
+
+    let mut header = [0; BLOCK_SIZE as usize];
+    let mut offset = 0;
+    let mut overrides = Overrides::default();
+    loop {
+        // Archive might end without zero blocks, or within the padding of the last entry
+        if offset >= len {
+            break;
+        }
+        if len - offset < BLOCK_SIZE {
+            return Err(invalid_data("truncated tar header"));
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut header)?;
+
+        // Archive ends with zero blocks
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        verify_checksum(&header)?;
+
+        let type_flag = header[156];
+        let mut size = header_number(&header[124..136])?;
+        let data_offset = offset + BLOCK_SIZE;
+        if data_offset.checked_add(size).map_or(true, |end| end > len) {
+            return Err(invalid_data("tar entry exceeds archive size"));
+        }
+        match type_flag {
+            // GNU long name
+            b'L' => {
+                let data = read_data(reader, size)?;
+                overrides.path = Some(header_string(&data));
+            }
+            // PAX extended header
+            b'x' => parse_pax(&read_data(reader, size)?, &mut overrides),
+            _ => {
+                let overrides = std::mem::take(&mut overrides);
+                let name = overrides.path.unwrap_or_else(|| {
+                    let name = header_string(&header[0..100]);
+                    let prefix = header_string(&header[345..500]);
+                    // GNU headers (magic `ustar  \0`) use this field for other data
+                    if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                        format!("{prefix}/{name}")
+                    } else {
+                        name
+                    }
+                });
+                if let Some(override_size) = overrides.size {
+                    if data_offset
+                        .checked_add(override_size)
+                        .map_or(true, |end| end > len)
+                    {
+                        return Err(invalid_data("tar entry exceeds archive size"));
+                    }
+                    size = override_size;
+                }
+                let modified = overrides
+                    .mtime
+                    .or_else(|| header_number(&header[136..148]).ok())
+                    .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+
+                let is_dir = match type_flag {
+                    b'0' | b'\0' | b'7' => false,
+                    b'5' => true,
+                    // Links, devices and other special entries aren’t supported
+                    _ => {
+                        offset = next_offset(data_offset, size)?;
+                        continue;
+                    }
+                };
+
+                if let Some(path) = normalize(&name) {
+                    let entry = Entry {
+                        offset: (!is_dir).then_some(data_offset),
+                        info: FileInfo {
+                            is_dir,
+                            size: if is_dir { 0 } else { size },
+                            modified,
+                        },
+                    };
+                    add_entry(&mut entries, path, entry);
+                }
+            }
+        }
+
+        offset = next_offset(data_offset, size)?;
+    }
+
+    Ok(entries)
+}
+
+/// Reads from the file at the given position without changing the file cursor.
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Reads from the file at the given position, the file cursor is irrelevant for other readers.
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Reader for a file within an archive on disk, sharing the archive file with other readers
+struct EntryReader {
+    file: Arc<File>,
+    offset: u64,
+    size: u64,
+    position: u64,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let len = min(buf.len() as u64, remaining) as usize;
+        let len = read_at(&self.file, &mut buf[..len], self.offset + self.position)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for EntryReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or(ErrorKind::InvalidInput)?;
+
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Storage serving files from an uncompressed tar archive, either a file on disk or an archive
+/// embedded into the binary.
+///
+/// Only regular files and directories are supported, links and other special entries in the
+/// archive are ignored.
+#[derive(Debug)]
+pub struct TarArchive {
+    source: Source,
+    root: PathBuf,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl TarArchive {
+    /// Reads the list of files from a tar archive on disk. The archive is kept open, file data is
+    /// read from it on demand.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let entries = parse(&mut reader)?;
+        Ok(Self {
+            entries,
+            source: Source::File(Arc::new(reader.into_inner())),
+            root: PathBuf::from("/"),
+        })
+    }
+
+    /// Reads the list of files from a tar archive in memory, e.g. embedded into the binary via
+    /// `include_bytes!`.
+    pub fn from_static(data: &'static [u8]) -> Result<Self, Error> {
+        Ok(Self {
+            entries: parse(&mut Cursor::new(data))?,
+            source: Source::Static(data),
+            root: PathBuf::from("/"),
+        })
+    }
+}
+
+impl Storage for TarArchive {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn resolve_uri(&self, uri_path: &str) -> Result<PathBuf, Error> {
+        let uri_path = uri_path.strip_prefix('/').ok_or(ErrorKind::InvalidInput)?;
+
+        let mut path = self.root.clone();
+        for component in uri_path.split('/') {
+            // Archive paths are always valid UTF-8
+            let decoded = percent_decode_str(component)
+                .decode_utf8()
+                .map_err(|_| ErrorKind::NotFound)?;
+            match decoded.as_ref() {
+                "" | "." => {}
+                ".." => {
+                    if !path.pop() {
+                        return Err(ErrorKind::InvalidData.into());
+                    }
+                }
+                component => path.push(component),
+            }
+        }
+
+        if self.entries.contains_key(&path) {
+            Ok(path)
+        } else {
+            Err(ErrorKind::NotFound.into())
+        }
+    }
+
+    fn info(&self, path: &Path) -> Result<FileInfo, Error> {
+        self.entries
+            .get(path)
+            .map(|entry| entry.info.clone())
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, FileInfo)>, Error> {
+        if !self.is_dir(path) {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+
+        Ok(self
+            .entries
+            .iter()
+            .filter(|(entry_path, _)| entry_path.parent() == Some(path))
+            .filter_map(|(entry_path, entry)| {
+                Some((entry_path.file_name()?.to_owned(), entry.info.clone()))
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn FileData>, Error> {
+        let entry = self.entries.get(path).ok_or(ErrorKind::NotFound)?;
+        let offset = entry.offset.ok_or(ErrorKind::InvalidInput)?;
+        let size = entry.info.size;
+        match &self.source {
+            Source::File(archive) => Ok(Box::new(EntryReader {
+                file: archive.clone(),
+                offset,
+                size,
+                position: 0,
+            })),
+            Source::Static(data) => {
+                let data = usize::try_from(offset)
+                    .ok()
+                    .zip(usize::try_from(offset + size).ok())
+                    .and_then(|(start, end)| data.get(start..end))
+                    .ok_or(ErrorKind::UnexpectedEof)?;
+                Ok(Box::new(Cursor::new(data)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> TarArchive {
+        TarArchive::from_static(include_bytes!("../testdata/root.tar")).unwrap()
+    }
+
+    fn read(archive: &TarArchive, path: &str) -> String {
+        let mut data = String::new();
+        archive
+            .open(Path::new(path))
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn entries() {
+        let archive = archive();
+        assert!(archive.is_dir(Path::new("/")));
+        assert!(archive.is_dir(Path::new("/subdir")));
+        assert!(archive.is_file(Path::new("/file.txt")));
+        assert!(archive.is_file(Path::new("/subdir/файл söndärzeichen.txt")));
+        assert!(!archive.is_file(Path::new("/missing.txt")));
+
+        assert_eq!(read(&archive, "/file.txt"), "Hi!\n");
+        assert_eq!(read(&archive, "/subdir/файл söndärzeichen.txt"), "Hi!\n");
+        assert_eq!(read(&archive, "/subdir/empty.js"), "");
+        assert_eq!(archive.info(Path::new("/large.txt")).unwrap().size, 100_001);
+
+        let mut names = archive
+            .read_dir(Path::new("/subdir"))
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [".hidden", "empty.js", "файл söndärzeichen.txt"]);
+    }
+
+    #[test]
+    fn file_on_disk() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("root.tar");
+        let archive = TarArchive::open(&path).unwrap();
+
+        let mut file = archive.open(Path::new("/large.txt")).unwrap();
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(99_996)).unwrap();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "6789\n");
+
+        let mut data = [0; 4];
+        file.seek(SeekFrom::Start(2)).unwrap();
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"2345");
+    }
+
+    #[test]
+    fn resolve() {
+        let archive = archive();
+        assert_eq!(
+            archive.resolve_uri("/file%2Etxt").unwrap(),
+            Path::new("/file.txt")
+        );
+        assert_eq!(
+            archive.resolve_uri("/subdir/../file.txt").unwrap(),
+            Path::new("/file.txt")
+        );
+        assert_eq!(
+            archive.resolve_uri("/subdir/").unwrap(),
+            Path::new("/subdir")
+        );
+        assert_eq!(
+            archive
+                .resolve_uri("/subdir/%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt")
+                .unwrap(),
+            Path::new("/subdir/файл söndärzeichen.txt")
+        );
+        assert_eq!(
+            archive.resolve_uri("file.txt").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            archive.resolve_uri("/../file.txt").unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            archive.resolve_uri("/missing.txt").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn invalid_archive() {
+        assert_eq!(
+            TarArchive::from_static(&[1; 1024]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    /// Returns the test archive with the header of `/file.txt` modified by the callback.
+    fn modified_archive(modify: impl FnOnce(&mut [u8])) -> Vec<u8> {
+        let mut data = include_bytes!("../testdata/root.tar").to_vec();
+        let header = &mut data[512..1024];
+        assert_eq!(header_string(&header[0..100]), "./file.txt");
+        modify(header);
+
+        let checksum = header
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    *byte as u32
+                }
+            })
+            .sum::<u32>();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        data
+    }
+
+    fn parse_error(data: &[u8]) -> String {
+        let err = parse(&mut Cursor::new(data)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    #[test]
+    fn truncated_archive() {
+        let data = include_bytes!("../testdata/root.tar");
+        assert!(parse(&mut Cursor::new(&data[..1536])).is_ok());
+        assert_eq!(parse_error(&data[..800]), "truncated tar header");
+        assert_eq!(parse_error(&data[..1026]), "tar entry exceeds archive size");
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut data = include_bytes!("../testdata/root.tar").to_vec();
+        data[520] ^= 1;
+        assert_eq!(parse_error(&data), "invalid tar header checksum");
+
+        // Recalculated checksum makes the modification valid
+        let data = modified_archive(|header| header[8] ^= 1);
+        assert!(parse(&mut Cursor::new(data)).is_ok());
+    }
+
+    #[test]
+    fn name_prefix() {
+        let data = modified_archive(|header| header[345..349].copy_from_slice(b"dir\0"));
+        let archive = TarArchive::from_static(data.leak()).unwrap();
+        assert!(archive.is_file(Path::new("/dir/file.txt")));
+        assert!(!archive.is_file(Path::new("/file.txt")));
+
+        // GNU headers don't have a prefix field
+        let data = modified_archive(|header| {
+            header[257..265].copy_from_slice(b"ustar  \0");
+            header[345..349].copy_from_slice(b"dir\0");
+        });
+        let archive = TarArchive::from_static(data.leak()).unwrap();
+        assert!(archive.is_file(Path::new("/file.txt")));
+        assert!(!archive.is_file(Path::new("/dir/file.txt")));
+    }
+
+    #[test]
+    fn huge_size() {
+        let data = modified_archive(|header| header[124..136].copy_from_slice(b"77777777777\0"));
+        assert_eq!(parse_error(&data), "tar entry exceeds archive size");
+
+        let data = modified_archive(|header| {
+            header[124..136].copy_from_slice(&[
+                0x80, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ])
+        });
+        assert_eq!(parse_error(&data), "tar entry exceeds archive size");
+
+        let data = modified_archive(|header| header[124..136].fill(0xFF));
+        assert_eq!(parse_error(&data), "numeric field in tar header too large");
+    }
+}
@@ -14,6 +14,7 @@
 
 use crate::handler::StaticFilesHandler;
 use crate::metadata::Metadata;
+use crate::storage::register_embedded_archive;
 
 use compression_module::CompressionHandler;
 use const_format::{concatcp, str_repeat};
@@ -104,6 +105,19 @@ fn assert_headers(result: &mut AppResult, expected: Vec<(&str, &str)>) {
     assert_eq!(headers, expected);
 }
 
+fn response_header(result: &mut AppResult, name: &str) -> String {
+    result
+        .session()
+        .response_written()
+        .unwrap()
+        .headers
+        .get(name)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
 fn assert_body(result: &AppResult, expected: &str) {
     assert_eq!(result.body_str(), expected);
 }
@@ -161,6 +175,28 @@ async fn text_file() {
     assert_body(&result, concatcp!(str_repeat!("0123456789", 10000), "\n"));
 }
 
+#[test(tokio::test)]
+async fn empty_file() {
+    let meta = Metadata::from_path(&root_path("subdir/empty.js"), None).unwrap();
+
+    let mut app = make_app(default_conf());
+    let session = make_session("GET", "/subdir/empty.js").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "0"),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/javascript;charset=utf-8"),
+            ("last-modified", &meta.modified.unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "");
+}
+
 #[test(tokio::test)]
 async fn dir_index() {
     let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
@@ -384,6 +420,69 @@ async fn no_file_with_page_404() {
     assert_body(&result, "Hi!\n");
 }
 
+#[test(tokio::test)]
+async fn try_files() {
+    let mut app = make_app(extended_conf(
+        "try_files: [$uri, $uri.txt, /index.html]\ntry_files_exclude: /subdir/*\npage_404: /file.txt",
+    ));
+
+    let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
+
+    // Existing files are served normally
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // URI with extension added
+    let session = make_session("GET", "/file").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    // Falling back to the last entry
+    let session = make_session("GET", "/app/route?xyz").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "<html>Hi!</html>\n");
+
+    // Range requests work with fallbacks
+    let mut session = make_session("GET", "/app/route").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_body(&result, "<html>");
+
+    // Excluded paths get the error page
+    let session = make_session("GET", "/subdir/missing.js").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+    assert_body(&result, "Hi!\n");
+
+    // Fallbacks have to start with / or $uri
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(extended_conf("try_files: index.html"))
+        .unwrap();
+    assert!(Handler::try_from(conf).is_err());
+}
+
 #[test(tokio::test)]
 async fn no_index() {
     let mut app = make_app(default_conf());
@@ -403,6 +502,154 @@ async fn no_index() {
     assert_body(&result, &text);
 }
 
+#[test(tokio::test)]
+async fn autoindex() {
+    let mut app = make_app(extended_conf("autoindex: true"));
+
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let length = result.body_str().len().to_string();
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &length),
+            ("Content-Type", "text/html;charset=utf-8"),
+            ("Cache-Control", "no-cache"),
+            ("Vary", "Accept"),
+        ],
+    );
+    let body = result.body_str();
+    assert!(body.contains("<title>Index of /</title>"));
+    assert!(body.contains(r#"<a href="/subdir/">subdir/</a>"#));
+    assert!(body.contains(r#"<a href="/file.txt">file.txt</a>"#));
+    assert!(!body.contains("../"));
+    assert!(body.find("/subdir/") < body.find("/file.txt"));
+
+    let session = make_session("GET", "/subdir/?sort=name&order=desc").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let body = result.body_str();
+    assert!(body.contains(r#"<a href="/">../</a>"#));
+    assert!(body.contains(r#"<a href="/subdir/empty.js">empty.js</a>"#));
+    assert!(body.contains(concat!(
+        r#"<a href="/subdir/%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt">"#,
+        "файл söndärzeichen.txt</a>"
+    )));
+    assert!(body.contains(r#"<a href="?sort=name&amp;order=asc">Name</a>"#));
+    assert!(body.find("empty.js") > body.find("söndärzeichen"));
+    assert!(!body.contains(".hidden"));
+
+    // Directory index file takes precedence
+    let mut app = make_app(extended_conf("autoindex: true\nindex_file: index.html"));
+    let session = make_session("GET", "/").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "<html>Hi!</html>\n");
+
+    // Hidden files
+    let mut app = make_app(extended_conf("autoindex: true\nautoindex_hidden: true"));
+    let session = make_session("GET", "/subdir/").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert!(result
+        .body_str()
+        .contains(r#"<a href="/subdir/.hidden">.hidden</a>"#));
+
+    // HEAD request
+    let mut app = make_app(extended_conf("autoindex: true"));
+    let session = make_session("HEAD", "/subdir/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "");
+
+    // Wrong method
+    let session = make_session("POST", "/subdir/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 405);
+}
+
+#[test(tokio::test)]
+async fn autoindex_json() {
+    let mut app = make_app(extended_conf("autoindex: true"));
+
+    let mut session = make_session("GET", "/subdir/?sort=size").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "application/json, text/plain, */*")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let length = result.body_str().len().to_string();
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &length),
+            ("Content-Type", "application/json"),
+            ("Cache-Control", "no-cache"),
+            ("Vary", "Accept"),
+        ],
+    );
+
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&result.body_str()).unwrap();
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            assert!(entry["modified"].is_string());
+            (
+                entry["name"].as_str().unwrap().to_owned(),
+                entry["href"].as_str().unwrap().to_owned(),
+                entry["type"].as_str().unwrap().to_owned(),
+                entry["size"].as_u64().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        vec![
+            (
+                "empty.js".to_owned(),
+                "/subdir/empty.js".to_owned(),
+                "file".to_owned(),
+                0
+            ),
+            (
+                "файл söndärzeichen.txt".to_owned(),
+                "/subdir/%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt".to_owned(),
+                "file".to_owned(),
+                4
+            ),
+        ]
+    );
+}
+
+#[test(tokio::test)]
+async fn autoindex_stripped_prefix() {
+    let mut app = make_app(extended_conf(
+        "autoindex: true\nrewrite_rules: {from: /static/*, to: '${tail}${query}'}",
+    ));
+
+    let session = make_session("GET", "/static/subdir/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let body = result.body_str();
+    assert!(body.contains("<title>Index of /static/subdir/</title>"));
+    assert!(body.contains(r#"<a href="/static/">../</a>"#));
+    assert!(body.contains(r#"<a href="/static/subdir/empty.js">empty.js</a>"#));
+
+    // Canonical redirect still applies
+    let session = make_session("GET", "/static/subdir").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+}
+
 #[test(tokio::test)]
 async fn wrong_method() {
     let mut app = make_app(default_conf());
@@ -1028,6 +1275,99 @@ async fn ranged_request() {
     );
     assert_body(&result, "");
 
+    // Multiple ranges
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=10-12,2-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    let content_type = response_header(&mut result, "Content-Type");
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let body = format!(
+        "\r\n--{boundary}\r\n\
+         Content-Type: text/plain;charset=utf-8\r\n\
+         Content-Range: bytes 2-5/100001\r\n\
+         \r\n\
+         2345\r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain;charset=utf-8\r\n\
+         Content-Range: bytes 10-12/100001\r\n\
+         \r\n\
+         012\r\n\
+         --{boundary}--\r\n"
+    );
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &body.len().to_string()),
+            ("Content-Type", &content_type),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, &body);
+
+    // Overlapping ranges are merged
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=2-5,4-8")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "7"),
+            ("content-range", "bytes 2-8/100001"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+    assert_body(&result, "2345678");
+
+    // Outdated If-Range results in full response
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=10-12,2-5")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-Range", "\"xyz\"")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+
+    // Too many ranges result in full response
+    let mut app = make_app(extended_conf("max_ranges: 1"));
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=10-12,2-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+        ],
+    );
+
     // With compression enabled this should produce Vary header
     let mut app = make_app(extended_conf("compression_level_gzip: 3"));
     let mut session = make_session("GET", "/large.txt").await;
@@ -1051,6 +1391,191 @@ async fn ranged_request() {
     assert_body(&result, "");
 }
 
+#[test(tokio::test)]
+async fn cache() {
+    let root = std::env::temp_dir().join(format!("static-files-cache-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file.txt"), "Hi!\n").unwrap();
+    std::fs::write(root.join("large.txt"), "0123456789").unwrap();
+    std::fs::write(root.join("empty.txt"), "").unwrap();
+    let conf = |extra: &str| {
+        format!(
+            "root: {}\ncache_size: 100000\ncache_max_file_size: 5\n{extra}",
+            root.to_str().unwrap()
+        )
+    };
+
+    let mut app = make_app(conf(""));
+    let session = make_session("GET", "/file.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "Hi!\n");
+
+    let session = make_session("GET", "/large.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "0123456789");
+
+    let session = make_session("GET", "/empty.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(response_header(&mut result, "Content-Length"), "0");
+    assert_body(&result, "");
+
+    // Cached contents should be served for small files
+    std::fs::write(root.join("file.txt"), "Bye\n").unwrap();
+    std::fs::write(root.join("large.txt"), "9876543210").unwrap();
+    std::fs::write(root.join("empty.txt"), "Hi!\n").unwrap();
+    let session = make_session("GET", "/file.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=1-2")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_body(&result, "i!");
+
+    let session = make_session("GET", "/empty.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "");
+
+    // Large files are read from disk
+    let session = make_session("GET", "/large.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "9876543210");
+
+    // Expired cache entries are updated
+    let mut app = make_app(conf("cache_ttl: 0"));
+    let session = make_session("GET", "/file.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "Bye\n");
+    std::fs::write(root.join("file.txt"), "Hi!\n").unwrap();
+    let session = make_session("GET", "/file.txt").await;
+    let result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_body(&result, "Hi!\n");
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test(tokio::test)]
+async fn archive() {
+    let mut archive = root_path("");
+    archive.set_extension("tar");
+    let archive_conf = |extra: &str| format!("archive: {}\n{extra}", archive.to_str().unwrap());
+    let mut app = make_app(archive_conf("autoindex: true"));
+
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", "4"),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "text/plain;charset=utf-8"),
+            ("last-modified", "Wed, 01 May 2024 00:00:00 GMT"),
+            ("etag", "\"66318600-4\""),
+        ],
+    );
+    assert_body(&result, "Hi!\n");
+
+    let mut session = make_session("GET", "/large.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=-5")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_body(&result, "6789\n");
+
+    let session = make_session("GET", "/subdir").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 308);
+    assert_eq!(response_header(&mut result, "location"), "/subdir/");
+
+    let session = make_session("GET", "/subdir/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    let body = result.body_str();
+    assert!(body.contains(r#"<a href="/">../</a>"#));
+    assert!(body.contains(r#"<a href="/subdir/empty.js">empty.js</a>"#));
+    assert!(!body.contains(".hidden"));
+
+    let session = make_session("GET", "/missing.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 404);
+
+    let session = make_session("GET", "/../file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 400);
+
+    // Pre-compressed files are found in the archive as well
+    let mut app = make_app(archive_conf("precompressed: gz\ncache_size: 100000"));
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(response_header(&mut result, "Content-Encoding"), "gzip");
+    assert_eq!(response_header(&mut result, "Content-Length"), "265");
+    assert_eq!(result.body().len(), 265);
+
+    // Only one storage backend can be configured
+    let conf =
+        <Handler as RequestFilter>::Conf::from_yaml(extended_conf(archive_conf(""))).unwrap();
+    assert!(Handler::try_from(conf).is_err());
+}
+
+#[test(tokio::test)]
+async fn embedded() {
+    register_embedded_archive("test", include_bytes!("../testdata/root.tar"));
+
+    let mut app = make_app("embedded: test\nindex_file: index.html");
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "text/html;charset=utf-8"
+    );
+    assert_body(&result, "<html>Hi!</html>\n");
+
+    let session = make_session(
+        "GET",
+        "/subdir/%D1%84%D0%B0%D0%B9%D0%BB%20s%C3%B6nd%C3%A4rzeichen.txt",
+    )
+    .await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "Hi!\n");
+
+    let conf = <Handler as RequestFilter>::Conf::from_yaml("embedded: missing").unwrap();
+    assert!(Handler::try_from(conf).is_err());
+}
+
 #[test(tokio::test)]
 async fn dynamic_compression() {
     let meta = Metadata::from_path(&root_path("large.txt"), None).unwrap();
@@ -1180,6 +1705,52 @@ async fn static_compression() {
         ],
     );
 
+    // Multiple ranges should apply to the pre-compressed file
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-1,10-11")
+        .unwrap();
+
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+
+    assert_status(&mut result, 206);
+    let content_type = response_header(&mut result, "Content-Type");
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+    let part_header = |range: &str| {
+        format!(
+            "\r\n--{boundary}\r\n\
+             Content-Type: text/plain;charset=utf-8\r\n\
+             Content-Range: bytes {range}/{}\r\n\
+             \r\n",
+            meta_compressed.size
+        )
+    };
+    let length = part_header("0-1").len()
+        + 2
+        + part_header("10-11").len()
+        + 2
+        + format!("\r\n--{boundary}--\r\n").len();
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &length.to_string()),
+            ("Content-Type", &content_type),
+            ("last-modified", meta_compressed.modified.as_ref().unwrap()),
+            ("etag", &meta_compressed.etag),
+            ("Content-Encoding", "gzip"),
+            ("vary", "Accept-Encoding"),
+        ],
+    );
+    assert!(result.body_str().contains(&part_header("10-11")));
+
     // Request without matching encodings should result in uncompressed response
     let mut session = make_session("GET", "/large_precompressed.txt").await;
     session
@@ -1235,6 +1806,205 @@ async fn static_compression() {
     );
 }
 
+#[test(tokio::test)]
+async fn image_formats() {
+    let meta = Metadata::from_path(&root_path("images/photo.jpg"), None).unwrap();
+    let meta_avif = Metadata::from_path(&root_path("images/photo.jpg.avif"), None).unwrap();
+    let meta_webp = Metadata::from_path(&root_path("images/photo.webp"), None).unwrap();
+    let mut app = make_app(extended_conf("image_formats: [avif, webp]"));
+
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/avif,image/webp,image/*,*/*;q=0.8")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta_avif.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/avif"),
+            ("last-modified", meta_avif.modified.as_ref().unwrap()),
+            ("etag", &meta_avif.etag),
+            ("vary", "Accept"),
+        ],
+    );
+    assert_body(&result, "AVIF\n");
+
+    // Variant with replaced file extension
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/avif;q=0.8,image/webp")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta_webp.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/webp"),
+            ("last-modified", meta_webp.modified.as_ref().unwrap()),
+            ("etag", &meta_webp.etag),
+            ("vary", "Accept"),
+        ],
+    );
+    assert_body(&result, "WebP image\n");
+
+    // Wildcards don’t select alternative formats
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/*")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_headers(
+        &mut result,
+        vec![
+            ("Content-Length", &meta.size.to_string()),
+            ("accept-ranges", "bytes"),
+            ("Content-Type", "image/jpeg"),
+            ("last-modified", meta.modified.as_ref().unwrap()),
+            ("etag", &meta.etag),
+            ("vary", "Accept"),
+        ],
+    );
+    assert_body(&result, "JPEG image\n");
+
+    // Ranges and conditional requests refer to the variant
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/avif")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("Range", "bytes=0-1")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 206);
+    assert_eq!(
+        response_header(&mut result, "Content-Range"),
+        format!("bytes 0-1/{}", meta_avif.size)
+    );
+    assert_eq!(response_header(&mut result, "Vary"), "Accept");
+    assert_body(&result, "AV");
+
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/avif")
+        .unwrap();
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta_avif.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 304);
+    assert_eq!(response_header(&mut result, "Vary"), "Accept");
+
+    let mut session = make_session("GET", "/images/photo.jpg").await;
+    session
+        .req_header_mut()
+        .insert_header("If-None-Match", &meta_avif.etag)
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_body(&result, "JPEG image\n");
+
+    // Only images are affected
+    let mut session = make_session("GET", "/file.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept", "image/avif")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert!(result
+        .session()
+        .response_written()
+        .unwrap()
+        .headers
+        .get("Vary")
+        .is_none());
+    assert_body(&result, "Hi!\n");
+}
+
+#[test(tokio::test)]
+async fn mime_types() {
+    // Mappings from multiple configuration files are merged
+    let conf = <Handler as RequestFilter>::Conf::from_yaml(extended_conf(
+        "mime_types: {txt: text/markdown}\nprecompressed: gz\nindex_file: index.html",
+    ))
+    .unwrap()
+    .merge_from_yaml("mime_types: {'index.*': application/xhtml+xml}")
+    .unwrap();
+    let mut app = DefaultApp::<Handler>::new(conf.try_into().unwrap());
+
+    let session = make_session("GET", "/file.txt").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "text/markdown;charset=utf-8"
+    );
+
+    let session = make_session("GET", "/").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "application/xhtml+xml;charset=utf-8"
+    );
+
+    // MIME type of pre-compressed files is determined from the original path
+    let mut session = make_session("GET", "/large_precompressed.txt").await;
+    session
+        .req_header_mut()
+        .insert_header("Accept-Encoding", "gzip")
+        .unwrap();
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(response_header(&mut result, "Content-Encoding"), "gzip");
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "text/markdown;charset=utf-8"
+    );
+
+    let session = make_session("GET", "/subdir/.hidden").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "application/octet-stream"
+    );
+
+    let mut app = make_app(extended_conf("default_type: text/plain"));
+    let session = make_session("GET", "/subdir/.hidden").await;
+    let mut result = app.handle_request(session).await;
+    assert!(result.err().is_none());
+    assert_status(&mut result, 200);
+    assert_eq!(
+        response_header(&mut result, "Content-Type"),
+        "text/plain;charset=utf-8"
+    );
+}
+
 #[test(tokio::test)]
 async fn charset() {
     let meta = Metadata::from_path(&root_path("large_precompressed.txt.gz"), None).unwrap();
@@ -16,14 +16,27 @@
 
 use async_trait::async_trait;
 use clap::{value_parser, Parser};
-use http::header;
 use http::uri::{Scheme, Uri};
+use http::{header, HeaderName};
 use log::error;
-use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
+use nix::unistd::{close, dup, write};
+use once_cell::sync::Lazy;
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpPeer, SessionWrapper, SocketAddr as ClientAddr,
+};
+use pandora_module_utils::{DeserializeMap, OneOrMany, RequestFilter, RequestFilterResult};
 use serde::de::{Deserializer, Error as _};
-use serde::Deserialize as _;
-use std::net::{SocketAddr, ToSocketAddrs};
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
 
 /// Command line options of the compression module
 #[derive(Debug, Default, Parser)]
@@ -45,6 +58,30 @@ where
     Ok(Some(uri))
 }
 
+/// PROXY protocol version to be used for upstream connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// Version 1, text-based
+    V1,
+    /// Version 2, binary
+    V2,
+}
+
+/// Headers passing on information about the original request to the upstream server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For` header, client address is appended to existing values
+    XForwardedFor,
+    /// `X-Forwarded-Proto` header, `http` or `https`
+    XForwardedProto,
+    /// `X-Forwarded-Host` header, the original `Host` header value
+    XForwardedHost,
+    /// `Forwarded` header as defined in RFC 7239
+    Forwarded,
+}
+
 /// Configuration settings of the compression module
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct UpstreamConf {
@@ -52,6 +89,22 @@ pub struct UpstreamConf {
     /// Path and query parts of the URL have no effect.
     #[pandora(deserialize_with = "deserialize_uri")]
     pub upstream: Option<Uri>,
+
+    /// If set, a PROXY protocol header of this version will be sent to the upstream server,
+    /// indicating the client address.
+    pub upstream_proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// Headers to be added to upstream requests, passing on client address, scheme and host name
+    /// of the original request.
+    pub upstream_forwarded_headers: OneOrMany<ForwardedHeader>,
+
+    /// If `true`, forwarding headers sent by the client will be kept and extended. Otherwise
+    /// these will be removed if `upstream_forwarded_headers` is configured.
+    pub upstream_trust_forwarded: bool,
+
+    /// If `true`, the original `Host` header will be passed on to the upstream server rather than
+    /// the host name of the upstream URL.
+    pub upstream_preserve_host: bool,
 }
 
 impl UpstreamConf {
@@ -67,16 +120,333 @@ impl UpstreamConf {
 /// Context data of the handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpstreamContext {
+    upstream: String,
     addr: SocketAddr,
     tls: bool,
     sni: String,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// Headers carrying forwarding information
+const FORWARDING_HEADERS: [&str; 5] = [
+    "X-Forwarded-For",
+    "X-Forwarded-Proto",
+    "X-Forwarded-Host",
+    "Forwarded",
+    "X-Real-IP",
+];
+
+/// Hop-by-hop headers not meant for the upstream server. `Connection`, `Transfer-Encoding` and
+/// `Upgrade` are managed by Pingora. `TE: trailers` is passed on, see [`accepts_trailers`].
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+];
+
+/// Upstream servers used by any of the existing handlers
+static UPSTREAMS: Lazy<Mutex<Vec<Weak<UpstreamContext>>>> = Lazy::new(Default::default);
+
+/// Health status of an upstream server
+#[derive(Debug)]
+pub struct UpstreamHealth {
+    /// Upstream URL as configured
+    pub upstream: String,
+
+    /// Socket address the upstream URL resolved to
+    pub addr: SocketAddr,
+
+    /// Error connecting to the upstream server if any
+    pub error: Option<std::io::Error>,
+}
+
+impl Display for UpstreamHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): ", self.upstream, self.addr)?;
+        if let Some(error) = &self.error {
+            write!(f, "{error}")
+        } else {
+            write!(f, "ok")
+        }
+    }
+}
+
+/// Checks whether the upstream servers currently configured accept connections.
+///
+/// This will try to establish a TCP connection to each server, waiting at most `timeout` for each
+/// one. It will block and shouldn’t be called from an async context.
+pub fn check_upstream_health(timeout: Duration) -> Vec<UpstreamHealth> {
+    let upstreams = {
+        let mut upstreams = UPSTREAMS.lock().unwrap_or_else(|err| err.into_inner());
+        upstreams.retain(|context| context.strong_count() > 0);
+        upstreams
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|context| (context.upstream.clone(), context.addr))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    upstreams
+        .into_iter()
+        .map(|(upstream, addr)| UpstreamHealth {
+            upstream,
+            addr,
+            error: TcpStream::connect_timeout(&addr, timeout).err(),
+        })
+        .collect()
+}
+
+fn to_inet(addr: Option<&ClientAddr>) -> Option<SocketAddr> {
+    match addr {
+        Some(ClientAddr::Inet(addr)) => Some(match addr.ip() {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+                None => *addr,
+            },
+            IpAddr::V4(_) => *addr,
+        }),
+        _ => None,
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> (Ipv6Addr, u16) {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    (ip, addr.port())
+}
+
+/// Duplicate of a socket’s file descriptor, closed when dropped
+#[derive(Debug)]
+struct DuplicateFd(RawFd);
+
+impl DuplicateFd {
+    fn new(fd: RawFd) -> std::io::Result<Self> {
+        Ok(Self(dup(fd)?))
+    }
+}
+
+impl AsRawFd for DuplicateFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for DuplicateFd {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
+
+/// Writes all data to a non-blocking socket, waiting for it to become writable as necessary.
+///
+/// The socket is already registered with the runtime by Pingora, so a duplicate of its file
+/// descriptor is registered instead.
+async fn write_all(fd: RawFd, mut data: &[u8]) -> std::io::Result<()> {
+    let fd = AsyncFd::with_interest(DuplicateFd::new(fd)?, Interest::WRITABLE)?;
+    while !data.is_empty() {
+        let mut guard = fd.writable().await?;
+        if let Ok(result) =
+            guard.try_io(|fd| write(fd.as_raw_fd(), data).map_err(std::io::Error::from))
+        {
+            data = &data[result?..];
+        }
+    }
+    Ok(())
+}
+
+/// Produces the PROXY protocol header for a connection between the given addresses
+fn proxy_protocol_header(
+    version: ProxyProtocolVersion,
+    client_addr: Option<&ClientAddr>,
+    server_addr: Option<&ClientAddr>,
+) -> Vec<u8> {
+    let addrs = to_inet(client_addr).zip(to_inet(server_addr));
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((SocketAddr::V4(client), SocketAddr::V4(server))) => format!(
+                "PROXY TCP4 {} {} {} {}\r\n",
+                client.ip(),
+                server.ip(),
+                client.port(),
+                server.port()
+            ),
+            Some((client, server)) => {
+                let (client_ip, client_port) = to_ipv6(client);
+                let (server_ip, server_port) = to_ipv6(server);
+                format!("PROXY TCP6 {client_ip} {server_ip} {client_port} {server_port}\r\n")
+            }
+            None => "PROXY UNKNOWN\r\n".to_owned(),
+        }
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21".to_vec();
+            match addrs {
+                Some((SocketAddr::V4(client), SocketAddr::V4(server))) => {
+                    header.extend_from_slice(&[0x11, 0, 12]);
+                    header.extend_from_slice(&client.ip().octets());
+                    header.extend_from_slice(&server.ip().octets());
+                    header.extend_from_slice(&client.port().to_be_bytes());
+                    header.extend_from_slice(&server.port().to_be_bytes());
+                }
+                Some((client, server)) => {
+                    let (client_ip, client_port) = to_ipv6(client);
+                    let (server_ip, server_port) = to_ipv6(server);
+                    header.extend_from_slice(&[0x21, 0, 36]);
+                    header.extend_from_slice(&client_ip.octets());
+                    header.extend_from_slice(&server_ip.octets());
+                    header.extend_from_slice(&client_port.to_be_bytes());
+                    header.extend_from_slice(&server_port.to_be_bytes());
+                }
+                None => header.extend_from_slice(&[0x00, 0, 0]),
+            }
+            header
+        }
+    }
+}
+
+/// Checks whether the `TE` header of the request contains the `trailers` value. Unlike other `TE`
+/// values, this one is meant to be forwarded (RFC 9110 section 10.1.4), gRPC relies on it.
+fn accepts_trailers(session: &impl SessionWrapper) -> bool {
+    session
+        .req_header()
+        .headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Removes hop-by-hop headers from the request, including any listed in the `Connection` header
+fn remove_hop_by_hop_headers(session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+    let trailers = accepts_trailers(session);
+    let listed = session
+        .req_header()
+        .headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .filter(|name| {
+            name != header::CONNECTION
+                && name != header::TRANSFER_ENCODING
+                && name != header::UPGRADE
+        })
+        .collect::<Vec<_>>();
+
+    let header = session.req_header_mut();
+    for name in HOP_BY_HOP_HEADERS {
+        header.remove_header(name);
+    }
+    for name in listed {
+        header.remove_header(&name);
+    }
+    if trailers {
+        header.insert_header(header::TE, "trailers")?;
+    }
+    Ok(())
+}
+
+/// Formats the client address for the `for` parameter of the `Forwarded` header
+fn forwarded_node(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(SocketAddr::V4(addr)) => addr.ip().to_string(),
+        Some(SocketAddr::V6(addr)) => format!("\"[{}]\"", addr.ip()),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Quotes a value for the `Forwarded` header if necessary
+fn forwarded_value(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
 }
 
 /// Upstream module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UpstreamHandler {
     host_port: String,
-    context: Option<UpstreamContext>,
+    forwarded_headers: Vec<ForwardedHeader>,
+    trust_forwarded: bool,
+    preserve_host: bool,
+    context: Option<Arc<UpstreamContext>>,
+}
+
+impl UpstreamHandler {
+    /// Removes untrusted forwarding headers from the request and adds the configured ones
+    fn add_forwarded_headers(&self, session: &mut impl SessionWrapper) -> Result<(), Box<Error>> {
+        if self.forwarded_headers.is_empty() {
+            return Ok(());
+        }
+
+        if !self.trust_forwarded {
+            let header = session.req_header_mut();
+            for name in FORWARDING_HEADERS {
+                header.remove_header(name);
+            }
+        }
+
+        let client_ip = to_inet(session.client_addr());
+        let host = session.host().map(|host| host.into_owned());
+        let proto = if session
+            .digest()
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .is_some()
+        {
+            "https"
+        } else {
+            "http"
+        };
+
+        let header = session.req_header_mut();
+        for forwarded_header in &self.forwarded_headers {
+            match forwarded_header {
+                ForwardedHeader::XForwardedFor => {
+                    if let Some(addr) = client_ip {
+                        let mut values = header
+                            .headers
+                            .get_all("X-Forwarded-For")
+                            .iter()
+                            .map(|value| value.as_bytes().to_vec())
+                            .collect::<Vec<_>>();
+                        values.push(addr.ip().to_string().into_bytes());
+                        header.insert_header("X-Forwarded-For", values.join(&b", "[..]))?;
+                    }
+                }
+                ForwardedHeader::XForwardedProto => {
+                    header.insert_header("X-Forwarded-Proto", proto)?;
+                }
+                ForwardedHeader::XForwardedHost => {
+                    if let Some(host) = &host {
+                        header.insert_header("X-Forwarded-Host", host)?;
+                    }
+                }
+                ForwardedHeader::Forwarded => {
+                    let mut value = format!("for={}", forwarded_node(client_ip));
+                    if let Some(host) = &host {
+                        value.push_str(";host=");
+                        value.push_str(&forwarded_value(host));
+                    }
+                    value.push_str(";proto=");
+                    value.push_str(proto);
+                    header.append_header("Forwarded", value)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl TryFrom<UpstreamConf> for UpstreamHandler {
@@ -103,6 +473,11 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
                 Error::new(ErrorType::InternalError)
             })?;
 
+            if tls && conf.upstream_proxy_protocol.is_some() {
+                error!("PROXY protocol isn't supported for HTTPS upstream servers: {upstream}");
+                return Err(Error::new(ErrorType::InternalError));
+            }
+
             let port = upstream.port_u16().unwrap_or(if tls { 443 } else { 80 });
 
             let addr = (host, port)
@@ -123,17 +498,31 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
                 host_port.push_str(port.as_str());
             }
 
+            let context = Arc::new(UpstreamContext {
+                upstream: upstream.to_string(),
+                tls,
+                addr,
+                sni: host.to_owned(),
+                proxy_protocol: conf.upstream_proxy_protocol,
+            });
+            UPSTREAMS
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .push(Arc::downgrade(&context));
+
             Ok(Self {
                 host_port,
-                context: Some(UpstreamContext {
-                    tls,
-                    addr,
-                    sni: host.to_owned(),
-                }),
+                forwarded_headers: conf.upstream_forwarded_headers.into(),
+                trust_forwarded: conf.upstream_trust_forwarded,
+                preserve_host: conf.upstream_preserve_host,
+                context: Some(context),
             })
         } else {
             Ok(Self {
                 host_port: Default::default(),
+                forwarded_headers: Default::default(),
+                trust_forwarded: false,
+                preserve_host: false,
                 context: None,
             })
         }
@@ -143,7 +532,7 @@ impl TryFrom<UpstreamConf> for UpstreamHandler {
 #[async_trait]
 impl RequestFilter for UpstreamHandler {
     type Conf = UpstreamConf;
-    type CTX = Option<UpstreamContext>;
+    type CTX = Option<Arc<UpstreamContext>>;
     fn new_ctx() -> Self::CTX {
         None
     }
@@ -154,9 +543,17 @@ impl RequestFilter for UpstreamHandler {
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
         if let Some(context) = &self.context {
+            remove_hop_by_hop_headers(session)?;
+            self.add_forwarded_headers(session)?;
+
+            let host = if self.preserve_host {
+                session.host().map(|host| host.into_owned())
+            } else {
+                None
+            };
             session
                 .req_header_mut()
-                .insert_header(header::HOST, &self.host_port)?;
+                .insert_header(header::HOST, host.as_ref().unwrap_or(&self.host_port))?;
 
             *ctx = Some(context.clone());
 
@@ -168,19 +565,51 @@ impl RequestFilter for UpstreamHandler {
 
     async fn upstream_peer(
         &self,
-        _session: &mut impl SessionWrapper,
+        session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
         if let Some(context) = ctx {
-            Ok(Some(Box::new(HttpPeer::new(
-                context.addr,
-                context.tls,
-                context.sni.clone(),
-            ))))
+            let mut peer = HttpPeer::new(context.addr, context.tls, context.sni.clone());
+            if context.proxy_protocol.is_some() {
+                // The PROXY protocol header is only sent when the connection is established,
+                // connections cannot be shared between different clients.
+                let mut hasher = DefaultHasher::new();
+                session.client_addr().hash(&mut hasher);
+                session.server_addr().hash(&mut hasher);
+                peer.group_key = hasher.finish();
+            }
+            Ok(Some(Box::new(peer)))
         } else {
             Ok(None)
         }
     }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut impl SessionWrapper,
+        reused: bool,
+        _peer: &HttpPeer,
+        fd: RawFd,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        let Some(version) = ctx.as_ref().and_then(|context| context.proxy_protocol) else {
+            return Ok(());
+        };
+        if reused {
+            return Ok(());
+        }
+
+        let header = proxy_protocol_header(version, session.client_addr(), session.server_addr());
+        // Pingora only exposes the connection’s file descriptor here. Nothing has been written to
+        // the connection yet, so the header will go out first.
+        write_all(fd, &header).await.map_err(|err| {
+            Error::because(
+                ErrorType::WriteError,
+                "failed sending PROXY protocol header",
+                err,
+            )
+        })
+    }
 }
 
 #[cfg(test)]
@@ -189,12 +618,59 @@ mod tests {
 
     use http::HeaderValue;
     use pandora_module_utils::pingora::{
-        create_test_session, RequestHeader, ResponseHeader, Session,
+        create_test_session, ProxyHttp, RequestHeader, ResponseHeader, Session,
     };
     use pandora_module_utils::FromYaml;
     use startup_module::DefaultApp;
+    use std::str::FromStr;
     use test_log::test;
 
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct IPAddressConf {
+        ip_address: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct IPAddressHandler {
+        ip_address: Option<String>,
+    }
+
+    #[async_trait]
+    impl RequestFilter for IPAddressHandler {
+        type Conf = IPAddressConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn early_request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            if let Some(ip_address) = &self.ip_address {
+                session.set_client_addr(ClientAddr::Inet(
+                    (IpAddr::from_str(ip_address).unwrap(), 8000).into(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl TryFrom<IPAddressConf> for IPAddressHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: IPAddressConf) -> Result<Self, Self::Error> {
+            Ok(Self {
+                ip_address: conf.ip_address,
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct Handler {
+        address: IPAddressHandler,
+        upstream: UpstreamHandler,
+    }
+
     fn make_app(configured: bool) -> DefaultApp<UpstreamHandler> {
         let conf = if configured {
             UpstreamConf::from_yaml(
@@ -225,6 +701,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn health() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = format!("http://{addr}/");
+        let check = || {
+            check_upstream_health(Duration::from_secs(1))
+                .into_iter()
+                .find(|health| health.upstream == upstream)
+        };
+
+        let handler: UpstreamHandler = UpstreamConf::from_yaml(format!("upstream: {upstream}"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let health = check().unwrap();
+        assert_eq!(health.addr, addr);
+        assert!(health.error.is_none());
+
+        drop(listener);
+        assert!(check().unwrap().error.is_some());
+
+        drop(handler);
+        assert!(check().is_none());
+    }
+
     #[test(tokio::test)]
     async fn handled() {
         let mut app = make_app(true);
@@ -243,4 +745,310 @@ mod tests {
             .await;
         assert!(result.err().is_none());
     }
+
+    async fn forwarded_request(conf: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut app: DefaultApp<Handler> = DefaultApp::new(
+            <Handler as RequestFilter>::Conf::from_yaml(conf)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        );
+
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            header.append_header(String::from(*name), *value).unwrap();
+        }
+        let session = create_test_session(header).await;
+
+        let request = Mutex::new(None);
+        let result = app
+            .handle_request_with_upstream(session, |session, _| {
+                *request.lock().unwrap() = Some(session.req_header().clone());
+                ResponseHeader::build(200, None)
+            })
+            .await;
+        assert!(result.err().is_none());
+        request.into_inner().unwrap().unwrap()
+    }
+
+    fn header_values(header: &RequestHeader, name: &str) -> Vec<String> {
+        header
+            .headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test(tokio::test)]
+    async fn forwarded_headers() {
+        let conf = "ip_address: 192.0.2.1
+upstream: http://127.0.0.1:8081
+upstream_forwarded_headers: [x-forwarded-for, x-forwarded-proto, x-forwarded-host, forwarded]";
+        let headers = [
+            ("Host", "example.com:8080"),
+            ("X-Forwarded-For", "203.0.113.1"),
+            ("X-Forwarded-Host", "evil.example.com"),
+            ("Forwarded", "for=203.0.113.1"),
+            ("X-Real-IP", "203.0.113.1"),
+        ];
+
+        let request = forwarded_request(conf, &headers).await;
+        assert_eq!(header_values(&request, "Host"), ["127.0.0.1:8081"]);
+        assert_eq!(header_values(&request, "X-Forwarded-For"), ["192.0.2.1"]);
+        assert_eq!(header_values(&request, "X-Forwarded-Proto"), ["http"]);
+        assert_eq!(
+            header_values(&request, "X-Forwarded-Host"),
+            ["example.com:8080"]
+        );
+        assert_eq!(
+            header_values(&request, "Forwarded"),
+            ["for=192.0.2.1;host=\"example.com:8080\";proto=http"]
+        );
+        assert!(header_values(&request, "X-Real-IP").is_empty());
+
+        // Client-supplied headers are kept if trusted
+        let request = forwarded_request(
+            &format!("{conf}\nupstream_trust_forwarded: true\nupstream_preserve_host: true"),
+            &headers,
+        )
+        .await;
+        assert_eq!(header_values(&request, "Host"), ["example.com:8080"]);
+        assert_eq!(
+            header_values(&request, "X-Forwarded-For"),
+            ["203.0.113.1, 192.0.2.1"]
+        );
+        assert_eq!(
+            header_values(&request, "X-Forwarded-Host"),
+            ["example.com:8080"]
+        );
+        assert_eq!(
+            header_values(&request, "Forwarded"),
+            [
+                "for=203.0.113.1",
+                "for=192.0.2.1;host=\"example.com:8080\";proto=http"
+            ]
+        );
+        assert_eq!(header_values(&request, "X-Real-IP"), ["203.0.113.1"]);
+
+        // IPv6 and unknown client addresses
+        let request = forwarded_request(
+            r#"
+                ip_address: 2001:db8::1
+                upstream: http://127.0.0.1:8081
+                upstream_forwarded_headers: [x-forwarded-for, forwarded]
+            "#,
+            &[("Host", "example.com")],
+        )
+        .await;
+        assert_eq!(header_values(&request, "X-Forwarded-For"), ["2001:db8::1"]);
+        assert_eq!(
+            header_values(&request, "Forwarded"),
+            ["for=\"[2001:db8::1]\";host=example.com;proto=http"]
+        );
+
+        let request = forwarded_request(
+            r#"
+                upstream: http://127.0.0.1:8081
+                upstream_forwarded_headers: forwarded
+            "#,
+            &[],
+        )
+        .await;
+        assert_eq!(
+            header_values(&request, "Forwarded"),
+            ["for=unknown;proto=http"]
+        );
+
+        // Client-supplied headers are passed on unchanged if no forwarding headers are configured
+        let request = forwarded_request(
+            r#"
+                ip_address: 192.0.2.1
+                upstream: http://127.0.0.1:8081
+            "#,
+            &headers,
+        )
+        .await;
+        assert_eq!(header_values(&request, "X-Forwarded-For"), ["203.0.113.1"]);
+        assert_eq!(
+            header_values(&request, "X-Forwarded-Host"),
+            ["evil.example.com"]
+        );
+        assert_eq!(header_values(&request, "Forwarded"), ["for=203.0.113.1"]);
+        assert_eq!(header_values(&request, "X-Real-IP"), ["203.0.113.1"]);
+    }
+
+    #[test(tokio::test)]
+    async fn hop_by_hop_headers() {
+        let request = forwarded_request(
+            r#"
+                upstream: http://127.0.0.1:8081
+            "#,
+            &[
+                ("Connection", "keep-alive, X-Custom, Upgrade"),
+                ("Keep-Alive", "timeout=5"),
+                ("Proxy-Authorization", "Basic dXNlcjpwYXNz"),
+                ("TE", "trailers"),
+                ("X-Custom", "1"),
+                ("X-Other", "2"),
+            ],
+        )
+        .await;
+        assert!(header_values(&request, "Keep-Alive").is_empty());
+        assert!(header_values(&request, "Proxy-Authorization").is_empty());
+        assert_eq!(header_values(&request, "TE"), ["trailers"]);
+        assert!(header_values(&request, "X-Custom").is_empty());
+        assert_eq!(header_values(&request, "X-Other"), ["2"]);
+
+        let request = forwarded_request(
+            r#"
+                upstream: http://127.0.0.1:8081
+            "#,
+            &[("TE", "gzip, deflate;q=0.5")],
+        )
+        .await;
+        assert!(header_values(&request, "TE").is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn grpc_request() {
+        // gRPC clients require trailers, other TE values are still removed
+        let request = forwarded_request(
+            r#"
+                upstream: http://127.0.0.1:8081
+            "#,
+            &[
+                ("Content-Type", "application/grpc"),
+                ("Connection", "TE"),
+                ("TE", "gzip;q=0.5, trailers"),
+                ("Grpc-Timeout", "10S"),
+            ],
+        )
+        .await;
+        assert_eq!(header_values(&request, "TE"), ["trailers"]);
+        assert_eq!(
+            header_values(&request, "Content-Type"),
+            ["application/grpc"]
+        );
+        assert_eq!(header_values(&request, "Grpc-Timeout"), ["10S"]);
+    }
+
+    #[test]
+    fn proxy_protocol_headers() {
+        let addr = |addr: &str| ClientAddr::Inet(addr.parse().unwrap());
+        let client = addr("192.0.2.1:56324");
+        let server = addr("192.0.2.2:443");
+        let client6 = addr("[2001:db8::1]:56324");
+        let server6 = addr("[::ffff:192.0.2.2]:443");
+
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V1, Some(&client), Some(&server)),
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"
+        );
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V1, Some(&client), Some(&server6)),
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"
+        );
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V1, Some(&client6), Some(&server6)),
+            b"PROXY TCP6 2001:db8::1 ::ffff:192.0.2.2 56324 443\r\n"
+        );
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V1, Some(&client), None),
+            b"PROXY UNKNOWN\r\n"
+        );
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C".to_vec();
+        expected.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V2, Some(&client), Some(&server)),
+            expected
+        );
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&"::ffff:192.0.2.2".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&[0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V2, Some(&client6), Some(&server6)),
+            expected
+        );
+
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocolVersion::V2, None, None),
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00"
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn partial_writes() {
+        use std::io::Read;
+
+        let (sender, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        sender.set_nonblocking(true).unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            receiver.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        // This is more than the socket buffer can hold
+        let data = vec![0x2A; 4 * 1024 * 1024];
+        write_all(sender.as_raw_fd(), &data).await.unwrap();
+        drop(sender);
+        assert_eq!(reader.join().unwrap(), data);
+    }
+
+    #[test(tokio::test)]
+    async fn proxy_protocol() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let app: DefaultApp<UpstreamHandler> = DefaultApp::new(
+            UpstreamConf::from_yaml(
+                r#"
+                    upstream: http://127.0.0.1
+                    upstream_proxy_protocol: v1
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+        let mut ctx = app.new_ctx();
+
+        let mut session = make_session().await;
+        assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+
+        let (upstream, mut receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        for reused in [false, true] {
+            app.connected_to_upstream(
+                &mut session,
+                reused,
+                &peer,
+                upstream.as_raw_fd(),
+                None,
+                &mut ctx,
+            )
+            .await
+            .unwrap();
+        }
+        drop(upstream);
+
+        let mut received = Vec::new();
+        receiver.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"PROXY UNKNOWN\r\n");
+
+        assert!(UpstreamHandler::try_from(
+            UpstreamConf::from_yaml(
+                r#"
+                    upstream: https://127.0.0.1
+                    upstream_proxy_protocol: v2
+                "#,
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
 }
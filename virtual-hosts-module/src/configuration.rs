@@ -35,6 +35,10 @@ pub struct VirtualHostConf<C: Default> {
     /// If true, this virtual host should be used as fallback when no other virtual host
     /// configuration applies
     pub default: bool,
+    /// Names of the listening addresses this virtual host is restricted to
+    ///
+    /// If empty, requests received on any listening address can be handled by this virtual host.
+    pub listeners: OneOrMany<String>,
     /// Maps virtual host's paths to their special configurations
     pub subpaths: HashMap<PathMatcher, SubPathConf<C>>,
     /// Generic handler settings
@@ -21,6 +21,7 @@ use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
 
 use crate::configuration::VirtualHostsConf;
 
@@ -60,10 +61,14 @@ impl<Ctx> DerefMut for VirtualHostsCtx<Ctx> {
     }
 }
 
+/// Virtual host entry in the router: path prefix to strip, virtual host name, listeners the
+/// virtual host is restricted to and the handler
+type Entry<H> = (Option<Path>, String, Vec<String>, H);
+
 /// Virtual Hosts module handler
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VirtualHostsHandler<H: Debug> {
-    handlers: Router<(Option<Path>, H)>,
+    handlers: Router<Entry<H>>,
 }
 
 impl<H: Debug> VirtualHostsHandler<H> {
@@ -77,7 +82,7 @@ impl<H: Debug> VirtualHostsHandler<H> {
         H::Conf: Default,
         H::CTX: Send,
     {
-        self.handlers.retrieve(ctx.index?).map(|(_, h)| h)
+        self.handlers.retrieve(ctx.index?).map(|(_, _, _, h)| h)
     }
 }
 
@@ -110,9 +115,25 @@ where
     ) -> Result<(), Box<Error>> {
         let path = session.uri().path();
         let host = session.host().unwrap_or_default();
+        let listener = session.listener();
+
+        // Virtual hosts restricted to other listeners don’t apply, the default virtual host might
+        let allowed = |(_, _, listeners, _): &Entry<H>| {
+            listeners.is_empty()
+                || listener.is_some_and(|listener| listeners.iter().any(|l| l == listener))
+        };
+        let result = self
+            .handlers
+            .lookup(host.as_ref(), &path)
+            .filter(|result| allowed(result.as_value()))
+            .or_else(|| {
+                self.handlers
+                    .lookup("", &path)
+                    .filter(|result| allowed(result.as_value()))
+            });
 
-        if let Some(result) = self.handlers.lookup(host.as_ref(), &path) {
-            let (strip_path, handler) = result.as_value();
+        if let Some(result) = result {
+            let (strip_path, name, _, handler) = result.as_value();
             let index = result.index();
             let new_path = strip_path
                 .as_ref()
@@ -124,6 +145,8 @@ where
                 session.set_uri(set_uri_path(session.uri(), new_path));
             }
 
+            session.set_virtual_host(name.clone());
+
             handler.early_request_filter(session, ctx).await?;
         }
 
@@ -154,6 +177,23 @@ where
         }
     }
 
+    async fn connected_to_upstream(
+        &self,
+        session: &mut impl SessionWrapper,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler
+                .connected_to_upstream(session, reused, peer, fd, ctx)
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
     async fn logging(
         &self,
         session: &mut impl SessionWrapper,
@@ -178,6 +218,7 @@ where
         let mut default: Option<Vec<String>> = None;
         for (mut hosts, host_conf) in conf.vhosts.into_iter() {
             let handler = host_conf.config.try_into()?;
+            let listeners: Vec<String> = host_conf.listeners.into();
 
             let mut names = BTreeSet::new();
             if host_conf.default {
@@ -201,14 +242,19 @@ where
                     true
                 }
             });
+            // The first host name identifies the virtual host, e.g. in metrics
+            let name = hosts
+                .first()
+                .cloned()
+                .unwrap_or_else(|| "default".to_owned());
             names.extend(hosts);
 
             for host in &names {
                 if handlers.push(
                     host,
                     "",
-                    (None, handler.clone()),
-                    Some((None, handler.clone())),
+                    (None, name.clone(), listeners.clone(), handler.clone()),
+                    Some((None, name.clone(), listeners.clone(), handler.clone())),
                 ) {
                     warn!("overriding existing entry for virtual host {host}");
                 }
@@ -232,11 +278,21 @@ where
                     handlers.push(
                         host,
                         &*rule.path,
-                        (strip_path.cloned(), handler.clone()),
+                        (
+                            strip_path.cloned(),
+                            name.clone(),
+                            listeners.clone(),
+                            handler.clone(),
+                        ),
                         if rule.exact {
                             None
                         } else {
-                            Some((strip_path.cloned(), handler.clone()))
+                            Some((
+                                strip_path.cloned(),
+                                name.clone(),
+                                listeners.clone(),
+                                handler.clone(),
+                            ))
                         },
                     );
                 }
@@ -255,8 +311,9 @@ mod tests {
     use pandora_module_utils::pingora::{
         create_test_session, ErrorType, RequestHeader, ResponseHeader, Session,
     };
-    use pandora_module_utils::FromYaml;
+    use pandora_module_utils::{DeserializeMap, FromYaml};
     use startup_module::DefaultApp;
+    use std::cell::Cell;
     use test_log::test;
     use upstream_module::UpstreamHandler;
 
@@ -326,13 +383,14 @@ mod tests {
     async fn host_alias_match() {
         let mut app = make_app(false);
         let session = make_session("/", Some("[::1]:8080")).await;
-        let result = app
+        let mut result = app
             .handle_request_with_upstream(session, |_, peer| {
                 assert_eq!(peer.sni, "127.0.0.1");
                 Ok(response_header())
             })
             .await;
         assert!(result.err().is_none());
+        assert_eq!(result.session().virtual_host(), Some("localhost:8080"));
     }
 
     #[test(tokio::test)]
@@ -378,24 +436,49 @@ mod tests {
     async fn default_fallback() {
         let mut app = make_app(true);
         let session = make_session("/", Some("example.net")).await;
-        let result = app
+        let mut result = app
             .handle_request_with_upstream(session, |_, peer| {
                 assert_eq!(peer.sni, "127.0.0.1");
                 Ok(response_header())
             })
             .await;
         assert!(result.err().is_none());
+        assert_eq!(result.session().virtual_host(), Some("localhost:8080"));
+    }
+
+    #[test(tokio::test)]
+    async fn default_name() {
+        let mut app: DefaultApp<VirtualHostsHandler<UpstreamHandler>> = DefaultApp::new(
+            <VirtualHostsHandler<UpstreamHandler> as RequestFilter>::Conf::from_yaml(
+                r#"
+                    vhosts:
+                        []:
+                            default: true
+                            upstream: http://127.0.0.1
+                "#,
+            )
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+        let session = make_session("/", Some("example.net")).await;
+        let mut result = app
+            .handle_request_with_upstream(session, |_, _| Ok(response_header()))
+            .await;
+        assert!(result.err().is_none());
+        assert_eq!(result.session().virtual_host(), Some("default"));
     }
 
     #[test(tokio::test)]
     async fn no_default_fallback() {
         let mut app = make_app(false);
         let session = make_session("/", Some("example.net")).await;
-        let result = app.handle_request(session).await;
+        let mut result = app.handle_request(session).await;
         assert_eq!(
             result.err().as_ref().map(|err| &err.etype),
             Some(&ErrorType::HTTPStatus(404))
         );
+        assert_eq!(result.session().virtual_host(), None);
     }
 
     #[test(tokio::test)]
@@ -560,4 +643,110 @@ mod tests {
         assert_eq!(result.session().uri(), "/file.txt/xyz");
         assert_eq!(result.session().original_uri(), "/subdir/file.txt/xyz");
     }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct ListenerConf {
+        listener: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ListenerHandler {
+        listener: String,
+    }
+
+    #[async_trait]
+    impl RequestFilter for ListenerHandler {
+        type Conf = ListenerConf;
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn early_request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<(), Box<Error>> {
+            if !self.listener.is_empty() {
+                session.set_listener(self.listener.clone());
+            }
+            Ok(())
+        }
+    }
+
+    impl TryFrom<ListenerConf> for ListenerHandler {
+        type Error = Box<Error>;
+
+        fn try_from(conf: ListenerConf) -> Result<Self, Self::Error> {
+            Ok(Self {
+                listener: conf.listener,
+            })
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
+    struct ListenerVirtualHostsHandler {
+        listener: ListenerHandler,
+        vhosts: VirtualHostsHandler<UpstreamHandler>,
+    }
+
+    async fn listener_upstream(listener: &str, host: &str) -> Option<String> {
+        let mut app = DefaultApp::<ListenerVirtualHostsHandler>::new(
+            <ListenerVirtualHostsHandler as RequestFilter>::Conf::from_yaml(format!(
+                r#"
+                    listener: "{listener}"
+                    vhosts:
+                        localhost:
+                            default: true
+                            listeners: [public, internal]
+                            upstream: http://127.0.0.1
+                        example.com:
+                            upstream: http://127.0.0.2
+                        internal.example.com:
+                            listeners: internal
+                            upstream: http://127.0.0.3
+                            subpaths:
+                                /subdir/*:
+                                    upstream: http://127.0.0.4
+                "#
+            ))
+            .unwrap()
+            .try_into()
+            .unwrap(),
+        );
+
+        let session = make_session("/subdir/", Some(host)).await;
+        let upstream = Cell::new(None);
+        let _ = app
+            .handle_request_with_upstream(session, |_, peer| {
+                upstream.set(Some(peer.sni.clone()));
+                Ok(response_header())
+            })
+            .await;
+        upstream.into_inner()
+    }
+
+    #[test(tokio::test)]
+    async fn listener_restriction() {
+        assert_eq!(
+            listener_upstream("internal", "internal.example.com")
+                .await
+                .as_deref(),
+            Some("127.0.0.4")
+        );
+        assert_eq!(
+            listener_upstream("public", "internal.example.com")
+                .await
+                .as_deref(),
+            Some("127.0.0.1")
+        );
+        assert_eq!(
+            listener_upstream("public", "example.com").await.as_deref(),
+            Some("127.0.0.2")
+        );
+        assert_eq!(
+            listener_upstream("", "example.com").await.as_deref(),
+            Some("127.0.0.2")
+        );
+        assert_eq!(listener_upstream("", "internal.example.com").await, None);
+        assert_eq!(listener_upstream("other", "localhost").await, None);
+    }
 }